@@ -3,29 +3,106 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use clap::{Parser, Subcommand};
-
-use crate::client::{load_balancing, worker::WorkerType};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    client::{
+        load_balancing::{self, HashKey},
+        merge_options, parse_options_table,
+        worker::WorkerType,
+        ByteSize, HealthCheckConfig, QueueConfig, ROption, RecycleConfig, R_OPTIONS_ENV,
+    },
+    config::{ConfigFile, DEFAULT_CONFIG_FILE},
+    error::{FaucetError, FaucetResult},
+    server::{AdminConfig, LbCookieConfig, MetricsConfig, SameSite, TlsConfig},
+};
 
-fn is_plumber(dir: &Path) -> bool {
+pub(crate) fn is_plumber(dir: &Path) -> bool {
     let plumber = dir.join("plumber.R");
     let plumber_entrypoint = dir.join("entrypoint.R");
     plumber.exists() || plumber_entrypoint.exists()
 }
 
-fn is_shiny(dir: &Path) -> bool {
+/// plumber2 apps are described by a `_server.yml` using the plumber2 engine.
+pub(crate) fn is_plumber2(dir: &Path) -> bool {
+    let Ok(server) = std::fs::read_to_string(dir.join("_server.yml")) else {
+        return false;
+    };
+    server.lines().any(|line| {
+        line.split_once(':').is_some_and(|(key, value)| {
+            key.trim() == "engine" && value.trim().trim_matches(['"', '\'']) == "plumber2"
+        })
+    })
+}
+
+/// Whether the R script at `path` uses `package`.
+fn uses_package(path: &Path, package: &str) -> bool {
+    let Ok(source) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    [
+        format!("library({package})"),
+        format!("library(\"{package}\")"),
+        format!("require({package})"),
+        format!("{package}::"),
+    ]
+    .iter()
+    .any(|usage| source.contains(usage.as_str()))
+}
+
+/// ambiorix apps are started from an `app.R` using ambiorix.
+pub(crate) fn is_ambiorix(dir: &Path) -> bool {
+    uses_package(&dir.join("app.R"), "ambiorix")
+}
+
+pub(crate) fn is_shiny(dir: &Path) -> bool {
     let shiny_app = dir.join("app.R");
     let shiny_ui = dir.join("ui.R");
     let shiny_server = dir.join("server.R");
     shiny_app.exists() || (shiny_ui.exists() && shiny_server.exists())
 }
 
+/// Whether the Python script at `path` imports `module`.
+fn imports(path: &Path, module: &str) -> bool {
+    let Ok(source) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    source.lines().map(str::trim_start).any(|line| {
+        [format!("import {module}"), format!("from {module}")]
+            .iter()
+            .any(|statement| {
+                line.strip_prefix(statement.as_str())
+                    .is_some_and(|rest| rest.starts_with([' ', '.', ',']) || rest.is_empty())
+            })
+    })
+}
+
+/// The script a Streamlit app is run from: `streamlit_app.py`, or an
+/// `app.py` importing streamlit.
+pub(crate) fn streamlit_script(dir: &Path) -> Option<PathBuf> {
+    let streamlit_app = dir.join("streamlit_app.py");
+    if streamlit_app.is_file() {
+        return Some(streamlit_app);
+    }
+    let app = dir.join("app.py");
+    imports(&app, "streamlit").then_some(app)
+}
+
+/// Dash apps are run from an `app.py` importing dash.
+pub(crate) fn is_dash(dir: &Path) -> bool {
+    imports(&dir.join("app.py"), "dash")
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
 enum ServerType {
     FastAPI,
     Plumber,
+    Plumber2,
+    Ambiorix,
     Shiny,
     QuartoShiny,
+    Streamlit,
+    Dash,
     Auto,
 }
 
@@ -41,6 +118,15 @@ pub enum Strategy {
     CookieHash,
     /// Round-robin with RPS (Requests Per Second) scaling.
     Rps,
+    /// Sends requests to the worker with the fewest requests and WebSocket
+    /// sessions in flight.
+    LeastConnections,
+    /// Like least connections, but compares two random workers. Meant for
+    /// large numbers of workers.
+    PowerOfTwo,
+    /// Hashes a header, cookie or JWT claim that identifies the user, so
+    /// each user keeps their worker across devices and networks.
+    HeaderHash,
 }
 
 impl From<Strategy> for load_balancing::Strategy {
@@ -50,6 +136,9 @@ impl From<Strategy> for load_balancing::Strategy {
             Strategy::IpHash => load_balancing::Strategy::IpHash,
             Strategy::CookieHash => load_balancing::Strategy::CookieHash,
             Strategy::Rps => load_balancing::Strategy::Rps,
+            Strategy::LeastConnections => load_balancing::Strategy::LeastConnections,
+            Strategy::PowerOfTwo => load_balancing::Strategy::PowerOfTwo,
+            Strategy::HeaderHash => load_balancing::Strategy::HeaderHash,
         }
     }
 }
@@ -101,13 +190,138 @@ pub struct StartArgs {
     #[arg(long, short, env = "FAUCET_APP_DIR", default_value = None)]
     pub app_dir: Option<String>,
 
+    /// File the app is started from, relative to `--app-dir`, for Plumber,
+    /// plumber2 and ambiorix. Defaults depend on the type.
+    #[arg(long, env = "FAUCET_ENTRYPOINT", default_value = None)]
+    pub entrypoint: Option<String>,
+
+    /// R option set before the app starts, as `NAME=VALUE`. Can be given
+    /// more than once. FAUCET_R_OPTIONS holds more, as the inside of a TOML
+    /// inline table: '"plumber.docs" = false, "plumber.apiPath" = "/v1"'.
+    #[arg(long = "r-option", value_name = "NAME=VALUE")]
+    pub r_options: Vec<ROption>,
+
     /// Quarto Shiny file path.
     #[arg(long, short, env = "FAUCET_QMD", default_value = None)]
     pub qmd: Option<PathBuf>,
 
+    /// Directory of static files served directly by faucet, such as a Shiny
+    /// app's `www` folder. Relative to `--dir`.
+    #[arg(long, env = "FAUCET_STATIC_DIR", default_value = None)]
+    pub static_dir: Option<PathBuf>,
+
+    /// Restart the workers when `.R`, `.py`, `.qmd` or `renv.lock` files
+    /// change. Meant for development.
+    #[arg(long, env = "FAUCET_WATCH")]
+    pub watch: bool,
+
     /// The maximum requests per second for the RPS autoscaler strategy.
     #[arg(long, env = "FAUCET_MAX_RPS", default_value = None)]
     pub max_rps: Option<f64>,
+
+    /// Actively check the workers' health over HTTP.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK")]
+    pub health_check: bool,
+
+    /// Path requested by the health check. Defaults depend on the worker type.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_PATH", default_value = None, requires = "health_check")]
+    pub health_check_path: Option<String>,
+
+    /// Status code a healthy worker answers with. Defaults to any 2xx or 3xx.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_STATUS", default_value = None, requires = "health_check")]
+    pub health_check_status: Option<u16>,
+
+    /// Seconds between health checks.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_INTERVAL", default_value = None, requires = "health_check")]
+    pub health_check_interval: Option<f64>,
+
+    /// Seconds to wait for a health check response.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_TIMEOUT", default_value = None, requires = "health_check")]
+    pub health_check_timeout: Option<f64>,
+
+    /// Consecutive failed checks before a worker stops receiving traffic.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_UNHEALTHY_THRESHOLD", default_value = None, requires = "health_check")]
+    pub health_check_unhealthy_threshold: Option<u32>,
+
+    /// Consecutive successful checks before a worker receives traffic again.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_HEALTHY_THRESHOLD", default_value = None, requires = "health_check")]
+    pub health_check_healthy_threshold: Option<u32>,
+
+    /// Consecutive failed checks before a worker is restarted. Not set means never.
+    #[arg(long, env = "FAUCET_HEALTH_CHECK_RESTART_AFTER", default_value = None, requires = "health_check")]
+    pub health_check_restart_after: Option<u32>,
+
+    /// Recycle a worker once its processes use this much memory, such as `2G`.
+    #[arg(long, env = "FAUCET_RECYCLE_MAX_MEMORY", default_value = None, value_parser = |s: &str| parse_size::parse_size(s))]
+    pub recycle_max_memory: Option<u64>,
+
+    /// Recycle a worker's process after it served this many requests.
+    #[arg(long, env = "FAUCET_RECYCLE_MAX_REQUESTS", default_value = None)]
+    pub recycle_max_requests: Option<u64>,
+
+    /// Recycle a worker's process after it ran for this many seconds.
+    #[arg(long, env = "FAUCET_RECYCLE_MAX_AGE", default_value = None)]
+    pub recycle_max_age: Option<f64>,
+
+    /// Seconds between samples of the memory and CPU of the workers.
+    #[arg(long, env = "FAUCET_RECYCLE_INTERVAL", default_value = None)]
+    pub recycle_interval: Option<f64>,
+
+    /// Requests each worker serves at once. Other requests wait in a queue.
+    #[arg(long, env = "FAUCET_QUEUE_MAX_CONCURRENCY", default_value = None)]
+    pub queue_max_concurrency: Option<usize>,
+
+    /// Requests that may wait for a worker before faucet answers with 503.
+    #[arg(long, env = "FAUCET_QUEUE_MAX_LENGTH", default_value = None, requires = "queue_max_concurrency")]
+    pub queue_max_length: Option<usize>,
+
+    /// Seconds a request may wait for a worker before faucet answers with 503.
+    #[arg(long, env = "FAUCET_QUEUE_TIMEOUT", default_value = None, requires = "queue_max_concurrency")]
+    pub queue_timeout: Option<f64>,
+
+    /// Header that identifies the user for the header-hash strategy.
+    #[arg(long, env = "FAUCET_HASH_HEADER", default_value = None, conflicts_with_all = ["hash_cookie", "hash_jwt_claim"])]
+    pub hash_header: Option<String>,
+
+    /// Cookie that identifies the user for the header-hash strategy.
+    #[arg(long, env = "FAUCET_HASH_COOKIE", default_value = None, conflicts_with = "hash_jwt_claim")]
+    pub hash_cookie: Option<String>,
+
+    /// Claim of the bearer JWT that identifies the user for the header-hash
+    /// strategy. The token is not verified.
+    #[arg(long, env = "FAUCET_HASH_JWT_CLAIM", default_value = None)]
+    pub hash_jwt_claim: Option<String>,
+
+    /// Name of the cookie of the cookie-hash strategy.
+    #[arg(long, env = "FAUCET_LB_COOKIE_NAME", default_value = None)]
+    pub lb_cookie_name: Option<String>,
+
+    /// Path of the cookie of the cookie-hash strategy. Defaults to `/`.
+    #[arg(long, env = "FAUCET_LB_COOKIE_PATH", default_value = None)]
+    pub lb_cookie_path: Option<String>,
+
+    /// Domain of the cookie of the cookie-hash strategy.
+    #[arg(long, env = "FAUCET_LB_COOKIE_DOMAIN", default_value = None)]
+    pub lb_cookie_domain: Option<String>,
+
+    /// Only send the cookie of the cookie-hash strategy over HTTPS.
+    #[arg(long, env = "FAUCET_LB_COOKIE_SECURE")]
+    pub lb_cookie_secure: bool,
+
+    /// SameSite attribute of the cookie of the cookie-hash strategy.
+    #[arg(long, env = "FAUCET_LB_COOKIE_SAME_SITE", default_value = None)]
+    pub lb_cookie_same_site: Option<SameSite>,
+
+    /// Seconds the cookie of the cookie-hash strategy is kept. Defaults to
+    /// until the browser closes.
+    #[arg(long, env = "FAUCET_LB_COOKIE_MAX_AGE", default_value = None)]
+    pub lb_cookie_max_age: Option<u64>,
+
+    /// Key of at least 32 bytes the cookie of the cookie-hash strategy is
+    /// signed with. Defaults to a random key, so cookies do not survive a
+    /// restart.
+    #[arg(long, env = "FAUCET_LB_COOKIE_SECRET", default_value = None)]
+    pub lb_cookie_secret: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -122,6 +336,15 @@ pub struct RouterArgs {
     pub conf: PathBuf,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// Validate the configuration and print the effective options, merged
+    /// from the command line, the environment and the config file.
+    Check,
+}
+
+// Parsed once at startup, the size of `StartArgs` does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Start a simple faucet server.
@@ -132,6 +355,19 @@ pub enum Commands {
     #[command(name = "router")]
     Router(RouterArgs),
 
+    /// Replace the workers of a running faucet one by one without dropping
+    /// connections. Talks to the admin API given by `--admin-host`.
+    #[command(name = "rolling-restart")]
+    RollingRestart {
+        /// Only restart the workers of this route (router mode).
+        #[arg(long)]
+        route: Option<String>,
+    },
+
+    /// Inspect the faucet configuration.
+    #[command(name = "config", subcommand)]
+    Config(ConfigCommands),
+
     /// Run an Rscript through faucet.
     Rscript {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
@@ -181,6 +417,11 @@ pub struct Args {
     #[command(subcommand)]
     pub command: Commands,
 
+    /// Configuration file. Defaults to `faucet.toml` if it exists. Options
+    /// given on the command line or the environment take precedence.
+    #[arg(long, env = "FAUCET_CONFIG", default_value = None)]
+    pub config: Option<PathBuf>,
+
     /// The host to bind to.
     #[arg(long, env = "FAUCET_HOST", default_value = "127.0.0.1:3838")]
     pub host: String,
@@ -241,20 +482,195 @@ pub struct Args {
     /// Represents the source code version of the service to run. This is useful for telemetry.
     #[arg(long, env = "FAUCET_TELEMETRY_VERSION", default_value = None)]
     pub telemetry_version: Option<String>,
+
+    /// Path to a PEM encoded TLS certificate (chain). Enables HTTPS on the listener.
+    #[arg(long, env = "FAUCET_TLS_CERT", default_value = None, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key of the TLS certificate.
+    #[arg(long, env = "FAUCET_TLS_KEY", default_value = None, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// The host to bind the admin API to. The admin API is disabled if not set.
+    #[arg(long, env = "FAUCET_ADMIN_HOST", default_value = None)]
+    pub admin_host: Option<String>,
+
+    /// Bearer token required by the admin API.
+    #[arg(long, env = "FAUCET_ADMIN_TOKEN", default_value = None, requires = "admin_host")]
+    pub admin_token: Option<String>,
+
+    /// Seconds a worker process replaced by a rolling restart may keep
+    /// serving its open connections before it is killed.
+    #[arg(long, env = "FAUCET_DRAIN_TIMEOUT", default_value = None)]
+    pub drain_timeout: Option<f64>,
+
+    /// The host to serve Prometheus metrics on, without authentication.
+    /// Metrics are also available on the admin API.
+    #[arg(long, env = "FAUCET_METRICS_HOST", default_value = None)]
+    pub metrics_host: Option<String>,
+}
+
+impl Args {
+    /// Parses the arguments, taking the options not given on the command
+    /// line or the environment from the configuration file.
+    pub fn parse_with_config_file() -> FaucetResult<Self> {
+        let mut args = Args::parse();
+        let config = match args.config_file()? {
+            Some(path) => {
+                let config = ConfigFile::load(&path)?;
+                let matches = config.apply(&path, Args::command()).get_matches();
+                args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+                Some(config)
+            }
+            None => None,
+        };
+        if let Commands::Start(start) = &mut args.command {
+            match &config {
+                Some(config) => config.fill_start_args(start)?,
+                None => start.merge_r_options(Vec::new())?,
+            }
+        }
+        Ok(args)
+    }
+    /// The configuration file to read, if any.
+    pub fn config_file(&self) -> FaucetResult<Option<PathBuf>> {
+        match &self.config {
+            Some(path) if !path.is_file() => Err(FaucetError::InvalidConfig(format!(
+                "Config file {} does not exist",
+                path.display()
+            ))),
+            Some(path) => Ok(Some(path.clone())),
+            None => {
+                let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+                Ok(default.is_file().then_some(default))
+            }
+        }
+    }
+    pub fn tls(&self) -> Option<TlsConfig> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig::new(cert, key)),
+            _ => None,
+        }
+    }
+    pub fn admin(&self) -> FaucetResult<Option<AdminConfig>> {
+        self.admin_host
+            .as_ref()
+            .map(|host| Ok(AdminConfig::new(host.parse()?, self.admin_token.clone())))
+            .transpose()
+    }
+    pub fn metrics(&self) -> FaucetResult<Option<MetricsConfig>> {
+        self.metrics_host
+            .as_ref()
+            .map(|host| {
+                Ok(MetricsConfig {
+                    host: host.parse()?,
+                })
+            })
+            .transpose()
+    }
 }
 
 impl StartArgs {
+    /// Adds the R options of the environment and of the configuration
+    /// file, `file`. An option on the command line wins over the same
+    /// option in the environment, and that one over the file.
+    pub fn merge_r_options(&mut self, file: Vec<ROption>) -> FaucetResult<()> {
+        let env = match std::env::var(R_OPTIONS_ENV) {
+            Ok(options) => parse_options_table(&options)?,
+            Err(std::env::VarError::NotPresent) => Vec::new(),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                return Err(FaucetError::InvalidConfig(format!(
+                    "{R_OPTIONS_ENV} is not valid UTF-8"
+                )))
+            }
+        };
+        self.r_options = merge_options([file, env, std::mem::take(&mut self.r_options)]);
+        Ok(())
+    }
+    pub fn health_check(&self) -> Option<HealthCheckConfig> {
+        if !self.health_check {
+            return None;
+        }
+        let defaults = HealthCheckConfig::default();
+        Some(HealthCheckConfig {
+            path: self.health_check_path.clone(),
+            expected_status: self.health_check_status,
+            interval: self.health_check_interval.unwrap_or(defaults.interval),
+            timeout: self.health_check_timeout.unwrap_or(defaults.timeout),
+            unhealthy_threshold: self
+                .health_check_unhealthy_threshold
+                .unwrap_or(defaults.unhealthy_threshold),
+            healthy_threshold: self
+                .health_check_healthy_threshold
+                .unwrap_or(defaults.healthy_threshold),
+            restart_after: self.health_check_restart_after,
+        })
+    }
+    pub fn recycle(&self) -> Option<RecycleConfig> {
+        let recycle = RecycleConfig {
+            max_memory: self.recycle_max_memory.map(ByteSize),
+            max_requests: self.recycle_max_requests,
+            max_age: self.recycle_max_age,
+            interval: self.recycle_interval,
+        };
+        (recycle != RecycleConfig::default()).then_some(recycle)
+    }
+    pub fn queue(&self) -> Option<QueueConfig> {
+        self.queue_max_concurrency
+            .map(|max_concurrency| QueueConfig {
+                max_concurrency: Some(max_concurrency),
+                max_length: self.queue_max_length,
+                timeout: self.queue_timeout,
+            })
+    }
+    pub fn hash_key(&self) -> Option<HashKey> {
+        let header = self.hash_header.clone().map(HashKey::Header);
+        let cookie = self.hash_cookie.clone().map(HashKey::Cookie);
+        let jwt_claim = self.hash_jwt_claim.clone().map(HashKey::JwtClaim);
+        header.or(cookie).or(jwt_claim)
+    }
+    pub fn lb_cookie(&self) -> Option<LbCookieConfig> {
+        let lb_cookie = LbCookieConfig {
+            name: self.lb_cookie_name.clone(),
+            path: self.lb_cookie_path.clone(),
+            domain: self.lb_cookie_domain.clone(),
+            secure: self.lb_cookie_secure.then_some(true),
+            same_site: self.lb_cookie_same_site,
+            max_age: self.lb_cookie_max_age,
+            secret: self.lb_cookie_secret.clone(),
+        };
+        (lb_cookie != LbCookieConfig::default()).then_some(lb_cookie)
+    }
+    /// The worker type as given, without resolving `auto`.
+    pub(crate) fn server_type_name(&self) -> String {
+        self.type_
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
     pub fn server_type(&self) -> WorkerType {
         match self.type_ {
             ServerType::FastAPI => WorkerType::FastAPI,
             ServerType::Plumber => WorkerType::Plumber,
+            ServerType::Plumber2 => WorkerType::Plumber2,
+            ServerType::Ambiorix => WorkerType::Ambiorix,
             ServerType::Shiny => WorkerType::Shiny,
             ServerType::QuartoShiny => WorkerType::QuartoShiny,
+            ServerType::Streamlit => WorkerType::Streamlit,
+            ServerType::Dash => WorkerType::Dash,
             ServerType::Auto => {
-                if is_plumber(&self.dir) {
+                if is_plumber2(&self.dir) {
+                    WorkerType::Plumber2
+                } else if is_plumber(&self.dir) {
                     WorkerType::Plumber
+                } else if is_ambiorix(&self.dir) {
+                    WorkerType::Ambiorix
                 } else if is_shiny(&self.dir) {
                     WorkerType::Shiny
+                } else if streamlit_script(&self.dir).is_some() {
+                    WorkerType::Streamlit
+                } else if is_dash(&self.dir) {
+                    WorkerType::Dash
                 } else {
                     log::error!(target: "faucet", "Could not determine worker type. Please specify with --type.");
                     std::process::exit(1);
@@ -263,3 +679,54 @@ impl StartArgs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn detect_python_apps() {
+        let dir = TempDir::new("faucet-cli");
+        assert_eq!(streamlit_script(&dir), None);
+        assert!(!is_dash(&dir));
+
+        std::fs::write(
+            dir.join("app.py"),
+            "from dash import Dash, html\nimport dashboard_utils\n",
+        )
+        .unwrap();
+        assert_eq!(streamlit_script(&dir), None);
+        assert!(is_dash(&dir));
+
+        std::fs::write(dir.join("app.py"), "import streamlit as st\n").unwrap();
+        assert_eq!(streamlit_script(&dir), Some(dir.join("app.py")));
+        assert!(!is_dash(&dir));
+
+        std::fs::write(dir.join("streamlit_app.py"), "").unwrap();
+        assert_eq!(streamlit_script(&dir), Some(dir.join("streamlit_app.py")));
+    }
+
+    #[test]
+    fn detect_r_apps() {
+        let dir = TempDir::new("faucet-cli");
+
+        std::fs::write(dir.join("app.R"), "library(shiny)\n").unwrap();
+        assert!(!is_ambiorix(&dir));
+        std::fs::write(
+            dir.join("app.R"),
+            "app <- ambiorix::Ambiorix$new()\napp$start()\n",
+        )
+        .unwrap();
+        assert!(is_ambiorix(&dir));
+
+        std::fs::write(dir.join("_server.yml"), "engine: plumber\n").unwrap();
+        assert!(!is_plumber2(&dir));
+        std::fs::write(
+            dir.join("_server.yml"),
+            "engine: 'plumber2'\nroutes:\n  - api.R\n",
+        )
+        .unwrap();
+        assert!(is_plumber2(&dir));
+    }
+}
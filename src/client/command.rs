@@ -0,0 +1,184 @@
+//! Workers started from a command given in the configuration, so any
+//! process serving HTTP on the port faucet gives it can be load balanced.
+
+use super::health::{HealthCheck, HealthCheckConfig};
+use super::worker::WorkerType;
+use crate::error::{FaucetError, FaucetResult};
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+const PLACEHOLDER_PORT: &str = "{port}";
+const PLACEHOLDER_HOST: &str = "{host}";
+const PLACEHOLDER_WORKER_ID: &str = "{worker_id}";
+
+/// The `command` table of a route with `server_type = "command"`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandConfig {
+    /// Program and arguments. `{port}`, `{host}` and `{worker_id}` are
+    /// replaced for each worker.
+    pub argv: Vec<String>,
+    /// Environment variables added to the process, with the same
+    /// placeholders as `argv`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Path requested to know whether the process is ready. If not set the
+    /// process is ready once it accepts connections.
+    pub ready_path: Option<String>,
+    /// Seconds the process has to become ready before it is restarted. If
+    /// not set faucet waits for as long as it takes.
+    pub ready_timeout: Option<f64>,
+}
+
+/// A [`CommandConfig`] ready to start processes.
+#[derive(Debug)]
+pub struct WorkerCommand {
+    argv: Vec<String>,
+    env: Vec<(String, String)>,
+    ready: Option<HealthCheck>,
+    pub ready_timeout: Option<Duration>,
+}
+
+fn expand(template: &str, addr: SocketAddr, worker_id: usize) -> String {
+    template
+        .replace(PLACEHOLDER_PORT, &addr.port().to_string())
+        .replace(PLACEHOLDER_HOST, &addr.ip().to_string())
+        .replace(PLACEHOLDER_WORKER_ID, &worker_id.to_string())
+}
+
+impl CommandConfig {
+    pub(crate) fn resolve(&self) -> FaucetResult<WorkerCommand> {
+        let invalid = |message: &str| FaucetError::InvalidConfig(format!("command {message}"));
+        if self.argv.first().map_or(true, String::is_empty) {
+            return Err(invalid("`argv` must start with the program to run"));
+        }
+        // Without its port the process would listen somewhere faucet does not know.
+        if !self
+            .argv
+            .iter()
+            .chain(self.env.values())
+            .any(|value| value.contains(PLACEHOLDER_PORT))
+        {
+            return Err(invalid(
+                "must pass `{port}` to the process in `argv` or `env`",
+            ));
+        }
+        let ready = self
+            .ready_path
+            .as_ref()
+            .map(|path| {
+                HealthCheckConfig {
+                    path: Some(path.clone()),
+                    ..Default::default()
+                }
+                .resolve(WorkerType::Command)
+                .map_err(|_| invalid(&format!("ready_path {path:?} must start with '/'")))
+            })
+            .transpose()?;
+        let ready_timeout = self
+            .ready_timeout
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs)
+                    .ok()
+                    .filter(|timeout| !timeout.is_zero())
+                    .ok_or_else(|| invalid("ready_timeout must be a positive number of seconds"))
+            })
+            .transpose()?;
+        Ok(WorkerCommand {
+            argv: self.argv.clone(),
+            env: self
+                .env
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            ready,
+            ready_timeout,
+        })
+    }
+}
+
+impl WorkerCommand {
+    /// The process of worker `worker_id`, listening on `addr`.
+    pub(crate) fn process(&self, addr: SocketAddr, worker_id: usize) -> tokio::process::Command {
+        let mut argv = self.argv.iter().map(|arg| expand(arg, addr, worker_id));
+        let program = argv.next().expect("Commands always have a program");
+        let mut cmd = tokio::process::Command::new(program);
+        cmd.args(argv).envs(
+            self.env
+                .iter()
+                .map(|(key, value)| (key, expand(value, addr, worker_id))),
+        );
+        cmd
+    }
+
+    /// Whether the process on `addr` answers its readiness check, if it
+    /// has one.
+    pub(crate) async fn is_ready(&self, addr: SocketAddr) -> Option<bool> {
+        let ready = self.ready.as_ref()?;
+        Some(ready.probe(addr).await.is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(source: &str) -> CommandConfig {
+        toml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn placeholders_are_replaced() {
+        let command = config(
+            r#"
+            argv = ["gunicorn", "--bind", "{host}:{port}", "app:server"]
+            env = { WORKER = "w{worker_id}" }
+            "#,
+        )
+        .resolve()
+        .unwrap();
+        let process = command.process("127.0.0.1:8123".parse().unwrap(), 2);
+        let std = process.as_std();
+        assert_eq!(std.get_program(), "gunicorn");
+        assert_eq!(
+            std.get_args().collect::<Vec<_>>(),
+            ["--bind", "127.0.0.1:8123", "app:server"]
+        );
+        assert_eq!(
+            std.get_envs().collect::<Vec<_>>(),
+            [("WORKER".as_ref(), Some("w2".as_ref()))]
+        );
+    }
+
+    #[test]
+    fn invalid_commands() {
+        for (source, message) in [
+            ("argv = []", "must start with the program"),
+            (
+                r#"argv = ["streamlit", "run", "app.py"]"#,
+                "must pass `{port}`",
+            ),
+            (
+                r#"argv = ["app", "{port}"]
+                ready_path = "health""#,
+                "must start with '/'",
+            ),
+            (
+                r#"argv = ["app", "{port}"]
+                ready_timeout = 0"#,
+                "ready_timeout",
+            ),
+        ] {
+            let error = config(source).resolve().unwrap_err().to_string();
+            assert!(error.contains(message), "{source}: {error}");
+        }
+        // The port can also be passed in the environment.
+        config(
+            r#"
+            argv = ["node", "server.js"]
+            env = { PORT = "{port}" }
+            "#,
+        )
+        .resolve()
+        .unwrap();
+    }
+}
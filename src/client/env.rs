@@ -0,0 +1,139 @@
+//! Environment variables given to the processes of a route's workers, on
+//! top of the environment faucet inherits.
+
+use crate::error::{FaucetError, FaucetResult};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Variables holding faucet's own credentials, which workers never inherit.
+const SCRUBBED: &[&str] = &[
+    "FAUCET_TELEMETRY_POSTGRES_STRING",
+    "FAUCET_ADMIN_TOKEN",
+    "FAUCET_LB_COOKIE_SECRET",
+];
+
+#[derive(Debug, Default)]
+pub struct WorkerEnv {
+    env_file: Option<PathBuf>,
+    vars: Vec<(String, String)>,
+    secrets: Vec<(String, PathBuf)>,
+}
+
+/// Contents of a secret file, without the trailing newline most tools add.
+fn read_secret(name: &str, path: &Path) -> FaucetResult<String> {
+    let secret = std::fs::read_to_string(path).map_err(|e| {
+        FaucetError::InvalidConfig(format!("Unable to read secret {name} from {path:?}: {e}"))
+    })?;
+    Ok(secret.trim_end_matches(['\n', '\r']).to_string())
+}
+
+impl WorkerEnv {
+    /// `env_file` and the files of `secrets` are relative to `workdir`.
+    pub(crate) fn new(
+        workdir: &Path,
+        env_file: Option<&Path>,
+        vars: BTreeMap<String, String>,
+        secrets: BTreeMap<String, PathBuf>,
+    ) -> Self {
+        WorkerEnv {
+            env_file: env_file.map(|file| workdir.join(file)),
+            vars: vars.into_iter().collect(),
+            secrets: secrets
+                .into_iter()
+                .map(|(name, file)| (name, workdir.join(file)))
+                .collect(),
+        }
+    }
+
+    /// The variables to add, read again for every process so that rotated
+    /// secrets are picked up on restart. Later sources take precedence:
+    /// `env_file`, then `env`, then `secrets`.
+    pub(crate) fn vars(&self) -> FaucetResult<Vec<(String, String)>> {
+        let mut vars = Vec::new();
+        if let Some(env_file) = &self.env_file {
+            let invalid = |e: dotenv::Error| {
+                FaucetError::InvalidConfig(format!("Unable to read env_file {env_file:?}: {e}"))
+            };
+            // The replacement for the iterator loads the file into faucet's
+            // own environment, which every route would then share.
+            #[allow(deprecated)]
+            let file = dotenv::from_path_iter(env_file).map_err(invalid)?;
+            for var in file {
+                vars.push(var.map_err(invalid)?);
+            }
+        }
+        vars.extend(self.vars.iter().cloned());
+        for (name, path) in &self.secrets {
+            vars.push((name.clone(), read_secret(name, path)?));
+        }
+        Ok(vars)
+    }
+
+    /// Whether a worker's process gets `name`, from the route or from
+    /// faucet's own environment.
+    pub(crate) fn defines(&self, name: &str) -> FaucetResult<bool> {
+        let inherited = std::env::var_os(name).is_some() && !SCRUBBED.contains(&name);
+        Ok(inherited || self.vars()?.iter().any(|(var, _)| var == name))
+    }
+
+    /// Sets the environment of a worker's process.
+    pub(crate) fn apply(&self, cmd: &mut tokio::process::Command) -> FaucetResult<()> {
+        for name in SCRUBBED {
+            cmd.env_remove(name);
+        }
+        cmd.envs(self.vars()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn vars_from_every_source() {
+        let dir = TempDir::new("faucet-env");
+        std::fs::write(
+            dir.join(".env.app"),
+            "API_URL=http://localhost\nMODE=file\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("db_password"), "s3cret\n").unwrap();
+
+        let env = WorkerEnv::new(
+            &dir,
+            Some(Path::new(".env.app")),
+            [("MODE".to_string(), "table".to_string())].into(),
+            [("DB_PASSWORD".to_string(), PathBuf::from("db_password"))].into(),
+        );
+        let mut vars = env.vars().unwrap();
+        vars.sort();
+        assert_eq!(
+            vars,
+            [
+                ("API_URL", "http://localhost"),
+                ("DB_PASSWORD", "s3cret"),
+                ("MODE", "file"),
+                ("MODE", "table"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+
+        let mut cmd = tokio::process::Command::new("true");
+        env.apply(&mut cmd).unwrap();
+        let envs = cmd.as_std().get_envs().collect::<BTreeMap<_, _>>();
+        let get = |name: &str| envs[std::ffi::OsStr::new(name)];
+        assert_eq!(get("MODE"), Some(std::ffi::OsStr::new("table")));
+        assert_eq!(get("FAUCET_TELEMETRY_POSTGRES_STRING"), None);
+        assert_eq!(get("FAUCET_LB_COOKIE_SECRET"), None);
+        assert!(env.defines("API_URL").unwrap());
+        assert!(!env.defines("FAUCET_WORKER_ENV_UNSET").unwrap());
+
+        std::fs::remove_file(dir.join("db_password")).unwrap();
+        let error = env.vars().unwrap_err().to_string();
+        assert!(error.contains("DB_PASSWORD"), "{error}");
+    }
+}
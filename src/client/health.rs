@@ -0,0 +1,363 @@
+use super::worker::{WorkerConfig, WorkerType};
+use crate::error::{FaucetError, FaucetResult};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{header, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::{net::SocketAddr, sync::atomic::Ordering, time::Duration};
+use tokio::net::TcpStream;
+
+const DEFAULT_INTERVAL_SECS: f64 = 10.0;
+const DEFAULT_TIMEOUT_SECS: f64 = 5.0;
+const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+const DEFAULT_HEALTHY_THRESHOLD: u32 = 2;
+
+/// User facing health check configuration. Every field is optional so the
+/// same struct can be filled from the CLI or from a `[route.health_check]`
+/// table in `frouter.toml`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// Path to request on the worker. Defaults depend on the worker type.
+    pub path: Option<String>,
+    /// Status code the worker must answer with. If not set any 2xx or 3xx
+    /// status is considered healthy.
+    pub expected_status: Option<u16>,
+    /// Seconds between checks.
+    pub interval: f64,
+    /// Seconds to wait for a response before counting the check as failed.
+    pub timeout: f64,
+    /// Consecutive failures before the worker stops receiving traffic.
+    pub unhealthy_threshold: u32,
+    /// Consecutive successes before an unhealthy worker receives traffic again.
+    pub healthy_threshold: u32,
+    /// Consecutive failures before the worker process is restarted.
+    pub restart_after: Option<u32>,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            path: None,
+            expected_status: None,
+            interval: DEFAULT_INTERVAL_SECS,
+            timeout: DEFAULT_TIMEOUT_SECS,
+            unhealthy_threshold: DEFAULT_UNHEALTHY_THRESHOLD,
+            healthy_threshold: DEFAULT_HEALTHY_THRESHOLD,
+            restart_after: None,
+        }
+    }
+}
+
+fn default_path(wtype: WorkerType) -> &'static str {
+    match wtype {
+        WorkerType::Shiny
+        | WorkerType::QuartoShiny
+        | WorkerType::Upstream
+        | WorkerType::Command => "/",
+        WorkerType::Plumber => "/openapi.json",
+        WorkerType::Plumber2 => "/__docs__/openapi.json",
+        WorkerType::Ambiorix => "/",
+        WorkerType::FastAPI => "/docs",
+        WorkerType::Streamlit => "/_stcore/health",
+        WorkerType::Dash => "/",
+        #[cfg(test)]
+        WorkerType::Dummy => "/",
+    }
+}
+
+fn duration_from_secs(secs: f64, field: &str) -> FaucetResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| {
+            FaucetError::InvalidConfig(format!(
+                "health check {field} must be a positive number of seconds"
+            ))
+        })
+}
+
+impl HealthCheckConfig {
+    pub(crate) fn resolve(&self, wtype: WorkerType) -> FaucetResult<HealthCheck> {
+        let path = self
+            .path
+            .clone()
+            .unwrap_or_else(|| default_path(wtype).to_string());
+        if !path.starts_with('/') {
+            return Err(FaucetError::InvalidConfig(format!(
+                "health check path '{path}' must start with '/'"
+            )));
+        }
+        let expected_status = self
+            .expected_status
+            .map(StatusCode::from_u16)
+            .transpose()
+            .map_err(|e| FaucetError::InvalidConfig(format!("health check status: {e}")))?;
+        if self.unhealthy_threshold == 0 || self.healthy_threshold == 0 {
+            return Err(FaucetError::InvalidConfig(
+                "health check thresholds must be greater than 0".to_string(),
+            ));
+        }
+        if self.restart_after == Some(0) {
+            return Err(FaucetError::InvalidConfig(
+                "health check restart_after must be greater than 0".to_string(),
+            ));
+        }
+        Ok(HealthCheck {
+            path,
+            expected_status,
+            interval: duration_from_secs(self.interval, "interval")?,
+            timeout: duration_from_secs(self.timeout, "timeout")?,
+            unhealthy_threshold: self.unhealthy_threshold,
+            healthy_threshold: self.healthy_threshold,
+            restart_after: self.restart_after,
+        })
+    }
+}
+
+/// Health check ready to be run against a worker.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path: String,
+    pub expected_status: Option<StatusCode>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub unhealthy_threshold: u32,
+    pub healthy_threshold: u32,
+    pub restart_after: Option<u32>,
+}
+
+impl HealthCheck {
+    fn is_healthy_status(&self, status: StatusCode) -> bool {
+        match self.expected_status {
+            Some(expected) => status == expected,
+            None => status.is_success() || status.is_redirection(),
+        }
+    }
+
+    async fn request(&self, addr: SocketAddr) -> FaucetResult<StatusCode> {
+        let stream = TcpStream::connect(addr).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                log::debug!(target: "faucet", "Health check connection error: {e}");
+            }
+        });
+        let request = Request::get(self.path.as_str())
+            .header(header::HOST, addr.to_string())
+            .header(header::USER_AGENT, "faucet-health-check")
+            .body(Empty::<Bytes>::new())?;
+        Ok(sender.send_request(request).await?.status())
+    }
+
+    /// Runs a single check against `addr`, returning the reason if it failed.
+    pub(crate) async fn probe(&self, addr: SocketAddr) -> Result<(), String> {
+        match tokio::time::timeout(self.timeout, self.request(addr)).await {
+            Err(_) => Err(format!("timed out after {:?}", self.timeout)),
+            Ok(Err(e)) => Err(e.to_string()),
+            Ok(Ok(status)) if self.is_healthy_status(status) => Ok(()),
+            Ok(Ok(status)) => Err(format!("unexpected status {status}")),
+        }
+    }
+
+    /// Periodically checks the worker while it runs, taking it out of
+    /// rotation when it fails and putting it back once it recovers.
+    ///
+    /// Only returns when the worker should be restarted.
+    pub(crate) async fn monitor(&self, worker: &WorkerConfig) {
+        let mut failures = 0u32;
+        let mut successes = 0u32;
+        loop {
+            tokio::time::sleep(self.interval).await;
+            match self.probe(worker.addr()).await {
+                Ok(()) => {
+                    failures = 0;
+                    successes = successes.saturating_add(1);
+                    if successes >= self.healthy_threshold && worker.set_online() {
+                        log::info!(target: "faucet", "{target} passed its health check and is back online", target = worker.target);
+                    }
+                }
+                Err(reason) => {
+                    successes = 0;
+                    failures = failures.saturating_add(1);
+                    log::debug!(target: "faucet", "Health check for {target} failed ({failures} in a row): {reason}", target = worker.target);
+                    if failures >= self.unhealthy_threshold
+                        && worker.is_online.swap(false, Ordering::SeqCst)
+                    {
+                        log::warn!(target: "faucet", "{target} failed {failures} health checks in a row, taking it offline: {reason}", target = worker.target);
+                    }
+                    if self.restart_after.is_some_and(|n| failures >= n) {
+                        log::warn!(target: "faucet", "{target} failed {failures} health checks in a row, restarting it", target = worker.target);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::get_available_socket;
+    use hyper::{body::Incoming, server::conn::http1, service::service_fn, Response};
+    use std::sync::atomic::AtomicU16;
+    use tokio::net::TcpListener;
+
+    async fn status_server(status: &'static AtomicU16, delay: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(
+                            TokioIo::new(tcp),
+                            service_fn(|_: Request<Incoming>| async move {
+                                tokio::time::sleep(delay).await;
+                                let mut res = Response::new(Empty::<Bytes>::new());
+                                *res.status_mut() =
+                                    StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap();
+                                Ok::<_, hyper::Error>(res)
+                            }),
+                        )
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn fast_check(config: HealthCheckConfig) -> HealthCheck {
+        HealthCheckConfig {
+            interval: 0.01,
+            timeout: 0.2,
+            ..config
+        }
+        .resolve(WorkerType::Shiny)
+        .unwrap()
+    }
+
+    #[test]
+    fn deserialize_health_check_with_defaults() {
+        let config: HealthCheckConfig = toml::from_str(r#"path = "/healthz""#).unwrap();
+        assert_eq!(config.path.as_deref(), Some("/healthz"));
+        assert_eq!(config.interval, DEFAULT_INTERVAL_SECS);
+        assert_eq!(config.unhealthy_threshold, DEFAULT_UNHEALTHY_THRESHOLD);
+        assert_eq!(config.restart_after, None);
+    }
+
+    #[test]
+    fn resolve_uses_worker_type_default_path() {
+        let config = HealthCheckConfig::default();
+        assert_eq!(config.resolve(WorkerType::Shiny).unwrap().path, "/");
+        assert_eq!(
+            config.resolve(WorkerType::Plumber).unwrap().path,
+            "/openapi.json"
+        );
+        assert_eq!(config.resolve(WorkerType::FastAPI).unwrap().path, "/docs");
+    }
+
+    #[test]
+    fn resolve_rejects_invalid_values() {
+        let invalid = [
+            HealthCheckConfig {
+                path: Some("healthz".into()),
+                ..Default::default()
+            },
+            HealthCheckConfig {
+                expected_status: Some(1000),
+                ..Default::default()
+            },
+            HealthCheckConfig {
+                interval: 0.0,
+                ..Default::default()
+            },
+            HealthCheckConfig {
+                healthy_threshold: 0,
+                ..Default::default()
+            },
+            HealthCheckConfig {
+                restart_after: Some(0),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.resolve(WorkerType::Shiny).is_err(), "{config:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_checks_status_and_timeout() {
+        let addr = status_server(crate::leak!(AtomicU16::new(200)), Duration::ZERO).await;
+        assert!(fast_check(Default::default()).probe(addr).await.is_ok());
+
+        let strict = fast_check(HealthCheckConfig {
+            expected_status: Some(204),
+            ..Default::default()
+        });
+        assert!(strict.probe(addr).await.is_err());
+
+        let slow = status_server(crate::leak!(AtomicU16::new(200)), Duration::from_secs(5)).await;
+        let err = fast_check(Default::default())
+            .probe(slow)
+            .await
+            .unwrap_err();
+        assert!(err.contains("timed out"), "{err}");
+
+        let closed = get_available_socket(20).await.unwrap();
+        assert!(fast_check(Default::default()).probe(closed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn monitor_flips_online_state() {
+        let status = crate::leak!(AtomicU16::new(500));
+        let addr = status_server(status, Duration::ZERO).await;
+        let worker: &'static WorkerConfig =
+            crate::leak!(WorkerConfig::dummy("test", &addr.to_string(), true));
+        let check: &'static HealthCheck = crate::leak!(fast_check(HealthCheckConfig {
+            unhealthy_threshold: 2,
+            healthy_threshold: 2,
+            ..Default::default()
+        }));
+
+        let monitor = tokio::spawn(check.monitor(worker));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while worker.is_online.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("worker should be taken offline");
+
+        status.store(200, Ordering::SeqCst);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !worker.is_online.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("worker should be back online");
+
+        monitor.abort();
+    }
+
+    #[tokio::test]
+    async fn monitor_returns_when_restart_is_needed() {
+        let addr = status_server(crate::leak!(AtomicU16::new(503)), Duration::ZERO).await;
+        let worker = WorkerConfig::dummy("test", &addr.to_string(), true);
+        let check = fast_check(HealthCheckConfig {
+            unhealthy_threshold: 1,
+            restart_after: Some(3),
+            ..Default::default()
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), check.monitor(&worker))
+            .await
+            .expect("monitor should ask for a restart");
+        assert!(!worker.is_online.load(Ordering::SeqCst));
+    }
+}
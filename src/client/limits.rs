@@ -0,0 +1,498 @@
+//! Resource limits of the worker processes and the user they run as, so a
+//! single runaway worker cannot take the host down with it.
+
+use crate::error::{FaucetError, FaucetResult};
+use std::{
+    ffi::{CStr, CString},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A size in bytes, given either as a number or as a string like `"2G"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl<'de> serde::Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Size {
+            Bytes(u64),
+            Text(String),
+        }
+        match Size::deserialize(deserializer)? {
+            Size::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Size::Text(text) => parse_size::parse_size(&text)
+                .map(ByteSize)
+                .map_err(|e| serde::de::Error::custom(format!("invalid size {text:?}: {e}"))),
+        }
+    }
+}
+
+/// The `limits` table of a route.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum virtual memory of each process (`RLIMIT_AS`).
+    pub memory: Option<ByteSize>,
+    /// Seconds of CPU time each process may use (`RLIMIT_CPU`).
+    pub cpu_seconds: Option<u64>,
+    /// Maximum number of open files of each process (`RLIMIT_NOFILE`).
+    pub open_files: Option<u64>,
+    /// cgroup v2 directory, delegated to faucet, under which each worker
+    /// gets its own cgroup.
+    pub cgroup: Option<PathBuf>,
+    /// `memory.max` of each worker's cgroup.
+    pub cgroup_memory_max: Option<ByteSize>,
+    /// `cpu.max` of each worker's cgroup, in CPUs.
+    pub cgroup_cpu_max: Option<f64>,
+    /// User the processes run as, by name or uid.
+    pub user: Option<String>,
+    /// Group the processes run as, by name or gid. Defaults to the
+    /// primary group of `user`.
+    pub group: Option<String>,
+}
+
+/// Period of the `cpu.max` quota, the kernel's default.
+const CPU_MAX_PERIOD: u64 = 100_000;
+
+#[derive(Debug)]
+struct RunAs {
+    uid: u32,
+    gid: u32,
+    /// Home directory and name of the user, for `HOME` and `USER`.
+    home: Option<(PathBuf, String)>,
+}
+
+/// [`LimitsConfig`] checked and ready to apply to processes.
+#[derive(Debug, Default)]
+pub struct ProcessLimits {
+    memory: Option<u64>,
+    cpu_seconds: Option<u64>,
+    open_files: Option<u64>,
+    cgroup: Option<PathBuf>,
+    cgroup_memory_max: Option<u64>,
+    cgroup_cpu_max: Option<u64>,
+    run_as: Option<RunAs>,
+}
+
+fn invalid(message: String) -> FaucetError {
+    FaucetError::InvalidConfig(format!("limits: {message}"))
+}
+
+#[cfg(unix)]
+fn resolve_user(user: &str) -> FaucetResult<nix::unistd::User> {
+    use nix::unistd::{Uid, User};
+    let found = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(user),
+    };
+    found
+        .map_err(|e| invalid(format!("unable to look up user {user:?}: {e}")))?
+        .ok_or_else(|| invalid(format!("user {user:?} does not exist")))
+}
+
+#[cfg(unix)]
+fn resolve_group(group: &str) -> FaucetResult<u32> {
+    use nix::unistd::{Gid, Group};
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid).as_raw());
+    }
+    Group::from_name(group)
+        .map_err(|e| invalid(format!("unable to look up group {group:?}: {e}")))?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| invalid(format!("group {group:?} does not exist")))
+}
+
+impl LimitsConfig {
+    pub(crate) fn resolve(&self) -> FaucetResult<ProcessLimits> {
+        if let Some(cgroup) = &self.cgroup {
+            if !cgroup.join("cgroup.procs").is_file() {
+                return Err(invalid(format!(
+                    "cgroup {cgroup:?} is not a cgroup v2 directory"
+                )));
+            }
+        } else if self.cgroup_memory_max.is_some() || self.cgroup_cpu_max.is_some() {
+            return Err(invalid(
+                "`cgroup_memory_max` and `cgroup_cpu_max` require `cgroup`".to_string(),
+            ));
+        }
+        let cgroup_cpu_max = self
+            .cgroup_cpu_max
+            .map(|cpus| {
+                if cpus.is_finite() && cpus > 0.0 {
+                    Ok(((cpus * CPU_MAX_PERIOD as f64) as u64).max(1000))
+                } else {
+                    Err(invalid(
+                        "cgroup_cpu_max must be a positive number of CPUs".to_string(),
+                    ))
+                }
+            })
+            .transpose()?;
+        if self.cpu_seconds == Some(0) {
+            return Err(invalid("cpu_seconds must be positive".to_string()));
+        }
+        Ok(ProcessLimits {
+            memory: self.memory.map(|size| size.0),
+            cpu_seconds: self.cpu_seconds,
+            open_files: self.open_files,
+            cgroup: self.cgroup.clone(),
+            cgroup_memory_max: self.cgroup_memory_max.map(|size| size.0),
+            cgroup_cpu_max,
+            run_as: self.run_as()?,
+        })
+    }
+
+    #[cfg(unix)]
+    fn run_as(&self) -> FaucetResult<Option<RunAs>> {
+        let user = self.user.as_deref().map(resolve_user).transpose()?;
+        let group = self.group.as_deref().map(resolve_group).transpose()?;
+        Ok(match (user, group) {
+            (None, None) => None,
+            (Some(user), group) => Some(RunAs {
+                uid: user.uid.as_raw(),
+                gid: group.unwrap_or(user.gid.as_raw()),
+                home: Some((user.dir, user.name)),
+            }),
+            (None, Some(gid)) => Some(RunAs {
+                uid: nix::unistd::getuid().as_raw(),
+                gid,
+                home: None,
+            }),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn run_as(&self) -> FaucetResult<Option<RunAs>> {
+        if self.user.is_some() || self.group.is_some() {
+            return Err(invalid(
+                "`user` and `group` are only supported on Unix".to_string(),
+            ));
+        }
+        Ok(None)
+    }
+}
+
+/// The cgroups of one worker. Each process it starts gets a cgroup of its
+/// own, so nothing a previous process left behind is accounted to the next.
+#[derive(Debug)]
+pub struct WorkerCgroup {
+    /// Directory the cgroups are created in.
+    parent: PathBuf,
+    name: String,
+    /// Processes started so far, to name their cgroups.
+    generation: AtomicU64,
+}
+
+/// The cgroup of one process of a worker. Whatever still runs in it is
+/// killed and the cgroup removed when it is dropped.
+#[derive(Debug)]
+pub(crate) struct ProcessCgroup {
+    dir: PathBuf,
+    /// `cgroup.procs` of the cgroup, written by the child before exec.
+    procs: CString,
+}
+
+/// Times the removal of a cgroup is tried while its processes exit.
+const REMOVE_TRIES: usize = 50;
+const REMOVE_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+impl ProcessCgroup {
+    pub(crate) fn procs(&self) -> &CStr {
+        &self.procs
+    }
+    /// `cgroup.procs`, to read the processes in the cgroup.
+    pub(crate) fn procs_path(&self) -> PathBuf {
+        self.dir.join("cgroup.procs")
+    }
+}
+
+impl Drop for ProcessCgroup {
+    fn drop(&mut self) {
+        // A cgroup can only be removed once it is empty, so processes the
+        // worker started and left behind go with it.
+        let _ = std::fs::write(self.dir.join("cgroup.kill"), "1");
+        if std::fs::remove_dir(&self.dir).is_ok() {
+            return;
+        }
+        let dir = std::mem::take(&mut self.dir);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let mut result = Ok(());
+                for _ in 0..REMOVE_TRIES {
+                    tokio::time::sleep(REMOVE_RETRY_INTERVAL).await;
+                    result = std::fs::remove_dir(&dir);
+                    match &result {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => (),
+                        _ => return,
+                    }
+                }
+                if let Err(e) = result {
+                    log::warn!(target: "faucet", "Unable to remove cgroup {dir:?}: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Name of the cgroups of a worker: its route, made safe for a file name,
+/// a hash of the route, so routes that read the same do not share it, and
+/// its id.
+fn cgroup_name(route: Option<&str>, worker_id: usize) -> String {
+    let route = route.unwrap_or("/");
+    let readable: String = route
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let readable = readable.trim_matches('_');
+    let readable = if readable.is_empty() {
+        "root"
+    } else {
+        readable
+    };
+    format!(
+        "{readable}-{hash:016x}-worker-{worker_id}",
+        hash = fxhash::hash64(route)
+    )
+}
+
+fn oom_kills(dir: &Path) -> u64 {
+    std::fs::read_to_string(dir.join("memory.events"))
+        .ok()
+        .and_then(|events| {
+            events.lines().find_map(|line| {
+                line.strip_prefix("oom_kill ")
+                    .and_then(|n| n.trim().parse().ok())
+            })
+        })
+        .unwrap_or(0)
+}
+
+impl ProcessLimits {
+    /// The cgroups of worker `worker_id` of `route`, if the limits use them.
+    pub(crate) fn worker_cgroup(
+        &self,
+        route: Option<&str>,
+        worker_id: usize,
+    ) -> Option<WorkerCgroup> {
+        Some(WorkerCgroup {
+            parent: self.cgroup.clone()?,
+            name: cgroup_name(route, worker_id),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    /// Creates the cgroup of the next process of a worker and sets its
+    /// limits.
+    pub(crate) fn create_cgroup(&self, cgroup: &WorkerCgroup) -> FaucetResult<ProcessCgroup> {
+        let generation = cgroup.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let dir = cgroup.parent.join(format!("{}-{generation}", cgroup.name));
+        let error = |what: &str, e: std::io::Error| {
+            FaucetError::Unknown(format!("Unable to {what} cgroup {dir:?}: {e}"))
+        };
+        // Children only get the controllers their parent enables.
+        let mut controllers = Vec::new();
+        if self.cgroup_memory_max.is_some() {
+            controllers.push("+memory");
+        }
+        if self.cgroup_cpu_max.is_some() {
+            controllers.push("+cpu");
+        }
+        if !controllers.is_empty() {
+            std::fs::write(
+                cgroup.parent.join("cgroup.subtree_control"),
+                controllers.join(" "),
+            )
+            .map_err(|e| error("enable controllers for", e))?;
+        }
+        match std::fs::create_dir(&dir) {
+            // Left over by an earlier run of faucet that did not exit cleanly.
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+            result => result.map_err(|e| error("create", e))?,
+        }
+        let procs = CString::new(
+            dir.join("cgroup.procs")
+                .into_os_string()
+                .into_encoded_bytes(),
+        )
+        .expect("cgroup paths come from the configuration and have no NUL bytes");
+        // Removed again if setting its limits fails.
+        let process_cgroup = ProcessCgroup {
+            dir: dir.clone(),
+            procs,
+        };
+        if let Some(memory_max) = self.cgroup_memory_max {
+            std::fs::write(
+                process_cgroup.dir.join("memory.max"),
+                memory_max.to_string(),
+            )
+            .map_err(|e| error("set memory.max of", e))?;
+        }
+        if let Some(quota) = self.cgroup_cpu_max {
+            std::fs::write(dir.join("cpu.max"), format!("{quota} {CPU_MAX_PERIOD}"))
+                .map_err(|e| error("set cpu.max of", e))?;
+        }
+        Ok(process_cgroup)
+    }
+
+    /// `HOME`, `USER` and `LOGNAME` of the user the processes run as.
+    pub(crate) fn user_env(&self) -> Vec<(&'static str, std::ffi::OsString)> {
+        match self.run_as.as_ref().and_then(|run_as| run_as.home.as_ref()) {
+            Some((home, name)) => vec![
+                ("HOME", home.clone().into_os_string()),
+                ("USER", name.into()),
+                ("LOGNAME", name.into()),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// Applies the limits to the current process. Runs in the child between
+    /// fork and exec, so it only makes system calls.
+    #[cfg(unix)]
+    pub(crate) fn apply(&self, cgroup_procs: Option<&CStr>) -> std::io::Result<()> {
+        use nix::sys::resource::{setrlimit, Resource};
+        let limit = |resource, limit: u64, hard: u64| {
+            setrlimit(resource, limit, hard).map_err(std::io::Error::from)
+        };
+        if let Some(memory) = self.memory {
+            limit(Resource::RLIMIT_AS, memory, memory)?;
+        }
+        if let Some(seconds) = self.cpu_seconds {
+            // SIGXCPU at the soft limit, SIGKILL if it is ignored.
+            limit(Resource::RLIMIT_CPU, seconds, seconds + 1)?;
+        }
+        if let Some(files) = self.open_files {
+            limit(Resource::RLIMIT_NOFILE, files, files)?;
+        }
+        if let Some(procs) = cgroup_procs {
+            // Writing 0 moves the writing process, before it starts anything.
+            let fd = unsafe { nix::libc::open(procs.as_ptr(), nix::libc::O_WRONLY) };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let written = unsafe { nix::libc::write(fd, b"0".as_ptr().cast(), 1) };
+            unsafe { nix::libc::close(fd) };
+            if written != 1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(run_as) = &self.run_as {
+            use nix::unistd::{setgid, setuid, Gid, Uid};
+            let gid = Gid::from_raw(run_as.gid);
+            #[cfg(target_os = "linux")]
+            nix::unistd::setgroups(&[gid]).map_err(std::io::Error::from)?;
+            setgid(gid).map_err(std::io::Error::from)?;
+            setuid(Uid::from_raw(run_as.uid)).map_err(std::io::Error::from)?;
+        }
+        Ok(())
+    }
+
+    /// Why a process that exited with `status` stopped, if it was because
+    /// of one of the limits.
+    pub(crate) fn exit_reason(
+        &self,
+        status: ExitStatus,
+        cgroup: Option<&ProcessCgroup>,
+    ) -> Option<&'static str> {
+        // The cgroup only ever held this process and what it started.
+        if cgroup.is_some_and(|cgroup| oom_kills(&cgroup.dir) > 0) {
+            return Some("was killed for exceeding its cgroup memory limit");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            match status.signal() {
+                Some(nix::libc::SIGXCPU) if self.cpu_seconds.is_some() => {
+                    return Some("exceeded its CPU time limit")
+                }
+                Some(nix::libc::SIGKILL) if self.cpu_seconds.is_some() => {
+                    return Some("was killed, possibly for exceeding its CPU time limit")
+                }
+                Some(nix::libc::SIGABRT | nix::libc::SIGSEGV) if self.memory.is_some() => {
+                    return Some("crashed, possibly for exceeding its memory limit")
+                }
+                _ => (),
+            }
+        }
+        let _ = status;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_from_toml() {
+        let config: LimitsConfig = toml::from_str(
+            r#"
+            memory = "2GiB"
+            cpu_seconds = 3600
+            open_files = 1024
+            "#,
+        )
+        .unwrap();
+        let limits = config.resolve().unwrap();
+        assert_eq!(limits.memory, Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(limits.open_files, Some(1024));
+        assert!(limits.run_as.is_none());
+
+        for (source, message) in [
+            ("cgroup_memory_max = \"1G\"", "require `cgroup`"),
+            ("cgroup = \"/nonexistent\"", "not a cgroup v2 directory"),
+            ("cpu_seconds = 0", "cpu_seconds"),
+            ("user = \"faucet-no-such-user\"", "does not exist"),
+        ] {
+            let config: LimitsConfig = toml::from_str(source).unwrap();
+            let error = config.resolve().unwrap_err().to_string();
+            assert!(error.contains(message), "{source}: {error}");
+        }
+        assert!(toml::from_str::<LimitsConfig>("memory = \"lots\"").is_err());
+    }
+
+    #[test]
+    fn cgroup_names() {
+        let root = cgroup_name(Some("/"), 1);
+        assert!(
+            root.starts_with("root-") && root.ends_with("-worker-1"),
+            "{root}"
+        );
+        assert_eq!(cgroup_name(None, 1), root);
+        let sales = cgroup_name(Some("apps.example.com/sales/"), 3);
+        assert!(sales.starts_with("apps_example_com_sales-"), "{sales}");
+        assert!(sales.ends_with("-worker-3"), "{sales}");
+
+        // Routes that only differ in the characters replaced still get
+        // their own cgroups.
+        assert_ne!(cgroup_name(Some("/a-b/"), 1), cgroup_name(Some("/a_b/"), 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_reasons() {
+        use std::os::unix::process::ExitStatusExt;
+        let limits = LimitsConfig {
+            memory: Some(ByteSize(1 << 30)),
+            cpu_seconds: Some(60),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+        let signaled = |signal| ExitStatus::from_raw(signal);
+        assert_eq!(
+            limits.exit_reason(signaled(nix::libc::SIGXCPU), None),
+            Some("exceeded its CPU time limit")
+        );
+        assert!(limits
+            .exit_reason(signaled(nix::libc::SIGSEGV), None)
+            .is_some_and(|reason| reason.contains("memory")));
+        assert_eq!(limits.exit_reason(ExitStatus::from_raw(1 << 8), None), None);
+        assert_eq!(
+            ProcessLimits::default().exit_reason(signaled(nix::libc::SIGXCPU), None),
+            None
+        );
+    }
+}
@@ -1,30 +1,12 @@
 use uuid::Uuid;
 
+use super::rendezvous::{mix, Rendezvous};
 use super::LoadBalancingStrategy;
 use super::WorkerConfig;
 use crate::client::Client;
-use crate::leak;
-use std::time::Duration;
-
-struct Targets {
-    targets: &'static [Client],
-}
-
-impl Targets {
-    fn new(configs: &[&'static WorkerConfig]) -> Self {
-        let mut targets = Vec::new();
-        for state in configs {
-            let client = Client::new(state);
-            targets.push(client);
-        }
-        let targets = leak!(targets);
-        Targets { targets }
-    }
-}
 
 pub struct CookieHash {
-    targets: Targets,
-    targets_len: usize,
+    pub(super) targets: Rendezvous,
 }
 
 impl CookieHash {
@@ -34,61 +16,22 @@ impl CookieHash {
             config.spawn_worker_task().await;
         }
         Self {
-            targets_len: configs.as_ref().len(),
-            targets: Targets::new(configs),
+            targets: Rendezvous::new(configs),
         }
     }
 }
 
-fn calculate_hash(cookie_uuid: Uuid) -> u64 {
-    let mut hash_value = cookie_uuid.as_u128() as u64;
-    hash_value ^= hash_value >> 33;
-    hash_value = hash_value.wrapping_mul(0xff51afd7ed558ccd);
-    hash_value ^= hash_value >> 33;
-    hash_value = hash_value.wrapping_mul(0xc4ceb9fe1a85ec53);
-    hash_value ^= hash_value >> 33;
-
-    hash_value
-}
-
-fn hash_to_index(value: Uuid, length: usize) -> usize {
-    let hash = calculate_hash(value);
-    (hash % length as u64) as usize
-}
-
-// 50ms is the minimum backoff time for exponential backoff
-const BASE_BACKOFF: Duration = Duration::from_millis(1);
-
-const MAX_BACKOFF: Duration = Duration::from_millis(500);
-
-fn calculate_exponential_backoff(retries: u32) -> Duration {
-    (BASE_BACKOFF * 2u32.pow(retries)).min(MAX_BACKOFF)
+pub(super) fn calculate_hash(cookie_uuid: Uuid) -> u64 {
+    let hash_value = cookie_uuid.as_u128() as u64;
+    mix(hash_value)
 }
 
 impl LoadBalancingStrategy for CookieHash {
     type Input = Uuid;
     async fn entry(&self, id: Uuid) -> Client {
-        let mut retries = 0;
-        let index = hash_to_index(id, self.targets_len);
-        let client = self.targets.targets[index].clone();
-        loop {
-            if client.is_online() {
-                break client;
-            }
-
-            let backoff = calculate_exponential_backoff(retries);
-
-            log::debug!(
-                target: "faucet",
-                "LB Session {} tried to connect to offline {}, retrying in {:?}",
-                id,
-                client.config.target,
-                backoff
-            );
-
-            tokio::time::sleep(backoff).await;
-            retries += 1;
-        }
+        self.targets
+            .entry(calculate_hash(id), || format!("LB Session {id}"))
+            .await
     }
 }
 
@@ -97,8 +40,14 @@ mod tests {
     use super::*;
     use crate::client::ExtractSocketAddr;
 
+    use crate::client::load_balancing::rendezvous::rank;
     use uuid::Uuid;
 
+    /// The worker `uuid` goes to while every worker is online and idle.
+    fn preferred_index(uuid: Uuid, len: usize) -> usize {
+        rank(calculate_hash(uuid), len)[0]
+    }
+
     #[test]
     fn uuid_test_distribution_of_hash_function_len_4() {
         const N_UUIDS: usize = 100_000;
@@ -108,7 +57,7 @@ mod tests {
         let mut counts = [0; 4];
 
         uuids.iter().for_each(|uuid| {
-            let index = hash_to_index(*uuid, 4);
+            let index = preferred_index(*uuid, 4);
             counts[index] += 1;
         });
 
@@ -131,7 +80,7 @@ mod tests {
         let mut counts = [0; 3];
 
         uuids.iter().for_each(|uuid| {
-            let index = hash_to_index(*uuid, 3);
+            let index = preferred_index(*uuid, 3);
             counts[index] += 1;
         });
 
@@ -152,7 +101,7 @@ mod tests {
         let mut counts = [0; 2];
 
         uuids.iter().for_each(|uuid| {
-            let index = hash_to_index(*uuid, 2);
+            let index = preferred_index(*uuid, 2);
             counts[index] += 1;
         });
 
@@ -169,7 +118,7 @@ mod tests {
             "127.0.0.1:9999",
             true,
         )));
-        let Targets { targets } = Targets::new(&[worker_state]);
+        let Rendezvous { targets, .. } = Rendezvous::new(&[worker_state]);
 
         assert_eq!(targets.len(), 1);
     }
@@ -181,25 +130,13 @@ mod tests {
             "127.0.0.1:9999",
             true,
         )));
-        let CookieHash {
-            targets,
-            targets_len,
-        } = CookieHash::new(&[worker_state]).await;
+        let CookieHash { targets } = CookieHash::new(&[worker_state]).await;
 
         assert_eq!(targets.targets.len(), 1);
-        assert_eq!(targets_len, 1);
 
         worker_state.wait_until_done().await;
     }
 
-    #[test]
-    fn test_calculate_exponential_backoff() {
-        assert_eq!(calculate_exponential_backoff(0), BASE_BACKOFF);
-        assert_eq!(calculate_exponential_backoff(1), BASE_BACKOFF * 2);
-        assert_eq!(calculate_exponential_backoff(2), BASE_BACKOFF * 4);
-        assert_eq!(calculate_exponential_backoff(3), BASE_BACKOFF * 8);
-    }
-
     #[tokio::test]
     async fn test_load_balancing_strategy() {
         let worker1: &'static WorkerConfig = Box::leak(Box::new(WorkerConfig::dummy(
@@ -0,0 +1,239 @@
+use super::rendezvous::Rendezvous;
+use super::LoadBalancingStrategy;
+use super::WorkerConfig;
+use crate::client::Client;
+use crate::error::{FaucetError, FaucetResult};
+use base64::Engine;
+use hyper::{header::AUTHORIZATION, http::HeaderName, Request};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+
+/// What identifies the user of a request for the header hash strategy.
+///
+/// In `frouter.toml` this is a table with one of the keys, such as
+/// `hash_key = { jwt_claim = "sub" }`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum HashKey {
+    /// The value of a request header, such as one set by an authenticating
+    /// proxy.
+    Header(String),
+    /// The value of a cookie.
+    Cookie(String),
+    /// A claim of the JWT in the `Authorization: Bearer` header. The token
+    /// is not verified, it only chooses the worker.
+    JwtClaim(String),
+}
+
+impl HashKey {
+    pub(crate) fn validate(&self) -> FaucetResult<()> {
+        let invalid = |message: &str| Err(FaucetError::InvalidConfig(message.to_string()));
+        match self {
+            HashKey::Header(name) if HeaderName::from_bytes(name.as_bytes()).is_err() => {
+                return invalid("hash_key header must be a valid header name")
+            }
+            HashKey::Cookie(name) if name.is_empty() => {
+                return invalid("hash_key cookie must not be empty")
+            }
+            HashKey::JwtClaim(claim) if claim.is_empty() => {
+                return invalid("hash_key jwt_claim must not be empty")
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// The identity of the user of `req`, if the request carries it.
+    pub(crate) fn extract<B>(&self, req: &Request<B>) -> Option<String> {
+        match self {
+            HashKey::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string),
+            HashKey::Cookie(name) => cookie_value(req, name),
+            HashKey::JwtClaim(claim) => req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| jwt_claim(value, claim)),
+        }
+    }
+
+    /// The key `req` is hashed by. Requests without an identity, such as
+    /// those before a login, fall back to the client's IP address.
+    pub(crate) fn hash<B>(&self, req: &Request<B>, ip: IpAddr) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self.extract(req) {
+            Some(identity) => identity.hash(&mut hasher),
+            None => ip.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+}
+
+fn cookie_value<B>(req: &Request<B>, name: &str) -> Option<String> {
+    // HTTP/2 clients may split cookies over several `cookie` headers.
+    req.headers()
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(cookie::Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// A claim from the payload of the bearer token in `authorization`. Strings
+/// are used as they are, other values as JSON.
+fn jwt_claim(authorization: &str, claim: &str) -> Option<String> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let payload = token.trim().split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let payload: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    match payload.get(claim)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+pub struct HeaderHash {
+    pub(super) targets: Rendezvous,
+    key: &'static HashKey,
+}
+
+impl HeaderHash {
+    pub(crate) async fn new(configs: &[&'static WorkerConfig], key: &'static HashKey) -> Self {
+        // Start the process of each config
+        for config in configs {
+            config.spawn_worker_task().await;
+        }
+        Self {
+            targets: Rendezvous::new(configs),
+            key,
+        }
+    }
+    pub(crate) fn key(&self) -> &'static HashKey {
+        self.key
+    }
+}
+
+impl LoadBalancingStrategy for HeaderHash {
+    type Input = u64;
+    async fn entry(&self, hash: u64) -> Client {
+        self.targets.entry(hash, || "Request".to_string()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ExtractSocketAddr;
+
+    fn jwt(payload: &str) -> String {
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        format!(
+            "Bearer {}.{}.signature",
+            engine.encode(r#"{"alg":"HS256"}"#),
+            engine.encode(payload)
+        )
+    }
+
+    #[test]
+    fn hash_key_from_toml() {
+        #[derive(serde::Deserialize)]
+        struct Route {
+            hash_key: HashKey,
+        }
+        let route: Route = toml::from_str(r#"hash_key = { jwt_claim = "sub" }"#).unwrap();
+        assert_eq!(route.hash_key, HashKey::JwtClaim("sub".to_string()));
+        assert!(toml::from_str::<Route>(r#"hash_key = { claim = "sub" }"#).is_err());
+
+        assert!(HashKey::Header("X-User".to_string()).validate().is_ok());
+        assert!(HashKey::Header("X User".to_string()).validate().is_err());
+        assert!(HashKey::Cookie(String::new()).validate().is_err());
+    }
+
+    #[test]
+    fn extract_identities() {
+        let req = Request::builder()
+            .header("x-user", "ana")
+            .header("cookie", "theme=dark")
+            .header("cookie", "session=abc123")
+            .header("authorization", jwt(r#"{"sub":"user-1","org":42}"#))
+            .body(())
+            .unwrap();
+        let extract = |key: HashKey| key.extract(&req);
+        assert_eq!(
+            extract(HashKey::Header("X-User".into())).as_deref(),
+            Some("ana")
+        );
+        assert_eq!(
+            extract(HashKey::Cookie("session".into())).as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(
+            extract(HashKey::JwtClaim("sub".into())).as_deref(),
+            Some("user-1")
+        );
+        assert_eq!(
+            extract(HashKey::JwtClaim("org".into())).as_deref(),
+            Some("42")
+        );
+        assert_eq!(extract(HashKey::JwtClaim("email".into())), None);
+        assert_eq!(extract(HashKey::Header("X-Tenant".into())), None);
+
+        assert_eq!(jwt_claim("Basic dXNlcjpwYXNz", "sub"), None);
+        assert_eq!(jwt_claim("Bearer not-a-jwt", "sub"), None);
+    }
+
+    #[test]
+    fn requests_without_identity_hash_by_ip() {
+        let key = HashKey::Header("X-User".to_string());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let anonymous = Request::new(());
+        let user = |name: &str| Request::builder().header("x-user", name).body(()).unwrap();
+
+        assert_eq!(key.hash(&user("ana"), ip), key.hash(&user("ana"), ip));
+        assert_eq!(
+            key.hash(&user("ana"), ip),
+            key.hash(&user("ana"), "10.0.0.2".parse().unwrap())
+        );
+        assert_ne!(key.hash(&user("ana"), ip), key.hash(&user("bob"), ip));
+        assert_eq!(key.hash(&anonymous, ip), key.hash(&Request::new(()), ip));
+    }
+
+    #[tokio::test]
+    async fn same_identity_same_worker() {
+        let configs: [&'static WorkerConfig; 2] = [
+            crate::leak!(WorkerConfig::dummy("test", "127.0.0.1:9131", true)),
+            crate::leak!(WorkerConfig::dummy("test", "127.0.0.1:9132", true)),
+        ];
+        let key = crate::leak!(HashKey::Cookie("session".to_string()));
+        let header_hash = HeaderHash::new(&configs, key).await;
+        let req = Request::builder()
+            .header("cookie", "session=abc123")
+            .body(())
+            .unwrap();
+        let hash = key.hash(&req, "10.0.0.1".parse().unwrap());
+        let first = header_hash.entry(hash).await;
+        for _ in 0..10 {
+            assert_eq!(
+                header_hash.entry(hash).await.socket_addr(),
+                first.socket_addr()
+            );
+        }
+
+        for config in configs {
+            config.wait_until_done().await;
+        }
+    }
+}
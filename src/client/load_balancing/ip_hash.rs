@@ -1,29 +1,11 @@
+use super::rendezvous::{mix, Rendezvous};
 use super::LoadBalancingStrategy;
 use super::WorkerConfig;
 use crate::client::Client;
-use crate::leak;
 use std::net::IpAddr;
-use std::time::Duration;
-
-struct Targets {
-    targets: &'static [Client],
-}
-
-impl Targets {
-    fn new(configs: &[&'static WorkerConfig]) -> Self {
-        let mut targets = Vec::new();
-        for state in configs {
-            let client = Client::new(state);
-            targets.push(client);
-        }
-        let targets = leak!(targets);
-        Targets { targets }
-    }
-}
 
 pub struct IpHash {
-    targets: Targets,
-    targets_len: usize,
+    pub(super) targets: Rendezvous,
 }
 
 impl IpHash {
@@ -33,62 +15,25 @@ impl IpHash {
             config.spawn_worker_task().await;
         }
         Self {
-            targets_len: configs.as_ref().len(),
-            targets: Targets::new(configs),
+            targets: Rendezvous::new(configs),
         }
     }
 }
 
-fn calculate_hash(ip: IpAddr) -> u64 {
-    let mut hash_value = match ip {
+pub(super) fn calculate_hash(ip: IpAddr) -> u64 {
+    let hash_value = match ip {
         IpAddr::V4(ip) => ip.to_bits() as u64,
         IpAddr::V6(ip) => ip.to_bits() as u64,
     };
-    hash_value ^= hash_value >> 33;
-    hash_value = hash_value.wrapping_mul(0xff51afd7ed558ccd);
-    hash_value ^= hash_value >> 33;
-    hash_value = hash_value.wrapping_mul(0xc4ceb9fe1a85ec53);
-    hash_value ^= hash_value >> 33;
-
-    hash_value
-}
-
-fn hash_to_index(value: IpAddr, length: usize) -> usize {
-    let hash = calculate_hash(value);
-    (hash % length as u64) as usize
-}
-
-// 50ms is the minimum backoff time for exponential backoff
-const BASE_BACKOFF: Duration = Duration::from_millis(50);
-
-fn calculate_exponential_backoff(retries: u32) -> Duration {
-    BASE_BACKOFF * 2u32.pow(retries)
+    mix(hash_value)
 }
 
 impl LoadBalancingStrategy for IpHash {
     type Input = IpAddr;
     async fn entry(&self, ip: IpAddr) -> Client {
-        let mut retries = 0;
-        let index = hash_to_index(ip, self.targets_len);
-        let client = self.targets.targets[index].clone();
-        loop {
-            if client.is_online() {
-                break client;
-            }
-
-            let backoff = calculate_exponential_backoff(retries);
-
-            log::debug!(
-                target: "faucet",
-                "IP {} tried to connect to offline {}, retrying in {:?}",
-                ip,
-                client.config.target,
-                backoff
-            );
-
-            tokio::time::sleep(backoff).await;
-            retries += 1;
-        }
+        self.targets
+            .entry(calculate_hash(ip), || format!("IP {ip}"))
+            .await
     }
 }
 
@@ -96,8 +41,15 @@ impl LoadBalancingStrategy for IpHash {
 mod tests {
 
     use std::sync::{atomic::AtomicBool, Arc};
+    use std::time::Duration;
 
     use super::*;
+    use crate::client::load_balancing::rendezvous::rank;
+
+    /// The worker `ip` goes to while every worker is online and idle.
+    fn preferred_index(ip: IpAddr, len: usize) -> usize {
+        rank(calculate_hash(ip), len)[0]
+    }
 
     #[test]
     fn ip_v4_test_distribution_of_hash_function_len_4() {
@@ -113,7 +65,7 @@ mod tests {
         let mut counts = [0; 4];
 
         ips.iter().for_each(|ip| {
-            let index = hash_to_index(*ip, 4);
+            let index = preferred_index(*ip, 4);
             counts[index] += 1;
         });
 
@@ -141,7 +93,7 @@ mod tests {
         let mut counts = [0; 3];
 
         ips.iter().for_each(|ip| {
-            let index = hash_to_index(*ip, 3);
+            let index = preferred_index(*ip, 3);
             counts[index] += 1;
         });
 
@@ -167,7 +119,7 @@ mod tests {
         let mut counts = [0; 2];
 
         ips.iter().for_each(|ip| {
-            let index = hash_to_index(*ip, 2);
+            let index = preferred_index(*ip, 2);
             counts[index] += 1;
         });
 
@@ -191,7 +143,7 @@ mod tests {
         let mut counts = [0; 4];
 
         ips.iter().for_each(|ip| {
-            let index = hash_to_index(*ip, 4);
+            let index = preferred_index(*ip, 4);
             counts[index] += 1;
         });
 
@@ -219,7 +171,7 @@ mod tests {
         let mut counts = [0; 3];
 
         ips.iter().for_each(|ip| {
-            let index = hash_to_index(*ip, 3);
+            let index = preferred_index(*ip, 3);
             counts[index] += 1;
         });
 
@@ -245,7 +197,7 @@ mod tests {
         let mut counts = [0; 2];
 
         ips.iter().for_each(|ip| {
-            let index = hash_to_index(*ip, 2);
+            let index = preferred_index(*ip, 2);
             counts[index] += 1;
         });
 
@@ -262,7 +214,7 @@ mod tests {
             "127.0.0.1:9999",
             true,
         )));
-        let Targets { targets } = Targets::new(&[worker_state]);
+        let Rendezvous { targets, .. } = Rendezvous::new(&[worker_state]);
 
         assert_eq!(targets.len(), 1);
     }
@@ -274,25 +226,13 @@ mod tests {
             "127.0.0.1:9999",
             true,
         )));
-        let IpHash {
-            targets,
-            targets_len,
-        } = IpHash::new(&[worker_state]).await;
+        let IpHash { targets } = IpHash::new(&[worker_state]).await;
 
         assert_eq!(targets.targets.len(), 1);
-        assert_eq!(targets_len, 1);
 
         worker_state.wait_until_done().await;
     }
 
-    #[test]
-    fn test_calculate_exponential_backoff() {
-        assert_eq!(calculate_exponential_backoff(0), BASE_BACKOFF);
-        assert_eq!(calculate_exponential_backoff(1), BASE_BACKOFF * 2);
-        assert_eq!(calculate_exponential_backoff(2), BASE_BACKOFF * 4);
-        assert_eq!(calculate_exponential_backoff(3), BASE_BACKOFF * 8);
-    }
-
     #[tokio::test]
     async fn test_load_balancing_strategy() {
         use crate::client::ExtractSocketAddr;
@@ -313,8 +253,8 @@ mod tests {
         assert_eq!(client1.socket_addr(), client2.socket_addr());
 
         // This IP address should hash to a different index
-        let client3 = ip_hash.entry("192.168.0.10".parse().unwrap()).await;
-        let client4 = ip_hash.entry("192.168.0.10".parse().unwrap()).await;
+        let client3 = ip_hash.entry("192.168.0.4".parse().unwrap()).await;
+        let client4 = ip_hash.entry("192.168.0.4".parse().unwrap()).await;
 
         assert_eq!(client3.socket_addr(), client4.socket_addr());
         assert_eq!(client1.socket_addr(), client2.socket_addr());
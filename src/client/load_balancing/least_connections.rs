@@ -0,0 +1,159 @@
+use super::LoadBalancingStrategy;
+use crate::client::{worker::WorkerConfig, Client};
+use rand::Rng;
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Same wait as round robin when every target is offline
+const WAIT_TIME_UNTIL_RETRY: std::time::Duration = std::time::Duration::from_micros(500);
+
+/// Sends each request to the online worker with the fewest requests and
+/// WebSocket sessions in flight.
+///
+/// With `two_choices` only two random workers are compared. Large pools
+/// then avoid scanning every worker, and sending a burst of requests to
+/// the one that looked idlest.
+pub struct LeastConnections {
+    targets: &'static [Client],
+    /// Where the scan starts, so idle workers take turns.
+    next: AtomicUsize,
+    two_choices: bool,
+}
+
+impl LeastConnections {
+    pub(crate) async fn new(configs: &[&'static WorkerConfig], two_choices: bool) -> Self {
+        // Start the process of each config
+        for config in configs {
+            config.spawn_worker_task().await;
+        }
+        let targets = configs.iter().map(|config| Client::new(config)).collect();
+        Self {
+            targets: Box::leak(targets),
+            next: AtomicUsize::new(0),
+            two_choices,
+        }
+    }
+    pub(crate) fn two_choices(&self) -> bool {
+        self.two_choices
+    }
+    fn least_loaded(&self) -> Option<&Client> {
+        let len = self.targets.len();
+        if len == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        (0..len)
+            .map(|offset| &self.targets[(start + offset) % len])
+            .filter(|client| client.is_online())
+            .min_by_key(|client| client.in_flight_count())
+    }
+    fn least_loaded_of_two(&self) -> Option<&Client> {
+        let len = self.targets.len();
+        if len < 2 {
+            return self.least_loaded();
+        }
+        let mut rng = rand::rng();
+        let first = rng.random_range(0..len);
+        let second = (first + rng.random_range(1..len)) % len;
+        let candidates = [&self.targets[first], &self.targets[second]];
+        candidates
+            .into_iter()
+            .filter(|client| client.is_online())
+            .min_by_key(|client| client.in_flight_count())
+            // Both are offline, look for any online worker.
+            .or_else(|| self.least_loaded())
+    }
+}
+
+impl LoadBalancingStrategy for LeastConnections {
+    type Input = IpAddr;
+    async fn entry(&self, _ip: IpAddr) -> Client {
+        loop {
+            let client = if self.two_choices {
+                self.least_loaded_of_two()
+            } else {
+                self.least_loaded()
+            };
+            if let Some(client) = client {
+                break client.clone();
+            }
+            tokio::time::sleep(WAIT_TIME_UNTIL_RETRY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ExtractSocketAddr;
+
+    fn workers(online: &[bool]) -> Vec<&'static WorkerConfig> {
+        online
+            .iter()
+            .enumerate()
+            .map(|(i, online)| {
+                &*Box::leak(Box::new(WorkerConfig::dummy(
+                    "test",
+                    &format!("127.0.0.1:910{i}"),
+                    *online,
+                )))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn picks_the_idlest_online_worker() {
+        let configs = workers(&[true, true, false]);
+        let lb = LeastConnections::new(&configs, false).await;
+        let ip = "0.0.0.0".parse().unwrap();
+
+        let busy = lb.entry(ip).await;
+        let _first = busy.in_flight();
+        let _second = busy.in_flight();
+        let idle = lb.entry(ip).await;
+        assert_ne!(idle.socket_addr(), busy.socket_addr());
+        assert_ne!(idle.socket_addr(), configs[2].addr());
+
+        for config in configs {
+            config.wait_until_done().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_workers_take_turns() {
+        let configs = workers(&[true, true, true]);
+        let lb = LeastConnections::new(&configs, false).await;
+        let ip = "0.0.0.0".parse().unwrap();
+        for config in configs.iter().chain(&configs) {
+            assert_eq!(lb.entry(ip).await.socket_addr(), config.addr());
+        }
+
+        for config in configs {
+            config.wait_until_done().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn two_choices_avoid_busy_and_offline_workers() {
+        let configs = workers(&[true, false]);
+        let lb = LeastConnections::new(&configs, true).await;
+        let ip = "0.0.0.0".parse().unwrap();
+        for _ in 0..10 {
+            assert_eq!(lb.entry(ip).await.socket_addr(), configs[0].addr());
+        }
+
+        let configs = workers(&[true, true]);
+        let lb = LeastConnections::new(&configs, true).await;
+        let busy = Client::new(configs[0]);
+        let _in_flight = busy.in_flight();
+        for _ in 0..10 {
+            assert_eq!(lb.entry(ip).await.socket_addr(), configs[1].addr());
+        }
+
+        for config in configs {
+            config.wait_until_done().await;
+        }
+    }
+}
@@ -1,21 +1,33 @@
 pub mod cookie_hash;
+pub mod header_hash;
 mod ip_extractor;
 pub mod ip_hash;
+pub mod least_connections;
+pub mod queue;
+mod rendezvous;
 pub mod round_robin;
 pub mod rps_autoscale;
 
 use super::worker::WorkerConfig;
-use crate::client::Client;
-use crate::error::FaucetResult;
+use crate::client::{Client, InFlight};
+use crate::error::{FaucetError, FaucetResult};
 use crate::leak;
+use crate::shutdown::ShutdownSignal;
 use cookie_hash::CookieHash;
+pub use header_hash::HashKey;
+use header_hash::HeaderHash;
 use hyper::Request;
 pub use ip_extractor::IpExtractor;
+pub(crate) use rendezvous::Session;
 use std::net::IpAddr;
 use std::str::FromStr;
+use tokio::sync::Notify;
 use uuid::Uuid;
 
 use self::ip_hash::IpHash;
+use self::least_connections::LeastConnections;
+use self::queue::{QueuePolicy, RequestQueue};
+use self::rendezvous::Rendezvous;
 use self::round_robin::RoundRobin;
 use self::rps_autoscale::RpsAutoscale;
 
@@ -37,6 +49,16 @@ pub enum Strategy {
     CookieHash,
     #[serde(alias = "rps", alias = "Rps", alias = "rps")]
     Rps,
+    #[serde(
+        alias = "least_connections",
+        alias = "LeastConnections",
+        alias = "least-connections"
+    )]
+    LeastConnections,
+    #[serde(alias = "power_of_two", alias = "PowerOfTwo", alias = "power-of-two")]
+    PowerOfTwo,
+    #[serde(alias = "header_hash", alias = "HeaderHash", alias = "header-hash")]
+    HeaderHash,
 }
 
 impl FromStr for Strategy {
@@ -47,15 +69,21 @@ impl FromStr for Strategy {
             "ip_hash" => Ok(Self::IpHash),
             "cookie_hash" => Ok(Self::CookieHash),
             "rps" => Ok(Self::Rps),
+            "least_connections" => Ok(Self::LeastConnections),
+            "power_of_two" => Ok(Self::PowerOfTwo),
+            "header_hash" => Ok(Self::HeaderHash),
             _ => Err("invalid strategy"),
         }
     }
 }
 
+/// What the strategy chooses a worker by.
 #[derive(Debug, Clone, Copy)]
-enum LBIdent {
+pub(crate) enum LBIdent {
     Ip(IpAddr),
     Uuid(Uuid),
+    /// Hash of the user's identity, see [`HashKey`].
+    Key(u64),
 }
 
 #[derive(Copy, Clone)]
@@ -64,6 +92,8 @@ enum DynLoadBalancer {
     RoundRobin(&'static round_robin::RoundRobin),
     CookieHash(&'static cookie_hash::CookieHash),
     Rps(&'static rps_autoscale::RpsAutoscale),
+    LeastConnections(&'static least_connections::LeastConnections),
+    HeaderHash(&'static header_hash::HeaderHash),
 }
 
 impl LoadBalancingStrategy for DynLoadBalancer {
@@ -74,6 +104,7 @@ impl LoadBalancingStrategy for DynLoadBalancer {
                 DynLoadBalancer::RoundRobin(rr) => rr.entry(ip).await,
                 DynLoadBalancer::IpHash(ih) => ih.entry(ip).await,
                 DynLoadBalancer::Rps(rr) => rr.entry(ip).await,
+                DynLoadBalancer::LeastConnections(lc) => lc.entry(ip).await,
                 _ => unreachable!(
                     "This should never happen, ip should never be passed to cookie hash"
                 ),
@@ -84,6 +115,28 @@ impl LoadBalancingStrategy for DynLoadBalancer {
                     "This should never happen, uuid should never be passed to round robin or ip hash"
                 ),
             },
+            LBIdent::Key(hash) => match self {
+                DynLoadBalancer::HeaderHash(hh) => hh.entry(hash).await,
+                _ => unreachable!(
+                    "This should never happen, keys should only be passed to header hash"
+                ),
+            },
+        }
+    }
+}
+
+impl DynLoadBalancer {
+    /// The assignments and key of `ident` for the sticky strategies.
+    fn sticky(&self, ident: LBIdent) -> Option<(&'static Rendezvous, u64)> {
+        match (*self, ident) {
+            (DynLoadBalancer::IpHash(ih), LBIdent::Ip(ip)) => {
+                Some((&ih.targets, ip_hash::calculate_hash(ip)))
+            }
+            (DynLoadBalancer::CookieHash(ch), LBIdent::Uuid(uuid)) => {
+                Some((&ch.targets, cookie_hash::calculate_hash(uuid)))
+            }
+            (DynLoadBalancer::HeaderHash(hh), LBIdent::Key(key)) => Some((&hh.targets, key)),
+            _ => None,
         }
     }
 }
@@ -91,6 +144,11 @@ impl LoadBalancingStrategy for DynLoadBalancer {
 pub(crate) struct LoadBalancer {
     strategy: DynLoadBalancer,
     extractor: IpExtractor,
+    /// Workers the strategy chooses from.
+    workers: usize,
+    /// Woken when a worker comes online or finishes a request.
+    available: &'static Notify,
+    queue: Option<&'static RequestQueue>,
 }
 
 impl LoadBalancer {
@@ -99,6 +157,7 @@ impl LoadBalancer {
         extractor: IpExtractor,
         workers: &[&'static WorkerConfig],
         max_rps_config: Option<f64>, // New parameter
+        hash_key: Option<&'static HashKey>,
     ) -> FaucetResult<Self> {
         let strategy: DynLoadBalancer = match strategy {
             Strategy::RoundRobin => {
@@ -112,31 +171,123 @@ impl LoadBalancer {
                 let rps_value = max_rps_config.unwrap_or(DEFAULT_MAX_RPS);
                 DynLoadBalancer::Rps(leak!(RpsAutoscale::new(workers, rps_value).await))
             }
+            Strategy::LeastConnections => DynLoadBalancer::LeastConnections(leak!(
+                LeastConnections::new(workers, false).await
+            )),
+            Strategy::PowerOfTwo => {
+                DynLoadBalancer::LeastConnections(leak!(LeastConnections::new(workers, true).await))
+            }
+            Strategy::HeaderHash => {
+                let key = hash_key.ok_or(FaucetError::MissingArgument("hash_key"))?;
+                DynLoadBalancer::HeaderHash(leak!(HeaderHash::new(workers, key).await))
+            }
         };
         Ok(Self {
             strategy,
             extractor,
+            workers: workers.len(),
+            available: match workers.first() {
+                Some(worker) => worker.available,
+                None => leak!(Notify::new()),
+            },
+            queue: None,
         })
     }
+    /// Limits the requests each worker serves at once, the rest wait in a
+    /// queue for a worker to be free. The queue is reported in the metrics
+    /// until `shutdown` stops the route.
+    pub fn with_queue(
+        mut self,
+        policy: Option<&'static QueuePolicy>,
+        route: Option<&'static str>,
+        shutdown: &'static ShutdownSignal,
+    ) -> Self {
+        self.queue = policy.map(|policy| -> &'static RequestQueue {
+            let queue = leak!(RequestQueue::new(policy, route, self.available));
+            crate::telemetry::metrics::register_queue(queue, shutdown);
+            queue
+        });
+        self
+    }
+    /// Route of the workers, if they serve one and have a queue.
+    pub fn route(&self) -> Option<&'static str> {
+        self.queue.and_then(|queue| queue.route)
+    }
     pub fn get_strategy(&self) -> Strategy {
         match self.strategy {
             DynLoadBalancer::RoundRobin(_) => Strategy::RoundRobin,
             DynLoadBalancer::IpHash(_) => Strategy::IpHash,
             DynLoadBalancer::CookieHash(_) => Strategy::CookieHash,
             DynLoadBalancer::Rps(_) => Strategy::Rps,
+            DynLoadBalancer::LeastConnections(lc) if lc.two_choices() => Strategy::PowerOfTwo,
+            DynLoadBalancer::LeastConnections(_) => Strategy::LeastConnections,
+            DynLoadBalancer::HeaderHash(_) => Strategy::HeaderHash,
         }
     }
-    async fn get_client_ip(&self, ip: IpAddr) -> FaucetResult<Client> {
-        Ok(self.strategy.entry(LBIdent::Ip(ip)).await)
+    /// What the strategy chooses a worker for `req`, from `ip`, by.
+    /// `lb_cookie` is the cookie of the cookie hash strategy.
+    pub fn ident<B>(&self, req: &Request<B>, ip: IpAddr, lb_cookie: Option<Uuid>) -> LBIdent {
+        match (self.strategy, lb_cookie) {
+            (DynLoadBalancer::HeaderHash(hh), _) => LBIdent::Key(hh.key().hash(req, ip)),
+            (_, Some(uuid)) => LBIdent::Uuid(uuid),
+            _ => LBIdent::Ip(ip),
+        }
     }
-    async fn get_client_uuid(&self, uuid: Uuid) -> FaucetResult<Client> {
-        Ok(self.strategy.entry(LBIdent::Uuid(uuid)).await)
+    pub async fn get_client(&self, ident: LBIdent) -> FaucetResult<Client> {
+        Ok(self.strategy.entry(ident).await)
     }
-    pub async fn get_client(&self, ip: IpAddr, uuid: Option<Uuid>) -> FaucetResult<Client> {
-        if let Some(uuid) = uuid {
-            self.get_client_uuid(uuid).await
-        } else {
-            self.get_client_ip(ip).await
+    /// Picks a worker for a WebSocket session and marks the session as in
+    /// flight on it. Sessions do not wait for a request slot, and keep a
+    /// sticky key on its worker while they are open.
+    pub async fn connect(&self, ident: LBIdent) -> FaucetResult<(Client, InFlight)> {
+        let client = self.get_client(ident).await?;
+        let mut in_flight = client.in_flight();
+        if let Some((rendezvous, key)) = self.strategy.sticky(ident) {
+            in_flight = in_flight.with_session(rendezvous.session(key));
+        }
+        Ok((client, in_flight))
+    }
+    /// Picks a worker for a request and marks the request as in flight on it.
+    ///
+    /// With a queue, waits for a worker with a free request slot, and fails
+    /// with [`FaucetError::Overloaded`](crate::error::FaucetError::Overloaded)
+    /// if the queue is full or the wait is too long.
+    pub async fn admit(&self, ident: LBIdent) -> FaucetResult<(Client, InFlight)> {
+        let Some(queue) = self.queue else {
+            let client = self.get_client(ident).await?;
+            let in_flight = client.in_flight();
+            return Ok((client, in_flight));
+        };
+        // Sticky strategies ask for their worker once and then spill over,
+        // the others may try every worker before waiting.
+        let sticky = self.strategy.sticky(ident);
+        let attempts = match sticky {
+            Some(_) => 1,
+            None => self.workers.max(1),
+        };
+        let deadline = tokio::time::Instant::now() + queue.policy.timeout;
+        let mut waiting = None;
+        loop {
+            let released = queue.released();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            for _ in 0..attempts {
+                let client = self.get_client(ident).await?;
+                if let Some(in_flight) = queue.try_reserve(&client) {
+                    return Ok((client, in_flight));
+                }
+            }
+            if let Some((rendezvous, key)) = sticky {
+                if let Some(admission) = rendezvous.spill(key, |client| queue.try_reserve(client)) {
+                    return Ok(admission);
+                }
+            }
+            if waiting.is_none() {
+                waiting = Some(queue.enter()?);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(queue.timed_out());
+            }
         }
     }
     pub fn extract_ip<B>(
@@ -153,6 +304,9 @@ impl Clone for LoadBalancer {
         Self {
             strategy: self.strategy,
             extractor: self.extractor,
+            workers: self.workers,
+            available: self.available,
+            queue: self.queue,
         }
     }
 }
@@ -169,6 +323,18 @@ mod tests {
             Strategy::RoundRobin
         );
         assert_eq!(Strategy::from_str("ip_hash").unwrap(), Strategy::IpHash);
+        assert_eq!(
+            Strategy::from_str("least_connections").unwrap(),
+            Strategy::LeastConnections
+        );
+        assert_eq!(
+            Strategy::from_str("power_of_two").unwrap(),
+            Strategy::PowerOfTwo
+        );
+        assert_eq!(
+            Strategy::from_str("header_hash").unwrap(),
+            Strategy::HeaderHash
+        );
         assert!(Strategy::from_str("invalid").is_err());
     }
 
@@ -180,6 +346,7 @@ mod tests {
             IpExtractor::XForwardedFor,
             &configs,
             None,
+            None,
         )
         .await
         .expect("failed to create load balancer");
@@ -188,9 +355,15 @@ mod tests {
     #[tokio::test]
     async fn test_load_balancer_new_ip_hash() {
         let configs = Vec::new();
-        let _ = LoadBalancer::new(Strategy::IpHash, IpExtractor::XForwardedFor, &configs, None)
-            .await
-            .expect("failed to create load balancer");
+        let _ = LoadBalancer::new(
+            Strategy::IpHash,
+            IpExtractor::XForwardedFor,
+            &configs,
+            None,
+            None,
+        )
+        .await
+        .expect("failed to create load balancer");
     }
 
     #[tokio::test]
@@ -201,6 +374,7 @@ mod tests {
             IpExtractor::XForwardedFor,
             &configs,
             None,
+            None,
         )
         .await
         .expect("failed to create load balancer");
@@ -235,18 +409,19 @@ mod tests {
             IpExtractor::XForwardedFor,
             &configs,
             None,
+            None,
         )
         .await
         .expect("failed to create load balancer");
         let ip = "192.168.0.1".parse().unwrap();
         let client = load_balancer
-            .get_client_ip(ip)
+            .get_client(LBIdent::Ip(ip))
             .await
             .expect("failed to get client");
         assert_eq!(client.socket_addr(), "127.0.0.1:9999".parse().unwrap());
 
         let client = load_balancer
-            .get_client_ip(ip)
+            .get_client(LBIdent::Ip(ip))
             .await
             .expect("failed to get client");
 
@@ -257,6 +432,172 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_load_balancer_queue() {
+        use crate::client::ExtractSocketAddr;
+        use crate::error::FaucetError;
+        let configs: [&'static WorkerConfig; 2] = [
+            leak!(WorkerConfig::dummy("test", "127.0.0.1:9989", true)),
+            leak!(WorkerConfig::dummy("test", "127.0.0.1:9988", true)),
+        ];
+        let policy = leak!(queue::QueueConfig {
+            max_concurrency: Some(1),
+            max_length: Some(1),
+            timeout: Some(0.2),
+        }
+        .resolve()
+        .unwrap());
+        let load_balancer = LoadBalancer::new(
+            Strategy::RoundRobin,
+            IpExtractor::XForwardedFor,
+            &configs,
+            None,
+            None,
+        )
+        .await
+        .expect("failed to create load balancer")
+        .with_queue(
+            Some(policy),
+            Some("/queue-test/"),
+            leak!(ShutdownSignal::new()),
+        );
+        let load_balancer: &'static LoadBalancer = leak!(load_balancer);
+        let ip = "192.168.0.1".parse().unwrap();
+
+        // Both workers are busy after a request each.
+        let (first, first_in_flight) = load_balancer.admit(LBIdent::Ip(ip)).await.unwrap();
+        let (second, _second_in_flight) = load_balancer.admit(LBIdent::Ip(ip)).await.unwrap();
+        assert_ne!(first.socket_addr(), second.socket_addr());
+
+        // The next request waits for the first worker to finish, and fills
+        // the queue.
+        let waiting = tokio::spawn(load_balancer.admit(LBIdent::Ip(ip)));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        match load_balancer.admit(LBIdent::Ip(ip)).await {
+            Err(FaucetError::Overloaded { retry_after, .. }) => assert_eq!(retry_after, 1),
+            _ => panic!("the queue should be full"),
+        }
+        drop(first_in_flight);
+        let (third, _third_in_flight) = waiting.await.unwrap().unwrap();
+        assert_eq!(third.socket_addr(), first.socket_addr());
+
+        // Nothing is released before the timeout.
+        assert!(matches!(
+            load_balancer.admit(LBIdent::Ip(ip)).await,
+            Err(FaucetError::Overloaded { .. })
+        ));
+
+        for config in configs.iter() {
+            config.wait_until_done().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn hot_sticky_keys_spill_over() {
+        use crate::client::ExtractSocketAddr;
+        let configs: [&'static WorkerConfig; 2] = [
+            leak!(WorkerConfig::dummy("test", "127.0.0.1:9984", true)),
+            leak!(WorkerConfig::dummy("test", "127.0.0.1:9983", true)),
+        ];
+        let policy = leak!(queue::QueueConfig {
+            max_concurrency: Some(1),
+            timeout: Some(0.2),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap());
+        let load_balancer: &'static LoadBalancer = leak!(LoadBalancer::new(
+            Strategy::IpHash,
+            IpExtractor::XForwardedFor,
+            &configs,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+        .with_queue(
+            Some(policy),
+            Some("/spill-test/"),
+            leak!(ShutdownSignal::new()),
+        ));
+        let ip = LBIdent::Ip("192.168.0.1".parse().unwrap());
+
+        // The key saturates its worker, its next request goes to the other
+        // worker and the key stays there.
+        let (first, first_in_flight) = load_balancer.admit(ip).await.unwrap();
+        let (second, second_in_flight) = load_balancer.admit(ip).await.unwrap();
+        assert_ne!(first.socket_addr(), second.socket_addr());
+        drop((first_in_flight, second_in_flight));
+        let (third, _third_in_flight) = load_balancer.admit(ip).await.unwrap();
+        assert_eq!(third.socket_addr(), second.socket_addr());
+
+        // A key with an open WebSocket session waits for its worker.
+        let (_, session) = load_balancer.connect(ip).await.unwrap();
+        assert!(matches!(
+            load_balancer.admit(ip).await,
+            Err(crate::error::FaucetError::Overloaded { .. })
+        ));
+        drop(session);
+        let (fourth, _fourth_in_flight) = load_balancer.admit(ip).await.unwrap();
+        assert_eq!(fourth.socket_addr(), first.socket_addr());
+
+        for config in configs.iter() {
+            config.wait_until_done().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_requests_take_workers_that_come_online() {
+        let available = leak!(Notify::new());
+        let worker = |addr, online| -> &'static WorkerConfig {
+            let mut config = WorkerConfig::dummy("test", addr, online);
+            config.available = available;
+            leak!(config)
+        };
+        let configs = [
+            worker("127.0.0.1:9986", true),
+            worker("127.0.0.1:9985", false),
+        ];
+        let policy = leak!(queue::QueueConfig {
+            max_concurrency: Some(1),
+            timeout: Some(5.0),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap());
+        let load_balancer: &'static LoadBalancer = leak!(LoadBalancer::new(
+            Strategy::LeastConnections,
+            IpExtractor::XForwardedFor,
+            &configs,
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+        .with_queue(
+            Some(policy),
+            Some("/online-test/"),
+            leak!(ShutdownSignal::new()),
+        ));
+        let ip = "192.168.0.1".parse().unwrap();
+
+        let (_first, _first_in_flight) = load_balancer.admit(LBIdent::Ip(ip)).await.unwrap();
+        let waiting = tokio::spawn(load_balancer.admit(LBIdent::Ip(ip)));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        // The busy worker is not released, the request goes to the worker
+        // that came online.
+        assert!(configs[1].set_online());
+        let (second, _second_in_flight) =
+            tokio::time::timeout(std::time::Duration::from_secs(1), waiting)
+                .await
+                .expect("the request should not wait for the queue timeout")
+                .unwrap()
+                .unwrap();
+        assert_eq!(second.config.addr(), configs[1].addr());
+    }
+
     #[tokio::test]
     async fn test_clone_load_balancer() {
         let configs = Vec::new();
@@ -265,6 +606,7 @@ mod tests {
             IpExtractor::XForwardedFor,
             &configs,
             None,
+            None,
         )
         .await
         .expect("failed to create load balancer");
@@ -0,0 +1,191 @@
+//! Limits on the requests each worker serves at once, and the queue where
+//! requests wait for a worker to be free.
+
+use crate::client::{Client, InFlight};
+use crate::error::{FaucetError, FaucetResult};
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+const DEFAULT_MAX_LENGTH: usize = 100;
+const DEFAULT_TIMEOUT_SECS: f64 = 30.0;
+
+/// User facing queue configuration, from the CLI or from a `[route.queue]`
+/// table in `frouter.toml`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Requests each worker serves at once.
+    pub max_concurrency: Option<usize>,
+    /// Requests that may wait for a worker.
+    pub max_length: Option<usize>,
+    /// Seconds a request may wait for a worker.
+    pub timeout: Option<f64>,
+}
+
+impl QueueConfig {
+    pub(crate) fn resolve(&self) -> FaucetResult<QueuePolicy> {
+        let max_concurrency = match self.max_concurrency {
+            Some(0) => {
+                return Err(FaucetError::InvalidConfig(
+                    "queue max_concurrency must be greater than 0".to_string(),
+                ))
+            }
+            Some(n) => n,
+            None => {
+                return Err(FaucetError::InvalidConfig(
+                    "queue max_concurrency is required".to_string(),
+                ))
+            }
+        };
+        let timeout = Duration::try_from_secs_f64(self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS))
+            .ok()
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| {
+                FaucetError::InvalidConfig(
+                    "queue timeout must be a positive number of seconds".to_string(),
+                )
+            })?;
+        Ok(QueuePolicy {
+            max_concurrency,
+            max_length: self.max_length.unwrap_or(DEFAULT_MAX_LENGTH),
+            timeout,
+        })
+    }
+}
+
+/// How many requests the workers serve at once and how long the rest wait.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuePolicy {
+    pub max_concurrency: usize,
+    pub max_length: usize,
+    pub timeout: Duration,
+}
+
+impl QueuePolicy {
+    /// Seconds clients are told to wait before retrying a rejected request.
+    pub fn retry_after(&self) -> u64 {
+        (self.timeout.as_secs_f64().ceil() as u64).max(1)
+    }
+}
+
+/// Requests waiting for a worker of a route.
+pub struct RequestQueue {
+    pub policy: &'static QueuePolicy,
+    /// Route of the workers, for the metrics.
+    pub route: Option<&'static str>,
+    waiting: AtomicUsize,
+    /// Woken every time a worker finishes a request or comes online.
+    released: &'static Notify,
+    rejected_full: AtomicU64,
+    rejected_timeout: AtomicU64,
+}
+
+/// A place in the queue, given up when dropped.
+pub(crate) struct Waiting(&'static RequestQueue);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RequestQueue {
+    /// `released` is the notify the route's workers wake when they come
+    /// online.
+    pub(crate) fn new(
+        policy: &'static QueuePolicy,
+        route: Option<&'static str>,
+        released: &'static Notify,
+    ) -> Self {
+        RequestQueue {
+            policy,
+            route,
+            waiting: AtomicUsize::new(0),
+            released,
+            rejected_full: AtomicU64::new(0),
+            rejected_timeout: AtomicU64::new(0),
+        }
+    }
+    /// Requests waiting for a worker.
+    pub fn len(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Requests rejected because the queue was full.
+    pub fn rejected_full(&self) -> u64 {
+        self.rejected_full.load(Ordering::SeqCst)
+    }
+    /// Requests rejected because they waited too long.
+    pub fn rejected_timeout(&self) -> u64 {
+        self.rejected_timeout.load(Ordering::SeqCst)
+    }
+    /// Takes a request slot on the worker of `client` if it has one free.
+    pub(crate) fn try_reserve(&'static self, client: &Client) -> Option<InFlight> {
+        client.try_reserve(self.policy.max_concurrency, self.released)
+    }
+    /// Resolves the next time a worker finishes a request or comes online.
+    /// Must be enabled before checking the workers, so that no release is
+    /// missed.
+    pub(crate) fn released(&self) -> tokio::sync::futures::Notified<'_> {
+        self.released.notified()
+    }
+    /// Takes a place in the queue, unless it is full.
+    pub(crate) fn enter(&'static self) -> FaucetResult<Waiting> {
+        self.waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.policy.max_length).then_some(n + 1)
+            })
+            .map(|_| Waiting(self))
+            .map_err(|_| {
+                self.rejected_full.fetch_add(1, Ordering::SeqCst);
+                FaucetError::Overloaded {
+                    reason: "the request queue is full",
+                    retry_after: self.policy.retry_after(),
+                }
+            })
+    }
+    /// The error for a request that waited longer than the timeout.
+    pub(crate) fn timed_out(&self) -> FaucetError {
+        self.rejected_timeout.fetch_add(1, Ordering::SeqCst);
+        FaucetError::Overloaded {
+            reason: "no worker was free before the queue timeout",
+            retry_after: self.policy.retry_after(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_from_toml() {
+        let config: QueueConfig = toml::from_str(
+            r#"
+            max_concurrency = 1
+            timeout = 2.5
+            "#,
+        )
+        .unwrap();
+        let policy = config.resolve().unwrap();
+        assert_eq!(policy.max_concurrency, 1);
+        assert_eq!(policy.max_length, 100);
+        assert_eq!(policy.timeout, Duration::from_millis(2500));
+        assert_eq!(policy.retry_after(), 3);
+
+        for source in [
+            "max_length = 10",
+            "max_concurrency = 0",
+            "max_concurrency = 1\ntimeout = 0",
+        ] {
+            let config: QueueConfig = toml::from_str(source).unwrap();
+            assert!(config.resolve().is_err(), "{source}");
+        }
+        assert!(toml::from_str::<QueueConfig>("max_lenght = 1").is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//! Rendezvous hashing with bounded loads, used by the sticky strategies.
+//!
+//! Every key ranks the workers in its own order of preference. A new key
+//! goes to its preferred worker unless that worker is much busier than the
+//! others, and then to its next worker in line. Keys are remembered and
+//! stay on their worker, they only move when it goes offline, or when it
+//! has no free request slot and the key has no open WebSocket session.
+//! Adding a worker only moves the new keys that prefer it.
+
+use super::WorkerConfig;
+use crate::client::{
+    recent::{RecentKeys, MAX_KEYS},
+    Client,
+};
+use crate::leak;
+use std::cmp::Reverse;
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How much busier than the average a worker may be before the keys that
+/// prefer it spill over to their next worker.
+const LOAD_FACTOR: f64 = 1.25;
+
+/// First wait for a worker to come online when every worker is offline.
+const BASE_BACKOFF: Duration = Duration::from_millis(1);
+/// Longest wait for a worker to come online.
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Wait after `retries` tries found every worker offline, doubling up to
+/// [`MAX_BACKOFF`].
+fn calculate_exponential_backoff(retries: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(retries))
+        .min(MAX_BACKOFF)
+}
+
+/// Finalizer of MurmurHash3, spreads the bits of `value` over the whole
+/// `u64`.
+pub(crate) fn mix(mut value: u64) -> u64 {
+    value ^= value >> 33;
+    value = value.wrapping_mul(0xff51afd7ed558ccd);
+    value ^= value >> 33;
+    value = value.wrapping_mul(0xc4ceb9fe1a85ec53);
+    value ^= value >> 33;
+    value
+}
+
+/// Score of the worker at `index` for `key`, the highest score wins.
+fn score(key: u64, index: usize) -> u64 {
+    mix(key ^ mix(index as u64 + 1))
+}
+
+/// Indices of `len` workers in the order `key` prefers them.
+pub(crate) fn rank(key: u64, len: usize) -> Vec<usize> {
+    let mut indices = (0..len).collect::<Vec<_>>();
+    indices.sort_unstable_by_key(|&index| Reverse(score(key, index)));
+    indices
+}
+
+/// The most requests a worker may serve before keys spill over, with
+/// `total` requests in flight over `online` workers.
+fn bound(total: usize, online: usize) -> usize {
+    (LOAD_FACTOR * (total + 1) as f64 / online as f64).ceil() as usize
+}
+
+/// Locks the assignments are spread over, so that requests for different
+/// keys rarely wait for each other.
+const SHARDS: usize = 16;
+
+#[derive(Default)]
+struct Shard {
+    /// Index of the worker each key was assigned to.
+    assigned: RecentKeys<usize>,
+    /// Open WebSocket sessions of each key, which keep it on its worker.
+    sessions: HashMap<u64, usize>,
+}
+
+/// An open WebSocket session of a key, counted until it is dropped.
+pub(crate) struct Session {
+    shard: &'static Mutex<Shard>,
+    key: u64,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut shard = self.shard.lock().expect("Assignments lock poisoned");
+        if let Entry::Occupied(mut sessions) = shard.sessions.entry(self.key) {
+            *sessions.get_mut() -= 1;
+            if *sessions.get() == 0 {
+                sessions.remove();
+            }
+        }
+    }
+}
+
+pub(crate) struct Rendezvous {
+    pub(crate) targets: &'static [Client],
+    shards: &'static [Mutex<Shard>],
+}
+
+impl Rendezvous {
+    pub(crate) fn new(configs: &[&'static WorkerConfig]) -> Self {
+        let targets = configs
+            .iter()
+            .map(|config| Client::new(config))
+            .collect::<Vec<_>>();
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    assigned: RecentKeys::with_max_keys(MAX_KEYS / SHARDS),
+                    sessions: HashMap::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+        Rendezvous {
+            targets: leak!(targets),
+            shards: leak!(shards),
+        }
+    }
+    fn shard(&self, key: u64) -> &'static Mutex<Shard> {
+        &self.shards[key as usize % SHARDS]
+    }
+    /// The worker of `key`, waiting for one to come online if every worker
+    /// is offline. `waiting` names what waits in the log.
+    pub(crate) async fn entry(&self, key: u64, waiting: impl Fn() -> String) -> Client {
+        let mut retries = 0;
+        loop {
+            if let Some(client) = self.pick(key) {
+                // Sessions that started on a process replaced by a rolling
+                // restart stay on it until it drains.
+                if let Some(previous) = client.config.sticky_client(key) {
+                    break previous;
+                }
+                break client.clone();
+            }
+
+            let backoff = calculate_exponential_backoff(retries);
+
+            log::debug!(
+                target: "faucet",
+                "{} found every worker offline, retrying in {backoff:?}",
+                waiting()
+            );
+
+            tokio::time::sleep(backoff).await;
+            retries += 1;
+        }
+    }
+    /// The worker `key` was assigned to while it is online. Otherwise the
+    /// first online worker in `key`'s order that is not over the bound,
+    /// which `key` is then assigned to. `None` if every worker is offline.
+    pub(crate) fn pick(&self, key: u64) -> Option<&Client> {
+        let now = tokio::time::Instant::now();
+        let mut shard = self.shard(key).lock().expect("Assignments lock poisoned");
+        let index = match shard.assigned.get(key, now) {
+            Some(&index) if self.targets[index].is_online() => index,
+            _ => self.assign(key)?,
+        };
+        shard.assigned.insert(key, index, now);
+        Some(&self.targets[index])
+    }
+    /// Moves `key` to another worker when its own has no free request slot.
+    /// Workers are tried in the order of the bounded choice, and `key` is
+    /// assigned to the first that `reserve` takes a slot on. Keys with an
+    /// open WebSocket session stay on their worker.
+    pub(crate) fn spill<T>(
+        &self,
+        key: u64,
+        reserve: impl Fn(&Client) -> Option<T>,
+    ) -> Option<(Client, T)> {
+        let mut shard = self.shard(key).lock().expect("Assignments lock poisoned");
+        if shard.sessions.contains_key(&key) {
+            return None;
+        }
+        let (bound, ranked) = self.ranked_online(key)?;
+        let (under, over): (Vec<_>, Vec<_>) = ranked
+            .into_iter()
+            .partition(|&index| self.targets[index].requests_in_flight() < bound);
+        let (index, reserved) = under
+            .into_iter()
+            .chain(over)
+            .find_map(|index| Some((index, reserve(&self.targets[index])?)))?;
+        shard
+            .assigned
+            .insert(key, index, tokio::time::Instant::now());
+        Some((self.targets[index].clone(), reserved))
+    }
+    /// Counts a WebSocket session of `key` as open until the returned
+    /// [`Session`] is dropped.
+    pub(crate) fn session(&self, key: u64) -> Session {
+        let shard = self.shard(key);
+        *shard
+            .lock()
+            .expect("Assignments lock poisoned")
+            .sessions
+            .entry(key)
+            .or_default() += 1;
+        Session { shard, key }
+    }
+    /// The bound and the online workers in `key`'s order. `None` if every
+    /// worker is offline.
+    fn ranked_online(&self, key: u64) -> Option<(usize, Vec<usize>)> {
+        let (online, total) = self
+            .targets
+            .iter()
+            .filter(|client| client.is_online())
+            .fold((0, 0), |(online, total), client| {
+                (online + 1, total + client.requests_in_flight())
+            });
+        if online == 0 {
+            return None;
+        }
+        let ranked = rank(key, self.targets.len())
+            .into_iter()
+            .filter(|&index| self.targets[index].is_online())
+            .collect();
+        Some((bound(total, online), ranked))
+    }
+    fn assign(&self, key: u64) -> Option<usize> {
+        let (bound, ranked) = self.ranked_online(key)?;
+        let preferred = *ranked.first()?;
+        Some(
+            ranked
+                .into_iter()
+                .find(|&index| self.targets[index].requests_in_flight() < bound)
+                .unwrap_or(preferred),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ExtractSocketAddr;
+
+    #[test]
+    fn test_calculate_exponential_backoff() {
+        assert_eq!(calculate_exponential_backoff(0), BASE_BACKOFF);
+        assert_eq!(calculate_exponential_backoff(1), BASE_BACKOFF * 2);
+        assert_eq!(calculate_exponential_backoff(3), BASE_BACKOFF * 8);
+        assert_eq!(calculate_exponential_backoff(9), MAX_BACKOFF);
+        assert_eq!(calculate_exponential_backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn keys_are_spread_evenly() {
+        const N_KEYS: usize = 100_000;
+        for len in 2..=4 {
+            let mut counts = vec![0; len];
+            for _ in 0..N_KEYS {
+                counts[rank(rand::random(), len)[0]] += 1;
+            }
+            let expected = 1.0 / len as f64;
+            for count in counts {
+                let share = count as f64 / N_KEYS as f64;
+                assert!((share - expected).abs() < 0.01, "{share} {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn adding_a_worker_only_moves_keys_to_it() {
+        let mut moved = 0;
+        for _ in 0..10_000 {
+            let key = rand::random();
+            let before = rank(key, 4)[0];
+            let after = rank(key, 5)[0];
+            if before != after {
+                assert_eq!(after, 4);
+                moved += 1;
+            }
+        }
+        // About a fifth of the keys
+        assert!((1500..2500).contains(&moved), "{moved}");
+    }
+
+    #[test]
+    fn bounds() {
+        assert_eq!(bound(0, 2), 1);
+        assert_eq!(bound(1, 2), 2);
+        assert_eq!(bound(2, 2), 2);
+        assert_eq!(bound(10, 4), 4);
+    }
+
+    fn workers(port: u16) -> Vec<&'static WorkerConfig> {
+        (0..2)
+            .map(|i| -> &'static WorkerConfig {
+                leak!(WorkerConfig::dummy(
+                    "test",
+                    &format!("127.0.0.1:{}", port + i),
+                    true
+                ))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sticky_keys_stay_on_busy_workers() {
+        let configs = workers(9120);
+        let rendezvous = Rendezvous::new(&configs);
+        let key = 42;
+        let ranked = rank(key, 2);
+        let addr = |index: usize| configs[index].addr();
+        assert_eq!(rendezvous.pick(key).unwrap().socket_addr(), addr(ranked[0]));
+
+        // The worker is far over the average, its key stays on it.
+        let preferred = &rendezvous.targets[ranked[0]];
+        let busy = (0..3).map(|_| preferred.in_flight()).collect::<Vec<_>>();
+        assert_eq!(rendezvous.pick(key).unwrap().socket_addr(), addr(ranked[0]));
+
+        // A new key that prefers it spills over, and stays on its next
+        // worker once the load is gone.
+        let other = (0..)
+            .find(|&other| other != key && rank(other, 2)[0] == ranked[0])
+            .unwrap();
+        assert_eq!(
+            rendezvous.pick(other).unwrap().socket_addr(),
+            addr(ranked[1])
+        );
+        drop(busy);
+        assert_eq!(
+            rendezvous.pick(other).unwrap().socket_addr(),
+            addr(ranked[1])
+        );
+        assert_eq!(rendezvous.pick(key).unwrap().socket_addr(), addr(ranked[0]));
+    }
+
+    #[tokio::test]
+    async fn keys_move_off_offline_workers() {
+        let configs = workers(9122);
+        let rendezvous = Rendezvous::new(&configs);
+        let key = 42;
+        let ranked = rank(key, 2);
+        let addr = |index: usize| configs[index].addr();
+        assert_eq!(rendezvous.pick(key).unwrap().socket_addr(), addr(ranked[0]));
+
+        let set_online = |index: usize, online: bool| {
+            configs[index]
+                .is_online
+                .store(online, std::sync::atomic::Ordering::SeqCst)
+        };
+        set_online(ranked[0], false);
+        assert_eq!(rendezvous.pick(key).unwrap().socket_addr(), addr(ranked[1]));
+
+        // The key now belongs to its new worker.
+        set_online(ranked[0], true);
+        assert_eq!(rendezvous.pick(key).unwrap().socket_addr(), addr(ranked[1]));
+
+        set_online(0, false);
+        set_online(1, false);
+        assert!(rendezvous.pick(key).is_none());
+    }
+}
@@ -3,6 +3,7 @@ use tokio::sync::Mutex;
 
 use super::LoadBalancingStrategy;
 use crate::client::{worker::WorkerConfig, Client};
+use crate::telemetry::metrics;
 use std::net::IpAddr;
 
 struct RequestCounter {
@@ -94,8 +95,15 @@ impl Targets {
 
         let request_per_second_calculator_handle = tokio::spawn(async move {
             let mut last_big_reset_time = std::time::Instant::now();
+            // Every worker of a route shares the same shutdown signal.
+            let Some(shutdown) = targets.first().map(|target| target.config.shutdown) else {
+                return;
+            };
             loop {
-                tokio::time::sleep(std::time::Duration::from_secs_f64(WINDOW_SIZE)).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(std::time::Duration::from_secs_f64(WINDOW_SIZE)) => (),
+                    _ = shutdown.wait() => break,
+                }
 
                 let is_big_reset_due =
                     last_big_reset_time.elapsed().as_secs_f64() >= BIG_RESET_WINDOW_SIZE;
@@ -107,6 +115,7 @@ impl Targets {
                 for i in 0..targets.len() {
                     let mut rc_guard = request_counter_static_slice[i].lock().await;
                     let calculated_rps = rc_guard.set_new_window();
+                    metrics::set_worker_rps(targets[i].config, calculated_rps, rc_guard.max_rps);
 
                     if calculated_rps > rc_guard.max_rps {
                         log::debug!(
@@ -1,10 +1,28 @@
 mod body;
+mod command;
+mod env;
+mod health;
+mod limits;
+mod monitor;
 mod pool;
+mod r_options;
+mod recent;
+mod watch;
 mod websockets;
 
 pub mod load_balancing;
 pub mod worker;
 pub use body::ExclusiveBody;
-pub(crate) use pool::Client;
+pub use command::{CommandConfig, WorkerCommand};
+pub use env::WorkerEnv;
+pub use health::{HealthCheck, HealthCheckConfig};
+pub use limits::{ByteSize, LimitsConfig, ProcessLimits};
+pub use load_balancing::queue::{QueueConfig, QueuePolicy};
+pub use monitor::{ProcessStats, RecycleConfig, RecyclePolicy};
 pub use pool::ExtractSocketAddr;
-pub use websockets::UpgradeStatus;
+pub(crate) use pool::{Client, InFlight};
+pub(crate) use r_options::{merge_options, parse_options_table};
+pub use r_options::{ROption, ROptionValue, R_OPTIONS_ENV};
+pub(crate) use watch::spawn_watcher;
+pub(crate) use websockets::{decline_h2c_upgrade, is_upgrade_request};
+pub use websockets::{websocket_sessions, UpgradeStatus, WebSocketSessionInfo};
@@ -0,0 +1,443 @@
+//! Sampling of the memory and CPU used by the workers' processes, and the
+//! policies that recycle a worker's process before it grows too old.
+
+use super::limits::ByteSize;
+use crate::error::{FaucetError, FaucetResult};
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+const DEFAULT_INTERVAL_SECS: f64 = 10.0;
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// User facing recycle configuration, from the CLI or from a
+/// `[route.recycle]` table in `frouter.toml`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecycleConfig {
+    /// Memory, resident in RAM, the processes of a worker may use.
+    pub max_memory: Option<ByteSize>,
+    /// Requests a process may serve.
+    pub max_requests: Option<u64>,
+    /// Seconds a process may run.
+    pub max_age: Option<f64>,
+    /// Seconds between samples of the memory and CPU of the processes.
+    pub interval: Option<f64>,
+}
+
+fn duration_from_secs(secs: f64, field: &str) -> FaucetResult<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| {
+            FaucetError::InvalidConfig(format!(
+                "recycle {field} must be a positive number of seconds"
+            ))
+        })
+}
+
+impl RecycleConfig {
+    pub(crate) fn resolve(&self) -> FaucetResult<RecyclePolicy> {
+        if self.max_requests == Some(0) {
+            return Err(FaucetError::InvalidConfig(
+                "recycle max_requests must be greater than 0".to_string(),
+            ));
+        }
+        Ok(RecyclePolicy {
+            max_memory: self.max_memory.map(|size| size.0),
+            max_requests: self.max_requests,
+            max_age: self
+                .max_age
+                .map(|secs| duration_from_secs(secs, "max_age"))
+                .transpose()?,
+            interval: duration_from_secs(
+                self.interval.unwrap_or(DEFAULT_INTERVAL_SECS),
+                "interval",
+            )?,
+            sample_usage: false,
+        })
+    }
+}
+
+/// When to replace a worker's process with a fresh one.
+#[derive(Debug, Clone)]
+pub struct RecyclePolicy {
+    pub max_memory: Option<u64>,
+    pub max_requests: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub interval: Duration,
+    /// Whether the memory and CPU of the processes are sampled without a
+    /// `max_memory`, because the admin API or the metrics report them.
+    pub sample_usage: bool,
+}
+
+impl Default for RecyclePolicy {
+    /// Never recycles nor samples.
+    fn default() -> Self {
+        RecyclePolicy {
+            max_memory: None,
+            max_requests: None,
+            max_age: None,
+            interval: Duration::from_secs_f64(DEFAULT_INTERVAL_SECS),
+            sample_usage: false,
+        }
+    }
+}
+
+/// Usage of the current process of a worker.
+#[derive(Debug, Default)]
+pub struct ProcessStats {
+    requests: AtomicU64,
+    memory: AtomicU64,
+    /// CPUs used over the last interval, as the bits of an `f64`.
+    cpu: AtomicU64,
+}
+
+impl ProcessStats {
+    /// Requests sent to the process.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::SeqCst)
+    }
+    /// Resident memory of the process and its children, in bytes.
+    pub fn memory(&self) -> u64 {
+        self.memory.load(Ordering::SeqCst)
+    }
+    /// CPUs used by the process and its children over the last interval.
+    pub fn cpu(&self) -> f64 {
+        f64::from_bits(self.cpu.load(Ordering::SeqCst))
+    }
+    pub(crate) fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+    /// Starts over for a new process.
+    pub(crate) fn reset(&self) {
+        self.requests.store(0, Ordering::SeqCst);
+        self.memory.store(0, Ordering::SeqCst);
+        self.cpu.store(0f64.to_bits(), Ordering::SeqCst);
+    }
+}
+
+/// Memory and CPU time of a group of processes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Usage {
+    memory: u64,
+    cpu_seconds: f64,
+}
+
+/// Process group, CPU ticks and resident pages from `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<(u32, u64, u64)> {
+    // The command name may contain spaces, the fields start after it.
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields = fields.split_whitespace().collect::<Vec<_>>();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    let pgrp = u32::try_from(field(5)?).ok()?;
+    Some((pgrp, field(14)? + field(15)?, field(24)?))
+}
+
+/// CPU ticks and resident pages of every process in the process group
+/// `pgid`, found by reading every process in `/proc`.
+#[cfg(target_os = "linux")]
+fn process_group_samples(pgid: u32) -> Option<Vec<(u64, u64)>> {
+    let mut samples = Vec::new();
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let is_pid = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+        // Processes may exit while we read them.
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        match parse_stat(&stat) {
+            Some((pgrp, cpu, pages)) if pgrp == pgid => samples.push((cpu, pages)),
+            _ => (),
+        }
+    }
+    Some(samples)
+}
+
+/// CPU ticks and resident pages of the processes listed in `procs`, the
+/// `cgroup.procs` of a cgroup.
+#[cfg(target_os = "linux")]
+fn cgroup_samples(procs: &Path) -> Option<Vec<(u64, u64)>> {
+    let samples = std::fs::read_to_string(procs)
+        .ok()?
+        .lines()
+        .filter_map(|pid| std::fs::read_to_string(format!("/proc/{pid}/stat")).ok())
+        .filter_map(|stat| parse_stat(&stat))
+        .map(|(_, cpu, pages)| (cpu, pages))
+        .collect();
+    Some(samples)
+}
+
+/// Usage of the processes of a worker: those in its cgroup, if it has one,
+/// or every process in the process group `pgid`. Workers start their own
+/// process group, so this includes whatever they started.
+#[cfg(target_os = "linux")]
+fn worker_usage(pgid: u32, cgroup_procs: Option<&Path>) -> Option<Usage> {
+    let page_size = unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) }.max(1) as u64;
+    let ticks = unsafe { nix::libc::sysconf(nix::libc::_SC_CLK_TCK) }.max(1) as f64;
+    let samples = match cgroup_procs {
+        Some(procs) => cgroup_samples(procs)?,
+        None => process_group_samples(pgid)?,
+    };
+    samples
+        .into_iter()
+        .map(|(cpu, pages)| Usage {
+            memory: pages * page_size,
+            cpu_seconds: cpu as f64 / ticks,
+        })
+        .reduce(|total, usage| Usage {
+            memory: total.memory + usage.memory,
+            cpu_seconds: total.cpu_seconds + usage.cpu_seconds,
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn worker_usage(_pgid: u32, _cgroup_procs: Option<&Path>) -> Option<Usage> {
+    None
+}
+
+impl RecyclePolicy {
+    /// The age at which a process is recycled, up to 10% later than
+    /// `max_age` so that workers started together are not all replaced at
+    /// the same time.
+    fn deadline(&self, started: Instant) -> Option<Instant> {
+        self.max_age
+            .map(|max_age| started + max_age.mul_f64(1.0 + rand::random::<f64>() / 10.0))
+    }
+
+    /// Why a process that used `memory` bytes and served `requests` should
+    /// be recycled, if it should.
+    fn exceeded(&self, memory: u64, requests: u64) -> Option<String> {
+        if let Some(max_memory) = self.max_memory.filter(|max| memory > *max) {
+            return Some(format!(
+                "uses {} MiB of memory, over the limit of {} MiB",
+                memory / (1024 * 1024),
+                max_memory / (1024 * 1024)
+            ));
+        }
+        if self.max_requests.is_some_and(|max| requests >= max) {
+            return Some(format!("served {requests} requests"));
+        }
+        None
+    }
+
+    /// How long to wait before recycling a process again after `failures`
+    /// replacements in a row failed to start: the interval, doubled after
+    /// each failure, up to an hour.
+    pub(crate) fn backoff(&self, failures: u32) -> Duration {
+        self.interval
+            .saturating_mul(1 << failures.min(16))
+            .min(MAX_BACKOFF)
+    }
+
+    /// Samples the processes of the process `pid`, started at `started`,
+    /// into `stats` every interval: those in the cgroup with the
+    /// `cgroup_procs` file, if it runs in one, or else in its process group.
+    ///
+    /// Only returns, with the reason, when the process should be recycled,
+    /// and not before `not_before`.
+    pub(crate) async fn monitor(
+        &self,
+        stats: &ProcessStats,
+        pid: u32,
+        cgroup_procs: Option<&Path>,
+        started: Instant,
+        not_before: Instant,
+    ) -> String {
+        let deadline = self
+            .deadline(started)
+            .map(|deadline| deadline.max(Instant::now() + self.interval).max(not_before));
+        let sample = self.sample_usage || self.max_memory.is_some();
+        // With nothing to check every interval, only the age matters.
+        let tick = sample || self.max_requests.is_some();
+        let cgroup_procs = cgroup_procs.map(Path::to_path_buf);
+        let mut last: Option<(Instant, f64)> = None;
+        loop {
+            let sleep = async {
+                if tick {
+                    tokio::time::sleep(self.interval).await
+                } else {
+                    std::future::pending().await
+                }
+            };
+            match deadline {
+                Some(deadline) => tokio::select! {
+                    _ = sleep => (),
+                    _ = tokio::time::sleep_until(deadline) => {
+                        return format!("has run for {:?}", started.elapsed());
+                    }
+                },
+                None => sleep.await,
+            }
+            let now = Instant::now();
+            let usage = if sample {
+                let cgroup_procs = cgroup_procs.clone();
+                tokio::task::spawn_blocking(move || worker_usage(pid, cgroup_procs.as_deref()))
+                    .await
+                    .ok()
+                    .flatten()
+            } else {
+                None
+            };
+            if let Some(usage) = usage {
+                stats.memory.store(usage.memory, Ordering::SeqCst);
+                if let Some((at, cpu_seconds)) = last {
+                    // Children that exited take their CPU time with them.
+                    let used = (usage.cpu_seconds - cpu_seconds).max(0.0);
+                    let cpu = used / now.duration_since(at).as_secs_f64();
+                    stats.cpu.store(cpu.to_bits(), Ordering::SeqCst);
+                }
+                last = Some((now, usage.cpu_seconds));
+            }
+            if now < not_before {
+                continue;
+            }
+            if let Some(reason) = self.exceeded(stats.memory(), stats.requests()) {
+                return reason;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycle_from_toml() {
+        let config: RecycleConfig = toml::from_str(
+            r#"
+            max_memory = "1GiB"
+            max_requests = 10000
+            max_age = 86400
+            "#,
+        )
+        .unwrap();
+        let policy = config.resolve().unwrap();
+        assert_eq!(policy.max_memory, Some(1 << 30));
+        assert_eq!(policy.max_age, Some(Duration::from_secs(86400)));
+        assert_eq!(policy.interval, Duration::from_secs(10));
+
+        for source in ["max_requests = 0", "max_age = 0", "interval = -1"] {
+            let config: RecycleConfig = toml::from_str(source).unwrap();
+            assert!(config.resolve().is_err(), "{source}");
+        }
+        assert!(toml::from_str::<RecycleConfig>("max_memroy = 1").is_err());
+    }
+
+    #[test]
+    fn stat_fields() {
+        let stat = "4211 (R worker) S 4200 4211 4211 0 -1 4194560 27013 0 0 0 \
+                    250 50 0 0 20 0 3 0 123456 912345678 51200 18446744073709551615";
+        assert_eq!(parse_stat(stat), Some((4211, 300, 51200)));
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn policies() {
+        let policy = RecyclePolicy {
+            max_memory: Some(512 * 1024 * 1024),
+            max_requests: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(policy.exceeded(1024, 99), None);
+        assert_eq!(
+            policy.exceeded(600 * 1024 * 1024, 0).as_deref(),
+            Some("uses 600 MiB of memory, over the limit of 512 MiB")
+        );
+        assert_eq!(
+            policy.exceeded(0, 100).as_deref(),
+            Some("served 100 requests")
+        );
+        assert_eq!(RecyclePolicy::default().exceeded(u64::MAX, u64::MAX), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn samples_own_process_group() {
+        let pgid = unsafe { nix::libc::getpgrp() } as u32;
+        let usage = worker_usage(pgid, None).unwrap();
+        assert!(usage.memory > 0);
+
+        // In a cgroup only the processes it lists are sampled.
+        let dir = crate::testing::TempDir::new("faucet-monitor");
+        let procs = dir.join("cgroup.procs");
+        std::fs::write(&procs, format!("{}\n", std::process::id())).unwrap();
+        let own = worker_usage(0, Some(&procs)).unwrap();
+        assert!(own.memory > 0);
+        std::fs::write(&procs, "").unwrap();
+        assert_eq!(worker_usage(0, Some(&procs)), None);
+
+        let policy = RecyclePolicy {
+            max_memory: Some(1),
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let stats = ProcessStats::default();
+        let reason = tokio::time::timeout(
+            Duration::from_secs(5),
+            policy.monitor(&stats, pgid, None, Instant::now(), Instant::now()),
+        )
+        .await
+        .unwrap();
+        assert!(reason.contains("memory"), "{reason}");
+        assert!(stats.memory() > 0);
+    }
+
+    #[tokio::test]
+    async fn recycles_old_processes() {
+        let policy = RecyclePolicy {
+            max_age: Some(Duration::from_millis(20)),
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let reason = tokio::time::timeout(
+            Duration::from_secs(5),
+            policy.monitor(
+                &ProcessStats::default(),
+                0,
+                None,
+                Instant::now(),
+                Instant::now(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(reason.starts_with("has run for"), "{reason}");
+    }
+
+    #[tokio::test]
+    async fn failed_replacements_back_off() {
+        let policy = RecyclePolicy {
+            max_requests: Some(1),
+            max_age: Some(Duration::from_millis(1)),
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(3), Duration::from_millis(80));
+        assert_eq!(RecyclePolicy::default().backoff(u32::MAX), MAX_BACKOFF);
+
+        // The process is over every limit, but the replacement started for
+        // it failed, so it is not recycled again before the backoff.
+        let stats = ProcessStats::default();
+        stats.count_request();
+        let started = Instant::now();
+        let not_before = started + policy.backoff(3);
+        let reason = tokio::time::timeout(
+            Duration::from_secs(5),
+            policy.monitor(&stats, 0, None, started, not_before),
+        )
+        .await
+        .unwrap();
+        assert!(Instant::now() >= not_before, "{reason}");
+    }
+}
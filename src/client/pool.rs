@@ -1,4 +1,5 @@
 use super::body::ExclusiveBody;
+use super::load_balancing::Session;
 use super::worker::WorkerConfig;
 use crate::error::{FaucetError, FaucetResult};
 use crate::global_conn::{add_connection, remove_connection};
@@ -6,22 +7,36 @@ use deadpool::managed::{self, Object, Pool, RecycleError};
 use http_body_util::BodyExt;
 use hyper::body::Incoming;
 use hyper::client::conn::http1::SendRequest;
-use hyper::{Request, Response};
+use hyper::{header::HeaderValue, Request, Response, Uri, Version};
 use hyper_util::rt::TokioIo;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::{net::TcpStream, sync::Notify};
 
 struct ConnectionHandle {
     sender: SendRequest<Incoming>,
+    /// Address of the process this connection was made to.
+    addr: SocketAddr,
 }
 
 struct ConnectionManager {
     config: &'static WorkerConfig,
+    /// Connect to this process instead of the worker's current one.
+    pinned_addr: Option<SocketAddr>,
 }
 
 impl ConnectionManager {
-    fn new(config: &'static WorkerConfig) -> Self {
-        Self { config }
+    fn new(config: &'static WorkerConfig, pinned_addr: Option<SocketAddr>) -> Self {
+        Self {
+            config,
+            pinned_addr,
+        }
+    }
+    fn addr(&self) -> SocketAddr {
+        self.pinned_addr.unwrap_or_else(|| self.config.addr())
     }
 }
 
@@ -33,8 +48,9 @@ impl managed::Manager for ConnectionManager {
 
     async fn create(&self) -> FaucetResult<Self::Type> {
         log::debug!(target: "faucet", "Establishing TCP connection to {}", self.config.target);
+        let addr = self.addr();
         let connection_res = loop {
-            match TcpStream::connect(self.config.addr).await {
+            match TcpStream::connect(addr).await {
                 Ok(stream) => break stream,
                 Err(_) => tokio::time::sleep(RETRY_DELAY).await,
             }
@@ -50,7 +66,7 @@ impl managed::Manager for ConnectionManager {
             }
         });
         log::debug!(target: "faucet", "Established TCP connection to {}", self.config.target);
-        Ok(ConnectionHandle { sender })
+        Ok(ConnectionHandle { sender, addr })
     }
 
     async fn recycle(
@@ -58,13 +74,19 @@ impl managed::Manager for ConnectionManager {
         conn: &mut ConnectionHandle,
         _: &managed::Metrics,
     ) -> managed::RecycleResult<FaucetError> {
-        if !self
-            .config
-            .is_online
-            .load(std::sync::atomic::Ordering::SeqCst)
+        // A pinned pool talks to a process that is being drained, it is
+        // offline on purpose.
+        if self.pinned_addr.is_none()
+            && !self
+                .config
+                .is_online
+                .load(std::sync::atomic::Ordering::SeqCst)
         {
             return Err(RecycleError::message("Worker is offline"));
         }
+        if conn.addr != self.addr() {
+            return Err(RecycleError::message("Worker moved to a new process"));
+        }
         if conn.sender.is_closed() {
             Err(RecycleError::message("Connection closed"))
         } else {
@@ -74,16 +96,91 @@ impl managed::Manager for ConnectionManager {
     }
 }
 
+/// Workers only speak HTTP/1.1, so requests that arrived over HTTP/2 are
+/// rewritten into a shape an HTTP/1.1 server understands: the `:authority`
+/// pseudo-header becomes `Host`, the URI goes back to origin-form and split
+/// `cookie` headers are joined into one.
+pub(crate) fn downgrade_to_http11<B>(request: &mut Request<B>) {
+    if request.version() == Version::HTTP_11 || request.version() == Version::HTTP_10 {
+        return;
+    }
+
+    *request.version_mut() = Version::HTTP_11;
+
+    if !request.headers().contains_key(hyper::header::HOST) {
+        if let Some(host) = request
+            .uri()
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        {
+            request.headers_mut().insert(hyper::header::HOST, host);
+        }
+    }
+
+    if let Some(path_and_query) = request.uri().path_and_query().cloned() {
+        *request.uri_mut() = Uri::from(path_and_query);
+    }
+
+    let cookies = request
+        .headers()
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+    if cookies.len() > 1 {
+        if let Ok(joined) = HeaderValue::from_str(&cookies.join("; ")) {
+            request.headers_mut().insert(hyper::header::COOKIE, joined);
+        }
+    }
+}
+
+/// Counts a request or WebSocket session as in flight on its worker until
+/// it is dropped.
+pub(crate) struct InFlight {
+    counter: &'static AtomicUsize,
+    /// Woken when the request is done, for the requests waiting in a queue.
+    released: Option<&'static Notify>,
+    /// Keeps the sticky key of a WebSocket session on its worker.
+    session: Option<Session>,
+}
+
+impl InFlight {
+    fn new(counter: &'static AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            counter,
+            released: None,
+            session: None,
+        }
+    }
+    /// Holds `session` open for as long as the request is in flight.
+    pub(crate) fn with_session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+        if let Some(released) = self.released {
+            released.notify_waiters();
+        }
+    }
+}
+
 pub struct HttpConnection {
     inner: Object<ConnectionManager>,
+    _in_flight: Arc<InFlight>,
 }
 
 impl HttpConnection {
     pub async fn send_request(
         mut self,
-        request: Request<Incoming>,
+        mut request: Request<Incoming>,
     ) -> FaucetResult<Response<ExclusiveBody>> {
-        add_connection();
+        add_connection(self.inner.addr);
+        downgrade_to_http11(&mut request);
         let (parts, body) = self.inner.sender.send_request(request).await?.into_parts();
         let body = ExclusiveBody::new(body.map_err(Into::into), Some(self));
         Ok(Response::from_parts(parts, body))
@@ -92,7 +189,7 @@ impl HttpConnection {
 
 impl Drop for HttpConnection {
     fn drop(&mut self) {
-        remove_connection();
+        remove_connection(self.inner.addr);
     }
 }
 
@@ -102,22 +199,77 @@ const DEFAULT_MAX_SIZE: usize = 1024;
 pub(crate) struct Client {
     pool: Pool<ConnectionManager>,
     pub(crate) config: &'static WorkerConfig,
+    pinned_addr: Option<SocketAddr>,
 }
 
 impl Client {
-    pub fn new(config: &'static WorkerConfig) -> Self {
-        let builder = Pool::builder(ConnectionManager::new(config)).max_size(DEFAULT_MAX_SIZE);
+    fn with_pinned_addr(config: &'static WorkerConfig, pinned_addr: Option<SocketAddr>) -> Self {
+        let builder =
+            Pool::builder(ConnectionManager::new(config, pinned_addr)).max_size(DEFAULT_MAX_SIZE);
         let pool = builder
             .build()
             .expect("Failed to create connection pool. This is a bug");
-        Self { pool, config }
+        Self {
+            pool,
+            config,
+            pinned_addr,
+        }
+    }
+    pub fn new(config: &'static WorkerConfig) -> Self {
+        Self::with_pinned_addr(config, None)
+    }
+    /// A client that keeps talking to the process on `addr` even after the
+    /// worker moves to a new one. Used for the sessions of a draining process.
+    pub fn pinned(config: &'static WorkerConfig, addr: SocketAddr) -> Self {
+        Self::with_pinned_addr(config, Some(addr))
     }
 
-    pub async fn get(&self) -> FaucetResult<HttpConnection> {
+    /// A connection to the worker, that keeps `in_flight` until the
+    /// response is done.
+    pub(crate) async fn get(&self, in_flight: Arc<InFlight>) -> FaucetResult<HttpConnection> {
+        // Requests to a draining process do not count towards the current one.
+        if self.pinned_addr.is_none() {
+            self.config.stats.count_request();
+        }
         Ok(HttpConnection {
             inner: self.pool.get().await?,
+            _in_flight: in_flight,
         })
     }
+    /// Marks a request or WebSocket session as in flight on the worker.
+    pub(crate) fn in_flight(&self) -> InFlight {
+        InFlight::new(self.config.in_flight)
+    }
+    /// Marks a request as in flight on the worker, if it is serving fewer
+    /// than `max` requests. WebSocket sessions do not take a request slot.
+    /// `released` is woken once the request is done.
+    pub(crate) fn try_reserve(&self, max: usize, released: &'static Notify) -> Option<InFlight> {
+        let counter = self.config.in_flight;
+        let websockets = self.config.websockets.load(Ordering::SeqCst);
+        counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n.saturating_sub(websockets) < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| InFlight {
+                counter,
+                released: Some(released),
+                session: None,
+            })
+    }
+    /// Marks a WebSocket session as open on the worker.
+    pub(crate) fn websocket(&self) -> InFlight {
+        InFlight::new(self.config.websockets)
+    }
+    /// Requests and WebSocket sessions the worker is serving.
+    pub fn in_flight_count(&self) -> usize {
+        self.config.in_flight.load(Ordering::SeqCst)
+    }
+    /// HTTP requests the worker is serving, without its WebSocket sessions.
+    pub fn requests_in_flight(&self) -> usize {
+        self.in_flight_count()
+            .saturating_sub(self.config.websockets.load(Ordering::SeqCst))
+    }
     pub fn is_online(&self) -> bool {
         self.config
             .is_online
@@ -132,6 +284,49 @@ pub trait ExtractSocketAddr {
 impl ExtractSocketAddr for Client {
     #[inline(always)]
     fn socket_addr(&self) -> SocketAddr {
-        self.config.addr
+        self.pinned_addr.unwrap_or_else(|| self.config.addr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downgrade_http2_request_to_http11() {
+        let mut request = Request::builder()
+            .version(Version::HTTP_2)
+            .uri("https://example.com:8443/app/?a=1")
+            .header("cookie", "a=1")
+            .header("cookie", "FAUCET_LB_COOKIE=abc")
+            .body(())
+            .unwrap();
+
+        downgrade_to_http11(&mut request);
+
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert_eq!(request.uri(), "/app/?a=1");
+        assert_eq!(request.headers()["host"], "example.com:8443");
+        assert_eq!(
+            request.headers().get_all("cookie").iter().count(),
+            1,
+            "cookies should be joined into a single header"
+        );
+        assert_eq!(request.headers()["cookie"], "a=1; FAUCET_LB_COOKIE=abc");
+    }
+
+    #[test]
+    fn downgrade_keeps_http11_request_untouched() {
+        let mut request = Request::builder()
+            .uri("/path")
+            .header("host", "localhost")
+            .body(())
+            .unwrap();
+
+        downgrade_to_http11(&mut request);
+
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert_eq!(request.uri(), "/path");
+        assert_eq!(request.headers()["host"], "localhost");
     }
 }
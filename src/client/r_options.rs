@@ -0,0 +1,245 @@
+//! R options set in the R session of a worker before the application
+//! starts, such as `plumber.docs` or `shiny.maxRequestSize`.
+
+use crate::error::{FaucetError, FaucetResult};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// Variable with R options, written as the inside of a TOML inline table
+/// so that values keep their type, such as
+/// `"plumber.docs" = false, "plumber.apiPath" = "/v1"`.
+pub const R_OPTIONS_ENV: &str = "FAUCET_R_OPTIONS";
+
+/// Value of an R option, written in the configuration file as a TOML
+/// boolean, number or string.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ROptionValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+/// `value` as an R string literal.
+pub(crate) fn r_string(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+impl ROptionValue {
+    /// The value as R code.
+    fn to_r(&self) -> String {
+        match self {
+            ROptionValue::Bool(true) => "TRUE".to_string(),
+            ROptionValue::Bool(false) => "FALSE".to_string(),
+            ROptionValue::Integer(n) => n.to_string(),
+            ROptionValue::Float(f) if f.is_nan() => "NaN".to_string(),
+            ROptionValue::Float(f) if f.is_infinite() => {
+                if *f > 0.0 { "Inf" } else { "-Inf" }.to_string()
+            }
+            ROptionValue::Float(f) => format!("{f:?}"),
+            ROptionValue::String(s) => r_string(s),
+        }
+    }
+}
+
+/// The value as given on the command line.
+impl fmt::Display for ROptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ROptionValue::Bool(true) => f.write_str("TRUE"),
+            ROptionValue::Bool(false) => f.write_str("FALSE"),
+            ROptionValue::Integer(n) => write!(f, "{n}"),
+            ROptionValue::Float(x) => write!(f, "{x:?}"),
+            ROptionValue::String(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Values given on the command line are booleans (`TRUE`, `FALSE`, `true`
+/// or `false`) or numbers if they read as one, and strings otherwise.
+impl FromStr for ROptionValue {
+    type Err = std::convert::Infallible;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "TRUE" | "true" => ROptionValue::Bool(true),
+            "FALSE" | "false" => ROptionValue::Bool(false),
+            _ => match (value.parse::<i64>(), value.parse::<f64>()) {
+                (Ok(n), _) => ROptionValue::Integer(n),
+                (_, Ok(x)) if x.is_finite() => ROptionValue::Float(x),
+                _ => ROptionValue::String(value.to_string()),
+            },
+        })
+    }
+}
+
+/// An R option, `NAME=VALUE` on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ROption {
+    pub name: String,
+    pub value: ROptionValue,
+}
+
+impl FromStr for ROption {
+    type Err = String;
+    fn from_str(option: &str) -> Result<Self, Self::Err> {
+        match option.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok(ROption {
+                name: name.trim().to_string(),
+                value: value.parse().unwrap_or_else(|e| match e {}),
+            }),
+            _ => Err(format!("invalid R option {option:?}, expected NAME=VALUE")),
+        }
+    }
+}
+
+impl fmt::Display for ROption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Options in the form of [`R_OPTIONS_ENV`].
+pub(crate) fn parse_options_table(options: &str) -> FaucetResult<Vec<ROption>> {
+    #[derive(serde::Deserialize)]
+    struct Table {
+        options: BTreeMap<String, ROptionValue>,
+    }
+    let table: Table = toml::from_str(&format!("options = {{ {options} }}")).map_err(|e| {
+        FaucetError::InvalidConfig(format!(
+            "{R_OPTIONS_ENV} must look like '\"plumber.docs\" = false, \"plumber.apiPath\" = \"/v1\"': {}",
+            e.message()
+        ))
+    })?;
+    Ok(table
+        .options
+        .into_iter()
+        .map(|(name, value)| ROption { name, value })
+        .collect())
+}
+
+/// The R options of every layer, with an option of a later layer
+/// replacing the one of the same name in an earlier layer.
+pub(crate) fn merge_options<const N: usize>(layers: [Vec<ROption>; N]) -> Vec<ROption> {
+    let mut merged: Vec<ROption> = Vec::new();
+    for option in layers.into_iter().flatten() {
+        match merged.iter_mut().find(|merged| merged.name == option.name) {
+            Some(merged) => merged.value = option.value,
+            None => merged.push(option),
+        }
+    }
+    merged
+}
+
+/// R code setting `options`, empty if there are none.
+pub(crate) fn set_options(options: &[ROption]) -> String {
+    if options.is_empty() {
+        return String::new();
+    }
+    let options = options
+        .iter()
+        .map(|option| format!("{} = {}", r_string(&option.name), option.value.to_r()))
+        .collect::<Vec<_>>();
+    format!("options({})\n", options.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_from_the_command_line() {
+        let options = [
+            "plumber.docs=FALSE",
+            "shiny.maxRequestSize=31457280",
+            "x=0.5",
+            "plumber.apiPath=/api",
+        ]
+        .map(|option| option.parse::<ROption>().unwrap());
+        assert_eq!(
+            options.iter().map(|o| &o.value).collect::<Vec<_>>(),
+            [
+                &ROptionValue::Bool(false),
+                &ROptionValue::Integer(31457280),
+                &ROptionValue::Float(0.5),
+                &ROptionValue::String("/api".to_string()),
+            ]
+        );
+        assert_eq!(options[3].to_string(), "plumber.apiPath=/api");
+        assert!("=1".parse::<ROption>().is_err());
+        assert!("plumber.docs".parse::<ROption>().is_err());
+    }
+
+    #[test]
+    fn options_from_the_environment() {
+        let options = parse_options_table(
+            r#""plumber.docs" = false, "app.flag" = "TRUE", "app.sep" = "a;b=c""#,
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            [
+                ("app.flag", ROptionValue::String("TRUE".to_string())),
+                ("app.sep", ROptionValue::String("a;b=c".to_string())),
+                ("plumber.docs", ROptionValue::Bool(false)),
+            ]
+            .map(|(name, value)| ROption {
+                name: name.to_string(),
+                value,
+            })
+        );
+        assert_eq!(parse_options_table("").unwrap(), []);
+        // The old `NAME=VALUE;NAME=VALUE` form is rejected.
+        assert!(parse_options_table("plumber.docs=FALSE;app.x=1").is_err());
+        assert!(parse_options_table("plumber.docs=FALSE").is_err());
+    }
+
+    #[test]
+    fn later_options_win() {
+        let option = |option: &str| option.parse::<ROption>().unwrap();
+        let merged = merge_options([
+            vec![option("a=file"), option("b=file"), option("c=file")],
+            vec![option("b=env"), option("c=env")],
+            vec![option("c=cli"), option("d=cli")],
+        ]);
+        assert_eq!(
+            merged,
+            ["a=file", "b=env", "c=cli", "d=cli"].map(option).to_vec()
+        );
+    }
+
+    #[test]
+    fn options_as_r_code() {
+        assert_eq!(set_options(&[]), "");
+        let options = [
+            ROption {
+                name: "plumber.docs".to_string(),
+                value: ROptionValue::Bool(true),
+            },
+            ROption {
+                name: "app.title".to_string(),
+                value: ROptionValue::String("Say \"hi\"\\\n".to_string()),
+            },
+            ROption {
+                name: "app.ratio".to_string(),
+                value: ROptionValue::Float(1.0),
+            },
+        ];
+        assert_eq!(
+            set_options(&options),
+            "options(\"plumber.docs\" = TRUE, \"app.title\" = \"Say \\\"hi\\\"\\\\\\n\", \"app.ratio\" = 1.0)\n"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Keys seen recently, such as the sticky sessions of a process or the
+//! worker a key was assigned to. Keys not seen for [`TTL`] are forgotten,
+//! and only the most recent are kept past [`MAX_KEYS`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// How long a key is remembered after it was last seen.
+pub(crate) const TTL: Duration = Duration::from_secs(3600);
+/// Most keys remembered.
+pub(crate) const MAX_KEYS: usize = 100_000;
+
+pub(crate) struct RecentKeys<V = ()> {
+    last_seen: HashMap<u64, (V, Instant)>,
+    max_keys: usize,
+}
+
+impl<V> Default for RecentKeys<V> {
+    fn default() -> Self {
+        RecentKeys::with_max_keys(MAX_KEYS)
+    }
+}
+
+fn is_fresh(seen: Instant, now: Instant) -> bool {
+    now.duration_since(seen) < TTL
+}
+
+impl<V> RecentKeys<V> {
+    /// Keeps at most `max_keys` keys, for maps that share [`MAX_KEYS`].
+    pub(crate) fn with_max_keys(max_keys: usize) -> Self {
+        RecentKeys {
+            last_seen: HashMap::new(),
+            max_keys,
+        }
+    }
+    pub(crate) fn insert(&mut self, key: u64, value: V, now: Instant) {
+        self.last_seen.insert(key, (value, now));
+        if self.last_seen.len() > self.max_keys {
+            self.prune(now);
+        }
+    }
+    /// The value of `key`, unless it was forgotten.
+    pub(crate) fn get(&self, key: u64, now: Instant) -> Option<&V> {
+        self.last_seen
+            .get(&key)
+            .filter(|(_, seen)| is_fresh(*seen, now))
+            .map(|(value, _)| value)
+    }
+    /// Forgets expired keys and, if there still are too many, the oldest
+    /// down to half of the limit, so pruning happens rarely.
+    fn prune(&mut self, now: Instant) {
+        self.last_seen.retain(|_, (_, seen)| is_fresh(*seen, now));
+        let excess = self.last_seen.len().saturating_sub(self.max_keys / 2);
+        if excess > 0 {
+            let mut oldest = self
+                .last_seen
+                .iter()
+                .map(|(&key, &(_, seen))| (seen, key))
+                .collect::<Vec<_>>();
+            oldest.select_nth_unstable(excess - 1);
+            for (_, key) in &oldest[..excess] {
+                self.last_seen.remove(key);
+            }
+        }
+    }
+    pub(crate) fn into_keys(self, now: Instant) -> HashSet<u64> {
+        self.last_seen
+            .into_iter()
+            .filter(|(_, (_, seen))| is_fresh(*seen, now))
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_keys_are_bounded() {
+        let start = Instant::now();
+        let mut keys = RecentKeys::default();
+        for key in 0..MAX_KEYS as u64 {
+            keys.insert(key, (), start);
+        }
+        assert_eq!(keys.last_seen.len(), MAX_KEYS);
+
+        // Over the limit only the most recent half is kept.
+        keys.insert(u64::MAX, (), start + Duration::from_secs(1));
+        assert_eq!(keys.last_seen.len(), MAX_KEYS / 2);
+        assert!(keys.get(u64::MAX, start).is_some());
+
+        // Keys not seen for an hour are forgotten.
+        let later = start + TTL + Duration::from_secs(1);
+        let mut keys = RecentKeys::default();
+        keys.insert(1, "a", start);
+        keys.insert(2, "b", later);
+        assert_eq!(keys.get(1, later), None);
+        assert_eq!(keys.get(2, later), Some(&"b"));
+        assert_eq!(keys.into_keys(later), HashSet::from([2]));
+    }
+}
@@ -0,0 +1,258 @@
+//! Development mode that restarts the workers when the application's
+//! source files change.
+//!
+//! Files are polled instead of relying on OS notifications so it behaves
+//! the same on every platform and inside containers with mounted volumes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime},
+};
+
+use super::worker::WorkerConfig;
+use crate::shutdown::ShutdownSignal;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Editors and `git checkout` touch several files in a row, we wait for the
+// files to stop changing for this long before restarting.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+const WATCHED_EXTENSIONS: &[&str] = &["r", "py", "qmd"];
+const WATCHED_FILES: &[&str] = &["renv.lock"];
+
+// Directories full of files we don't care about. Hidden directories
+// (`.git`, `.venv`, ...) are skipped as well.
+const SKIPPED_DIRS: &[&str] = &["renv", "node_modules", "__pycache__"];
+
+type Snapshot = HashMap<PathBuf, (SystemTime, u64)>;
+
+fn is_watched_file(path: &Path) -> bool {
+    let file_name = path.file_name().and_then(|name| name.to_str());
+    if file_name.is_some_and(|name| WATCHED_FILES.contains(&name)) {
+        return true;
+    }
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| WATCHED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn is_skipped_dir(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.') || SKIPPED_DIRS.contains(&name))
+}
+
+fn scan(path: &Path, snapshot: &mut Snapshot) {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return;
+    };
+    if metadata.is_file() {
+        if is_watched_file(path) {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            snapshot.insert(path.to_path_buf(), (modified, metadata.len()));
+        }
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if is_dir && is_skipped_dir(&path) {
+            continue;
+        }
+        scan(&path, snapshot);
+    }
+}
+
+fn take_snapshot(roots: &[PathBuf]) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for root in roots {
+        scan(root, &mut snapshot);
+    }
+    snapshot
+}
+
+/// Returns a file that differs between both snapshots, if any.
+fn changed_file<'a>(before: &'a Snapshot, after: &'a Snapshot) -> Option<&'a Path> {
+    after
+        .iter()
+        .find(|(path, stamp)| before.get(*path) != Some(*stamp))
+        .or_else(|| before.iter().find(|(path, _)| !after.contains_key(*path)))
+        .map(|(path, _)| path.as_path())
+}
+
+/// Directories and files that make up the application of `config`. Paths
+/// inside another watched directory are left out.
+fn watch_roots(config: &WorkerConfig) -> Vec<PathBuf> {
+    let mut candidates = vec![config.workdir.to_path_buf()];
+    // Workers are started inside `workdir`, relative paths are relative to it.
+    candidates.extend(config.app_dir.map(|app_dir| config.workdir.join(app_dir)));
+    candidates.extend(config.qmd.map(|qmd| config.workdir.join(qmd)));
+
+    let candidates = candidates
+        .into_iter()
+        .map(|path| std::fs::canonicalize(&path).unwrap_or(path))
+        .collect::<Vec<_>>();
+
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for candidate in candidates {
+        let covered = roots.iter().any(|root| candidate.starts_with(root));
+        if !covered {
+            roots.retain(|root| !root.starts_with(&candidate));
+            roots.push(candidate);
+        }
+    }
+    roots
+}
+
+async fn snapshot_in_background(roots: &'static [PathBuf]) -> Snapshot {
+    tokio::task::spawn_blocking(|| take_snapshot(roots))
+        .await
+        .unwrap_or_default()
+}
+
+/// Restarts `workers` whenever a watched file under their directories
+/// changes, until shutdown.
+pub(crate) fn spawn_watcher(workers: &[&'static WorkerConfig], shutdown: &'static ShutdownSignal) {
+    let Some(first) = workers.first() else {
+        return;
+    };
+    let roots: &'static [PathBuf] = watch_roots(first).leak();
+    let workers = workers.to_vec();
+
+    for root in roots {
+        log::info!(target: "faucet", "Watching {root:?} for changes");
+    }
+
+    tokio::spawn(async move {
+        let mut current = snapshot_in_background(roots).await;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => (),
+                _ = shutdown.wait() => break,
+            }
+
+            let mut latest = snapshot_in_background(roots).await;
+            let Some(changed) = changed_file(&current, &latest).map(Path::to_path_buf) else {
+                continue;
+            };
+
+            // Wait for the burst of changes to settle.
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(DEBOUNCE) => (),
+                    _ = shutdown.wait() => return,
+                }
+                let settled = snapshot_in_background(roots).await;
+                if settled == latest {
+                    break;
+                }
+                latest = settled;
+            }
+            current = latest;
+
+            log::info!(target: "faucet", "{changed:?} changed, restarting workers");
+            for worker in &workers {
+                // Workers without a process (stopped by the RPS autoscaler)
+                // pick up the changes the next time they start.
+                if worker.pid.load(Ordering::SeqCst) != 0 {
+                    worker.restart.notify_one();
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn temp_dir() -> TempDir {
+        TempDir::new("faucet-watch")
+    }
+
+    #[test]
+    fn watched_files() {
+        assert!(is_watched_file(Path::new("app.R")));
+        assert!(is_watched_file(Path::new("utils.r")));
+        assert!(is_watched_file(Path::new("main.py")));
+        assert!(is_watched_file(Path::new("doc.qmd")));
+        assert!(is_watched_file(Path::new("renv.lock")));
+        assert!(!is_watched_file(Path::new("data.csv")));
+        assert!(!is_watched_file(Path::new("README")));
+    }
+
+    #[test]
+    fn snapshot_skips_hidden_and_library_dirs() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("app.R"), "1").unwrap();
+        std::fs::write(dir.join("renv.lock"), "{}").unwrap();
+        std::fs::write(dir.join("data.csv"), "a,b").unwrap();
+        for skipped in [".git", "renv", "__pycache__"] {
+            std::fs::create_dir_all(dir.join(skipped)).unwrap();
+            std::fs::write(dir.join(skipped).join("x.R"), "1").unwrap();
+        }
+        std::fs::create_dir_all(dir.join("R")).unwrap();
+        std::fs::write(dir.join("R").join("mod.R"), "1").unwrap();
+
+        let snapshot = take_snapshot(&[dir.to_path_buf()]);
+        let mut files = snapshot
+            .keys()
+            .map(|path| path.strip_prefix(&dir).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("R/mod.R"),
+                PathBuf::from("app.R"),
+                PathBuf::from("renv.lock")
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_changed_added_and_removed_files() {
+        let dir = temp_dir();
+        let app = dir.join("app.R");
+        std::fs::write(&app, "1").unwrap();
+        let roots = [dir.to_path_buf()];
+        let before = take_snapshot(&roots);
+        assert_eq!(changed_file(&before, &before), None);
+
+        std::fs::write(&app, "12").unwrap();
+        let after = take_snapshot(&roots);
+        assert_eq!(changed_file(&before, &after), Some(app.as_path()));
+
+        let added = dir.join("global.R");
+        std::fs::write(&added, "1").unwrap();
+        let with_added = take_snapshot(&roots);
+        assert_eq!(changed_file(&after, &with_added), Some(added.as_path()));
+
+        std::fs::remove_file(&added).unwrap();
+        assert_eq!(changed_file(&with_added, &after), Some(added.as_path()));
+    }
+
+    #[test]
+    fn nested_roots_are_merged() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(dir.join("app")).unwrap();
+        let mut worker = WorkerConfig::dummy("Worker::1", "127.0.0.1:9961", true);
+        worker.workdir = crate::leak!(dir.to_path_buf(), Path);
+        worker.app_dir = Some("app");
+        assert_eq!(watch_roots(&worker), [dir.canonicalize().unwrap()]);
+
+        let outside = temp_dir();
+        worker.app_dir = Some(crate::leak!(outside.to_str().unwrap(), str));
+        assert_eq!(
+            watch_roots(&worker),
+            [dir.canonicalize().unwrap(), outside.canonicalize().unwrap()]
+        );
+    }
+}
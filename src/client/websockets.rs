@@ -1,4 +1,7 @@
-use super::{pool::ExtractSocketAddr, Client, ExclusiveBody};
+use super::{
+    pool::{ExtractSocketAddr, InFlight},
+    Client, ExclusiveBody,
+};
 use crate::{
     error::{BadRequestReason, FaucetError, FaucetResult},
     global_conn::{add_connection, remove_connection},
@@ -10,20 +13,26 @@ use base64::Engine;
 use bytes::Bytes;
 use futures_util::StreamExt;
 use hyper::{
-    header::UPGRADE,
+    ext::Protocol,
+    header::{CONNECTION, HOST, SEC_WEBSOCKET_VERSION, UPGRADE},
     http::{uri::PathAndQuery, HeaderValue},
     upgrade::Upgraded,
-    HeaderMap, Request, Response, StatusCode, Uri,
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
 };
 use hyper_util::rt::TokioIo;
 use serde_json::json;
 use sha1::{Digest, Sha1};
 use std::{
-    collections::HashMap, future::Future, net::SocketAddr, str::FromStr, sync::LazyLock,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, LazyLock},
     time::Duration,
 };
 use tokio::sync::Mutex;
 use tokio_tungstenite::tungstenite::{
+    handshake::client::generate_key,
     protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
     Message, Utf8Bytes,
 };
@@ -32,14 +41,53 @@ use uuid::Uuid;
 struct UpgradeInfo {
     headers: HeaderMap,
     uri: Uri,
+    worker_addr: SocketAddr,
 }
 
 impl UpgradeInfo {
     fn new<ReqBody>(req: &Request<ReqBody>, socket_addr: SocketAddr) -> FaucetResult<Self> {
-        let headers = req.headers().clone();
+        let mut headers = req.headers().clone();
         let uri = build_uri(socket_addr, req.uri().path_and_query())?;
-        Ok(Self { headers, uri })
+        if is_extended_connect(req) {
+            add_http11_handshake_headers(&mut headers, req.uri());
+        }
+        Ok(Self {
+            headers,
+            uri,
+            worker_addr: socket_addr,
+        })
+    }
+}
+
+/// Returns true if the request is a WebSocket bootstrapped over HTTP/2
+/// with an extended CONNECT (RFC 8441).
+fn is_extended_connect<ReqBody>(req: &Request<ReqBody>) -> bool {
+    req.method() == Method::CONNECT
+        && req
+            .extensions()
+            .get::<Protocol>()
+            .is_some_and(|protocol| case_insensitive_eq(protocol.as_str(), "websocket"))
+}
+
+/// HTTP/2 WebSocket requests don't carry the HTTP/1.1 handshake headers,
+/// the workers however only understand the HTTP/1.1 handshake, so we
+/// fill in the blanks.
+fn add_http11_handshake_headers(headers: &mut HeaderMap, uri: &Uri) {
+    if !headers.contains_key(HOST) {
+        if let Some(host) = uri
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        {
+            headers.insert(HOST, host);
+        }
     }
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+    headers.insert(
+        SEC_WEBSOCKET_KEY,
+        HeaderValue::from_str(&generate_key()).expect("Generated keys are valid base64"),
+    );
 }
 
 const SEC_WEBSOCKET_APPEND: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
@@ -90,6 +138,17 @@ struct ConnectionInstance {
     purged: bool,
     access_count: usize,
     pair: Option<ConnectionPair>,
+    worker_addr: Option<SocketAddr>,
+}
+
+/// Snapshot of a cached WebSocket session, used by the admin API.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct WebSocketSessionInfo {
+    pub session_id: Uuid,
+    pub worker_addr: Option<SocketAddr>,
+    /// Whether a client is currently attached to the session.
+    pub connected: bool,
+    pub purged: bool,
 }
 
 impl ConnectionInstance {
@@ -125,11 +184,13 @@ impl ConnectionManager {
         &self,
         session_id: Uuid,
         attempt: usize,
+        worker_addr: SocketAddr,
         init: impl Future<Output = FaucetResult<ConnectionPair>>,
     ) -> Option<FaucetResult<ConnectionPair>> {
         {
             let mut inner = self.inner.lock().await;
             let entry = inner.map.entry(session_id).or_default();
+            entry.worker_addr.get_or_insert(worker_addr);
             if entry.access_count != 0 {
                 return None;
             }
@@ -149,6 +210,19 @@ impl ConnectionManager {
         };
         Some(Ok(connection_pair))
     }
+    async fn sessions(&self) -> Vec<WebSocketSessionInfo> {
+        let inner = self.inner.lock().await;
+        inner
+            .map
+            .iter()
+            .map(|(session_id, instance)| WebSocketSessionInfo {
+                session_id: *session_id,
+                worker_addr: instance.worker_addr,
+                connected: instance.access_count % 2 == 1,
+                purged: instance.purged,
+            })
+            .collect()
+    }
     async fn attempt_take(&self, session_id: Uuid) -> FaucetResult<ConnectionPair> {
         match self.inner.try_lock() {
             Ok(mut inner) => {
@@ -184,6 +258,11 @@ impl ConnectionManager {
 // We use a std::sync::Mutex as the lock is not held across .await points.
 static SHINY_CONNECTION_CACHE: LazyLock<ConnectionManager> = LazyLock::new(ConnectionManager::new);
 
+/// Lists the WebSocket sessions currently held in the connection cache.
+pub async fn websocket_sessions() -> Vec<WebSocketSessionInfo> {
+    SHINY_CONNECTION_CACHE.sessions().await
+}
+
 async fn connect_to_worker(
     mut upgrade_info: UpgradeInfo,
     session_id: Uuid,
@@ -216,10 +295,12 @@ async fn connect_or_retrieve(
     attempt: usize,
     config: &'static WebSocketConfig,
 ) -> FaucetResult<ConnectionPair> {
+    let worker_addr = upgrade_info.worker_addr;
     let init_pair = SHINY_CONNECTION_CACHE
         .initialize_if_not(
             session_id,
             attempt,
+            worker_addr,
             connect_to_worker(upgrade_info, session_id, config),
         )
         .await;
@@ -526,6 +607,24 @@ async fn upgrade_connection_from_request<ReqBody>(
     Ok(())
 }
 
+fn spawn_upgrade<ReqBody: Send + Sync + 'static>(
+    req: Request<ReqBody>,
+    client: impl ExtractSocketAddr + Send + Sync + 'static,
+    shutdown: &'static ShutdownSignal,
+    websocket_config: &'static WebSocketConfig,
+) {
+    tokio::task::spawn(async move {
+        let worker_addr = client.socket_addr();
+        add_connection(worker_addr);
+        if let Err(e) =
+            upgrade_connection_from_request(req, client, shutdown, websocket_config).await
+        {
+            log::error!(target: "faucet", "upgrade error: {e:?}");
+        }
+        remove_connection(worker_addr);
+    });
+}
+
 async fn init_upgrade<ReqBody: Send + Sync + 'static>(
     req: Request<ReqBody>,
     client: impl ExtractSocketAddr + Send + Sync + 'static,
@@ -533,20 +632,20 @@ async fn init_upgrade<ReqBody: Send + Sync + 'static>(
     websocket_config: &'static WebSocketConfig,
 ) -> FaucetResult<Response<ExclusiveBody>> {
     let mut res = Response::new(ExclusiveBody::empty());
+
+    // Over HTTP/2 there is no key to answer, accepting the stream with a
+    // 200 is enough to start exchanging frames.
+    if is_extended_connect(&req) {
+        spawn_upgrade(req, client, shutdown, websocket_config);
+        return Ok(res);
+    }
+
     let sec_websocket_key = req
         .headers()
         .get(SEC_WEBSOCKET_KEY)
         .cloned()
         .ok_or(FaucetError::no_sec_web_socket_key())?;
-    tokio::task::spawn(async move {
-        add_connection();
-        if let Err(e) =
-            upgrade_connection_from_request(req, client, shutdown, websocket_config).await
-        {
-            log::error!(target: "faucet", "upgrade error: {e:?}");
-        }
-        remove_connection();
-    });
+    spawn_upgrade(req, client, shutdown, websocket_config);
     *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
     res.headers_mut()
         .insert(UPGRADE, HeaderValue::from_static("websocket"));
@@ -565,6 +664,67 @@ async fn init_upgrade<ReqBody: Send + Sync + 'static>(
     Ok(res)
 }
 
+/// `Upgrade: h2c` asks to switch a cleartext HTTP/1.1 connection to HTTP/2
+/// (RFC 7540, section 3.2). Requests that reach the workers with it were
+/// not switched, because they came over TLS or carry a body, so like any
+/// server that declines the upgrade faucet serves them over HTTP/1.1,
+/// without the upgrade headers.
+pub(crate) fn decline_h2c_upgrade<ReqBody>(req: &mut Request<ReqBody>) {
+    let Some(upgrade) = req.headers().get(UPGRADE).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    if !upgrade
+        .split(',')
+        .any(|protocol| case_insensitive_eq(protocol.trim(), "h2c"))
+    {
+        return;
+    }
+    let others = upgrade
+        .split(',')
+        .map(str::trim)
+        .filter(|protocol| !case_insensitive_eq(protocol, "h2c"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let headers = req.headers_mut();
+    headers.remove("http2-settings");
+    match HeaderValue::from_str(&others) {
+        Ok(others) if !others.is_empty() => {
+            headers.insert(UPGRADE, others);
+            return;
+        }
+        _ => {
+            headers.remove(UPGRADE);
+        }
+    }
+    let connection = headers
+        .get(CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|connection| {
+            connection
+                .split(',')
+                .map(str::trim)
+                .filter(|option| {
+                    !case_insensitive_eq(option, "upgrade")
+                        && !case_insensitive_eq(option, "http2-settings")
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+    match connection.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        Some(connection) if !connection.is_empty() => {
+            headers.insert(CONNECTION, connection);
+        }
+        _ => {
+            headers.remove(CONNECTION);
+        }
+    }
+}
+
+/// Whether the request asks to open a WebSocket.
+pub(crate) fn is_upgrade_request<ReqBody>(req: &Request<ReqBody>) -> bool {
+    req.headers().contains_key(UPGRADE) || is_extended_connect(req)
+}
+
 #[inline(always)]
 async fn attempt_upgrade<ReqBody: Send + Sync + 'static>(
     req: Request<ReqBody>,
@@ -572,7 +732,7 @@ async fn attempt_upgrade<ReqBody: Send + Sync + 'static>(
     shutdown: &'static ShutdownSignal,
     websocket_config: &'static WebSocketConfig,
 ) -> FaucetResult<UpgradeStatus<ReqBody>> {
-    if req.headers().contains_key(UPGRADE) {
+    if is_upgrade_request(&req) {
         return Ok(UpgradeStatus::Upgraded(
             init_upgrade(req, client, shutdown, websocket_config).await?,
         ));
@@ -580,17 +740,37 @@ async fn attempt_upgrade<ReqBody: Send + Sync + 'static>(
     Ok(UpgradeStatus::NotUpgraded(req))
 }
 
+/// A client whose WebSocket session counts as in flight on the worker for
+/// as long as the session's task holds it.
+struct TrackedClient {
+    client: Client,
+    _in_flight: Arc<InFlight>,
+    _websocket: InFlight,
+}
+
+impl ExtractSocketAddr for TrackedClient {
+    fn socket_addr(&self) -> SocketAddr {
+        self.client.socket_addr()
+    }
+}
+
 impl Client {
-    pub async fn attempt_upgrade<ReqBody>(
+    pub(crate) async fn attempt_upgrade<ReqBody>(
         &self,
         req: Request<ReqBody>,
+        in_flight: Arc<InFlight>,
         shutdown: &'static ShutdownSignal,
         websocket_config: &'static WebSocketConfig,
     ) -> FaucetResult<UpgradeStatus<ReqBody>>
     where
         ReqBody: Send + Sync + 'static,
     {
-        attempt_upgrade(req, self.clone(), shutdown, websocket_config).await
+        let client = TrackedClient {
+            client: self.clone(),
+            _in_flight: in_flight,
+            _websocket: self.websocket(),
+        };
+        attempt_upgrade(req, client, shutdown, websocket_config).await
     }
 }
 
@@ -601,6 +781,29 @@ mod tests {
     use super::*;
     use uuid::Uuid;
 
+    #[test]
+    fn h2c_upgrade_is_declined() {
+        let mut req = Request::builder()
+            .header("upgrade", "h2c")
+            .header("connection", "Upgrade, HTTP2-Settings, keep-alive")
+            .header("http2-settings", "AAMAAABkAAQAoAAAAAIAAAAA")
+            .body(())
+            .unwrap();
+        decline_h2c_upgrade(&mut req);
+        assert!(!is_upgrade_request(&req));
+        assert!(!req.headers().contains_key("http2-settings"));
+        assert_eq!(req.headers()["connection"], "keep-alive");
+
+        let mut req = Request::builder()
+            .header("upgrade", "websocket")
+            .header("connection", "Upgrade")
+            .body(())
+            .unwrap();
+        decline_h2c_upgrade(&mut req);
+        assert!(is_upgrade_request(&req));
+        assert_eq!(req.headers()["connection"], "Upgrade");
+    }
+
     #[test]
     fn test_insensitive_compare() {
         let session_id = "sessionid";
@@ -843,6 +1046,83 @@ mod tests {
         }
     }
 
+    #[test]
+    fn extended_connect_adds_http11_handshake_headers() {
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .version(hyper::Version::HTTP_2)
+            .uri("https://localhost:3838/websocket/?sessionId=1")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+
+        assert!(is_extended_connect(&req));
+
+        let socket_addr: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let info = UpgradeInfo::new(&req, socket_addr).unwrap();
+        assert_eq!(info.uri, "ws://127.0.0.1:8000/websocket/?sessionId=1");
+        assert_eq!(info.headers.get(HOST).unwrap(), "localhost:3838");
+        assert_eq!(info.headers.get(UPGRADE).unwrap(), "websocket");
+        assert_eq!(info.headers.get(CONNECTION).unwrap(), "Upgrade");
+        assert_eq!(info.headers.get(SEC_WEBSOCKET_VERSION).unwrap(), "13");
+        assert!(info.headers.contains_key(SEC_WEBSOCKET_KEY));
+    }
+
+    #[test]
+    fn plain_connect_is_not_a_websocket() {
+        let req = Request::builder()
+            .method(Method::CONNECT)
+            .uri("localhost:3838")
+            .body(())
+            .unwrap();
+        assert!(!is_extended_connect(&req));
+    }
+
+    #[tokio::test]
+    async fn test_attempt_upgrade_with_extended_connect() {
+        struct MockClient {
+            socket_addr: SocketAddr,
+        }
+
+        impl ExtractSocketAddr for MockClient {
+            fn socket_addr(&self) -> SocketAddr {
+                self.socket_addr
+            }
+        }
+
+        let websocket_config = leak!(WebSocketConfig::default());
+        let socket_addr = get_available_socket(20).await.unwrap();
+        let client = MockClient { socket_addr };
+
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .version(hyper::Version::HTTP_2)
+            .uri(format!(
+                "https://localhost/?{}={}",
+                SESSION_ID_QUERY,
+                Uuid::now_v7()
+            ))
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+
+        let shutdown = leak!(ShutdownSignal::new());
+        let result = attempt_upgrade(req, client, shutdown, websocket_config)
+            .await
+            .unwrap();
+
+        match result {
+            UpgradeStatus::Upgraded(res) => {
+                assert_eq!(res.status(), StatusCode::OK);
+                assert!(res.headers().get(SEC_WEBSOCKET_ACCEPT).is_none());
+            }
+            _ => panic!("Expected Upgraded"),
+        }
+    }
+
     mod dummy_websocket_server {
         use std::{io::Error, net::SocketAddr};
 
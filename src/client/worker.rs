@@ -1,5 +1,16 @@
+use super::{
+    health::HealthCheck,
+    limits::{ProcessCgroup, ProcessLimits, WorkerCgroup},
+    monitor::{ProcessStats, RecyclePolicy},
+    r_options,
+    recent::RecentKeys,
+    websockets::websocket_sessions,
+    Client, ROption, WorkerCommand, WorkerEnv,
+};
 use crate::{
+    cli::streamlit_script,
     error::{FaucetError, FaucetResult},
+    global_conn::worker_connections,
     leak,
     networking::get_available_socket,
     server::{
@@ -10,10 +21,14 @@ use crate::{
     telemetry::send_log_event,
 };
 use std::{
+    collections::HashSet,
     ffi::OsStr,
     net::SocketAddr,
     path::Path,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        MutexGuard,
+    },
     time::Duration,
 };
 use tokio::{
@@ -28,16 +43,51 @@ use tokio_util::codec::FramedRead;
 pub enum WorkerType {
     #[serde(alias = "plumber", alias = "Plumber")]
     Plumber,
+    #[serde(alias = "plumber2", alias = "Plumber2")]
+    Plumber2,
+    #[serde(alias = "ambiorix", alias = "Ambiorix")]
+    Ambiorix,
     #[serde(alias = "shiny", alias = "Shiny")]
     Shiny,
     #[serde(alias = "quarto-shiny", alias = "QuartoShiny", alias = "quarto_shiny")]
     QuartoShiny,
     #[serde(alias = "fast-api", alias = "FastAPI")]
     FastAPI,
+    #[serde(alias = "streamlit", alias = "Streamlit")]
+    Streamlit,
+    #[serde(alias = "dash", alias = "Dash")]
+    Dash,
+    /// An HTTP service faucet does not start, reached at the route's
+    /// `upstream` addresses.
+    #[serde(alias = "upstream", alias = "Upstream")]
+    Upstream,
+    /// Any process serving HTTP, started from the route's `command`.
+    #[serde(alias = "command", alias = "Command")]
+    Command,
     #[cfg(test)]
     Dummy,
 }
 
+/// Normalizes an upstream address, `host:port` or `http://host:port`, to
+/// the `host:port` faucet connects to.
+pub(crate) fn upstream_authority(upstream: &str) -> Result<String, String> {
+    if upstream.starts_with("https://") {
+        return Err(format!(
+            "upstream {upstream:?} uses https, only plain HTTP upstreams are supported"
+        ));
+    }
+    let authority = upstream.strip_prefix("http://").unwrap_or(upstream);
+    let authority = authority.strip_suffix('/').unwrap_or(authority);
+    match authority.parse::<hyper::http::uri::Authority>() {
+        Ok(parsed) if parsed.port().is_some() && !authority.contains('@') => {
+            Ok(parsed.as_str().to_string())
+        }
+        _ => Err(format!(
+            "invalid upstream {upstream:?}, expected \"host:port\" or \"http://host:port\""
+        )),
+    }
+}
+
 pub fn log_stdio(mut child: Child, target: &'static str) -> FaucetResult<Child> {
     let pid = child.id().expect("Failed to get plumber worker PID");
 
@@ -59,7 +109,7 @@ pub fn log_stdio(mut child: Child, target: &'static str) -> FaucetResult<Child>
         while let Some(line) = stderr.next().await {
             match line {
                 Ok(line) => match std::str::from_utf8(&line) {
-                    Ok(line) => match parse_faucet_event(&line) {
+                    Ok(line) => match parse_faucet_event(line) {
                         FaucetEventResult::Output(line) => log::warn!(target: target, "{line}"),
                         FaucetEventResult::Event(e) => {
                             send_log_event(e);
@@ -95,23 +145,92 @@ pub fn log_stdio(mut child: Child, target: &'static str) -> FaucetResult<Child>
     Ok(child)
 }
 
+/// A process replaced by a rolling restart that is finishing its connections.
+struct PreviousProcess {
+    addr: SocketAddr,
+    /// Sticky sessions that started on this process.
+    sessions: HashSet<u64>,
+    client: Client,
+}
+
+/// The process currently behind a worker.
+struct WorkerProcess {
+    addr: SocketAddr,
+    /// Sticky sessions seen recently on this process.
+    sessions: RecentKeys,
+    previous: Option<PreviousProcess>,
+    /// Replaced processes that have not been killed yet.
+    retiring: usize,
+}
+
+impl WorkerProcess {
+    fn new(addr: SocketAddr) -> Self {
+        WorkerProcess {
+            addr,
+            sessions: RecentKeys::default(),
+            previous: None,
+            retiring: 0,
+        }
+    }
+}
+
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+
 #[derive(Copy, Clone)]
 pub struct WorkerConfig {
     pub wtype: WorkerType,
     pub app_dir: Option<&'static str>,
+    /// File the application is started from, relative to `app_dir`.
+    pub entrypoint: Option<&'static str>,
+    /// Options set in the R session before the application starts.
+    pub r_options: &'static [ROption],
     pub rscript: &'static OsStr,
     pub uv: &'static OsStr,
     pub quarto: &'static OsStr,
     pub workdir: &'static Path,
-    pub addr: SocketAddr,
+    process: &'static std::sync::Mutex<WorkerProcess>,
     pub target: &'static str,
     pub worker_id: usize,
     pub worker_route: Option<&'static str>,
     pub is_online: &'static AtomicBool,
+    /// Notified when a worker of the route may take a request: it came
+    /// online or finished one. Shared by the workers of a route.
+    pub(crate) available: &'static Notify,
     pub qmd: Option<&'static Path>,
     pub handle: &'static Mutex<Option<JoinHandle<FaucetResult<()>>>>,
     pub shutdown: &'static ShutdownSignal,
     pub idle_stop: &'static Notify,
+    pub health_check: Option<&'static HealthCheck>,
+    pub command: Option<&'static WorkerCommand>,
+    /// Variables added to the environment of the processes.
+    pub env: &'static WorkerEnv,
+    /// Resource limits and user of the processes.
+    pub(crate) limits: &'static ProcessLimits,
+    /// cgroup the processes are placed in, if the limits use one.
+    pub(crate) cgroup: Option<&'static WorkerCgroup>,
+    /// When the process is replaced by a fresh one.
+    pub recycle: &'static RecyclePolicy,
+    /// Requests served and resources used by the current process.
+    pub stats: &'static ProcessStats,
+    /// Requests and WebSocket sessions the worker is serving.
+    pub in_flight: &'static AtomicUsize,
+    /// WebSocket sessions open to the worker, also counted in `in_flight`.
+    pub websockets: &'static AtomicUsize,
+    /// PID of the running process, 0 if there is none.
+    pub pid: &'static AtomicU32,
+    /// Number of times the process has been started again after the first spawn.
+    pub restarts: &'static AtomicUsize,
+    /// Notified to kill the current process and start a new one.
+    pub restart: &'static Notify,
+    /// A draining worker keeps its process and open connections but is
+    /// kept offline so it stops receiving new requests.
+    pub draining: &'static AtomicBool,
+    /// Notified to replace the current process without dropping connections.
+    pub rollover: &'static Notify,
+    /// Notified once a rolling restart of this worker finished or failed.
+    rollover_done: &'static Notify,
+    /// How long a replaced process may keep serving its connections.
+    pub drain_timeout: Duration,
 }
 
 impl WorkerConfig {
@@ -120,15 +239,19 @@ impl WorkerConfig {
         addr: SocketAddr,
         server_config: &FaucetServerConfig,
         shutdown: &'static ShutdownSignal,
+        available: &'static Notify,
     ) -> Self {
         Self {
-            addr,
+            process: leak!(std::sync::Mutex::new(WorkerProcess::new(addr))),
             worker_id,
             is_online: leak!(AtomicBool::new(false)),
+            available,
             workdir: server_config.workdir,
             worker_route: server_config.route,
             target: leak!(format!("Worker::{}", worker_id)),
             app_dir: server_config.app_dir,
+            entrypoint: server_config.entrypoint,
+            r_options: server_config.r_options,
             wtype: server_config.server_type,
             rscript: server_config.rscript,
             quarto: server_config.quarto,
@@ -137,6 +260,25 @@ impl WorkerConfig {
             handle: leak!(Mutex::new(None)),
             shutdown,
             idle_stop: leak!(Notify::new()),
+            health_check: server_config.health_check,
+            command: server_config.command,
+            env: server_config.env,
+            limits: server_config.limits,
+            cgroup: server_config
+                .limits
+                .worker_cgroup(server_config.route, worker_id)
+                .map(|cgroup| leak!(cgroup) as &'static WorkerCgroup),
+            recycle: server_config.recycle,
+            stats: leak!(ProcessStats::default()),
+            in_flight: leak!(AtomicUsize::new(0)),
+            websockets: leak!(AtomicUsize::new(0)),
+            pid: leak!(AtomicU32::new(0)),
+            restarts: leak!(AtomicUsize::new(0)),
+            restart: leak!(Notify::new()),
+            draining: leak!(AtomicBool::new(false)),
+            rollover: leak!(Notify::new()),
+            rollover_done: leak!(Notify::new()),
+            drain_timeout: server_config.drain_timeout,
         }
     }
     #[allow(dead_code)]
@@ -145,8 +287,13 @@ impl WorkerConfig {
         WorkerConfig {
             target,
             is_online: leak!(AtomicBool::new(online)),
-            addr: addr.parse().unwrap(),
+            available: leak!(Notify::new()),
+            process: leak!(std::sync::Mutex::new(WorkerProcess::new(
+                addr.parse().unwrap()
+            ))),
             app_dir: None,
+            entrypoint: None,
+            r_options: &[],
             worker_route: None,
             rscript: OsStr::new(""),
             wtype: WorkerType::Dummy,
@@ -158,82 +305,345 @@ impl WorkerConfig {
             handle: leak!(Mutex::new(None)),
             shutdown: leak!(ShutdownSignal::new()),
             idle_stop: leak!(Notify::new()),
+            health_check: None,
+            command: None,
+            env: leak!(WorkerEnv::default()),
+            limits: leak!(ProcessLimits::default()),
+            cgroup: None,
+            recycle: leak!(RecyclePolicy::default()),
+            stats: leak!(ProcessStats::default()),
+            in_flight: leak!(AtomicUsize::new(0)),
+            websockets: leak!(AtomicUsize::new(0)),
+            pid: leak!(AtomicU32::new(0)),
+            restarts: leak!(AtomicUsize::new(0)),
+            restart: leak!(Notify::new()),
+            draining: leak!(AtomicBool::new(false)),
+            rollover: leak!(Notify::new()),
+            rollover_done: leak!(Notify::new()),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+    /// Takes the worker out of rotation. Its process and open connections
+    /// are kept.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.is_online.store(false, Ordering::SeqCst);
+    }
+    /// Puts a drained worker back in rotation if its process accepts
+    /// connections. Otherwise it comes back once the process is ready, like
+    /// after a restart. Returns whether the worker is online.
+    pub async fn undrain(&self) -> bool {
+        self.draining.store(false, Ordering::SeqCst);
+        if check_if_online(self.addr()).await {
+            self.set_online();
+        }
+        self.is_online.load(Ordering::SeqCst)
+    }
+    /// Puts the worker in rotation, unless it is draining, and wakes the
+    /// requests queued for the route. Returns whether it was offline.
+    pub(crate) fn set_online(&self) -> bool {
+        if self.draining.load(Ordering::SeqCst) || self.is_online.swap(true, Ordering::SeqCst) {
+            return false;
         }
+        self.available.notify_waiters();
+        true
+    }
+    fn process(&self) -> MutexGuard<'_, WorkerProcess> {
+        self.process.lock().expect("Worker process lock poisoned")
+    }
+    /// Address of the worker's current process.
+    pub fn addr(&self) -> SocketAddr {
+        self.process().addr
+    }
+    /// Address of the process a rolling restart is still draining, if any.
+    pub fn previous_addr(&self) -> Option<SocketAddr> {
+        self.process()
+            .previous
+            .as_ref()
+            .map(|previous| previous.addr)
+    }
+    /// Returns a client for the draining process if the sticky `session`
+    /// started on it. Otherwise the session is remembered as belonging to
+    /// the current process.
+    pub(crate) fn sticky_client(&self, session: u64) -> Option<Client> {
+        let mut process = self.process();
+        if let Some(previous) = &process.previous {
+            if previous.sessions.contains(&session) {
+                return Some(previous.client.clone());
+            }
+        }
+        process
+            .sessions
+            .insert(session, (), tokio::time::Instant::now());
+        None
+    }
+    /// Sends new traffic to the process on `addr`. The current process
+    /// becomes the previous one and keeps its sticky sessions.
+    fn hand_over(&'static self, addr: SocketAddr) -> SocketAddr {
+        let mut process = self.process();
+        let previous_addr = std::mem::replace(&mut process.addr, addr);
+        let sessions = std::mem::take(&mut process.sessions).into_keys(tokio::time::Instant::now());
+        process.previous = Some(PreviousProcess {
+            addr: previous_addr,
+            sessions,
+            client: Client::pinned(self, previous_addr),
+        });
+        process.retiring += 1;
+        previous_addr
+    }
+}
+
+/// A process of a worker, with the cgroup it runs in. The cgroup is
+/// removed once the process is gone.
+pub(crate) struct Process {
+    child: Child,
+    cgroup: Option<ProcessCgroup>,
+}
+
+impl std::ops::Deref for Process {
+    type Target = Child;
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl std::ops::DerefMut for Process {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl WorkerConfig {
+    /// Starts a process with everything the processes of this worker
+    /// share: their environment, process group, cgroup, resource limits
+    /// and user. Its output goes to the log.
+    fn spawn(&self, mut cmd: tokio::process::Command) -> FaucetResult<Process> {
+        cmd.envs(self.limits.user_env());
+        self.env.apply(&mut cmd)?;
+        let cgroup = self
+            .cgroup
+            .map(|cgroup| self.limits.create_cgroup(cgroup))
+            .transpose()?;
+
+        #[cfg(unix)]
+        {
+            let limits = self.limits;
+            let procs = cgroup.as_ref().map(|cgroup| cgroup.procs().to_owned());
+            unsafe {
+                cmd.pre_exec(move || {
+                    // Create a new process group for the child process
+                    nix::libc::setpgid(0, 0);
+                    limits.apply(procs.as_deref())
+                });
+            }
+        }
+
+        let child = log_stdio(cmd.spawn()?, self.target)?;
+        Ok(Process { child, cgroup })
     }
 }
 
-fn spawn_child_fastapi_server(config: &WorkerConfig) -> FaucetResult<Child> {
+fn spawn_child_uv_process(
+    config: &WorkerConfig,
+    args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    envs: &[(&str, &str)],
+) -> FaucetResult<Process> {
     let mut cmd = tokio::process::Command::new(config.uv);
 
     // Set the current directory to the directory containing the entrypoint
     cmd.current_dir(config.workdir)
-        .args(["run", "fastapi", "run", "--workers", "1"])
-        .arg("--port")
-        .arg(config.addr.port().to_string())
+        .arg("run")
+        .args(args)
+        .envs(envs.iter().copied())
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
         .env("FAUCET_WORKER_ID", config.worker_id.to_string())
         // This is needed to make sure the child process is killed when the parent is dropped
         .kill_on_drop(true);
+    config.spawn(cmd)
+}
 
-    #[cfg(unix)]
-    unsafe {
-        cmd.pre_exec(|| {
-            // Create a new process group for the child process
-            nix::libc::setpgid(0, 0);
-            Ok(())
-        });
-    }
+fn spawn_child_fastapi_server(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
+    spawn_child_uv_process(
+        config,
+        [
+            "fastapi",
+            "run",
+            "--workers",
+            "1",
+            "--port",
+            &port.to_string(),
+        ],
+        &[],
+    )
+}
 
-    let child = cmd.spawn()?;
+fn spawn_streamlit_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
+    let script = streamlit_script(config.workdir).ok_or_else(|| {
+        FaucetError::InvalidConfig(format!(
+            "No streamlit_app.py, or app.py importing streamlit, found in {:?}",
+            config.workdir
+        ))
+    })?;
+    let port = port.to_string();
+    spawn_child_uv_process(
+        config,
+        [
+            OsStr::new("streamlit"),
+            OsStr::new("run"),
+            script.as_os_str(),
+            OsStr::new("--server.port"),
+            OsStr::new(&port),
+            OsStr::new("--server.address"),
+            OsStr::new("127.0.0.1"),
+            OsStr::new("--server.headless"),
+            OsStr::new("true"),
+            OsStr::new("--browser.gatherUsageStats"),
+            OsStr::new("false"),
+        ],
+        &[],
+    )
+}
+
+/// Prefix of the route a Dash app is served under, which its pages use to
+/// request the app's resources. Only literal prefixes are known in advance.
+fn dash_pathname_prefix(route: &str) -> Option<&str> {
+    let path = &route[route.find('/')?..];
+    let literal = !path.contains(['{', '(', '[', '*', '?', '+', '\\', '$', '^', '|']);
+    (literal && path.ends_with('/')).then_some(path)
+}
 
-    log_stdio(child, config.target)
+fn spawn_dash_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
+    // Dash's development server, without the reloader and debugger that
+    // `app.run(debug=True)` in the script would start.
+    let command = format!("import app; app.app.run(host='127.0.0.1', port={port}, debug=False)");
+    // Faucet strips the route's prefix, Dash still needs it in its pages.
+    let envs = match config.worker_route.and_then(dash_pathname_prefix) {
+        Some(prefix) if !config.env.defines("DASH_REQUESTS_PATHNAME_PREFIX")? => vec![
+            ("DASH_REQUESTS_PATHNAME_PREFIX", prefix),
+            ("DASH_ROUTES_PATHNAME_PREFIX", "/"),
+        ],
+        _ => Vec::new(),
+    };
+    spawn_child_uv_process(config, ["python", "-c", &command], &envs)
 }
 
 fn spawn_child_rscript_process(
     config: &WorkerConfig,
     command: impl AsRef<str>,
-) -> FaucetResult<Child> {
+) -> FaucetResult<Process> {
     let mut cmd = tokio::process::Command::new(config.rscript);
 
     // Set the current directory to the directory containing the entrypoint
     cmd.current_dir(config.workdir)
         .arg("-e")
-        .arg(command.as_ref())
+        .arg(r_options::set_options(config.r_options) + command.as_ref())
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
         .env("FAUCET_WORKER_ID", config.worker_id.to_string())
         // This is needed to make sure the child process is killed when the parent is dropped
         .kill_on_drop(true);
+    config.spawn(cmd)
+}
 
-    #[cfg(unix)]
-    unsafe {
-        cmd.pre_exec(|| {
-            // Create a new process group for the child process
-            nix::libc::setpgid(0, 0);
-            Ok(())
-        });
+impl WorkerType {
+    /// File the application is started from when no `entrypoint` is
+    /// given, for the types that take one.
+    pub(crate) fn default_entrypoint(self, app_dir: &Path) -> Option<&'static str> {
+        match self {
+            WorkerType::Plumber2 if app_dir.join("_server.yml").is_file() => Some("_server.yml"),
+            WorkerType::Plumber2 => Some("plumber.R"),
+            WorkerType::Ambiorix => Some("app.R"),
+            _ => None,
+        }
     }
+    /// Whether the application can be started from an `entrypoint`.
+    pub(crate) fn has_entrypoint(self) -> bool {
+        matches!(
+            self,
+            WorkerType::Plumber | WorkerType::Plumber2 | WorkerType::Ambiorix
+        )
+    }
+    /// Whether the workers run an R session that `r_options` apply to.
+    pub(crate) fn runs_r(self) -> bool {
+        matches!(
+            self,
+            WorkerType::Plumber | WorkerType::Plumber2 | WorkerType::Ambiorix | WorkerType::Shiny
+        )
+    }
+}
 
-    cmd.spawn().map_err(Into::into)
+impl WorkerConfig {
+    /// Path of the file the application is started from, relative to the
+    /// workdir, as an R string.
+    fn r_entrypoint(&self) -> Option<String> {
+        let app_dir = Path::new(self.app_dir.unwrap_or("."));
+        let entrypoint = self
+            .entrypoint
+            .or_else(|| self.wtype.default_entrypoint(&self.workdir.join(app_dir)))?;
+        Some(r_options::r_string(
+            &app_dir.join(entrypoint).to_string_lossy(),
+        ))
+    }
 }
 
-fn spawn_plumber_worker(config: &WorkerConfig) -> FaucetResult<Child> {
+fn spawn_plumber_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
+    // plumb() only gives `entrypoint.R` its meaning, returning the router
+    // it builds, when it looks for the file in a directory.
+    let plumb = match config.entrypoint {
+        Some(entrypoint) if !entrypoint.eq_ignore_ascii_case("entrypoint.R") => format!(
+            "plumber::plumb(file = {})",
+            config.r_entrypoint().expect("Plumber has an entrypoint")
+        ),
+        entrypoint => {
+            let app_dir = Path::new(config.app_dir.unwrap_or("."));
+            let dir = match entrypoint.map(Path::new).and_then(Path::parent) {
+                Some(parent) => app_dir.join(parent),
+                None => app_dir.to_path_buf(),
+            };
+            format!(
+                "plumber::plumb(dir = {})",
+                r_options::r_string(&dir.to_string_lossy())
+            )
+        }
+    };
     let command = format!(
         r#"
         options("plumber.port" = {port})
-        plumber::pr_run(plumber::plumb())
+        plumber::pr_run({plumb})
+        "#
+    );
+    spawn_child_rscript_process(config, command)
+}
+
+fn spawn_plumber2_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
+    // api() takes its address from these options.
+    let command = format!(
+        r#"
+        options("plumber2.port" = {port}, "plumber2.host" = "127.0.0.1")
+        plumber2::api_run(plumber2::api({entrypoint}))
         "#,
-        port = config.addr.port()
+        entrypoint = config.r_entrypoint().expect("Plumber2 has an entrypoint")
     );
-    let child = spawn_child_rscript_process(config, command)?;
+    spawn_child_rscript_process(config, command)
+}
 
-    log_stdio(child, config.target)
+fn spawn_ambiorix_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
+    // The app is created and started by the script itself, it picks the
+    // address up from these options.
+    let command = format!(
+        r#"
+        options("ambiorix.port" = {port}, "ambiorix.host" = "127.0.0.1")
+        source({entrypoint}, chdir = TRUE)
+        "#,
+        entrypoint = config.r_entrypoint().expect("Ambiorix has an entrypoint")
+    );
+    spawn_child_rscript_process(config, command)
 }
 
-fn spawn_shiny_worker(config: &WorkerConfig) -> FaucetResult<Child> {
+fn spawn_shiny_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
     let command = format!(
         r###"
         options("shiny.port" = {port})
@@ -248,21 +658,18 @@ fn spawn_shiny_worker(config: &WorkerConfig) -> FaucetResult<Child> {
         }})
         shiny::runApp("{app_dir}")
         "###,
-        port = config.addr.port(),
         app_dir = config.app_dir.unwrap_or(".")
     );
 
-    let child = spawn_child_rscript_process(config, command)?;
-
-    log_stdio(child, config.target)
+    spawn_child_rscript_process(config, command)
 }
 
-fn spawn_quarto_shiny_worker(config: &WorkerConfig) -> FaucetResult<Child> {
+fn spawn_quarto_shiny_worker(config: &WorkerConfig, port: u16) -> FaucetResult<Process> {
     let mut cmd = tokio::process::Command::new(config.quarto);
     // Set the current directory to the directory containing the entrypoint
     cmd.current_dir(config.workdir)
         .arg("serve")
-        .args(["--port", config.addr.port().to_string().as_str()])
+        .args(["--port", port.to_string().as_str()])
         .arg(config.qmd.ok_or(FaucetError::MissingArgument("qmd"))?)
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::piped())
@@ -270,28 +677,59 @@ fn spawn_quarto_shiny_worker(config: &WorkerConfig) -> FaucetResult<Child> {
         .env("FAUCET_WORKER_ID", config.worker_id.to_string())
         // This is needed to make sure the child process is killed when the parent is dropped
         .kill_on_drop(true);
+    config.spawn(cmd)
+}
 
-    #[cfg(unix)]
-    unsafe {
-        cmd.pre_exec(|| {
-            // Create a new process group for the child process
-            nix::libc::setpgid(0, 0);
-            Ok(())
-        });
-    }
-
-    let child = cmd.spawn()?;
-
-    log_stdio(child, config.target)
+fn spawn_command_worker(config: &WorkerConfig, addr: SocketAddr) -> FaucetResult<Process> {
+    let command = config
+        .command
+        .ok_or(FaucetError::MissingArgument("command"))?;
+    let mut cmd = command.process(addr, config.worker_id);
+    cmd.current_dir(config.workdir)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .env("FAUCET_WORKER_ID", config.worker_id.to_string())
+        // This is needed to make sure the child process is killed when the parent is dropped
+        .kill_on_drop(true);
+    config.spawn(cmd)
 }
 
 impl WorkerConfig {
-    fn spawn_process(&self) -> FaucetResult<Child> {
+    /// Whether the process on `addr` can receive requests: it answers the
+    /// readiness check of command workers, or accepts connections.
+    async fn is_ready(&self, addr: SocketAddr) -> bool {
+        let ready = match self.command {
+            Some(command) => command.is_ready(addr).await,
+            None => None,
+        };
+        match ready {
+            Some(ready) => ready,
+            None => check_if_online(addr).await,
+        }
+    }
+    /// Whether a process started at `started` took longer than the
+    /// `ready_timeout` of its command.
+    fn ready_timed_out(&self, started: tokio::time::Instant) -> bool {
+        self.command
+            .and_then(|command| command.ready_timeout)
+            .is_some_and(|timeout| started.elapsed() >= timeout)
+    }
+    fn spawn_process(&self, addr: SocketAddr) -> FaucetResult<Process> {
+        let port = addr.port();
         let child_result = match self.wtype {
-            WorkerType::Plumber => spawn_plumber_worker(self),
-            WorkerType::Shiny => spawn_shiny_worker(self),
-            WorkerType::QuartoShiny => spawn_quarto_shiny_worker(self),
-            WorkerType::FastAPI => spawn_child_fastapi_server(self),
+            WorkerType::Plumber => spawn_plumber_worker(self, port),
+            WorkerType::Plumber2 => spawn_plumber2_worker(self, port),
+            WorkerType::Ambiorix => spawn_ambiorix_worker(self, port),
+            WorkerType::Shiny => spawn_shiny_worker(self, port),
+            WorkerType::QuartoShiny => spawn_quarto_shiny_worker(self, port),
+            WorkerType::FastAPI => spawn_child_fastapi_server(self, port),
+            WorkerType::Streamlit => spawn_streamlit_worker(self, port),
+            WorkerType::Dash => spawn_dash_worker(self, port),
+            WorkerType::Command => spawn_command_worker(self, addr),
+            WorkerType::Upstream => unreachable!(
+                "WorkerType::Upstream should be handled in spawn_worker_task and not reach spawn_process"
+            ),
             #[cfg(test)]
             WorkerType::Dummy => unreachable!(
                 "WorkerType::Dummy should be handled in spawn_worker_task and not reach spawn_process"
@@ -324,6 +762,152 @@ impl WorkerConfig {
                 }
             }
         }
+        // Processes replaced by a rolling restart are killed by their own task.
+        while self.process().retiring > 0 {
+            tokio::time::sleep(RECHECK_INTERVAL).await;
+        }
+    }
+    /// Replaces the worker's process without dropping connections. A new
+    /// process is started on a fresh port, receives new traffic once it is
+    /// online, and the previous one is killed after its connections finish
+    /// or `drain_timeout` passes. Returns once the new process receives
+    /// traffic or the replacement failed.
+    pub async fn rolling_restart(&self) {
+        if self.pid.load(Ordering::SeqCst) == 0 {
+            log::debug!(target: "faucet", "{target} has no running process, skipping rolling restart", target = self.target);
+            return;
+        }
+        let done = self.rollover_done.notified();
+        tokio::pin!(done);
+        done.as_mut().enable();
+        self.rollover.notify_one();
+        tokio::select! {
+            _ = done => (),
+            _ = self.shutdown.wait() => (),
+        }
+    }
+    /// Starts a process on a fresh port and waits for it to accept
+    /// connections. The current process keeps serving in the meantime.
+    async fn start_replacement(&self) -> FaucetResult<Option<(Process, SocketAddr)>> {
+        let current_addr = self.addr();
+        let addr = loop {
+            let candidate = get_available_socket(TRIES).await?;
+            if candidate != current_addr {
+                break candidate;
+            }
+        };
+        let mut child = self.spawn_process(addr)?;
+        log::info!(
+            target: "faucet",
+            "Starting replacement process {pid} for {target} on port {port}",
+            pid = child.id().unwrap_or_default(),
+            target = self.target,
+            port = addr.port()
+        );
+        let started = tokio::time::Instant::now();
+        loop {
+            if self.is_ready(addr).await {
+                return Ok(Some((child, addr)));
+            }
+            if child.try_wait()?.is_some() {
+                log::error!(target: "faucet", "Replacement process for {target} exited before coming online, keeping the current process", target = self.target);
+                return Ok(None);
+            }
+            if self.ready_timed_out(started) {
+                log::error!(target: "faucet", "Replacement process for {target} did not become ready in time, keeping the current process", target = self.target);
+                let _ = child.kill().await;
+                return Ok(None);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RECHECK_INTERVAL) => (),
+                _ = self.shutdown.wait() => return Ok(None),
+            }
+        }
+    }
+    /// Waits for the connections and WebSocket sessions of a replaced
+    /// process to finish, or for the drain timeout, and then kills it.
+    async fn drain_previous(&'static self, mut child: Process, pid: u32, addr: SocketAddr) {
+        let deadline = tokio::time::Instant::now() + self.drain_timeout;
+        loop {
+            let connections = worker_connections(addr);
+            let sessions = websocket_sessions()
+                .await
+                .iter()
+                .filter(|session| session.worker_addr == Some(addr) && !session.purged)
+                .count();
+            if connections == 0 && sessions == 0 {
+                log::info!(target: "faucet", "{target}'s previous process ({pid}) has no connections left", target = self.target);
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                log::warn!(
+                    target: "faucet",
+                    "{target}'s previous process ({pid}) reached the drain timeout with {connections} connections and {sessions} WebSocket sessions left",
+                    target = self.target
+                );
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(DRAIN_RECHECK_INTERVAL) => (),
+                _ = child.wait() => break,
+                _ = self.shutdown.wait() => break,
+            }
+        }
+        {
+            // Sticky sessions of the previous process go to the current one from now on.
+            let mut process = self.process();
+            if process.previous.as_ref().map(|previous| previous.addr) == Some(addr) {
+                process.previous = None;
+            }
+        }
+        let _ = child.kill().await;
+        log::info!(target: "faucet", "{target}'s previous process ({pid}) killed after draining", target = self.target);
+        self.process().retiring -= 1;
+    }
+    /// Upstreams have no process to run. Their task keeps the worker online
+    /// while the upstream accepts connections, or passes its health check,
+    /// until faucet shuts down.
+    async fn follow_upstream(&'static self) {
+        let addr = self.addr();
+        let mut is_restart = false;
+        loop {
+            if is_restart {
+                self.restarts.fetch_add(1, Ordering::SeqCst);
+            }
+            is_restart = true;
+            let watch = async {
+                while !check_if_online(addr).await {
+                    tokio::time::sleep(UPSTREAM_RECHECK_INTERVAL).await;
+                }
+                log::info!(target: "faucet", "{target} is online and ready to serve connections at {route} through upstream {addr}", target = self.target, route = self.worker_route.unwrap_or("/"));
+                self.set_online();
+                match self.health_check {
+                    Some(health_check) => health_check.monitor(self).await,
+                    None => {
+                        while check_if_online(addr).await {
+                            tokio::time::sleep(UPSTREAM_RECHECK_INTERVAL).await;
+                        }
+                    }
+                }
+            };
+            tokio::select! {
+                _ = self.shutdown.wait() => break,
+                _ = self.idle_stop.notified() => break,
+                _ = self.restart.notified() => {
+                    self.draining.store(false, Ordering::SeqCst);
+                    log::info!(target: "faucet", "{target} reconnecting to upstream {addr}", target = self.target);
+                }
+                _ = watch => {
+                    log::error!(target: "faucet", "{target} lost upstream {addr}, waiting for it to come back", target = self.target);
+                }
+            }
+            self.is_online.store(false, Ordering::SeqCst);
+        }
+        self.is_online.store(false, Ordering::SeqCst);
+        log::debug!(
+            "{target}'s upstream is no longer followed.",
+            target = self.target
+        );
     }
     pub async fn spawn_worker_task(&'static self) {
         let mut handle = self.handle.lock().await;
@@ -346,17 +930,41 @@ impl WorkerConfig {
                 return FaucetResult::Ok(());
             }
 
+            if self.wtype == WorkerType::Upstream {
+                self.follow_upstream().await;
+                return FaucetResult::Ok(());
+            }
+
+            let mut is_restart = false;
+            // The process the recycle policy applies to, and when it started.
+            let mut current = (0, tokio::time::Instant::now());
+            // Whether the pending rollover was started by the recycle policy,
+            // and how many of its replacements failed to start in a row.
+            let mut recycling = false;
+            let mut recycle_failures = 0;
+            let mut recycle_after = tokio::time::Instant::now();
+            // A process that is already running, either the replacement from
+            // a rolling restart or the current one if the replacement failed.
+            let mut running: Option<Process> = None;
             'outer: loop {
-                let mut child = match self.spawn_process() {
-                    Ok(c) => c,
-                    Err(e) => {
-                        log::error!(
-                            target: "faucet",
-                            "Worker task for {target} failed to spawn initial process: {e}",
-                            target = self.target
-                        );
-                        return Err(e);
-                    }
+                if is_restart {
+                    self.restarts.fetch_add(1, Ordering::SeqCst);
+                }
+                is_restart = true;
+                let addr = self.addr();
+                let mut child = match running.take() {
+                    Some(child) => child,
+                    None => match self.spawn_process(addr) {
+                        Ok(c) => c,
+                        Err(e) => {
+                            log::error!(
+                                target: "faucet",
+                                "Worker task for {target} failed to spawn initial process: {e}",
+                                target = self.target
+                            );
+                            return Err(e);
+                        }
+                    },
                 };
 
                 let pid = match child.id() {
@@ -370,19 +978,28 @@ impl WorkerConfig {
                         return Err(FaucetError::Unknown(err_msg));
                     }
                 };
+                self.pid.store(pid, Ordering::SeqCst);
+                if current.0 != pid {
+                    current = (pid, tokio::time::Instant::now());
+                    self.stats.reset();
+                    recycle_failures = 0;
+                    recycle_after = current.1;
+                }
+                let cgroup_procs = child.cgroup.as_ref().map(ProcessCgroup::procs_path);
 
                 // We will run this loop asynchrnously on this same thread.
                 // We will use this to wait for either the stop signal
                 // or the child exiting
                 let child_loop = async {
-                    log::info!(target: "faucet", "Starting process {pid} for {target} on port {port}", port = self.addr.port(), target = self.target);
+                    log::info!(target: "faucet", "Starting process {pid} for {target} on port {port}", port = addr.port(), target = self.target);
+                    let started = tokio::time::Instant::now();
                     loop {
                         // Try to connect to the socket
-                        let check_status = check_if_online(self.addr).await;
+                        let check_status = self.is_ready(addr).await;
                         // If it's online, we can break out of the loop and start serving connections
                         if check_status {
                             log::info!(target: "faucet", "{target} is online and ready to serve connections at {route}", target = self.target, route = self.worker_route.unwrap_or("/"));
-                            self.is_online.store(check_status, Ordering::SeqCst);
+                            self.set_online();
                             break;
                         }
                         // If it's not online but the child process has exited, we should break out of the loop
@@ -390,31 +1007,103 @@ impl WorkerConfig {
                         if child.try_wait()?.is_some() {
                             break;
                         }
+                        if self.ready_timed_out(started) {
+                            log::error!(target: "faucet", "{target}'s process ({pid}) did not become ready in time, killing it", target = self.target);
+                            let _ = child.kill().await;
+                            break;
+                        }
 
                         tokio::time::sleep(RECHECK_INTERVAL).await;
                     }
+                    if let Some(health_check) = self.health_check {
+                        tokio::select! {
+                            status = child.wait() => return FaucetResult::Ok(status?),
+                            _ = health_check.monitor(self) => {
+                                // The health check gave up on the process, we kill it
+                                // and let the outer loop start a new one.
+                                self.is_online.store(false, Ordering::SeqCst);
+                                let _ = child.kill().await;
+                            }
+                        }
+                    }
                     FaucetResult::Ok(child.wait().await?)
                 };
                 tokio::select! {
                     // If we receive a stop signal that means we will stop the outer loop
                     // and kill the process
                     _ = self.shutdown.wait() => {
+                        self.pid.store(0, Ordering::SeqCst);
+                        self.stats.reset();
                         let _ = child.kill().await;
                         log::info!(target: "faucet", "{target}'s process ({pid}) killed for shutdown", target = self.target);
                         break 'outer;
                     },
                     _ = self.idle_stop.notified() => {
                         self.is_online.store(false, std::sync::atomic::Ordering::SeqCst);
+                        self.pid.store(0, Ordering::SeqCst);
+                        self.stats.reset();
                         let _ = child.kill().await;
                         log::info!(target: "faucet", "{target}'s process ({pid}) killed for idle stop", target = self.target);
                         break 'outer;
                     },
+                    _ = self.restart.notified() => {
+                        self.is_online.store(false, Ordering::SeqCst);
+                        self.draining.store(false, Ordering::SeqCst);
+                        let _ = child.kill().await;
+                        log::info!(target: "faucet", "{target}'s process ({pid}) killed for restart", target = self.target);
+                        continue 'outer;
+                    },
+                    _ = self.rollover.notified() => {
+                        let replaced = match self.start_replacement().await {
+                            Ok(Some((replacement, new_addr))) => {
+                                self.hand_over(new_addr);
+                                log::info!(target: "faucet", "{target} moved to port {port}, draining process {pid}", target = self.target, port = new_addr.port());
+                                tokio::spawn(self.drain_previous(child, pid, addr));
+                                running = Some(replacement);
+                                true
+                            }
+                            Ok(None) => {
+                                running = Some(child);
+                                is_restart = false;
+                                false
+                            }
+                            Err(e) => {
+                                log::error!(target: "faucet", "Unable to start a replacement process for {target}: {e}", target = self.target);
+                                running = Some(child);
+                                is_restart = false;
+                                false
+                            }
+                        };
+                        if recycling && !replaced {
+                            let backoff = self.recycle.backoff(recycle_failures);
+                            recycle_failures += 1;
+                            recycle_after = tokio::time::Instant::now() + backoff;
+                            log::warn!(target: "faucet", "Recycling {target}'s process ({pid}) again in {backoff:?}", target = self.target);
+                        }
+                        recycling = false;
+                        self.rollover_done.notify_waiters();
+                        continue 'outer;
+                    },
+                    // Replaced like in a rolling restart, so its connections
+                    // are drained first.
+                    reason = self.recycle.monitor(self.stats, pid, cgroup_procs.as_deref(), current.1, recycle_after) => {
+                        log::info!(target: "faucet", "{target}'s process ({pid}) {reason}, recycling it", target = self.target);
+                        recycling = true;
+                        self.rollover.notify_one();
+                        running = Some(child);
+                        is_restart = false;
+                        continue 'outer;
+                    },
                     // If our child loop stops that means the process crashed. We will restart it
                     status = child_loop => {
                        self
                             .is_online
                             .store(false, std::sync::atomic::Ordering::SeqCst);
-                        log::error!(target: "faucet", "{target}'s process ({}) exited with status {}", pid, status?, target = self.target);
+                        let status = status?;
+                        match self.limits.exit_reason(status, child.cgroup.as_ref()) {
+                            Some(reason) => log::error!(target: "faucet", "{target}'s process ({}) {reason} and exited with status {}", pid, status, target = self.target),
+                            None => log::error!(target: "faucet", "{target}'s process ({}) exited with status {}", pid, status, target = self.target),
+                        }
                         continue 'outer;
                     }
                 }
@@ -431,6 +1120,8 @@ async fn check_if_online(addr: SocketAddr) -> bool {
 }
 
 const RECHECK_INTERVAL: Duration = Duration::from_millis(250);
+const UPSTREAM_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct WorkerConfigs {
     pub workers: Box<[&'static WorkerConfig]>,
@@ -445,6 +1136,24 @@ impl WorkerConfigs {
     ) -> FaucetResult<Self> {
         let mut workers =
             Vec::<&'static WorkerConfig>::with_capacity(server_config.n_workers.get());
+        let available: &'static Notify = leak!(Notify::new());
+
+        if server_config.server_type == WorkerType::Upstream {
+            for (id, upstream) in server_config.upstream.iter().enumerate() {
+                let addr = resolve_upstream(upstream).await?;
+                let config = leak!(WorkerConfig::new(
+                    id + 1,
+                    addr,
+                    &server_config,
+                    shutdown,
+                    available
+                )) as &'static WorkerConfig;
+                workers.push(config);
+            }
+            return Ok(Self {
+                workers: workers.into_boxed_slice(),
+            });
+        }
 
         for id in 0..server_config.n_workers.get() {
             // Probably hacky but it works. I need to guarantee that ports are never
@@ -452,7 +1161,7 @@ impl WorkerConfigs {
             let socket_addr = 'find_socket: loop {
                 let addr_candidate = get_available_socket(TRIES).await?;
                 // Check if another worker has already reserved this port
-                if workers.iter().any(|w| w.addr == addr_candidate) {
+                if workers.iter().any(|w| w.addr() == addr_candidate) {
                     continue 'find_socket;
                 }
                 break 'find_socket addr_candidate;
@@ -462,7 +1171,8 @@ impl WorkerConfigs {
                 id + 1,
                 socket_addr,
                 &server_config,
-                shutdown
+                shutdown,
+                available
             )) as &'static WorkerConfig;
             workers.push(config);
         }
@@ -472,3 +1182,181 @@ impl WorkerConfigs {
         Ok(Self { workers })
     }
 }
+
+/// Address of an upstream, resolved once when its route starts.
+async fn resolve_upstream(upstream: &str) -> FaucetResult<SocketAddr> {
+    let unresolved = |reason: String| {
+        FaucetError::InvalidConfig(format!("Unable to resolve upstream {upstream}: {reason}"))
+    };
+    tokio::net::lookup_host(upstream)
+        .await
+        .map_err(|e| unresolved(e.to_string()))?
+        .next()
+        .ok_or_else(|| unresolved("no address found".to_string()))
+}
+
+/// Replaces the processes of `workers` one at a time, see
+/// [`WorkerConfig::rolling_restart`].
+pub async fn rolling_restart(workers: &[&'static WorkerConfig]) {
+    for worker in workers {
+        worker.rolling_restart().await;
+    }
+    log::info!(target: "faucet", "Rolling restart finished");
+}
+
+/// Runs a rolling restart of `workers` every time faucet receives SIGHUP.
+#[cfg(unix)]
+pub(crate) fn rolling_restart_on_sighup(
+    workers: Vec<&'static WorkerConfig>,
+    shutdown: &'static ShutdownSignal,
+) -> FaucetResult<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    log::info!(target: "faucet", "Received SIGHUP, starting a rolling restart");
+                    rolling_restart(&workers).await;
+                }
+                _ = shutdown.wait() => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn rolling_restart_on_sighup(
+    _workers: Vec<&'static WorkerConfig>,
+    _shutdown: &'static ShutdownSignal,
+) -> FaucetResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ExtractSocketAddr;
+
+    #[tokio::test]
+    async fn sticky_sessions_stay_on_previous_process() {
+        let worker: &'static WorkerConfig =
+            leak!(WorkerConfig::dummy("Worker::1", "127.0.0.1:9981", true));
+
+        assert!(worker.sticky_client(1).is_none());
+
+        let previous = worker.hand_over("127.0.0.1:9982".parse().unwrap());
+        assert_eq!(previous, "127.0.0.1:9981".parse().unwrap());
+        assert_eq!(worker.addr(), "127.0.0.1:9982".parse().unwrap());
+        assert_eq!(worker.previous_addr(), Some(previous));
+
+        // The old session is pinned to the previous process, new ones are not.
+        let client = worker.sticky_client(1).expect("session 1 should be pinned");
+        assert_eq!(client.socket_addr(), previous);
+        assert!(worker.sticky_client(2).is_none());
+        assert_eq!(
+            Client::new(worker).socket_addr(),
+            "127.0.0.1:9982".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn dash_prefix_of_routes() {
+        assert_eq!(dash_pathname_prefix("/dash/"), Some("/dash/"));
+        assert_eq!(
+            dash_pathname_prefix("apps.example.com/dash/"),
+            Some("/dash/")
+        );
+        assert_eq!(dash_pathname_prefix("/tenant/{id}/"), None);
+        assert_eq!(dash_pathname_prefix("/v(?P<version>[0-9]+)/"), None);
+        assert_eq!(dash_pathname_prefix("/exact"), None);
+    }
+
+    #[test]
+    fn upstream_addresses() {
+        assert_eq!(
+            upstream_authority("http://node-app:3000/").unwrap(),
+            "node-app:3000"
+        );
+        assert_eq!(
+            upstream_authority("10.0.0.5:8080").unwrap(),
+            "10.0.0.5:8080"
+        );
+        assert_eq!(upstream_authority("[::1]:8080").unwrap(), "[::1]:8080");
+        for invalid in [
+            "node-app",
+            "https://node-app:3000",
+            "http://node-app:3000/api",
+            "user@node-app:3000",
+            "",
+        ] {
+            assert!(upstream_authority(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_workers_follow_the_upstream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut worker = WorkerConfig::dummy("Worker::1", &addr.to_string(), false);
+        worker.wtype = WorkerType::Upstream;
+        let worker: &'static WorkerConfig = leak!(worker);
+
+        worker.spawn_worker_task().await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !worker.is_online.load(Ordering::SeqCst) {
+                tokio::time::sleep(RECHECK_INTERVAL).await;
+            }
+        })
+        .await
+        .expect("the upstream accepts connections");
+        assert_eq!(worker.pid.load(Ordering::SeqCst), 0);
+
+        drop(listener);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while worker.is_online.load(Ordering::SeqCst) {
+                tokio::time::sleep(RECHECK_INTERVAL).await;
+            }
+        })
+        .await
+        .expect("the upstream is gone");
+
+        worker.shutdown.shutdown();
+        worker.wait_until_done().await;
+    }
+
+    #[tokio::test]
+    async fn drain_previous_kills_idle_process() {
+        let mut worker = WorkerConfig::dummy("Worker::1", "127.0.0.1:9983", true);
+        worker.drain_timeout = Duration::from_secs(30);
+        let worker: &'static WorkerConfig = leak!(worker);
+
+        let child = tokio::process::Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        worker.sticky_client(1);
+        let previous = worker.hand_over("127.0.0.1:9984".parse().unwrap());
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            worker.drain_previous(
+                Process {
+                    child,
+                    cgroup: None,
+                },
+                pid,
+                previous,
+            ),
+        )
+        .await
+        .expect("a process without connections should not wait for the timeout");
+
+        assert_eq!(worker.previous_addr(), None);
+        assert!(worker.sticky_client(1).is_none());
+        worker.wait_until_done().await;
+    }
+}
@@ -0,0 +1,789 @@
+//! The `faucet.toml` configuration file.
+//!
+//! Every option in the file maps to the environment variable of the
+//! matching command line option. The values in the file become the
+//! defaults of those options, which gives the precedence command line >
+//! environment > file.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use clap::{Command, CommandFactory, FromArgMatches, ValueEnum};
+use toml::{Table, Value};
+
+use crate::{
+    cli::{Args, StartArgs},
+    client::{
+        load_balancing::HashKey, HealthCheckConfig, QueueConfig, ROption, ROptionValue,
+        RecycleConfig,
+    },
+    error::{FaucetError, FaucetResult},
+    leak,
+    server::{LbCookieConfig, RouterConfig, TlsConfig},
+};
+
+/// File read when `--config` is not given, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "faucet.toml";
+
+const REDACTED: &str = "<redacted>";
+
+/// A size given either in bytes or as a string like `"10M"`.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    fn to_env(&self) -> String {
+        match self {
+            Size::Bytes(bytes) => bytes.to_string(),
+            Size::Text(text) => text.clone(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TelemetryFile {
+    postgres_string: Option<String>,
+    postgres_sslcert: Option<PathBuf>,
+    postgres_sslmode: Option<String>,
+    namespace: Option<String>,
+    version: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminFile {
+    host: String,
+    token: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetricsFile {
+    host: String,
+}
+
+/// The application run by `faucet start`. Same fields as a router route.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AppFile {
+    server_type: Option<String>,
+    workdir: Option<PathBuf>,
+    app_dir: Option<String>,
+    workers: Option<usize>,
+    strategy: Option<String>,
+    qmd: Option<PathBuf>,
+    entrypoint: Option<String>,
+    r_options: Option<BTreeMap<String, ROptionValue>>,
+    static_dir: Option<PathBuf>,
+    max_rps: Option<f64>,
+    watch: Option<bool>,
+    health_check: Option<HealthCheckConfig>,
+    recycle: Option<RecycleConfig>,
+    queue: Option<QueueConfig>,
+    hash_key: Option<HashKey>,
+    lb_cookie: Option<LbCookieConfig>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    host: Option<String>,
+    ip_from: Option<String>,
+    rscript: Option<PathBuf>,
+    quarto: Option<PathBuf>,
+    uv: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    max_log_file_size: Option<Size>,
+    shutdown: Option<String>,
+    max_message_size: Option<Size>,
+    drain_timeout: Option<f64>,
+    telemetry: Option<TelemetryFile>,
+    tls: Option<TlsConfig>,
+    admin: Option<AdminFile>,
+    metrics: Option<MetricsFile>,
+    app: Option<AppFile>,
+    /// Routes for `faucet router`, validated by [`RouterConfig`].
+    route: Option<Vec<Table>>,
+}
+
+fn path_env(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> FaucetResult<Self> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            FaucetError::InvalidConfig(format!("Unable to read {}: {e}", path.display()))
+        })?;
+        Self::parse(&content)
+            .map_err(|e| FaucetError::InvalidConfig(format!("{}: {e}", path.display())))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let config: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        if config.app.is_some() && config.route.is_some() {
+            return Err("`[app]` and `[[route]]` can not be used together".to_string());
+        }
+        Ok(config)
+    }
+
+    pub fn has_routes(&self) -> bool {
+        self.route.is_some()
+    }
+
+    /// The environment variables of the options set in the file.
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = Vec::new();
+        let mut set = |name: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                vars.push((name, value));
+            }
+        };
+
+        set("FAUCET_HOST", self.host.clone());
+        set("FAUCET_IP_FROM", self.ip_from.clone());
+        set("FAUCET_RSCRIPT", self.rscript.as_deref().map(path_env));
+        set("FAUCET_QUARTO", self.quarto.as_deref().map(path_env));
+        set("FAUCET_UV", self.uv.as_deref().map(path_env));
+        set("FAUCET_LOG_FILE", self.log_file.as_deref().map(path_env));
+        set(
+            "FAUCET_MAX_LOG_FILE_SIZE",
+            self.max_log_file_size.as_ref().map(Size::to_env),
+        );
+        set("FAUCET_SHUTDOWN", self.shutdown.clone());
+        set(
+            "FAUCET_MAX_MESSAGE_SIZE",
+            self.max_message_size.as_ref().map(Size::to_env),
+        );
+        set(
+            "FAUCET_DRAIN_TIMEOUT",
+            self.drain_timeout.map(|t| t.to_string()),
+        );
+
+        if let Some(telemetry) = &self.telemetry {
+            set(
+                "FAUCET_TELEMETRY_POSTGRES_STRING",
+                telemetry.postgres_string.clone(),
+            );
+            set(
+                "FAUCET_TELEMETRY_POSTGRES_SSLCERT",
+                telemetry.postgres_sslcert.as_deref().map(path_env),
+            );
+            set(
+                "FAUCET_TELEMETRY_POSTGRES_SSLMODE",
+                telemetry.postgres_sslmode.clone(),
+            );
+            set("FAUCET_TELEMETRY_NAMESPACE", telemetry.namespace.clone());
+            set("FAUCET_TELEMETRY_VERSION", telemetry.version.clone());
+        }
+        if let Some(tls) = &self.tls {
+            set("FAUCET_TLS_CERT", Some(path_env(&tls.cert)));
+            set("FAUCET_TLS_KEY", Some(path_env(&tls.key)));
+        }
+        if let Some(admin) = &self.admin {
+            set("FAUCET_ADMIN_HOST", Some(admin.host.clone()));
+            set("FAUCET_ADMIN_TOKEN", admin.token.clone());
+        }
+        if let Some(metrics) = &self.metrics {
+            set("FAUCET_METRICS_HOST", Some(metrics.host.clone()));
+        }
+
+        if let Some(app) = &self.app {
+            set("FAUCET_TYPE", app.server_type.clone());
+            set("FAUCET_DIR", app.workdir.as_deref().map(path_env));
+            set("FAUCET_APP_DIR", app.app_dir.clone());
+            set("FAUCET_WORKERS", app.workers.map(|n| n.to_string()));
+            set("FAUCET_STRATEGY", app.strategy.clone());
+            set("FAUCET_QMD", app.qmd.as_deref().map(path_env));
+            set("FAUCET_ENTRYPOINT", app.entrypoint.clone());
+            set("FAUCET_STATIC_DIR", app.static_dir.as_deref().map(path_env));
+            set("FAUCET_MAX_RPS", app.max_rps.map(|rps| rps.to_string()));
+            set("FAUCET_WATCH", app.watch.map(|watch| watch.to_string()));
+            if let Some(hc) = &app.health_check {
+                set("FAUCET_HEALTH_CHECK", Some("true".to_string()));
+                set("FAUCET_HEALTH_CHECK_PATH", hc.path.clone());
+                set(
+                    "FAUCET_HEALTH_CHECK_STATUS",
+                    hc.expected_status.map(|s| s.to_string()),
+                );
+                set(
+                    "FAUCET_HEALTH_CHECK_INTERVAL",
+                    Some(hc.interval.to_string()),
+                );
+                set("FAUCET_HEALTH_CHECK_TIMEOUT", Some(hc.timeout.to_string()));
+                set(
+                    "FAUCET_HEALTH_CHECK_UNHEALTHY_THRESHOLD",
+                    Some(hc.unhealthy_threshold.to_string()),
+                );
+                set(
+                    "FAUCET_HEALTH_CHECK_HEALTHY_THRESHOLD",
+                    Some(hc.healthy_threshold.to_string()),
+                );
+                set(
+                    "FAUCET_HEALTH_CHECK_RESTART_AFTER",
+                    hc.restart_after.map(|n| n.to_string()),
+                );
+            }
+            if let Some(recycle) = &app.recycle {
+                set(
+                    "FAUCET_RECYCLE_MAX_MEMORY",
+                    recycle.max_memory.map(|size| size.0.to_string()),
+                );
+                set(
+                    "FAUCET_RECYCLE_MAX_REQUESTS",
+                    recycle.max_requests.map(|n| n.to_string()),
+                );
+                set(
+                    "FAUCET_RECYCLE_MAX_AGE",
+                    recycle.max_age.map(|secs| secs.to_string()),
+                );
+                set(
+                    "FAUCET_RECYCLE_INTERVAL",
+                    recycle.interval.map(|secs| secs.to_string()),
+                );
+            }
+            if let Some(queue) = &app.queue {
+                set(
+                    "FAUCET_QUEUE_MAX_CONCURRENCY",
+                    queue.max_concurrency.map(|n| n.to_string()),
+                );
+                set(
+                    "FAUCET_QUEUE_MAX_LENGTH",
+                    queue.max_length.map(|n| n.to_string()),
+                );
+                set(
+                    "FAUCET_QUEUE_TIMEOUT",
+                    queue.timeout.map(|secs| secs.to_string()),
+                );
+            }
+            match &app.hash_key {
+                Some(HashKey::Header(name)) => set("FAUCET_HASH_HEADER", Some(name.clone())),
+                Some(HashKey::Cookie(name)) => set("FAUCET_HASH_COOKIE", Some(name.clone())),
+                Some(HashKey::JwtClaim(claim)) => set("FAUCET_HASH_JWT_CLAIM", Some(claim.clone())),
+                None => (),
+            }
+            if let Some(lb_cookie) = &app.lb_cookie {
+                set("FAUCET_LB_COOKIE_NAME", lb_cookie.name.clone());
+                set("FAUCET_LB_COOKIE_PATH", lb_cookie.path.clone());
+                set("FAUCET_LB_COOKIE_DOMAIN", lb_cookie.domain.clone());
+                set(
+                    "FAUCET_LB_COOKIE_SECURE",
+                    lb_cookie.secure.map(|secure| secure.to_string()),
+                );
+                set(
+                    "FAUCET_LB_COOKIE_SAME_SITE",
+                    lb_cookie.same_site.map(value_name),
+                );
+                set(
+                    "FAUCET_LB_COOKIE_MAX_AGE",
+                    lb_cookie.max_age.map(|secs| secs.to_string()),
+                );
+                set("FAUCET_LB_COOKIE_SECRET", lb_cookie.secret.clone());
+            }
+        }
+        vars
+    }
+
+    /// The R options of `[app]`. They are not passed through
+    /// `FAUCET_R_OPTIONS`, which would read strings such as `"TRUE"` or
+    /// `"1"` back as a boolean or a number.
+    fn r_options(&self) -> Vec<ROption> {
+        self.app
+            .iter()
+            .flat_map(|app| app.r_options.iter().flatten())
+            .map(|(name, value)| ROption {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect()
+    }
+
+    /// Sets the options of `start` that are not passed through the
+    /// environment, unless the command line or the environment set them.
+    pub fn fill_start_args(&self, start: &mut StartArgs) -> FaucetResult<()> {
+        start.merge_r_options(self.r_options())
+    }
+
+    /// Makes the options in the file the defaults of `command`, so the
+    /// command line and the environment still override them. `path` is the
+    /// file the configuration came from.
+    ///
+    /// The environment is left alone: the processes faucet starts would
+    /// inherit the secrets in the file.
+    pub fn apply(&self, path: &Path, command: Command) -> Command {
+        let mut vars = self.env_vars();
+        if self.has_routes() {
+            vars.push(("FAUCET_ROUTER_CONF", path_env(path)));
+        }
+        with_defaults(command, &vars)
+    }
+}
+
+/// Sets the default of each argument of `command` and its subcommands whose
+/// environment variable is in `vars`.
+fn with_defaults(command: Command, vars: &[(&'static str, String)]) -> Command {
+    let defaults = command
+        .get_arguments()
+        .filter_map(|arg| {
+            let env = arg.get_env()?;
+            let (_, value) = vars.iter().find(|(name, _)| env == *name)?;
+            Some((arg.get_id().clone(), value.as_str()))
+        })
+        .collect::<Vec<_>>();
+    let command = defaults.into_iter().fold(command, |command, (id, value)| {
+        command.mut_arg(id, |arg| arg.default_value(leak!(value, str)))
+    });
+    let subcommands = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect::<Vec<_>>();
+    subcommands.iter().fold(command, |command, name| {
+        command.mut_subcommand(name, |subcommand| with_defaults(subcommand, vars))
+    })
+}
+
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn path_value(path: &Path) -> Value {
+    Value::String(path_env(path))
+}
+
+fn r_option_value(value: &ROptionValue) -> Value {
+    match value {
+        ROptionValue::Bool(b) => Value::Boolean(*b),
+        ROptionValue::Integer(n) => Value::Integer(*n),
+        ROptionValue::Float(x) => Value::Float(*x),
+        ROptionValue::String(s) => Value::String(s.clone()),
+    }
+}
+
+fn app_table(start: &StartArgs) -> Table {
+    let mut app = Table::new();
+    app.insert("server_type".into(), start.server_type_name().into());
+    app.insert("workdir".into(), path_value(&start.dir));
+    if let Some(app_dir) = &start.app_dir {
+        app.insert("app_dir".into(), app_dir.clone().into());
+    }
+    app.insert("workers".into(), (start.workers as i64).into());
+    app.insert("strategy".into(), value_name(start.strategy).into());
+    if let Some(qmd) = &start.qmd {
+        app.insert("qmd".into(), path_value(qmd));
+    }
+    if let Some(entrypoint) = &start.entrypoint {
+        app.insert("entrypoint".into(), entrypoint.clone().into());
+    }
+    if !start.r_options.is_empty() {
+        let options = start
+            .r_options
+            .iter()
+            .map(|ROption { name, value }| (name.clone(), r_option_value(value)))
+            .collect::<Table>();
+        app.insert("r_options".into(), options.into());
+    }
+    if let Some(static_dir) = &start.static_dir {
+        app.insert("static_dir".into(), path_value(static_dir));
+    }
+    if let Some(max_rps) = start.max_rps {
+        app.insert("max_rps".into(), max_rps.into());
+    }
+    app.insert("watch".into(), start.watch.into());
+    if let Some(hc) = start.health_check() {
+        let mut table = Table::new();
+        if let Some(path) = hc.path {
+            table.insert("path".into(), path.into());
+        }
+        if let Some(status) = hc.expected_status {
+            table.insert("expected_status".into(), i64::from(status).into());
+        }
+        table.insert("interval".into(), hc.interval.into());
+        table.insert("timeout".into(), hc.timeout.into());
+        table.insert(
+            "unhealthy_threshold".into(),
+            i64::from(hc.unhealthy_threshold).into(),
+        );
+        table.insert(
+            "healthy_threshold".into(),
+            i64::from(hc.healthy_threshold).into(),
+        );
+        if let Some(restart_after) = hc.restart_after {
+            table.insert("restart_after".into(), i64::from(restart_after).into());
+        }
+        app.insert("health_check".into(), table.into());
+    }
+    if let Some(recycle) = start.recycle() {
+        let mut table = Table::new();
+        if let Some(max_memory) = recycle.max_memory {
+            table.insert("max_memory".into(), (max_memory.0 as i64).into());
+        }
+        if let Some(max_requests) = recycle.max_requests {
+            table.insert("max_requests".into(), (max_requests as i64).into());
+        }
+        if let Some(max_age) = recycle.max_age {
+            table.insert("max_age".into(), max_age.into());
+        }
+        if let Some(interval) = recycle.interval {
+            table.insert("interval".into(), interval.into());
+        }
+        app.insert("recycle".into(), table.into());
+    }
+    if let Some(queue) = start.queue() {
+        let mut table = Table::new();
+        if let Some(max_concurrency) = queue.max_concurrency {
+            table.insert("max_concurrency".into(), (max_concurrency as i64).into());
+        }
+        if let Some(max_length) = queue.max_length {
+            table.insert("max_length".into(), (max_length as i64).into());
+        }
+        if let Some(timeout) = queue.timeout {
+            table.insert("timeout".into(), timeout.into());
+        }
+        app.insert("queue".into(), table.into());
+    }
+    if let Some(hash_key) = start.hash_key() {
+        let (key, value) = match hash_key {
+            HashKey::Header(name) => ("header", name),
+            HashKey::Cookie(name) => ("cookie", name),
+            HashKey::JwtClaim(claim) => ("jwt_claim", claim),
+        };
+        let mut table = Table::new();
+        table.insert(key.into(), value.into());
+        app.insert("hash_key".into(), table.into());
+    }
+    if let Some(lb_cookie) = start.lb_cookie() {
+        let mut table = Table::new();
+        for (key, value) in [
+            ("name", lb_cookie.name),
+            ("path", lb_cookie.path),
+            ("domain", lb_cookie.domain),
+        ] {
+            if let Some(value) = value {
+                table.insert(key.into(), value.into());
+            }
+        }
+        if let Some(secure) = lb_cookie.secure {
+            table.insert("secure".into(), secure.into());
+        }
+        if let Some(same_site) = lb_cookie.same_site {
+            table.insert("same_site".into(), value_name(same_site).into());
+        }
+        if let Some(max_age) = lb_cookie.max_age {
+            table.insert("max_age".into(), (max_age as i64).into());
+        }
+        if lb_cookie.secret.is_some() {
+            table.insert("secret".into(), REDACTED.into());
+        }
+        app.insert("lb_cookie".into(), table.into());
+    }
+    app
+}
+
+/// Validates the configuration given through the command line, the
+/// environment and the configuration file, and returns the result as TOML.
+/// Secrets are redacted.
+pub fn check(args: &Args) -> FaucetResult<String> {
+    let mut effective = Table::new();
+
+    args.host.parse::<std::net::SocketAddr>()?;
+    effective.insert("host".into(), args.host.clone().into());
+    effective.insert("ip_from".into(), value_name(args.ip_from).into());
+    effective.insert("rscript".into(), path_value(Path::new(&args.rscript)));
+    effective.insert("quarto".into(), path_value(Path::new(&args.quarto)));
+    effective.insert("uv".into(), path_value(Path::new(&args.uv)));
+    if let Some(log_file) = &args.log_file {
+        effective.insert("log_file".into(), path_value(log_file));
+    }
+    if let Some(size) = args.max_log_file_size {
+        effective.insert("max_log_file_size".into(), (size as i64).into());
+    }
+    effective.insert("shutdown".into(), value_name(args.shutdown).into());
+    if let Some(size) = args.max_message_size {
+        effective.insert("max_message_size".into(), (size as i64).into());
+    }
+    if let Some(drain_timeout) = args.drain_timeout {
+        effective.insert("drain_timeout".into(), drain_timeout.into());
+    }
+
+    if args.pg_con_string.is_some() {
+        let mut telemetry = Table::new();
+        telemetry.insert("postgres_string".into(), REDACTED.into());
+        if let Some(cert) = &args.pg_sslcert {
+            telemetry.insert("postgres_sslcert".into(), path_value(cert));
+        }
+        telemetry.insert("postgres_sslmode".into(), args.pg_sslmode.as_str().into());
+        telemetry.insert("namespace".into(), args.telemetry_namespace.clone().into());
+        if let Some(version) = &args.telemetry_version {
+            telemetry.insert("version".into(), version.clone().into());
+        }
+        effective.insert("telemetry".into(), telemetry.into());
+    }
+    if let Some(tls) = args.tls() {
+        for (name, path) in [("certificate", &tls.cert), ("key", &tls.key)] {
+            if !path.is_file() {
+                return Err(FaucetError::InvalidConfig(format!(
+                    "TLS {name} {} does not exist",
+                    path.display()
+                )));
+            }
+        }
+        let mut table = Table::new();
+        table.insert("cert".into(), path_value(&tls.cert));
+        table.insert("key".into(), path_value(&tls.key));
+        effective.insert("tls".into(), table.into());
+    }
+    if let Some(admin) = args.admin()? {
+        let mut table = Table::new();
+        table.insert("host".into(), admin.host.to_string().into());
+        if admin.token.is_some() {
+            table.insert("token".into(), REDACTED.into());
+        }
+        effective.insert("admin".into(), table.into());
+    }
+    if let Some(metrics) = args.metrics()? {
+        let mut table = Table::new();
+        table.insert("host".into(), metrics.host.to_string().into());
+        effective.insert("metrics".into(), table.into());
+    }
+
+    let file = args
+        .config_file()?
+        .map(|path| Ok::<_, FaucetError>((ConfigFile::load(&path)?, path)))
+        .transpose()?;
+    let routes = match &file {
+        Some((config, path)) if config.has_routes() => Some(path),
+        _ => None,
+    };
+    match routes {
+        Some(path) => {
+            // Validated like `faucet router` does, printed as written.
+            RouterConfig::load(path)?;
+            let content = std::fs::read_to_string(path)?;
+            let table: Table = toml::from_str(&content)
+                .map_err(|e| FaucetError::InvalidConfig(format!("{}: {e}", path.display())))?;
+            if let Some(routes) = table.get("route") {
+                effective.insert("route".into(), routes.clone());
+            }
+        }
+        None => {
+            let command = match &file {
+                Some((config, path)) => config.apply(path, StartArgs::command()),
+                None => StartArgs::command(),
+            };
+            let mut start = command
+                .try_get_matches_from(["start"])
+                .and_then(|matches| StartArgs::from_arg_matches(&matches))
+                .map_err(|e| FaucetError::InvalidConfig(e.to_string()))?;
+            match &file {
+                Some((config, _)) => config.fill_start_args(&mut start)?,
+                None => start.merge_r_options(Vec::new())?,
+            }
+            effective.insert("app".into(), app_table(&start).into());
+        }
+    }
+
+    toml::to_string(&effective).map_err(|e| FaucetError::Unknown(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn options_map_to_environment_variables() {
+        let config = ConfigFile::parse(
+            r#"
+            host = "0.0.0.0:3838"
+            max_log_file_size = "10M"
+            max_message_size = 1024
+
+            [telemetry]
+            namespace = "apps"
+
+            [admin]
+            host = "127.0.0.1:3839"
+            token = "secret"
+
+            [app]
+            server_type = "shiny"
+            workers = 4
+            watch = true
+            hash_key = { cookie = "session" }
+
+            [app.health_check]
+            path = "/ready"
+
+            [app.recycle]
+            max_memory = "1KiB"
+            max_age = 86400
+
+            [app.queue]
+            max_concurrency = 1
+            timeout = 5
+
+            [app.lb_cookie]
+            secure = true
+            same_site = "Strict"
+            secret = "0123456789abcdef0123456789abcdef"
+
+            [app.r_options]
+            "shiny.maxRequestSize" = 31457280
+            "app.debug" = false
+            "app.flag" = "TRUE"
+            "app.sep" = "a;b=c"
+            "#,
+        )
+        .unwrap();
+        let vars = config.env_vars();
+        let get = |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("FAUCET_HOST"), Some("0.0.0.0:3838"));
+        assert_eq!(get("FAUCET_MAX_LOG_FILE_SIZE"), Some("10M"));
+        assert_eq!(get("FAUCET_MAX_MESSAGE_SIZE"), Some("1024"));
+        assert_eq!(get("FAUCET_TELEMETRY_NAMESPACE"), Some("apps"));
+        assert_eq!(get("FAUCET_ADMIN_TOKEN"), Some("secret"));
+        assert_eq!(get("FAUCET_TYPE"), Some("shiny"));
+        assert_eq!(get("FAUCET_WORKERS"), Some("4"));
+        assert_eq!(get("FAUCET_WATCH"), Some("true"));
+        assert_eq!(get("FAUCET_HEALTH_CHECK"), Some("true"));
+        assert_eq!(get("FAUCET_HEALTH_CHECK_PATH"), Some("/ready"));
+        assert_eq!(get("FAUCET_HEALTH_CHECK_INTERVAL"), Some("10"));
+        assert_eq!(get("FAUCET_RECYCLE_MAX_MEMORY"), Some("1024"));
+        assert_eq!(get("FAUCET_RECYCLE_MAX_AGE"), Some("86400"));
+        assert_eq!(get("FAUCET_RECYCLE_MAX_REQUESTS"), None);
+        assert_eq!(get("FAUCET_QUEUE_MAX_CONCURRENCY"), Some("1"));
+        assert_eq!(get("FAUCET_QUEUE_TIMEOUT"), Some("5"));
+        assert_eq!(get("FAUCET_QUEUE_MAX_LENGTH"), None);
+        assert_eq!(get("FAUCET_HASH_COOKIE"), Some("session"));
+        assert_eq!(get("FAUCET_HASH_HEADER"), None);
+        assert_eq!(get("FAUCET_LB_COOKIE_SECURE"), Some("true"));
+        assert_eq!(get("FAUCET_LB_COOKIE_SAME_SITE"), Some("strict"));
+        assert_eq!(
+            get("FAUCET_LB_COOKIE_SECRET"),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(get("FAUCET_LB_COOKIE_NAME"), None);
+        assert_eq!(get("FAUCET_R_OPTIONS"), None);
+        assert_eq!(
+            config.r_options(),
+            [
+                ("app.debug", ROptionValue::Bool(false)),
+                ("app.flag", ROptionValue::String("TRUE".to_string())),
+                ("app.sep", ROptionValue::String("a;b=c".to_string())),
+                ("shiny.maxRequestSize", ROptionValue::Integer(31457280)),
+            ]
+            .map(|(name, value)| ROption {
+                name: name.to_string(),
+                value,
+            })
+        );
+        assert_eq!(get("FAUCET_RSCRIPT"), None);
+        assert_eq!(get("FAUCET_TELEMETRY_POSTGRES_STRING"), None);
+    }
+
+    #[test]
+    fn options_become_defaults_of_the_arguments() {
+        use crate::cli::Commands;
+
+        let config = ConfigFile::parse(
+            r#"
+            host = "0.0.0.0:3838"
+
+            [admin]
+            host = "127.0.0.1:3839"
+            token = "secret"
+
+            [app]
+            workers = 4
+            watch = true
+            "#,
+        )
+        .unwrap();
+        let parse = |argv: &[&str]| {
+            let matches = config
+                .apply(Path::new("faucet.toml"), Args::command())
+                .try_get_matches_from(argv)
+                .unwrap();
+            Args::from_arg_matches(&matches).unwrap()
+        };
+
+        let args = parse(&["faucet", "start"]);
+        assert_eq!(args.host, "0.0.0.0:3838");
+        assert_eq!(args.admin_token.as_deref(), Some("secret"));
+        let Commands::Start(start) = &args.command else {
+            panic!("expected the start subcommand");
+        };
+        assert_eq!(start.workers, 4);
+        assert!(start.watch);
+        // Nothing reaches the environment the workers inherit.
+        assert_eq!(std::env::var_os("FAUCET_ADMIN_TOKEN"), None);
+
+        let args = parse(&["faucet", "--host", "127.0.0.1:8000", "start", "-w", "2"]);
+        assert_eq!(args.host, "127.0.0.1:8000");
+        let Commands::Start(start) = &args.command else {
+            panic!("expected the start subcommand");
+        };
+        assert_eq!(start.workers, 2);
+    }
+
+    #[test]
+    fn r_options_on_the_command_line_win_per_option() {
+        let config = ConfigFile::parse(
+            r#"
+            [app.r_options]
+            "plumber.docs" = false
+            "plumber.apiPath" = "/v1"
+            "#,
+        )
+        .unwrap();
+        let mut start =
+            StartArgs::try_parse_from(["start", "--r-option", "plumber.docs=TRUE"]).unwrap();
+        config.fill_start_args(&mut start).unwrap();
+        assert_eq!(
+            start.r_options,
+            [
+                ("plumber.apiPath", ROptionValue::String("/v1".to_string())),
+                ("plumber.docs", ROptionValue::Bool(true)),
+            ]
+            .map(|(name, value)| ROption {
+                name: name.to_string(),
+                value,
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        let error = ConfigFile::parse("hots = \"0.0.0.0:3838\"").err().unwrap();
+        assert!(error.contains("hots"), "{error}");
+        let error = ConfigFile::parse("[app]\nworker = 2").err().unwrap();
+        assert!(error.contains("worker"), "{error}");
+    }
+
+    #[test]
+    fn app_and_routes_are_exclusive() {
+        let config = ConfigFile::parse(
+            "[app]\nworkers = 1\n\n[[route]]\nroute = \"/\"\nworkers = 1\nserver_type = \"shiny\"\n",
+        );
+        assert!(config.is_err());
+
+        let config =
+            ConfigFile::parse("[[route]]\nroute = \"/\"\nworkers = 1\nserver_type = \"shiny\"\n")
+                .unwrap();
+        assert!(config.has_routes());
+    }
+}
@@ -88,6 +88,22 @@ pub enum FaucetError {
     WebSocketConnectionPurged,
     #[error("Attack attempt detected")]
     AttackAttempt,
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Service overloaded, {reason}")]
+    Overloaded {
+        reason: &'static str,
+        /// Seconds the client should wait before retrying.
+        retry_after: u64,
+    },
+}
+
+impl From<rustls::Error> for FaucetError {
+    fn from(value: rustls::Error) -> Self {
+        FaucetError::Tls(value.to_string())
+    }
 }
 
 impl From<tokio_tungstenite::tungstenite::Error> for FaucetError {
@@ -157,6 +173,11 @@ impl From<FaucetError> for hyper::Response<ExclusiveBody> {
     fn from(val: FaucetError) -> Self {
         let mut resp = hyper::Response::new(ExclusiveBody::plain_text(val.to_string()));
         *resp.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+        if let FaucetError::Overloaded { retry_after, .. } = val {
+            *resp.status_mut() = hyper::StatusCode::SERVICE_UNAVAILABLE;
+            resp.headers_mut()
+                .insert(hyper::header::RETRY_AFTER, retry_after.into());
+        }
         resp
     }
 }
@@ -270,4 +291,15 @@ mod tests {
         let resp: hyper::Response<ExclusiveBody> = err.into();
         assert_eq!(resp.status(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[test]
+    fn test_overloaded_to_hyper_response() {
+        let err = FaucetError::Overloaded {
+            reason: "the request queue is full",
+            retry_after: 5,
+        };
+        let resp: hyper::Response<ExclusiveBody> = err.into();
+        assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[hyper::header::RETRY_AFTER], "5");
+    }
 }
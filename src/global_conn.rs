@@ -1,17 +1,39 @@
-use std::sync::{atomic::AtomicI64, OnceLock};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::AtomicI64, LazyLock, Mutex, OnceLock},
+};
 
 pub static CORRENT_CONNECTIONS: OnceLock<AtomicI64> = OnceLock::new();
 
-pub fn add_connection() {
+// Connections per worker process, keyed by the address the process listens on.
+static WORKER_CONNECTIONS: LazyLock<Mutex<HashMap<SocketAddr, i64>>> =
+    LazyLock::new(Default::default);
+
+pub fn add_connection(worker_addr: SocketAddr) {
     CORRENT_CONNECTIONS
         .get_or_init(|| AtomicI64::new(0))
         .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    *WORKER_CONNECTIONS
+        .lock()
+        .expect("Connection count lock poisoned")
+        .entry(worker_addr)
+        .or_default() += 1;
 }
 
-pub fn remove_connection() {
+pub fn remove_connection(worker_addr: SocketAddr) {
     CORRENT_CONNECTIONS
         .get_or_init(|| unreachable!())
         .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    let mut worker_connections = WORKER_CONNECTIONS
+        .lock()
+        .expect("Connection count lock poisoned");
+    if let Some(count) = worker_connections.get_mut(&worker_addr) {
+        *count -= 1;
+        if *count <= 0 {
+            worker_connections.remove(&worker_addr);
+        }
+    }
 }
 
 pub fn current_connections() -> i64 {
@@ -19,3 +41,35 @@ pub fn current_connections() -> i64 {
         .get_or_init(|| AtomicI64::new(0))
         .load(std::sync::atomic::Ordering::SeqCst)
 }
+
+/// Open connections to the worker process listening on `worker_addr`.
+pub fn worker_connections(worker_addr: SocketAddr) -> i64 {
+    WORKER_CONNECTIONS
+        .lock()
+        .expect("Connection count lock poisoned")
+        .get(&worker_addr)
+        .copied()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_counted_per_worker() {
+        let a: SocketAddr = "127.0.0.1:9971".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:9972".parse().unwrap();
+        add_connection(a);
+        add_connection(a);
+        add_connection(b);
+        assert_eq!(worker_connections(a), 2);
+        assert_eq!(worker_connections(b), 1);
+        remove_connection(a);
+        remove_connection(b);
+        assert_eq!(worker_connections(a), 1);
+        assert_eq!(worker_connections(b), 0);
+        remove_connection(a);
+        assert_eq!(worker_connections(a), 0);
+    }
+}
@@ -1,11 +1,14 @@
 pub mod cli;
 pub mod client;
+pub mod config;
 pub mod error;
 pub mod global_conn;
 pub(crate) mod networking;
 pub mod server;
 pub mod shutdown;
 pub mod telemetry;
+#[cfg(test)]
+pub(crate) mod testing;
 
 #[macro_export]
 macro_rules! leak {
@@ -1,12 +1,13 @@
-use clap::Parser;
-use faucet_server::cli::{Args, Commands};
+use faucet_server::cli::{Args, Commands, ConfigCommands};
 use faucet_server::client::worker::log_stdio;
-use faucet_server::error::FaucetResult;
+use faucet_server::config;
+use faucet_server::error::{FaucetError, FaucetResult};
 use faucet_server::leak;
 use faucet_server::server::logger::build_logger;
-use faucet_server::server::{FaucetServerBuilder, RouterConfig};
+use faucet_server::server::{admin::request_rolling_restart, FaucetServerBuilder, RouterConfig};
 use faucet_server::telemetry::TelemetryManager;
 use faucet_server::{cli::Shutdown, shutdown};
+use std::time::Duration;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 
 #[tokio::main]
@@ -15,13 +16,22 @@ pub async fn main() -> FaucetResult<()> {
     dotenv::from_filename(".Renviron").ok();
     dotenv::from_filename(".env").ok();
 
-    let cli_args = Args::parse();
+    let cli_args = Args::parse_with_config_file()?;
+
+    if let Commands::Config(ConfigCommands::Check) = cli_args.command {
+        println!("{}", config::check(&cli_args)?);
+        return Ok(());
+    }
 
     let shutdown_signal = match cli_args.shutdown {
         Shutdown::Immediate => shutdown::immediate(),
         Shutdown::Graceful => shutdown::graceful(),
     };
 
+    let tls = cli_args.tls();
+    let admin = cli_args.admin()?;
+    let metrics = cli_args.metrics()?;
+
     let telemetry = cli_args.pg_con_string.map(|pg_con| {
         match TelemetryManager::start_postgres(
             &cli_args.telemetry_namespace,
@@ -64,22 +74,49 @@ pub async fn main() -> FaucetResult<()> {
                 .strategy(Some(start_args.strategy.into()))
                 .workers(start_args.workers)
                 .server_type(start_args.server_type())
+                .health_check(start_args.health_check())
+                .recycle(start_args.recycle())
+                .queue(start_args.queue())
+                .hash_key(start_args.hash_key())
+                .lb_cookie(start_args.lb_cookie())
                 .extractor(cli_args.ip_from.into())
                 .bind(cli_args.host.parse()?)
                 .workdir(start_args.dir)
                 .rscript(cli_args.rscript)
                 .uv(cli_args.uv)
                 .app_dir(start_args.app_dir)
+                .entrypoint(start_args.entrypoint)
+                .r_options(start_args.r_options)
                 .quarto(cli_args.quarto)
                 .qmd(start_args.qmd)
+                .static_dir(start_args.static_dir)
                 .max_rps(start_args.max_rps)
+                .watch(start_args.watch)
+                .tls(tls)
+                .admin(admin)
+                .metrics(metrics)
+                .drain_timeout(cli_args.drain_timeout.map(Duration::from_secs_f64))
                 .build()?
                 .run(shutdown_signal, websocket_config)
                 .await?;
         }
         Commands::Router(router_args) => {
-            let config: RouterConfig =
-                toml::from_str(&std::fs::read_to_string(router_args.conf).unwrap()).unwrap();
+            let mut config = RouterConfig::load(router_args.conf)?;
+
+            // TLS options given through the CLI or environment take precedence
+            // over the ones in the router config file.
+            if tls.is_some() {
+                config.tls = tls;
+            }
+            if admin.is_some() {
+                config.admin = admin;
+            }
+            if metrics.is_some() {
+                config.metrics = metrics;
+            }
+            if cli_args.drain_timeout.is_some() {
+                config.drain_timeout = cli_args.drain_timeout;
+            }
 
             config
                 .run(
@@ -93,6 +130,16 @@ pub async fn main() -> FaucetResult<()> {
                 )
                 .await?;
         }
+        Commands::RollingRestart { route } => {
+            let admin = admin.ok_or(FaucetError::MissingArgument("admin_host"))?;
+            let (status, body) = request_rolling_restart(&admin, route.as_deref()).await?;
+            println!("{body}");
+            shutdown_signal.shutdown();
+            if !status.is_success() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Config(_) => unreachable!("Handled before starting faucet"),
         Commands::Rscript { args } => {
             let child = tokio::process::Command::new(cli_args.rscript)
                 .args(args)
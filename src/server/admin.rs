@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    body::Incoming, client::conn::http1 as client_http1, header, server::conn::http1,
+    service::service_fn, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    client::{
+        websocket_sessions,
+        worker::{self, WorkerConfig},
+        ExclusiveBody,
+    },
+    error::FaucetResult,
+    shutdown::{self, ShutdownSignal},
+    telemetry::metrics,
+};
+
+/// Address and credentials of the admin API listener.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AdminConfig {
+    pub host: SocketAddr,
+    /// If set, every request must carry an `Authorization: Bearer <token>` header.
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    pub fn new(host: SocketAddr, token: Option<String>) -> Self {
+        AdminConfig { host, token }
+    }
+}
+
+/// Address of a listener that only serves Prometheus metrics, without
+/// authentication.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MetricsConfig {
+    pub host: SocketAddr,
+}
+
+/// The workers behind a single route. `route` is `None` outside of router mode.
+#[derive(Clone)]
+pub(crate) struct WorkerGroup {
+    pub route: Option<&'static str>,
+    pub workers: Box<[&'static WorkerConfig]>,
+}
+
+/// The worker groups served by the admin API. The router replaces them
+/// when its configuration is reloaded.
+#[derive(Clone)]
+pub(crate) struct WorkerGroups(Arc<RwLock<Arc<[WorkerGroup]>>>);
+
+impl WorkerGroups {
+    pub fn new(groups: Vec<WorkerGroup>) -> Self {
+        WorkerGroups(Arc::new(RwLock::new(groups.into())))
+    }
+    pub fn get(&self) -> Arc<[WorkerGroup]> {
+        self.0.read().expect("Worker groups lock poisoned").clone()
+    }
+    pub fn set(&self, groups: Vec<WorkerGroup>) {
+        *self.0.write().expect("Worker groups lock poisoned") = groups.into();
+    }
+}
+
+struct AdminState {
+    token: Option<String>,
+    groups: WorkerGroups,
+    shutdown: &'static ShutdownSignal,
+    /// Whether `POST /shutdown` already started a shutdown.
+    shutdown_requested: AtomicBool,
+    /// Only `/metrics` is served, used by the dedicated metrics listener.
+    metrics_only: bool,
+}
+
+fn json_response(status: StatusCode, value: serde_json::Value) -> Response<ExclusiveBody> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(ExclusiveBody::plain_text(value.to_string()))
+        .expect("Response should build")
+}
+
+fn error_response(status: StatusCode, message: impl AsRef<str>) -> Response<ExclusiveBody> {
+    json_response(status, json!({ "error": message.as_ref() }))
+}
+
+fn worker_json(worker: &WorkerConfig) -> serde_json::Value {
+    let pid = worker.pid.load(Ordering::SeqCst);
+    json!({
+        "id": worker.worker_id,
+        "target": worker.target,
+        "pid": (pid != 0).then_some(pid),
+        "port": worker.addr().port(),
+        "previous_port": worker.previous_addr().map(|addr| addr.port()),
+        "online": worker.is_online.load(Ordering::SeqCst),
+        "draining": worker.draining.load(Ordering::SeqCst),
+        "restarts": worker.restarts.load(Ordering::SeqCst),
+        "requests": worker.stats.requests(),
+        "in_flight": worker.in_flight.load(Ordering::SeqCst),
+        "memory_bytes": worker.stats.memory(),
+        "cpu": worker.stats.cpu(),
+    })
+}
+
+/// Compares the tokens without bailing out on the first different byte.
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn is_authorized<B>(req: &Request<B>, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(token, given.trim()))
+}
+
+fn query_param(req: &Request<Incoming>, name: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+impl AdminState {
+    fn find_worker(
+        &self,
+        id: &str,
+        route: Option<&str>,
+    ) -> Result<&'static WorkerConfig, (StatusCode, &'static str)> {
+        let id: usize = id
+            .parse()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid worker id"))?;
+        let groups = self.groups.get();
+        let group = match (route, &groups[..]) {
+            (None, [group]) => group,
+            (None, _) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "The `route` query parameter is required in router mode",
+                ))
+            }
+            (Some(route), groups) => groups
+                .iter()
+                .find(|group| group.route == Some(route))
+                .ok_or((StatusCode::NOT_FOUND, "Route not found"))?,
+        };
+        group
+            .workers
+            .iter()
+            .find(|worker| worker.worker_id == id)
+            .copied()
+            .ok_or((StatusCode::NOT_FOUND, "Worker not found"))
+    }
+
+    async fn workers(&self) -> Response<ExclusiveBody> {
+        let groups = self
+            .groups
+            .get()
+            .iter()
+            .map(|group| {
+                json!({
+                    "route": group.route,
+                    "workers": group.workers.iter().map(|w| worker_json(w)).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json_response(StatusCode::OK, json!({ "routes": groups }))
+    }
+
+    async fn websockets(&self) -> Response<ExclusiveBody> {
+        let targets: HashMap<SocketAddr, &WorkerConfig> = self
+            .groups
+            .get()
+            .iter()
+            .flat_map(|group| group.workers.iter())
+            .flat_map(|worker| {
+                std::iter::once(worker.addr())
+                    .chain(worker.previous_addr())
+                    .map(|addr| (addr, *worker))
+            })
+            .collect();
+        let sessions = websocket_sessions()
+            .await
+            .into_iter()
+            .map(|session| {
+                let worker = session.worker_addr.and_then(|addr| targets.get(&addr));
+                json!({
+                    "session_id": session.session_id,
+                    "route": worker.and_then(|w| w.worker_route),
+                    "worker": worker.map(|w| w.worker_id),
+                    "connected": session.connected,
+                    "purged": session.purged,
+                })
+            })
+            .collect::<Vec<_>>();
+        json_response(StatusCode::OK, json!({ "sessions": sessions }))
+    }
+
+    async fn metrics(&self) -> Response<ExclusiveBody> {
+        let workers = self
+            .groups
+            .get()
+            .iter()
+            .flat_map(|group| group.workers.iter().copied())
+            .collect::<Vec<_>>();
+        let sessions = websocket_sessions().await;
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(ExclusiveBody::plain_text(metrics::render(
+                &workers, &sessions,
+            )))
+            .expect("Response should build")
+    }
+
+    fn rolling_restart(&self, route: Option<&str>) -> Response<ExclusiveBody> {
+        let groups = self.groups.get();
+        let workers: Vec<&'static WorkerConfig> = match route {
+            None => groups
+                .iter()
+                .flat_map(|group| group.workers.iter().copied())
+                .collect(),
+            Some(route) => match groups.iter().find(|group| group.route == Some(route)) {
+                Some(group) => group.workers.to_vec(),
+                None => return error_response(StatusCode::NOT_FOUND, "Route not found"),
+            },
+        };
+        log::info!(target: "faucet", "Rolling restart of {} workers requested through the admin API", workers.len());
+        let targets = workers.iter().map(|w| w.target).collect::<Vec<_>>();
+        tokio::spawn(async move { worker::rolling_restart(&workers).await });
+        json_response(StatusCode::ACCEPTED, json!({ "rolling_restart": targets }))
+    }
+
+    async fn handle(&self, req: Request<Incoming>) -> Response<ExclusiveBody> {
+        if !is_authorized(&req, self.token.as_deref()) {
+            return error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+        }
+
+        let path = req.uri().path().trim_end_matches('/').to_string();
+        let segments: Vec<&str> = path.split('/').skip(1).collect();
+        let route = query_param(&req, "route");
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["metrics"]) => self.metrics().await,
+            (_, ["metrics"]) => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+            }
+            _ if self.metrics_only => error_response(StatusCode::NOT_FOUND, "Not found"),
+            (&Method::GET, ["workers"]) => self.workers().await,
+            (&Method::GET, ["websockets"]) => self.websockets().await,
+            (&Method::POST, ["workers", id, action @ ("restart" | "drain")]) => {
+                let worker = match self.find_worker(id, route.as_deref()) {
+                    Ok(worker) => worker,
+                    Err((status, message)) => return error_response(status, message),
+                };
+                if *action == "restart" {
+                    log::info!(target: "faucet", "Restart of {} requested through the admin API", worker.target);
+                    worker.restart.notify_one();
+                } else {
+                    log::info!(target: "faucet", "Drain of {} requested through the admin API", worker.target);
+                    worker.drain();
+                }
+                json_response(StatusCode::ACCEPTED, worker_json(worker))
+            }
+            (&Method::DELETE, ["workers", id, "drain"]) => {
+                let worker = match self.find_worker(id, route.as_deref()) {
+                    Ok(worker) => worker,
+                    Err((status, message)) => return error_response(status, message),
+                };
+                log::info!(target: "faucet", "End of the drain of {} requested through the admin API", worker.target);
+                worker.undrain().await;
+                json_response(StatusCode::OK, worker_json(worker))
+            }
+            (&Method::POST, ["rolling-restart"]) => self.rolling_restart(route.as_deref()),
+            (&Method::POST, ["shutdown"]) => {
+                // Repeated requests find the shutdown already under way.
+                if !self.shutdown_requested.swap(true, Ordering::SeqCst) {
+                    log::info!(target: "faucet", "Shutdown requested through the admin API");
+                    tokio::spawn(shutdown::drain_and_shutdown(self.shutdown));
+                }
+                json_response(StatusCode::ACCEPTED, json!({ "shutdown": "graceful" }))
+            }
+            (_, ["workers"] | ["websockets"] | ["rolling-restart"] | ["shutdown"])
+            | (_, ["workers", _, "restart" | "drain"]) => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+}
+
+fn serve(listener: TcpListener, state: AdminState) {
+    let shutdown = state.shutdown;
+    let state = Arc::new(state);
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = tokio::select! {
+                _ = shutdown.wait() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!(target: "faucet", "Unable to accept admin connection: {e}");
+                        continue;
+                    }
+                },
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                let conn = http1::Builder::new().serve_connection(
+                    TokioIo::new(tcp),
+                    service_fn(move |req| {
+                        let state = state.clone();
+                        async move { Ok::<_, hyper::Error>(state.handle(req).await) }
+                    }),
+                );
+                tokio::select! {
+                    result = conn => {
+                        if let Err(e) = result {
+                            log::debug!(target: "faucet", "Admin connection error: {e}");
+                        }
+                    }
+                    _ = shutdown.wait() => (),
+                }
+            });
+        }
+    });
+}
+
+/// Asks a running faucet to start a rolling restart through its admin API.
+/// Returns the status and body of the response.
+pub async fn request_rolling_restart(
+    config: &AdminConfig,
+    route: Option<&str>,
+) -> FaucetResult<(StatusCode, String)> {
+    let stream = TcpStream::connect(config.host).await?;
+    let (mut sender, conn) = client_http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            log::debug!(target: "faucet", "Admin connection error: {e}");
+        }
+    });
+    let uri = match route {
+        None => "/rolling-restart".to_string(),
+        Some(route) => format!(
+            "/rolling-restart?{}",
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("route", route)
+                .finish()
+        ),
+    };
+    let mut request = Request::post(uri).header(header::HOST, config.host.to_string());
+    if let Some(token) = &config.token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let response = sender
+        .send_request(request.body(Empty::<Bytes>::new())?)
+        .await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// Binds the admin listener and serves it in the background until shutdown.
+pub(crate) async fn spawn(
+    config: AdminConfig,
+    groups: WorkerGroups,
+    shutdown: &'static ShutdownSignal,
+) -> FaucetResult<()> {
+    let listener = TcpListener::bind(config.host).await?;
+    let addr = listener.local_addr()?;
+    if config.token.is_none() {
+        log::warn!(target: "faucet", "Admin API on {addr} has no token, anyone who can reach it can control faucet");
+    }
+    log::info!(target: "faucet", "Admin API listening on http://{addr}");
+    metrics::enable();
+    serve(
+        listener,
+        AdminState {
+            token: config.token,
+            groups,
+            shutdown,
+            shutdown_requested: AtomicBool::new(false),
+            metrics_only: false,
+        },
+    );
+    Ok(())
+}
+
+/// Binds a listener that only serves `/metrics` in the background until shutdown.
+pub(crate) async fn spawn_metrics(
+    config: MetricsConfig,
+    groups: WorkerGroups,
+    shutdown: &'static ShutdownSignal,
+) -> FaucetResult<()> {
+    let listener = TcpListener::bind(config.host).await?;
+    log::info!(target: "faucet", "Metrics available at http://{}/metrics", listener.local_addr()?);
+    metrics::enable();
+    serve(
+        listener,
+        AdminState {
+            token: None,
+            groups,
+            shutdown,
+            shutdown_requested: AtomicBool::new(false),
+            metrics_only: true,
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::leak;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use tokio::net::TcpStream;
+
+    fn group(route: Option<&'static str>) -> WorkerGroup {
+        let w1: &'static WorkerConfig =
+            leak!(WorkerConfig::dummy("Worker::1", "127.0.0.1:9991", true));
+        let mut w2 = WorkerConfig::dummy("Worker::2", "127.0.0.1:9992", false);
+        w2.worker_id = 2;
+        let w2: &'static WorkerConfig = leak!(w2);
+        WorkerGroup {
+            route,
+            workers: vec![w1, w2].into_boxed_slice(),
+        }
+    }
+
+    async fn start(groups: Vec<WorkerGroup>, token: Option<&str>) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let shutdown = leak!(ShutdownSignal::new());
+        spawn(
+            AdminConfig::new(addr, token.map(String::from)),
+            WorkerGroups::new(groups),
+            shutdown,
+        )
+        .await
+        .unwrap();
+        addr
+    }
+
+    async fn call(
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, serde_json::Value) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let mut req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, addr.to_string());
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let res = sender
+            .send_request(req.body(Empty::<Bytes>::new()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secret", "secreT"));
+        assert!(!token_matches("secret", "secret2"));
+    }
+
+    #[tokio::test]
+    async fn list_workers_requires_token() {
+        let addr = start(vec![group(None)], Some("secret")).await;
+
+        let (status, _) = call(addr, Method::GET, "/workers", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = call(addr, Method::GET, "/workers", Some("wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = call(addr, Method::GET, "/workers", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        let workers = &body["routes"][0]["workers"];
+        assert_eq!(workers.as_array().unwrap().len(), 2);
+        assert_eq!(workers[0]["id"], 1);
+        assert_eq!(workers[0]["port"], 9991);
+        assert_eq!(workers[0]["online"], true);
+        assert_eq!(workers[1]["online"], false);
+        assert_eq!(workers[0]["pid"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_requested_once() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let shutdown: &'static ShutdownSignal = leak!(ShutdownSignal::new());
+        spawn(
+            AdminConfig::new(addr, None),
+            WorkerGroups::new(vec![group(None)]),
+            shutdown,
+        )
+        .await
+        .unwrap();
+
+        // An open connection holds the shutdown back.
+        let worker_addr = "127.0.0.1:9979".parse().unwrap();
+        crate::global_conn::add_connection(worker_addr);
+        for _ in 0..2 {
+            let (status, body) = call(addr, Method::POST, "/shutdown", None).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(body["shutdown"], "graceful");
+        }
+        crate::global_conn::remove_connection(worker_addr);
+
+        tokio::time::timeout(std::time::Duration::from_secs(30), shutdown.wait())
+            .await
+            .expect("the shutdown completes once the connections close");
+    }
+
+    #[tokio::test]
+    async fn drain_worker_in_router_mode() {
+        let groups = vec![group(Some("/a/")), group(Some("/b/"))];
+        let drained = groups[1].workers[0];
+        let addr = start(groups, None).await;
+
+        let (status, _) = call(addr, Method::POST, "/workers/1/drain", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = call(addr, Method::POST, "/workers/1/drain?route=/c/", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = call(addr, Method::POST, "/workers/1/drain?route=%2Fb%2F", None).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["draining"], true);
+        assert!(drained.draining.load(Ordering::SeqCst));
+        assert!(!drained.is_online.load(Ordering::SeqCst));
+
+        let (_, body) = call(addr, Method::GET, "/workers", None).await;
+        assert_eq!(body["routes"][0]["route"], "/a/");
+        assert_eq!(body["routes"][0]["workers"][0]["draining"], false);
+        assert_eq!(body["routes"][1]["workers"][0]["draining"], true);
+    }
+
+    #[tokio::test]
+    async fn undrain_worker() {
+        // The worker's process accepts connections.
+        let process = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let worker: &'static WorkerConfig = leak!(WorkerConfig::dummy(
+            "Worker::1",
+            &process.local_addr().unwrap().to_string(),
+            true
+        ));
+        let groups = vec![WorkerGroup {
+            route: None,
+            workers: vec![worker].into_boxed_slice(),
+        }];
+        let addr = start(groups, None).await;
+
+        let (status, _) = call(addr, Method::POST, "/workers/1/drain", None).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(!worker.is_online.load(Ordering::SeqCst));
+
+        let (status, body) = call(addr, Method::DELETE, "/workers/1/drain", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["draining"], false);
+        assert_eq!(body["online"], true);
+        assert!(worker.is_online.load(Ordering::SeqCst));
+
+        // A process that is not ready is put back once it is.
+        drop(process);
+        worker.drain();
+        let (_, body) = call(addr, Method::DELETE, "/workers/1/drain", None).await;
+        assert_eq!(body["draining"], false);
+        assert_eq!(body["online"], false);
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_methods() {
+        let addr = start(vec![group(None)], None).await;
+
+        let (status, _) = call(addr, Method::GET, "/nope", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(addr, Method::GET, "/workers/1/restart", None).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let (status, _) = call(addr, Method::POST, "/workers/7/restart", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = call(addr, Method::GET, "/websockets", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["sessions"].is_array());
+    }
+
+    #[tokio::test]
+    async fn rolling_restart_through_client() {
+        let groups = vec![group(Some("/a/")), group(Some("/b/"))];
+        let addr = start(groups, Some("secret")).await;
+
+        let config = AdminConfig::new(addr, None);
+        let (status, _) = request_rolling_restart(&config, None).await.unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let config = AdminConfig::new(addr, Some("secret".into()));
+        let (status, body) = request_rolling_restart(&config, Some("/b/")).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["rolling_restart"], json!(["Worker::1", "Worker::2"]));
+
+        let (status, _) = request_rolling_restart(&config, Some("/c/")).await.unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,386 @@
+//! Switching cleartext HTTP/1.1 connections to HTTP/2 with `Upgrade: h2c`
+//! (RFC 7540, section 3.2).
+//!
+//! hyper only serves HTTP/2 from the start of a connection. After the
+//! `101 Switching Protocols`, the request that asked for the upgrade is
+//! handed to the HTTP/2 connection as stream 1, as if the client had sent it
+//! right after its connection preface.
+
+use super::connection_builder;
+use crate::{
+    client::{ExclusiveBody, UpgradeStatus},
+    error::FaucetResult,
+    shutdown::ShutdownSignal,
+};
+use hyper::{
+    body::{Body, Incoming},
+    header::{HeaderValue, CONNECTION, HOST, TE, UPGRADE},
+    service::service_fn,
+    Request, Response, StatusCode, Version,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+/// Largest frame a peer accepts until it says otherwise.
+const MAX_FRAME_SIZE: usize = 16_384;
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+/// Headers of the HTTP/1.1 connection that HTTP/2 does not allow.
+const CONNECTION_HEADERS: [&str; 7] = [
+    "connection",
+    "upgrade",
+    "http2-settings",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+];
+
+/// Whether `req` asks to switch to HTTP/2 and can: it comes over HTTP/1.1,
+/// carries its settings and has no body to read first.
+fn asks_for_h2c(req: &Request<Incoming>) -> bool {
+    let upgrade = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|upgrade| {
+            upgrade
+                .split(',')
+                .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"))
+        });
+    req.version() == Version::HTTP_11
+        && upgrade
+        && req.headers().get_all("http2-settings").iter().count() == 1
+        && req.body().is_end_stream()
+}
+
+/// Appends `value` as an HPACK integer with a `prefix` bit prefix, after
+/// the `flags` in the first byte.
+fn integer(out: &mut Vec<u8>, prefix: u32, flags: u8, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends a header as an HPACK literal without indexing, so that the
+/// block needs no shared state with the connection's decoder.
+fn literal(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    out.push(0);
+    integer(out, 7, 0, name.len());
+    out.extend_from_slice(name);
+    integer(out, 7, 0, value.len());
+    out.extend_from_slice(value);
+}
+
+/// The headers of `req` as an HTTP/2 header block, `None` if they do not
+/// fit in a single frame.
+fn header_block(req: &Request<Incoming>) -> Option<Vec<u8>> {
+    let headers = req.headers();
+    // Headers named in `Connection` are for this connection only as well.
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let authority = headers
+        .get(HOST)
+        .map(HeaderValue::as_bytes)
+        .or_else(|| req.uri().authority().map(|a| a.as_str().as_bytes()));
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+
+    let mut block = Vec::new();
+    literal(&mut block, b":method", req.method().as_str().as_bytes());
+    literal(&mut block, b":scheme", b"http");
+    if let Some(authority) = authority {
+        literal(&mut block, b":authority", authority);
+    }
+    literal(&mut block, b":path", path.as_bytes());
+    for (name, value) in headers {
+        let name = name.as_str();
+        if CONNECTION_HEADERS.contains(&name)
+            || listed.iter().any(|listed| listed == name)
+            || (name == TE && value != "trailers")
+        {
+            continue;
+        }
+        literal(&mut block, name.as_bytes(), value.as_bytes());
+    }
+    (block.len() <= MAX_FRAME_SIZE).then_some(block)
+}
+
+fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads the client's connection preface and the `SETTINGS` frame that
+/// must follow it.
+async fn read_preface(io: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP/2 preface");
+    let mut preface = vec![0; PREFACE.len() + FRAME_HEADER_LEN];
+    io.read_exact(&mut preface).await?;
+    let (start, header) = preface.split_at(PREFACE.len());
+    if start != PREFACE || header[3] != SETTINGS {
+        return Err(invalid());
+    }
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid());
+    }
+    let start = preface.len();
+    preface.resize(start + len, 0);
+    io.read_exact(&mut preface[start..]).await?;
+    Ok(preface)
+}
+
+/// `io`, with `prefix` read before anything that comes from it.
+struct Prefixed<T> {
+    prefix: Vec<u8>,
+    read: usize,
+    io: T,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Prefixed<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.read);
+            buf.put_slice(&this.prefix[this.read..this.read + n]);
+            this.read += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Prefixed<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// If `req` asks for `Upgrade: h2c`, answers it with `101 Switching
+/// Protocols` and serves the rest of the connection, `req` included, as
+/// HTTP/2 with `handle`. Otherwise gives `req` back to be served over
+/// HTTP/1.1, where the upgrade is declined.
+fn upgrade<F, Fut>(
+    mut req: Request<Incoming>,
+    handle: F,
+    shutdown: &'static ShutdownSignal,
+) -> UpgradeStatus<Incoming>
+where
+    F: Fn(Request<Incoming>) -> Fut + Send + 'static,
+    Fut: Future<Output = FaucetResult<Response<ExclusiveBody>>> + Send + 'static,
+{
+    if !asks_for_h2c(&req) {
+        return UpgradeStatus::NotUpgraded(req);
+    }
+    let Some(block) = header_block(&req) else {
+        return UpgradeStatus::NotUpgraded(req);
+    };
+    let first_stream = frame(HEADERS, END_STREAM | END_HEADERS, 1, &block);
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        let mut io = match on_upgrade.await {
+            Ok(upgraded) => TokioIo::new(upgraded),
+            Err(e) => {
+                log::debug!(target: "faucet", "h2c upgrade failed: {e}");
+                return;
+            }
+        };
+        let mut prefix = match read_preface(&mut io).await {
+            Ok(preface) => preface,
+            Err(e) => {
+                log::debug!(target: "faucet", "h2c upgrade failed: {e}");
+                return;
+            }
+        };
+        prefix.extend_from_slice(&first_stream);
+        let io = TokioIo::new(Prefixed {
+            prefix,
+            read: 0,
+            io,
+        });
+        let builder = connection_builder().http2_only();
+        let conn = builder.serve_connection(io, service_fn(handle));
+        tokio::select! {
+            result = conn => {
+                if let Err(e) = result {
+                    log::error!(target: "faucet", "Connection error: {e:?}");
+                }
+            }
+            _ = shutdown.wait() => ()
+        }
+    });
+    let mut res = Response::new(ExclusiveBody::empty());
+    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    res.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    res.headers_mut()
+        .insert(UPGRADE, HeaderValue::from_static("h2c"));
+    UpgradeStatus::Upgraded(res)
+}
+
+/// Serves `req` with `handle`, after switching the connection to HTTP/2 if
+/// `req` asks for it on a `cleartext` connection. TLS connections negotiate
+/// HTTP/2 with ALPN instead.
+pub(crate) async fn serve<F, Fut>(
+    req: Request<Incoming>,
+    cleartext: bool,
+    handle: F,
+    shutdown: &'static ShutdownSignal,
+) -> FaucetResult<Response<ExclusiveBody>>
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = FaucetResult<Response<ExclusiveBody>>> + Send + 'static,
+{
+    let req = match cleartext {
+        true => match upgrade(req, handle.clone(), shutdown) {
+            UpgradeStatus::Upgraded(switching) => return Ok(switching),
+            UpgradeStatus::NotUpgraded(req) => req,
+        },
+        false => req,
+    };
+    handle(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hpack_integers() {
+        // RFC 7541, appendix C.1
+        let mut out = Vec::new();
+        integer(&mut out, 5, 0, 10);
+        assert_eq!(out, [0b01010]);
+        let mut out = Vec::new();
+        integer(&mut out, 5, 0, 1337);
+        assert_eq!(out, [0b11111, 0b10011010, 0b00001010]);
+        let mut out = Vec::new();
+        integer(&mut out, 7, 0, 127);
+        assert_eq!(out, [0x7f, 0]);
+    }
+
+    #[tokio::test]
+    async fn preface_is_read_before_the_connection() {
+        let settings = frame(SETTINGS, 0, 0, &[0, 3, 0, 0, 0, 100]);
+        let mut client = [PREFACE, &settings, b"rest"].concat();
+        let preface = read_preface(&mut client.as_slice()).await.unwrap();
+        assert_eq!(preface, [PREFACE, &settings].concat());
+
+        let mut io = Prefixed {
+            prefix: b"first ".to_vec(),
+            read: 0,
+            io: &b"second"[..],
+        };
+        let mut read = String::new();
+        io.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "first second");
+
+        client[0] = b'X';
+        assert!(read_preface(&mut client.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upgraded_request_is_answered_over_http2() {
+        use tokio::io::AsyncWriteExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown: &'static ShutdownSignal = crate::leak!(ShutdownSignal::new());
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let handle = |req: Request<Incoming>| async move {
+                let body = format!("{:?} {}", req.version(), req.uri().path());
+                Ok(Response::new(ExclusiveBody::plain_text(body)))
+            };
+            connection_builder()
+                .serve_connection_with_upgrades(
+                    TokioIo::new(tcp),
+                    service_fn(move |req| serve(req, true, handle, shutdown)),
+                )
+                .await
+                .unwrap();
+        });
+
+        let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tcp.write_all(
+            b"GET /upgraded HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(tcp.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+        tcp.write_all(&[PREFACE, &frame(SETTINGS, 0, 0, &[])].concat())
+            .await
+            .unwrap();
+        // Frames until the body of stream 1.
+        loop {
+            let mut header = [0; FRAME_HEADER_LEN];
+            tcp.read_exact(&mut header).await.unwrap();
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let mut payload = vec![0; len];
+            tcp.read_exact(&mut payload).await.unwrap();
+            let stream = u32::from_be_bytes(header[5..].try_into().unwrap());
+            if header[3] == 0x0 && stream == 1 {
+                assert_eq!(payload, b"HTTP/2.0 /upgraded");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! The cookie that keeps clients on their worker with the cookie hash
+//! strategy. Its value is a UUID signed with an HMAC, so that clients
+//! cannot forge a cookie that sends them to a worker of their choice.
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use hyper::header::HeaderValue;
+use rand::Rng;
+use sha2::Sha256;
+
+use crate::error::{FaucetError, FaucetResult};
+
+const DEFAULT_NAME: &str = "FAUCET_LB_COOKIE";
+const MIN_SECRET_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    #[serde(alias = "Strict")]
+    Strict,
+    #[default]
+    #[serde(alias = "Lax")]
+    Lax,
+    #[serde(alias = "None")]
+    None,
+}
+
+impl From<SameSite> for cookie::SameSite {
+    fn from(value: SameSite) -> Self {
+        match value {
+            SameSite::Strict => cookie::SameSite::Strict,
+            SameSite::Lax => cookie::SameSite::Lax,
+            SameSite::None => cookie::SameSite::None,
+        }
+    }
+}
+
+/// User facing cookie configuration, from the CLI or from a
+/// `[route.lb_cookie]` table in `frouter.toml`.
+#[derive(Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LbCookieConfig {
+    pub name: Option<String>,
+    /// Defaults to the prefix of the route.
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub secure: Option<bool>,
+    pub same_site: Option<SameSite>,
+    /// Seconds the cookie is kept. Not set means until the browser closes.
+    pub max_age: Option<u64>,
+    /// Key the cookie is signed with. Not set means a random key, so
+    /// cookies do not survive a restart.
+    pub secret: Option<String>,
+}
+
+// The secret is kept out of the logs.
+impl std::fmt::Debug for LbCookieConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LbCookieConfig")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .field("domain", &self.domain)
+            .field("secure", &self.secure)
+            .field("same_site", &self.same_site)
+            .field("max_age", &self.max_age)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Characters allowed in a cookie name (RFC 6265, section 4.1.1).
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+/// Characters allowed in a `Path` or `Domain` attribute.
+fn is_attribute_value(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_graphic() && b != b';')
+}
+
+impl LbCookieConfig {
+    /// `default_path` is used unless `path` is set.
+    pub(crate) fn resolve(&self, default_path: &str) -> FaucetResult<LbCookie> {
+        let invalid = |message: &str| Err(FaucetError::InvalidConfig(message.to_string()));
+        let name = self.name.as_deref().unwrap_or(DEFAULT_NAME);
+        if !is_token(name) {
+            return invalid("lb_cookie name must be a valid cookie name");
+        }
+        let path = self.path.as_deref().unwrap_or(default_path);
+        if !path.starts_with('/') || !is_attribute_value(path) {
+            return invalid("lb_cookie path must start with `/`");
+        }
+        if let Some(domain) = &self.domain {
+            if !is_attribute_value(domain) {
+                return invalid("lb_cookie domain must be a valid domain");
+            }
+        }
+        let secure = self.secure.unwrap_or(false);
+        let same_site = self.same_site.unwrap_or_default();
+        if same_site == SameSite::None && !secure {
+            return invalid("lb_cookie same_site = \"none\" requires secure = true");
+        }
+        let key = match &self.secret {
+            Some(secret) if secret.len() < MIN_SECRET_LEN => {
+                return invalid("lb_cookie secret must be at least 32 bytes long")
+            }
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => rand::rng().random::<[u8; MIN_SECRET_LEN]>().to_vec(),
+        };
+        let mac = HmacSha256::new_from_slice(&key).expect("HMAC takes keys of any size");
+        Ok(LbCookie {
+            name: name.to_string(),
+            path: path.to_string(),
+            domain: self.domain.clone(),
+            secure,
+            same_site,
+            max_age: self.max_age,
+            mac,
+        })
+    }
+}
+
+/// The resolved cookie of a route, with the key it is signed with.
+#[derive(Clone)]
+pub struct LbCookie {
+    name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    same_site: SameSite,
+    max_age: Option<u64>,
+    mac: HmacSha256,
+}
+
+impl LbCookie {
+    fn signature(&self, uuid: uuid::Uuid) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        mac.update(uuid.as_bytes());
+        mac
+    }
+
+    fn sign(&self, uuid: uuid::Uuid) -> String {
+        let signature = self.signature(uuid).finalize().into_bytes();
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature);
+        format!("{uuid}.{signature}")
+    }
+
+    /// The UUID in `value`, if it was signed with this cookie's key.
+    fn verify(&self, value: &str) -> Option<uuid::Uuid> {
+        let (uuid, signature) = value.split_once('.')?;
+        let uuid = uuid.parse::<uuid::Uuid>().ok()?;
+        let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(signature)
+            .ok()?;
+        self.signature(uuid).verify_slice(&signature).ok()?;
+        Some(uuid)
+    }
+
+    /// The UUID of the cookie in `req`. Missing, malformed and forged
+    /// cookies are ignored, the client gets a new one.
+    pub(crate) fn extract<B>(&self, req: &hyper::Request<B>) -> Option<uuid::Uuid> {
+        // HTTP/2 clients may split cookies over several `cookie` headers
+        // (RFC 9113, section 8.2.3), so we look at all of them.
+        for cookie_header in req.headers().get_all(hyper::header::COOKIE) {
+            let Ok(cookie_str) = cookie_header.to_str() else {
+                continue;
+            };
+            for cookie in cookie::Cookie::split_parse(cookie_str) {
+                match cookie {
+                    Err(e) => {
+                        log::error!(target: "faucet", "Error parsing cookie: {e}");
+                        continue;
+                    }
+                    Ok(cookie) if cookie.name() == self.name => {
+                        let uuid = self.verify(cookie.value());
+                        if uuid.is_none() {
+                            log::debug!(target: "faucet", "Ignoring {} cookie with an invalid signature", self.name);
+                        }
+                        return uuid;
+                    }
+                    Ok(_) => (),
+                }
+            }
+        }
+        None
+    }
+
+    /// The `Set-Cookie` header giving `uuid` to the client.
+    pub(crate) fn header_value(&self, uuid: uuid::Uuid) -> HeaderValue {
+        let mut cookie = cookie::Cookie::build((self.name.as_str(), self.sign(uuid)))
+            .path(self.path.as_str())
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site.into());
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.as_str());
+        }
+        if let Some(max_age) = self.max_age {
+            cookie = cookie.max_age(cookie::time::Duration::seconds(
+                max_age.try_into().unwrap_or(i64::MAX),
+            ));
+        }
+        HeaderValue::from_str(&cookie.to_string())
+            .expect("Cookie attributes are validated, this is a bug! Report it please!")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: &str) -> hyper::Request<()> {
+        hyper::Request::builder()
+            .header("cookie", "theme=dark")
+            .header("cookie", cookie)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn default_cookie() {
+        let cookie = LbCookieConfig::default().resolve("/app/").unwrap();
+        let uuid = uuid::Uuid::now_v7();
+        let header = cookie.header_value(uuid);
+        let header = header.to_str().unwrap();
+        assert!(
+            header.starts_with(&format!("FAUCET_LB_COOKIE={uuid}.")),
+            "{header}"
+        );
+        assert!(header.contains("; HttpOnly"), "{header}");
+        assert!(header.contains("; SameSite=Lax"), "{header}");
+        assert!(header.contains("; Path=/app/"), "{header}");
+        assert!(!header.contains("Secure"), "{header}");
+        assert!(!header.contains("Max-Age"), "{header}");
+    }
+
+    #[test]
+    fn configured_cookie() {
+        let config: LbCookieConfig = toml::from_str(
+            r#"
+            name = "lb"
+            path = "/"
+            domain = "example.com"
+            secure = true
+            same_site = "strict"
+            max_age = 3600
+            "#,
+        )
+        .unwrap();
+        let cookie = config.resolve("/app/").unwrap();
+        let header = cookie.header_value(uuid::Uuid::now_v7());
+        let header = header.to_str().unwrap();
+        assert!(header.starts_with("lb="), "{header}");
+        for attribute in [
+            "; Path=/;",
+            "; Domain=example.com",
+            "; Secure",
+            "; SameSite=Strict",
+            "; Max-Age=3600",
+        ] {
+            assert!(header.contains(attribute), "{attribute} in {header}");
+        }
+    }
+
+    #[test]
+    fn invalid_cookies() {
+        for source in [
+            "name = \"a b\"",
+            "path = \"app\"",
+            "domain = \"a;b\"",
+            "same_site = \"none\"",
+            "secret = \"short\"",
+        ] {
+            let config: LbCookieConfig = toml::from_str(source).unwrap();
+            assert!(config.resolve("/").is_err(), "{source}");
+        }
+        assert!(toml::from_str::<LbCookieConfig>("same_site = \"loose\"").is_err());
+        assert!(toml::from_str::<LbCookieConfig>("naem = \"lb\"").is_err());
+    }
+
+    #[test]
+    fn only_signed_cookies_are_accepted() {
+        let config = LbCookieConfig {
+            secret: Some("0123456789abcdef0123456789abcdef".to_string()),
+            ..Default::default()
+        };
+        let cookie = config.resolve("/").unwrap();
+        let uuid = uuid::Uuid::now_v7();
+        let signed = cookie.sign(uuid);
+        assert_eq!(
+            cookie.extract(&request(&format!("FAUCET_LB_COOKIE={signed}"))),
+            Some(uuid)
+        );
+
+        // The same secret signs the same way, after a restart or on
+        // another instance.
+        let other = config.resolve("/").unwrap();
+        assert_eq!(other.verify(&signed), Some(uuid));
+
+        // A random key does not accept the cookie.
+        let random = LbCookieConfig::default().resolve("/").unwrap();
+        assert_eq!(random.verify(&signed), None);
+
+        let forged = format!("FAUCET_LB_COOKIE={}", cookie.sign(uuid::Uuid::nil()));
+        let forged = forged.replace(
+            &uuid::Uuid::nil().to_string(),
+            &uuid::Uuid::max().to_string(),
+        );
+        for value in [
+            forged.as_str(),
+            &format!("FAUCET_LB_COOKIE={uuid}"),
+            "FAUCET_LB_COOKIE=not-a-uuid.abc",
+            "other=1",
+        ] {
+            assert_eq!(cookie.extract(&request(value)), None, "{value}");
+        }
+    }
+}
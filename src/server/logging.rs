@@ -3,7 +3,10 @@ use serde::de::DeserializeOwned;
 use uuid::Uuid;
 
 use super::onion::{Layer, Service};
-use crate::{server::service::State, telemetry::send_http_event};
+use crate::{
+    server::service::{Rejected, State},
+    telemetry::{metrics, send_http_event},
+};
 use std::{net::IpAddr, time};
 
 pub mod logger {
@@ -156,6 +159,20 @@ impl StateLogData for State {
     }
 }
 
+/// Rejected requests did not reach a worker, they are logged as worker `0`.
+impl StateLogData for Rejected {
+    #[inline(always)]
+    fn get_state_data(&self) -> StateData {
+        StateData {
+            uuid: self.uuid,
+            ip: self.remote_addr,
+            worker_id: 0,
+            worker_route: self.route,
+            target: "faucet",
+        }
+    }
+}
+
 #[derive(PartialEq, Eq)]
 pub enum LogOption<T> {
     None,
@@ -223,15 +240,21 @@ impl HttpLogData {
 }
 
 #[inline(always)]
-async fn capture_log_data<Body, ResBody, Error, State: StateLogData>(
+async fn capture_log_data<Body, ResBody, Error, S: StateLogData, R: StateLogData>(
     inner: &impl Service<Request<Body>, Response = Response<ResBody>, Error = Error>,
     req: Request<Body>,
 ) -> Result<(Response<ResBody>, HttpLogData), Error> {
     let start = time::Instant::now();
 
     // Extract request info for logging
-    let state = req.extensions().get::<State>().expect("State not found");
-    let state_data = state.get_state_data();
+    let state_data = match req.extensions().get::<S>() {
+        Some(state) => state.get_state_data(),
+        None => req
+            .extensions()
+            .get::<R>()
+            .expect("State not found")
+            .get_state_data(),
+    };
     let method = req.method().clone();
     let path = req.uri().clone();
     let version = req.version();
@@ -274,9 +297,11 @@ where
         req: Request<Body>,
         _: Option<IpAddr>,
     ) -> Result<Self::Response, Self::Error> {
-        let (res, log_data) = capture_log_data::<_, _, _, State>(&self.inner, req).await?;
+        let (res, log_data) =
+            capture_log_data::<_, _, _, State, Rejected>(&self.inner, req).await?;
 
         log_data.log();
+        metrics::record_http_request(&log_data);
         send_http_event(log_data);
 
         Ok(res)
@@ -414,6 +439,20 @@ mod tests {
             }
         }
 
+        struct MockRejected;
+
+        impl StateLogData for MockRejected {
+            fn get_state_data(&self) -> StateData {
+                StateData {
+                    uuid: uuid::Uuid::now_v7(),
+                    ip: IpAddr::V4([127, 0, 0, 1].into()),
+                    target: "rejected",
+                    worker_id: 0,
+                    worker_route: None,
+                }
+            }
+        }
+
         struct Svc;
 
         impl Service<Request<()>> for Svc {
@@ -438,7 +477,7 @@ mod tests {
             .body(())
             .unwrap();
 
-        let (_, log_data) = capture_log_data::<_, _, _, MockState>(&Svc, req)
+        let (_, log_data) = capture_log_data::<_, _, _, MockState, MockRejected>(&Svc, req)
             .await
             .unwrap();
 
@@ -455,6 +494,46 @@ mod tests {
         assert_eq!(log_data.state_data.target, "test");
     }
 
+    #[tokio::test]
+    async fn rejections_are_logged_without_a_worker() {
+        struct Svc;
+
+        impl Service<Request<()>> for Svc {
+            type Response = Response<()>;
+            type Error = ();
+            async fn call(
+                &self,
+                _: Request<()>,
+                _: Option<IpAddr>,
+            ) -> Result<Self::Response, Self::Error> {
+                Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(())
+                    .unwrap())
+            }
+        }
+
+        let req = Request::builder()
+            .uri("/report")
+            .extension(Rejected {
+                uuid: uuid::Uuid::now_v7(),
+                remote_addr: IpAddr::V4([127, 0, 0, 1].into()),
+                route: Some("/app/"),
+                reason: "the request queue is full",
+                retry_after: 1,
+            })
+            .body(())
+            .unwrap();
+
+        let (_, log_data) = capture_log_data::<_, _, _, State, Rejected>(&Svc, req)
+            .await
+            .unwrap();
+        assert_eq!(log_data.status, 503);
+        assert_eq!(log_data.state_data.worker_id, 0);
+        assert_eq!(log_data.state_data.worker_route, Some("/app/"));
+        assert_eq!(log_data.state_data.target, "faucet");
+    }
+
     #[test]
     fn log_option_display() {
         assert_eq!(LogOption::<u8>::None.to_string(), "-");
@@ -1,34 +1,52 @@
+pub mod admin;
+mod h2c;
 pub mod logging;
 pub use logging::{logger, HttpLogData, LogOption};
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+pub mod lb_cookie;
 pub mod onion;
 mod router;
 mod service;
+mod static_files;
+pub mod tls;
 use crate::{
     client::{
-        load_balancing::{self, LoadBalancer, Strategy},
-        worker::{WorkerConfigs, WorkerType},
-        ExclusiveBody,
+        load_balancing::{self, HashKey, LoadBalancer, Strategy},
+        spawn_watcher,
+        worker::{self, WorkerConfigs, WorkerType},
+        CommandConfig, ExclusiveBody, HealthCheck, HealthCheckConfig, LimitsConfig, ProcessLimits,
+        QueueConfig, QueuePolicy, ROption, RecycleConfig, RecyclePolicy, WorkerCommand, WorkerEnv,
     },
     error::{FaucetError, FaucetResult},
     leak,
     shutdown::ShutdownSignal,
 };
-use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
-use hyper_util::rt::TokioIo;
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use lb_cookie::LbCookie;
 use onion::{Service, ServiceBuilder};
 use service::{AddStateLayer, ProxyService};
+use static_files::{StaticFiles, StaticLayer, StaticService};
 use std::{
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
     net::SocketAddr,
     num::NonZeroUsize,
     path::{Path, PathBuf},
     pin::pin,
     sync::Arc,
+    time::Duration,
 };
+use tls::FaucetStream;
 use tokio::net::TcpListener;
 
+pub use admin::{AdminConfig, MetricsConfig};
+pub use lb_cookie::{LbCookieConfig, SameSite};
 pub use router::RouterConfig;
+pub use tls::TlsConfig;
 
 use self::{logging::LogService, service::AddStateService};
 
@@ -38,26 +56,47 @@ fn determine_strategy(server_type: WorkerType, strategy: Option<Strategy>) -> St
             log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to round robin for FastAPI.");
             Strategy::RoundRobin
         }),
-        WorkerType::Plumber =>
+        WorkerType::Plumber | WorkerType::Plumber2 | WorkerType::Ambiorix =>
             strategy.unwrap_or_else(|| {
-                log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to round robin for plumber.");
+                log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to round robin for {server_type:?}.");
                 Strategy::RoundRobin
             }),
-        WorkerType::Shiny | WorkerType::QuartoShiny => match strategy {
+        WorkerType::Dash => strategy.unwrap_or_else(|| {
+            log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to round robin for Dash.");
+            Strategy::RoundRobin
+        }),
+        // Streamlit keeps each session's state in the process holding its WebSocket.
+        WorkerType::Shiny | WorkerType::QuartoShiny | WorkerType::Streamlit => match strategy {
             None => {
-                log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to Cookie Hash for shiny.");
+                log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to Cookie Hash for {server_type:?}.");
                 Strategy::CookieHash
             },
             Some(Strategy::Rps) => {
-                log::debug!(target: "faucet", "RPS load balancing strategy specified for shiny, switching to IP hash.");
+                log::debug!(target: "faucet", "RPS load balancing strategy specified for {server_type:?}, switching to IP hash.");
                 Strategy::IpHash
             },
             Some(Strategy::CookieHash) => Strategy::CookieHash,
-            Some(Strategy::RoundRobin) => {
-                log::debug!(target: "faucet", "Round robin load balancing strategy specified for shiny, switching to IP hash.");
+            Some(strategy @ (Strategy::RoundRobin | Strategy::LeastConnections | Strategy::PowerOfTwo)) => {
+                log::debug!(target: "faucet", "{strategy:?} load balancing strategy specified for {server_type:?}, switching to IP hash.");
                 Strategy::IpHash
             },
             Some(Strategy::IpHash) => Strategy::IpHash,
+            Some(Strategy::HeaderHash) => Strategy::HeaderHash,
+        },
+        WorkerType::Command => strategy.unwrap_or_else(|| {
+            log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to round robin for commands.");
+            Strategy::RoundRobin
+        }),
+        WorkerType::Upstream => match strategy {
+            None => {
+                log::debug!(target: "faucet", "No load balancing strategy specified. Defaulting to round robin for upstreams.");
+                Strategy::RoundRobin
+            },
+            Some(Strategy::Rps) => {
+                log::debug!(target: "faucet", "RPS load balancing strategy specified for upstreams, switching to round robin.");
+                Strategy::RoundRobin
+            },
+            Some(strategy) => strategy,
         },
         #[cfg(test)]
         WorkerType::Dummy => {
@@ -67,6 +106,16 @@ fn determine_strategy(server_type: WorkerType, strategy: Option<Strategy>) -> St
     }
 }
 
+/// Builds the connection handler for client facing listeners. Connections
+/// are served as HTTP/1.1 or HTTP/2 depending on what the client speaks
+/// (ALPN on TLS, the connection preface on cleartext).
+pub(crate) fn connection_builder() -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    // Allows WebSockets over HTTP/2 (RFC 8441)
+    builder.http2().enable_connect_protocol();
+    builder
+}
+
 pub struct FaucetServerBuilder {
     strategy: Option<Strategy>,
     bind: Option<SocketAddr>,
@@ -77,10 +126,30 @@ pub struct FaucetServerBuilder {
     rscript: Option<OsString>,
     uv: Option<OsString>,
     app_dir: Option<String>,
+    entrypoint: Option<String>,
+    r_options: Vec<ROption>,
     quarto: Option<OsString>,
     qmd: Option<PathBuf>,
     route: Option<String>,
     max_rps: Option<f64>,
+    tls: Option<TlsConfig>,
+    health_check: Option<HealthCheckConfig>,
+    admin: Option<AdminConfig>,
+    metrics: Option<MetricsConfig>,
+    drain_timeout: Option<Duration>,
+    watch: bool,
+    static_dir: Option<PathBuf>,
+    upstream: Vec<String>,
+    command: Option<CommandConfig>,
+    env: BTreeMap<String, String>,
+    env_file: Option<PathBuf>,
+    secrets: BTreeMap<String, PathBuf>,
+    limits: Option<LimitsConfig>,
+    recycle: Option<RecycleConfig>,
+    sample_usage: bool,
+    queue: Option<QueueConfig>,
+    hash_key: Option<HashKey>,
+    lb_cookie: Option<LbCookieConfig>,
 }
 
 impl FaucetServerBuilder {
@@ -95,16 +164,50 @@ impl FaucetServerBuilder {
             rscript: None,
             uv: None,
             app_dir: None,
+            entrypoint: None,
+            r_options: Vec::new(),
             route: None,
             quarto: None,
             qmd: None,
             max_rps: None,
+            tls: None,
+            health_check: None,
+            admin: None,
+            metrics: None,
+            drain_timeout: None,
+            watch: false,
+            static_dir: None,
+            upstream: Vec::new(),
+            command: None,
+            env: BTreeMap::new(),
+            env_file: None,
+            secrets: BTreeMap::new(),
+            limits: None,
+            recycle: None,
+            sample_usage: false,
+            queue: None,
+            hash_key: None,
+            lb_cookie: None,
         }
     }
     pub fn app_dir(mut self, app_dir: Option<impl AsRef<str>>) -> Self {
         self.app_dir = app_dir.map(|s| s.as_ref().into());
         self
     }
+    pub fn entrypoint(mut self, entrypoint: Option<impl AsRef<str>>) -> Self {
+        self.entrypoint = entrypoint.map(|s| s.as_ref().into());
+        if let Some(entrypoint) = &self.entrypoint {
+            log::debug!(target: "faucet", "Using entrypoint: {entrypoint}");
+        }
+        self
+    }
+    pub fn r_options(mut self, r_options: Vec<ROption>) -> Self {
+        if !r_options.is_empty() {
+            log::debug!(target: "faucet", "Using R options: {r_options:?}");
+        }
+        self.r_options = r_options;
+        self
+    }
     pub fn strategy(mut self, strategy: Option<Strategy>) -> Self {
         log::debug!(target: "faucet", "Using load balancing strategy: {strategy:?}");
         self.strategy = strategy;
@@ -168,16 +271,151 @@ impl FaucetServerBuilder {
         self.max_rps = max_rps;
         self
     }
+    pub fn tls(mut self, tls: Option<TlsConfig>) -> Self {
+        if let Some(tls) = &tls {
+            log::debug!(target: "faucet", "Using TLS certificate {:?} and key {:?}", tls.cert, tls.key);
+        }
+        self.tls = tls;
+        self
+    }
+    pub fn health_check(mut self, health_check: Option<HealthCheckConfig>) -> Self {
+        if let Some(health_check) = &health_check {
+            log::debug!(target: "faucet", "Using health check: {health_check:?}");
+        }
+        self.health_check = health_check;
+        self
+    }
+    pub fn admin(mut self, admin: Option<AdminConfig>) -> Self {
+        if let Some(admin) = &admin {
+            log::debug!(target: "faucet", "Admin API will bind to: {}", admin.host);
+        }
+        self.admin = admin;
+        self
+    }
+    pub fn metrics(mut self, metrics: Option<MetricsConfig>) -> Self {
+        if let Some(metrics) = &metrics {
+            log::debug!(target: "faucet", "Metrics will be served on: {}", metrics.host);
+        }
+        self.metrics = metrics;
+        self
+    }
+    pub fn drain_timeout(mut self, drain_timeout: Option<Duration>) -> Self {
+        if let Some(drain_timeout) = drain_timeout {
+            log::debug!(target: "faucet", "Replaced workers will drain for up to {drain_timeout:?}");
+        }
+        self.drain_timeout = drain_timeout;
+        self
+    }
+    pub fn watch(mut self, watch: bool) -> Self {
+        if watch {
+            log::debug!(target: "faucet", "Workers will be restarted when their files change");
+        }
+        self.watch = watch;
+        self
+    }
+    pub fn static_dir(mut self, static_dir: Option<impl AsRef<Path>>) -> Self {
+        self.static_dir = static_dir.map(|dir| dir.as_ref().into());
+        if let Some(static_dir) = &self.static_dir {
+            log::debug!(target: "faucet", "Serving static files from {static_dir:?}");
+        }
+        self
+    }
+    pub fn upstream(mut self, upstream: Vec<String>) -> Self {
+        log::debug!(target: "faucet", "Using upstreams: {upstream:?}");
+        self.upstream = upstream;
+        self
+    }
+    pub fn command(mut self, command: Option<CommandConfig>) -> Self {
+        if let Some(command) = &command {
+            log::debug!(target: "faucet", "Using command: {:?}", command.argv);
+        }
+        self.command = command;
+        self
+    }
+    pub fn env(mut self, env: BTreeMap<String, String>) -> Self {
+        if !env.is_empty() {
+            log::debug!(target: "faucet", "Adding environment variables: {:?}", env.keys());
+        }
+        self.env = env;
+        self
+    }
+    pub fn env_file(mut self, env_file: Option<impl AsRef<Path>>) -> Self {
+        self.env_file = env_file.map(|file| file.as_ref().into());
+        if let Some(env_file) = &self.env_file {
+            log::debug!(target: "faucet", "Adding environment variables from {env_file:?}");
+        }
+        self
+    }
+    pub fn secrets(mut self, secrets: BTreeMap<String, PathBuf>) -> Self {
+        if !secrets.is_empty() {
+            log::debug!(target: "faucet", "Reading secrets from files: {secrets:?}");
+        }
+        self.secrets = secrets;
+        self
+    }
+    pub fn limits(mut self, limits: Option<LimitsConfig>) -> Self {
+        if let Some(limits) = &limits {
+            log::debug!(target: "faucet", "Using resource limits: {limits:?}");
+        }
+        self.limits = limits;
+        self
+    }
+    pub fn recycle(mut self, recycle: Option<RecycleConfig>) -> Self {
+        if let Some(recycle) = &recycle {
+            log::debug!(target: "faucet", "Recycling workers with: {recycle:?}");
+        }
+        self.recycle = recycle;
+        self
+    }
+    /// Samples the memory and CPU of the workers for an admin API or
+    /// metrics listener that is not part of this server, like the router's.
+    pub fn sample_usage(mut self, sample_usage: bool) -> Self {
+        self.sample_usage = sample_usage;
+        self
+    }
+    pub fn queue(mut self, queue: Option<QueueConfig>) -> Self {
+        if let Some(queue) = &queue {
+            log::debug!(target: "faucet", "Queueing requests with: {queue:?}");
+        }
+        self.queue = queue;
+        self
+    }
+    pub fn hash_key(mut self, hash_key: Option<HashKey>) -> Self {
+        if let Some(hash_key) = &hash_key {
+            log::debug!(target: "faucet", "Hashing requests by: {hash_key:?}");
+        }
+        self.hash_key = hash_key;
+        self
+    }
+    pub fn lb_cookie(mut self, lb_cookie: Option<LbCookieConfig>) -> Self {
+        if let Some(lb_cookie) = &lb_cookie {
+            log::debug!(target: "faucet", "Load balancing cookie: {lb_cookie:?}");
+        }
+        self.lb_cookie = lb_cookie;
+        self
+    }
     pub fn build(self) -> FaucetResult<FaucetServerConfig> {
         let server_type = self
             .server_type
             .ok_or(FaucetError::MissingArgument("server_type"))?;
         let strategy = determine_strategy(server_type, self.strategy);
         let bind = self.bind;
-        let n_workers = self.n_workers.unwrap_or_else(|| {
+        let upstream = self
+            .upstream
+            .iter()
+            .map(|upstream| worker::upstream_authority(upstream).map(|a| leak!(a, str)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(FaucetError::InvalidConfig)?;
+        let upstream: &'static [&'static str] = leak!(upstream);
+        // Upstreams are not started by faucet, there is one worker per upstream.
+        let n_workers = match server_type {
+            WorkerType::Upstream => NonZeroUsize::new(upstream.len())
+                .ok_or(FaucetError::MissingArgument("upstream"))?,
+            _ => self.n_workers.unwrap_or_else(|| {
             log::debug!(target: "faucet", "No number of workers specified. Defaulting to the number of logical cores.");
             num_cpus::get().try_into().expect("num_cpus::get() returned 0")
-        });
+            }),
+        };
         let workdir = self.workdir
             .map(|wd| leak!(wd, Path))
             .unwrap_or_else(|| {
@@ -197,6 +435,8 @@ impl FaucetServerBuilder {
             load_balancing::IpExtractor::ClientAddr
         });
         let app_dir = self.app_dir.map(|app_dir| leak!(app_dir, str));
+        let entrypoint = self.entrypoint.map(|entrypoint| leak!(entrypoint, str));
+        let r_options: &'static [ROption] = leak!(self.r_options);
         let qmd = self.qmd.map(|qmd| leak!(qmd, Path));
         let quarto = self.quarto.map(|qmd| leak!(qmd, OsStr)).unwrap_or_else(|| {
             log::debug!(target: "faucet", "No quarto command specified. Defaulting to `quarto`.");
@@ -204,6 +444,57 @@ impl FaucetServerBuilder {
         });
         let route = self.route.map(|r| -> &'static _ { leak!(r) });
         let max_rps = self.max_rps;
+        let tls = self.tls;
+        let health_check = self
+            .health_check
+            .map(|hc| -> FaucetResult<&'static HealthCheck> { Ok(leak!(hc.resolve(server_type)?)) })
+            .transpose()?;
+        let command = self
+            .command
+            .map(|command| -> FaucetResult<&'static WorkerCommand> {
+                Ok(leak!(command.resolve()?))
+            })
+            .transpose()?;
+        if server_type == WorkerType::Command && command.is_none() {
+            return Err(FaucetError::MissingArgument("command"));
+        }
+        let env: &'static WorkerEnv = leak!(WorkerEnv::new(
+            workdir,
+            self.env_file.as_deref(),
+            self.env,
+            self.secrets
+        ));
+        let limits: &'static ProcessLimits = leak!(self.limits.unwrap_or_default().resolve()?);
+        let recycle: &'static RecyclePolicy = leak!(RecyclePolicy {
+            // Only sampled when something reports or acts on it.
+            sample_usage: self.sample_usage || self.admin.is_some() || self.metrics.is_some(),
+            ..self
+                .recycle
+                .map(|recycle| recycle.resolve())
+                .transpose()?
+                .unwrap_or_default()
+        });
+        let queue = self
+            .queue
+            .map(|queue| -> FaucetResult<&'static QueuePolicy> { Ok(leak!(queue.resolve()?)) })
+            .transpose()?;
+        if let Some(hash_key) = &self.hash_key {
+            hash_key.validate()?;
+        }
+        if strategy == Strategy::HeaderHash && self.hash_key.is_none() {
+            return Err(FaucetError::MissingArgument("hash_key"));
+        }
+        let hash_key = self
+            .hash_key
+            .map(|hash_key| -> &'static HashKey { leak!(hash_key) });
+        let lb_cookie: &'static LbCookie =
+            leak!(self.lb_cookie.unwrap_or_default().resolve("/")?);
+        let drain_timeout = self.drain_timeout.unwrap_or(worker::DEFAULT_DRAIN_TIMEOUT);
+        // Relative to the workdir, like the application's files.
+        let static_files = self
+            .static_dir
+            .map(|dir| StaticFiles::new(&workdir.join(dir)))
+            .transpose()?;
         Ok(FaucetServerConfig {
             strategy,
             bind,
@@ -213,11 +504,28 @@ impl FaucetServerBuilder {
             extractor,
             rscript,
             app_dir,
+            entrypoint,
+            r_options,
             uv,
             route,
             quarto,
             qmd,
             max_rps,
+            tls,
+            health_check,
+            admin: self.admin,
+            metrics: self.metrics,
+            drain_timeout,
+            watch: self.watch,
+            static_files,
+            upstream,
+            command,
+            env,
+            limits,
+            recycle,
+            queue,
+            hash_key,
+            lb_cookie,
         })
     }
 }
@@ -240,9 +548,36 @@ pub struct FaucetServerConfig {
     pub uv: &'static OsStr,
     pub quarto: &'static OsStr,
     pub app_dir: Option<&'static str>,
+    /// File the application is started from, relative to `app_dir`.
+    pub entrypoint: Option<&'static str>,
+    /// Options set in the R session of the workers.
+    pub r_options: &'static [ROption],
     pub route: Option<&'static str>,
     pub qmd: Option<&'static Path>,
     pub max_rps: Option<f64>,
+    pub tls: Option<TlsConfig>,
+    pub health_check: Option<&'static HealthCheck>,
+    pub admin: Option<AdminConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub drain_timeout: Duration,
+    pub watch: bool,
+    pub(crate) static_files: Option<StaticFiles>,
+    /// `host:port` of each upstream, for [`WorkerType::Upstream`].
+    pub upstream: &'static [&'static str],
+    /// Process started by each worker, for [`WorkerType::Command`].
+    pub command: Option<&'static WorkerCommand>,
+    /// Variables added to the environment of the workers.
+    pub env: &'static WorkerEnv,
+    /// Resource limits and user of the workers' processes.
+    pub(crate) limits: &'static ProcessLimits,
+    /// When the workers' processes are replaced by fresh ones.
+    pub recycle: &'static RecyclePolicy,
+    /// Requests each worker serves at once, and how the rest wait.
+    pub queue: Option<&'static QueuePolicy>,
+    /// What identifies users for [`Strategy::HeaderHash`].
+    pub hash_key: Option<&'static HashKey>,
+    /// Cookie of [`Strategy::CookieHash`].
+    pub(crate) lb_cookie: &'static LbCookie,
 }
 
 impl FaucetServerConfig {
@@ -252,15 +587,32 @@ impl FaucetServerConfig {
         websocket_config: &'static WebSocketConfig,
     ) -> FaucetResult<()> {
         let mut workers = WorkerConfigs::new(self.clone(), shutdown).await?;
+        if self.watch {
+            spawn_watcher(&workers.workers, shutdown);
+        }
         let load_balancer = LoadBalancer::new(
             self.strategy,
             self.extractor,
             &workers.workers,
             self.max_rps,
+            self.hash_key,
         )
-        .await?;
+        .await?
+        .with_queue(self.queue, self.route, shutdown);
         let bind = self.bind.ok_or(FaucetError::MissingArgument("bind"))?;
 
+        let groups = admin::WorkerGroups::new(vec![admin::WorkerGroup {
+            route: self.route,
+            workers: workers.workers.clone(),
+        }]);
+        if let Some(admin) = self.admin.clone() {
+            admin::spawn(admin, groups.clone(), shutdown).await?;
+        }
+        if let Some(metrics) = self.metrics.clone() {
+            admin::spawn_metrics(metrics, groups, shutdown).await?;
+        }
+        worker::rolling_restart_on_sighup(workers.workers.to_vec(), shutdown)?;
+
         let load_balancer = load_balancer.clone();
         let service = Arc::new(
             ServiceBuilder::new(ProxyService {
@@ -268,13 +620,25 @@ impl FaucetServerConfig {
                 websocket_config,
             })
             .layer(logging::LogLayer {})
-            .layer(AddStateLayer::new(load_balancer))
+            .layer(AddStateLayer::new(load_balancer, self.lb_cookie))
+            .layer(StaticLayer(self.static_files))
             .build(),
         );
 
+        let tls_acceptor = self
+            .tls
+            .as_ref()
+            .map(|tls| tls.build_acceptor(shutdown))
+            .transpose()?;
+        let scheme = if tls_acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+
         // Bind to the port and listen for incoming TCP connections
         let listener = TcpListener::bind(bind).await?;
-        log::info!(target: "faucet", "Listening on http://{bind}");
+        log::info!(target: "faucet", "Listening on {scheme}://{bind}");
         let main_loop = || async {
             loop {
                 match listener.accept().await {
@@ -283,23 +647,37 @@ impl FaucetServerConfig {
                         return;
                     }
                     Ok((tcp, client_addr)) => {
-                        let tcp = TokioIo::new(tcp);
                         log::debug!(target: "faucet", "Accepted TCP connection from {client_addr}");
 
                         let service = service.clone();
+                        let tls_acceptor = tls_acceptor.clone();
 
                         tokio::task::spawn(async move {
-                            let mut conn = http1::Builder::new()
-                                .half_close(true)
-                                .serve_connection(
-                                    tcp,
-                                    service_fn(|req: Request<Incoming>| {
-                                        service.call(req, Some(client_addr.ip()))
-                                    }),
-                                )
-                                .with_upgrades();
+                            let cleartext = tls_acceptor.is_none();
+                            let handle = move |req: Request<Incoming>| {
+                                let service = service.clone();
+                                async move { service.call(req, Some(client_addr.ip())).await }
+                            };
+                            let stream = match FaucetStream::accept(tcp, tls_acceptor.as_ref())
+                                .await
+                            {
+                                Ok(stream) => TokioIo::new(stream),
+                                Err(e) => {
+                                    log::debug!(target: "faucet", "TLS handshake with {client_addr} failed: {e}");
+                                    return;
+                                }
+                            };
 
-                            let conn = pin!(&mut conn);
+                            let mut builder = connection_builder();
+                            builder.http1().half_close(true);
+                            let conn = builder.serve_connection_with_upgrades(
+                                stream,
+                                service_fn(move |req: Request<Incoming>| {
+                                    h2c::serve(req, cleartext, handle.clone(), shutdown)
+                                }),
+                            );
+
+                            let conn = pin!(conn);
 
                             tokio::select! {
                                 result = conn => {
@@ -338,20 +716,26 @@ impl FaucetServerConfig {
         websocket_config: &'static WebSocketConfig,
     ) -> FaucetResult<(FaucetServerService, WorkerConfigs)> {
         let workers = WorkerConfigs::new(self.clone(), shutdown).await?;
+        if self.watch {
+            spawn_watcher(&workers.workers, shutdown);
+        }
         let load_balancer = LoadBalancer::new(
             self.strategy,
             self.extractor,
             &workers.workers,
             self.max_rps,
+            self.hash_key,
         )
-        .await?;
+        .await?
+        .with_queue(self.queue, self.route, shutdown);
         let service = Arc::new(
             ServiceBuilder::new(ProxyService {
                 shutdown,
                 websocket_config,
             })
             .layer(logging::LogLayer {})
-            .layer(AddStateLayer::new(load_balancer))
+            .layer(AddStateLayer::new(load_balancer, self.lb_cookie))
+            .layer(StaticLayer(self.static_files))
             .build(),
         );
 
@@ -360,7 +744,7 @@ impl FaucetServerConfig {
 }
 
 pub struct FaucetServerService {
-    inner: Arc<AddStateService<LogService<ProxyService>>>,
+    inner: Arc<StaticService<AddStateService<LogService<ProxyService>>>>,
 }
 
 impl Clone for FaucetServerService {
@@ -0,0 +1,161 @@
+//! Matching of routes on the `Host` of the request, to serve several
+//! domains from the same router.
+
+use hyper::{header::HOST, http::uri::Authority, Request};
+
+/// The `host` of a route, either a domain name or a wildcard such as
+/// `*.apps.example.com` matching any of its subdomains.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub(super) enum HostPattern {
+    Exact(String),
+    /// Domain under which any subdomain matches, without the leading `*.`.
+    Wildcard(String),
+}
+
+impl TryFrom<String> for HostPattern {
+    type Error = String;
+    fn try_from(host: String) -> Result<Self, Self::Error> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let (pattern, domain) = match host.strip_prefix("*.") {
+            Some(domain) => (HostPattern::Wildcard(domain.to_string()), domain),
+            None => (HostPattern::Exact(host.clone()), host.as_str()),
+        };
+        let valid = !domain.is_empty()
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        match valid {
+            true => Ok(pattern),
+            false => Err(format!(
+                "invalid host {host:?}, expected a domain name like \"app.example.com\" or a wildcard like \"*.example.com\", without port"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostPattern::Exact(host) => f.write_str(host),
+            HostPattern::Wildcard(domain) => write!(f, "*.{domain}"),
+        }
+    }
+}
+
+impl HostPattern {
+    /// Whether `host`, lowercase and without port, matches the pattern.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => host == exact,
+            HostPattern::Wildcard(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        }
+    }
+
+    /// Priority over other patterns matching the same host: exact hosts
+    /// first, then the wildcard with the longest domain.
+    pub fn specificity(&self) -> (u8, usize) {
+        match self {
+            HostPattern::Exact(_) => (2, 0),
+            HostPattern::Wildcard(domain) => (1, domain.len()),
+        }
+    }
+}
+
+/// Host the request was sent to, lowercase and without port. HTTP/2
+/// requests carry it in the URI, HTTP/1.1 requests in the `Host` header.
+pub(super) fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host.to_string(),
+        None => {
+            let header = req.headers().get(HOST)?.to_str().ok()?;
+            header.parse::<Authority>().ok()?.host().to_string()
+        }
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(host: &str) -> HostPattern {
+        HostPattern::try_from(host.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(
+            pattern("App.Example.com."),
+            HostPattern::Exact("app.example.com".into())
+        );
+        assert_eq!(
+            pattern("*.apps.example.com"),
+            HostPattern::Wildcard("apps.example.com".into())
+        );
+        assert_eq!(
+            pattern("*.apps.example.com").to_string(),
+            "*.apps.example.com"
+        );
+        for invalid in [
+            "",
+            "*",
+            "*.",
+            "a.*.com",
+            "example.com:8080",
+            "a..com",
+            "a/b",
+        ] {
+            assert!(
+                HostPattern::try_from(invalid.to_string()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn match_hosts() {
+        let exact = pattern("app.example.com");
+        assert!(exact.matches("app.example.com"));
+        assert!(!exact.matches("other.example.com"));
+
+        let wildcard = pattern("*.apps.example.com");
+        assert!(wildcard.matches("acme.apps.example.com"));
+        assert!(wildcard.matches("a.b.apps.example.com"));
+        assert!(!wildcard.matches("apps.example.com"));
+        assert!(!wildcard.matches("acmeapps.example.com"));
+    }
+
+    #[test]
+    fn exact_hosts_before_wildcards() {
+        let exact = pattern("acme.apps.example.com").specificity();
+        let narrow = pattern("*.apps.example.com").specificity();
+        let broad = pattern("*.example.com").specificity();
+        assert!(exact > narrow);
+        assert!(narrow > broad);
+    }
+
+    #[test]
+    fn host_of_requests() {
+        let req = Request::builder()
+            .uri("/path")
+            .header(HOST, "App.Example.com:8443")
+            .body(())
+            .unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("app.example.com"));
+
+        let req = Request::builder()
+            .uri("https://h2.example.com/path")
+            .body(())
+            .unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("h2.example.com"));
+
+        let req = Request::builder().uri("/path").body(()).unwrap();
+        assert_eq!(request_host(&req), None);
+    }
+}
@@ -0,0 +1,386 @@
+//! Matching of request paths against routes.
+//!
+//! A route's path is either a literal prefix, a path with parameters such
+//! as `/tenant/{id}/`, or a regular expression. When several routes match
+//! a request, the one matching the longest part of the path is used.
+
+use std::collections::BTreeMap;
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    http::uri::PathAndQuery,
+    Request, Uri,
+};
+use regex::Regex;
+
+fn strip_prefix_exact(path_and_query: &PathAndQuery, prefix: &str) -> Option<PathAndQuery> {
+    if path_and_query.path() == prefix {
+        return Some(match path_and_query.query() {
+            Some(query) => format!("/?{query}").parse().unwrap(),
+            None => "/".parse().unwrap(),
+        });
+    }
+    None
+}
+
+/// The rest of the path after a matched prefix, as seen by the worker.
+fn remainder(after_prefix: &str, query: Option<&str>) -> PathAndQuery {
+    let start_slash = after_prefix.starts_with('/');
+
+    match (start_slash, query) {
+        (true, None) => after_prefix.parse().unwrap(),
+        (true, Some(query)) => format!("{after_prefix}?{query}").parse().unwrap(),
+        (false, None) => format!("/{after_prefix}").parse().unwrap(),
+        (false, Some(query)) => format!("/{after_prefix}?{query}").parse().unwrap(),
+    }
+}
+
+fn strip_prefix_relative(path_and_query: &PathAndQuery, prefix: &str) -> Option<PathAndQuery> {
+    // Try to strip the prefix. It is fails we short-circuit.
+    let after_prefix = path_and_query.path().strip_prefix(prefix)?;
+    Some(remainder(after_prefix, path_and_query.query()))
+}
+
+/// The reason of a regex error, without the pattern the error message
+/// repeats over several lines.
+fn regex_error(error: regex::Error) -> String {
+    let message = error.to_string();
+    let reason = message.lines().last().unwrap_or_default();
+    reason.trim_start_matches("error: ").to_string()
+}
+
+fn with_path_and_query(uri: &Uri, path_and_query: PathAndQuery) -> Uri {
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query);
+    Uri::from_parts(parts).unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Kind {
+    Regex,
+    Params,
+    Literal,
+}
+
+/// How a route matches the path of a request.
+pub(super) struct PathMatcher {
+    kind: Kind,
+    /// `route` or `regex` as written in the configuration.
+    source: String,
+    /// Used for routes with parameters and regular expressions.
+    regex: Option<Regex>,
+}
+
+/// A request path matched by a route.
+pub(super) struct PathMatch {
+    /// The URI with the matched part of the path removed.
+    pub uri: Uri,
+    /// Length of the matched part of the path.
+    pub len: usize,
+    /// Values of the named parameters or capture groups.
+    pub params: Vec<(String, String)>,
+}
+
+fn is_param_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Translates a route with `{name}` parameters to a regular expression.
+fn params_to_regex(route: &str) -> Result<String, String> {
+    let mut regex = String::from("^");
+    let mut names = Vec::new();
+    let mut rest = route;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(format!("route {route:?} has an unmatched '}}'"));
+        }
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("route {route:?} has an unclosed '{{'"))?;
+        let name = &rest[start + 1..end];
+        if !is_param_name(name) {
+            return Err(format!(
+                "route {route:?} has an invalid parameter name {name:?}"
+            ));
+        }
+        if names.contains(&name) {
+            return Err(format!("route {route:?} has the parameter {name:?} twice"));
+        }
+        let after = &rest[end + 1..];
+        if !rest[..start].ends_with('/') || !(after.is_empty() || after.starts_with('/')) {
+            return Err(format!(
+                "route {route:?} must use parameters as whole path segments, like \"/tenant/{{id}}/\""
+            ));
+        }
+        regex.push_str(&regex::escape(&rest[..start]));
+        regex.push_str(&format!("(?P<{name}>[^/]+)"));
+        names.push(name);
+        rest = after;
+    }
+    regex.push_str(&regex::escape(rest));
+    // Like literal routes, routes without a trailing slash match a single path.
+    if !route.ends_with('/') {
+        regex.push('$');
+    }
+    Ok(regex)
+}
+
+impl PathMatcher {
+    /// Builds the matcher of a route from its `route` or `regex` setting.
+    /// Routes without either match every path.
+    pub fn new(route: Option<&str>, regex: Option<&str>) -> Result<Self, String> {
+        match (route, regex) {
+            (Some(_), Some(_)) => Err("set either `route` or `regex`, not both".to_string()),
+            (None, Some(regex)) => {
+                let compiled = Regex::new(&format!("^(?:{regex})"))
+                    .map_err(|e| format!("invalid regex {regex:?}: {}", regex_error(e)))?;
+                Ok(PathMatcher {
+                    kind: Kind::Regex,
+                    source: regex.to_string(),
+                    regex: Some(compiled),
+                })
+            }
+            (route, None) => {
+                let route = route.unwrap_or("/");
+                if !route.starts_with('/') {
+                    return Err(format!("route {route:?} must start with '/'"));
+                }
+                if !route.contains(['{', '}']) {
+                    return Ok(PathMatcher {
+                        kind: Kind::Literal,
+                        source: route.to_string(),
+                        regex: None,
+                    });
+                }
+                let compiled = Regex::new(&params_to_regex(route)?)
+                    .map_err(|e| format!("invalid route {route:?}: {}", regex_error(e)))?;
+                Ok(PathMatcher {
+                    kind: Kind::Params,
+                    source: route.to_string(),
+                    regex: Some(compiled),
+                })
+            }
+        }
+    }
+
+    /// Names of the parameters or named capture groups of the route.
+    pub fn param_names(&self) -> Vec<&str> {
+        self.regex
+            .iter()
+            .flat_map(|regex| regex.capture_names().flatten())
+            .collect()
+    }
+
+    /// Identifies routes matching exactly the same paths, regardless of
+    /// the names of their parameters.
+    pub fn key(&self) -> String {
+        match &self.regex {
+            Some(regex) if self.kind == Kind::Params => {
+                let names = Regex::new(r"\?P<[^>]+>").expect("Valid regex");
+                names.replace_all(regex.as_str(), "").into_owned()
+            }
+            _ => self.source.clone(),
+        }
+    }
+
+    /// Priority between routes matching the same part of the path:
+    /// literal routes first, then routes with parameters, then regexes.
+    pub fn rank(&self) -> impl Ord {
+        self.kind
+    }
+
+    pub fn matches(&self, uri: &Uri) -> Option<PathMatch> {
+        let path_and_query = uri.path_and_query()?;
+        let Some(regex) = &self.regex else {
+            let prefix = self.source.as_str();
+            let stripped = match prefix.ends_with('/') {
+                true => strip_prefix_relative(path_and_query, prefix)?,
+                false => strip_prefix_exact(path_and_query, prefix)?,
+            };
+            return Some(PathMatch {
+                uri: with_path_and_query(uri, stripped),
+                len: prefix.len(),
+                params: Vec::new(),
+            });
+        };
+
+        let path = path_and_query.path();
+        let captures = regex.captures(path)?;
+        let matched = captures.get(0)?;
+        let params = regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+            .collect();
+        let stripped = remainder(&path[matched.end()..], path_and_query.query());
+        Some(PathMatch {
+            uri: with_path_and_query(uri, stripped),
+            len: matched.end(),
+            params,
+        })
+    }
+}
+
+/// Request headers set from the parameters captured by a route.
+pub(super) struct ParamHeaders(Vec<(String, HeaderName)>);
+
+impl ParamHeaders {
+    pub fn new(matcher: &PathMatcher, headers: &BTreeMap<String, String>) -> Result<Self, String> {
+        let names = matcher.param_names();
+        let mut param_headers = Vec::with_capacity(headers.len());
+        for (param, header) in headers {
+            if !names.contains(&param.as_str()) {
+                return Err(format!(
+                    "`param_headers` uses {param:?}, which is not a parameter of route {:?}",
+                    matcher.source
+                ));
+            }
+            let header = HeaderName::try_from(header.as_str())
+                .map_err(|_| format!("`param_headers` has an invalid header name {header:?}"))?;
+            param_headers.push((param.clone(), header));
+        }
+        Ok(ParamHeaders(param_headers))
+    }
+
+    /// Sets the headers of `req` from `params`. Headers with the same name
+    /// sent by the client are removed so they cannot be spoofed.
+    pub fn apply<B>(&self, req: &mut Request<B>, params: &[(String, String)]) {
+        for (param, header) in &self.0 {
+            req.headers_mut().remove(header);
+            let value = params
+                .iter()
+                .find(|(name, _)| name == param)
+                .and_then(|(_, value)| HeaderValue::from_str(value).ok());
+            if let Some(value) = value {
+                req.headers_mut().insert(header.clone(), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(route: &str) -> PathMatcher {
+        PathMatcher::new(Some(route), None).unwrap()
+    }
+
+    fn regex(regex: &str) -> PathMatcher {
+        PathMatcher::new(None, Some(regex)).unwrap()
+    }
+
+    type Matched = (String, usize, Vec<(String, String)>);
+
+    fn matched(matcher: &PathMatcher, uri: &str) -> Option<Matched> {
+        let found = matcher.matches(&uri.parse().unwrap())?;
+        Some((found.uri.to_string(), found.len, found.params))
+    }
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn literal_routes() {
+        let prefix = matcher("/app/");
+        assert_eq!(
+            matched(&prefix, "/app/users/1?x=1"),
+            Some(("/users/1?x=1".into(), 5, vec![]))
+        );
+        assert_eq!(matched(&prefix, "/app"), None);
+
+        let exact = matcher("/health");
+        assert_eq!(
+            matched(&exact, "/health?x=1"),
+            Some(("/?x=1".into(), 7, vec![]))
+        );
+        assert_eq!(matched(&exact, "/health/more"), None);
+    }
+
+    #[test]
+    fn routes_with_params() {
+        let tenant = matcher("/tenant/{id}/");
+        assert_eq!(
+            matched(&tenant, "/tenant/acme/reports?y=2"),
+            Some(("/reports?y=2".into(), 13, params(&[("id", "acme")])))
+        );
+        assert_eq!(matched(&tenant, "/tenant//reports"), None);
+        assert_eq!(matched(&tenant, "/tenant/acme"), None);
+
+        let exact = matcher("/tenant/{id}/user/{user}");
+        assert_eq!(
+            matched(&exact, "/tenant/a.b/user/42"),
+            Some(("/".into(), 19, params(&[("id", "a.b"), ("user", "42")])))
+        );
+        assert_eq!(matched(&exact, "/tenant/a/user/42/x"), None);
+        assert_eq!(exact.param_names(), ["id", "user"]);
+    }
+
+    #[test]
+    fn regex_routes() {
+        let version = regex(r"/v(?P<version>[0-9]+)/");
+        assert_eq!(
+            matched(&version, "/v12/items"),
+            Some(("/items".into(), 5, params(&[("version", "12")])))
+        );
+        // Regexes are anchored to the start of the path.
+        assert_eq!(matched(&version, "/api/v12/items"), None);
+    }
+
+    #[test]
+    fn invalid_routes() {
+        for (route, error) in [
+            ("app/", "must start with '/'"),
+            ("/tenant/{id/", "unclosed '{'"),
+            ("/tenant/id}/", "unmatched '}'"),
+            ("/tenant/{1d}/", "invalid parameter name"),
+            ("/t/{id}/{id}/", "the parameter \"id\" twice"),
+            ("/tenant-{id}/", "whole path segments"),
+        ] {
+            let result = PathMatcher::new(Some(route), None);
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{route}: {:?}",
+                result.err()
+            );
+        }
+        assert!(PathMatcher::new(None, Some("/(")).is_err());
+        assert!(PathMatcher::new(Some("/"), Some("/")).is_err());
+    }
+
+    #[test]
+    fn keys_ignore_param_names() {
+        assert_eq!(matcher("/t/{id}/").key(), matcher("/t/{tenant}/").key());
+        assert_ne!(matcher("/t/{id}/").key(), matcher("/t/{id}").key());
+        assert_eq!(matcher("/app/").key(), "/app/");
+    }
+
+    #[test]
+    fn param_headers() {
+        let tenant = matcher("/tenant/{id}/");
+        let headers = BTreeMap::from([("id".to_string(), "X-Tenant-Id".to_string())]);
+        let param_headers = ParamHeaders::new(&tenant, &headers).unwrap();
+
+        let mut req = Request::builder()
+            .header("x-tenant-id", "spoofed")
+            .body(())
+            .unwrap();
+        param_headers.apply(&mut req, &params(&[("id", "acme")]));
+        assert_eq!(req.headers().get_all("x-tenant-id").iter().count(), 1);
+        assert_eq!(req.headers()["x-tenant-id"], "acme");
+
+        let unknown = BTreeMap::from([("user".to_string(), "X-User".to_string())]);
+        assert!(ParamHeaders::new(&tenant, &unknown).is_err());
+        let invalid = BTreeMap::from([("id".to_string(), "X Tenant".to_string())]);
+        assert!(ParamHeaders::new(&tenant, &invalid).is_err());
+    }
+}
@@ -1,43 +1,139 @@
+mod host;
+mod matcher;
+mod reload;
+mod validate;
+
 use std::{
-    collections::HashSet, ffi::OsStr, net::SocketAddr, num::NonZeroUsize, path::PathBuf, pin::pin,
-    sync::Arc,
+    collections::{BTreeMap, HashSet},
+    ffi::OsStr,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 
-use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request, Uri};
+use hyper::{body::Incoming, service::service_fn, Request};
 use hyper_util::rt::TokioIo;
 use tokio::net::TcpListener;
-use tokio_tungstenite::tungstenite::{http::uri::PathAndQuery, protocol::WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 
-use super::{onion::Service, FaucetServerBuilder, FaucetServerService};
+use super::{
+    admin::{self, AdminConfig, MetricsConfig},
+    connection_builder, h2c,
+    onion::Service,
+    static_files::StaticFiles,
+    tls::{FaucetStream, TlsConfig},
+    FaucetServerBuilder, FaucetServerConfig, FaucetServerService, LbCookieConfig,
+};
 use crate::{
     client::{
-        load_balancing::{IpExtractor, Strategy},
-        worker::{WorkerConfigs, WorkerType},
-        ExclusiveBody,
+        load_balancing::{HashKey, IpExtractor, Strategy},
+        websocket_sessions,
+        worker::{self, WorkerConfigs, WorkerType},
+        CommandConfig, ExclusiveBody, HealthCheckConfig, LimitsConfig, QueueConfig, ROption,
+        ROptionValue, RecycleConfig,
     },
     error::{FaucetError, FaucetResult},
+    global_conn::worker_connections,
+    leak,
     shutdown::ShutdownSignal,
 };
+use host::HostPattern;
+use matcher::{ParamHeaders, PathMatch, PathMatcher};
+use reload::Router;
+
+const DRAIN_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 fn default_workdir() -> PathBuf {
     PathBuf::from(".")
 }
 
-#[derive(serde::Deserialize)]
+/// The `server_type` of a route: one of the worker types, or `static` for
+/// routes serving the files in their `workdir` without any worker.
+#[derive(Clone, Copy, PartialEq, Debug, serde::Deserialize)]
+#[serde(try_from = "String")]
+enum RouteType {
+    Static,
+    App(WorkerType),
+}
+
+impl TryFrom<String> for RouteType {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        use serde::{
+            de::{value::StrDeserializer, IntoDeserializer},
+            Deserialize,
+        };
+        if value.eq_ignore_ascii_case("static") {
+            return Ok(RouteType::Static);
+        }
+        let deserializer: StrDeserializer<serde::de::value::Error> =
+            value.as_str().into_deserializer();
+        WorkerType::deserialize(deserializer)
+            .map(RouteType::App)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[derive(serde::Deserialize, PartialEq)]
 struct ReducedServerConfig {
     pub strategy: Option<Strategy>,
     #[serde(default = "default_workdir")]
     pub workdir: PathBuf,
     pub app_dir: Option<String>,
-    pub workers: NonZeroUsize,
-    pub server_type: WorkerType,
+    pub workers: Option<NonZeroUsize>,
+    pub server_type: RouteType,
     pub qmd: Option<PathBuf>,
+    /// File the application is started from, relative to `app_dir`.
+    pub entrypoint: Option<String>,
+    /// Options set in the R session of the workers, by name.
+    #[serde(default)]
+    pub r_options: BTreeMap<String, ROptionValue>,
+    /// Directory, relative to `workdir`, whose files are served before
+    /// forwarding the request to the workers.
+    pub static_dir: Option<PathBuf>,
+    /// Addresses of the services behind an `upstream` route.
+    #[serde(default)]
+    pub upstream: Vec<String>,
+    /// Process started by each worker of a `command` route.
+    pub command: Option<CommandConfig>,
+    /// Environment variables of the workers.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// File, relative to `workdir`, with more environment variables.
+    pub env_file: Option<PathBuf>,
+    /// Environment variables read from files, such as mounted secrets.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, PathBuf>,
+    /// Resource limits and user of the workers' processes.
+    pub limits: Option<LimitsConfig>,
+    /// When the workers' processes are replaced by fresh ones.
+    pub recycle: Option<RecycleConfig>,
+    /// Requests each worker serves at once, and how the rest wait.
+    pub queue: Option<QueueConfig>,
+    /// What identifies users for the header-hash strategy.
+    pub hash_key: Option<HashKey>,
+    /// Cookie of the cookie-hash strategy.
+    pub lb_cookie: Option<LbCookieConfig>,
     pub max_rps: Option<f64>,
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub watch: bool,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, PartialEq)]
 struct RouteConfig {
-    route: String,
+    route: Option<String>,
+    regex: Option<String>,
+    host: Option<HostPattern>,
+    /// Request headers set from the route's parameters, by parameter name.
+    #[serde(default)]
+    param_headers: BTreeMap<String, String>,
     #[serde(flatten)]
     config: ReducedServerConfig,
 }
@@ -45,50 +141,256 @@ struct RouteConfig {
 #[derive(serde::Deserialize)]
 pub struct RouterConfig {
     route: Vec<RouteConfig>,
+    /// TLS certificate and key used by the router's listener.
+    pub tls: Option<TlsConfig>,
+    /// Address and token of the admin API.
+    pub admin: Option<AdminConfig>,
+    /// Address of the dedicated Prometheus metrics listener.
+    pub metrics: Option<MetricsConfig>,
+    /// Seconds a process replaced by a rolling restart may keep serving
+    /// its connections.
+    pub drain_timeout: Option<f64>,
+    /// File the configuration was loaded from, reloaded on SIGHUP or when
+    /// it changes.
+    #[serde(skip)]
+    source: Option<&'static Path>,
+}
+
+/// Settings shared by every route, kept around to start the routes added
+/// by a reload.
+#[derive(Clone, Copy)]
+struct RouterContext {
+    rscript: &'static OsStr,
+    quarto: &'static OsStr,
+    uv: &'static OsStr,
+    ip_from: IpExtractor,
+    drain_timeout: Duration,
+    /// Whether the admin API or metrics report the usage of the workers.
+    sample_usage: bool,
+    shutdown: &'static ShutdownSignal,
+    websocket_config: &'static WebSocketConfig,
+}
+
+/// What serves the requests of a route once it is started.
+enum Backend {
+    Workers(Box<FaucetServerConfig>),
+    Static(StaticFiles),
+}
+
+enum RouteService {
+    Workers(FaucetServerService),
+    Static(StaticFiles),
+}
+
+/// A route and the workers serving it.
+struct Route {
+    /// Host and prefix identifying the route in logs, metrics and the
+    /// admin API.
+    name: &'static str,
+    matcher: PathMatcher,
+    param_headers: ParamHeaders,
+    /// The settings the route was started with, to tell on reload whether
+    /// it changed.
+    conf: RouteConfig,
+    service: RouteService,
+    /// Empty for static routes.
+    workers: WorkerConfigs,
+    /// Stops the route's workers without shutting down faucet.
+    shutdown: &'static ShutdownSignal,
+    in_flight: AtomicUsize,
 }
 
+type RouteTable = Arc<[Arc<Route>]>;
+
 #[derive(Clone)]
 struct RouterService {
-    routes: &'static [String],
-    clients: Arc<[FaucetServerService]>,
+    table: Arc<RwLock<RouteTable>>,
 }
 
-fn strip_prefix_exact(path_and_query: &PathAndQuery, prefix: &str) -> Option<PathAndQuery> {
-    if path_and_query.path() == prefix {
-        return Some(match path_and_query.query() {
-            Some(query) => format!("/?{query}").parse().unwrap(),
-            None => "/".parse().unwrap(),
-        });
+/// Decrements the in-flight counter of a route when the request finishes
+/// or is cancelled.
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl<'a> InFlightGuard<'a> {
+    fn new(in_flight: &'a AtomicUsize) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(in_flight)
     }
-    None
 }
 
-fn strip_prefix_relative(path_and_query: &PathAndQuery, prefix: &str) -> Option<PathAndQuery> {
-    // Try to strip the prefix. It is fails we short-circuit.
-    let after_prefix = path_and_query.path().strip_prefix(prefix)?;
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-    let start_slash = after_prefix.starts_with('/');
+impl Route {
+    async fn start(
+        route_conf: RouteConfig,
+        backend: Backend,
+        ctx: RouterContext,
+    ) -> FaucetResult<Route> {
+        let name = leak!(route_conf.name(), str);
+        let (matcher, param_headers) = route_conf.matcher()?;
+        let shutdown = route_shutdown(ctx.shutdown);
+        let started = match backend {
+            Backend::Workers(server_config) => server_config
+                .extract_service(shutdown, ctx.websocket_config)
+                .await
+                .map(|(service, workers)| (RouteService::Workers(service), workers)),
+            Backend::Static(files) => Ok((
+                RouteService::Static(files),
+                WorkerConfigs {
+                    workers: Box::new([]),
+                },
+            )),
+        };
+        match started {
+            Ok((service, workers)) => Ok(Route {
+                name,
+                matcher,
+                param_headers,
+                conf: route_conf,
+                service,
+                workers,
+                shutdown,
+                in_flight: AtomicUsize::new(0),
+            }),
+            Err(e) => {
+                shutdown.shutdown();
+                Err(e)
+            }
+        }
+    }
 
-    Some(match (start_slash, path_and_query.query()) {
-        (true, None) => after_prefix.parse().unwrap(),
-        (true, Some(query)) => format!("{after_prefix}?{query}").parse().unwrap(),
-        (false, None) => format!("/{after_prefix}").parse().unwrap(),
-        (false, Some(query)) => format!("/{after_prefix}?{query}").parse().unwrap(),
-    })
-}
+    fn worker_group(&self) -> admin::WorkerGroup {
+        admin::WorkerGroup {
+            route: Some(self.name),
+            workers: self.workers.workers.clone(),
+        }
+    }
 
-fn strip_prefix(uri: &Uri, prefix: &str) -> Option<Uri> {
-    let path_and_query = uri.path_and_query()?;
+    async fn call(
+        &self,
+        req: hyper::Request<Incoming>,
+        ip_addr: Option<std::net::IpAddr>,
+    ) -> FaucetResult<hyper::Response<ExclusiveBody>> {
+        let _guard = InFlightGuard::new(&self.in_flight);
+        match &self.service {
+            RouteService::Workers(service) => service.call(req, ip_addr).await,
+            RouteService::Static(files) => Ok(match files.serve(&req).await {
+                Some(response) => response,
+                None => not_found(),
+            }),
+        }
+    }
+
+    /// Requests and WebSocket sessions the route is still serving.
+    async fn open_connections(&self) -> usize {
+        let addrs = self
+            .workers
+            .workers
+            .iter()
+            .flat_map(|worker| std::iter::once(worker.addr()).chain(worker.previous_addr()))
+            .collect::<HashSet<_>>();
+        let connections = addrs
+            .iter()
+            .map(|addr| worker_connections(*addr).max(0) as usize)
+            .sum::<usize>();
+        let sessions = websocket_sessions()
+            .await
+            .iter()
+            .filter(|session| {
+                !session.purged
+                    && session
+                        .worker_addr
+                        .is_some_and(|addr| addrs.contains(&addr))
+            })
+            .count();
+        self.in_flight.load(Ordering::SeqCst) + connections + sessions
+    }
+
+    /// Waits for the connections of a route removed from the table to
+    /// finish, or for the drain timeout, and then stops its workers.
+    async fn drain(self: Arc<Self>, timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let open = self.open_connections().await;
+            if open == 0 {
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                log::warn!(target: "faucet", "Route {} reached the drain timeout with {open} open connections", self.name);
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(DRAIN_RECHECK_INTERVAL) => (),
+                _ = self.shutdown.wait() => break,
+            }
+        }
+        log::info!(target: "faucet", "Stopping the workers of removed route {}", self.name);
+        self.shutdown.shutdown();
+    }
+}
 
-    let new_path_and_query = match prefix.ends_with('/') {
-        true => strip_prefix_relative(path_and_query, prefix)?,
-        false => strip_prefix_exact(path_and_query, prefix)?,
-    };
+fn not_found() -> hyper::Response<ExclusiveBody> {
+    hyper::Response::builder()
+        .status(404)
+        .body(ExclusiveBody::plain_text("404 not found"))
+        .expect("Response should build")
+}
 
-    let mut parts = uri.clone().into_parts();
-    parts.path_and_query = Some(new_path_and_query);
+/// A shutdown signal for a single route, also triggered when faucet shuts down.
+fn route_shutdown(shutdown: &'static ShutdownSignal) -> &'static ShutdownSignal {
+    let route_shutdown = leak!(ShutdownSignal::new()) as &'static ShutdownSignal;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown.wait() => route_shutdown.shutdown(),
+            _ = route_shutdown.wait() => (),
+        }
+    });
+    route_shutdown
+}
 
-    Some(Uri::from_parts(parts).unwrap())
+impl RouterService {
+    fn table(&self) -> RouteTable {
+        self.table
+            .read()
+            .expect("Route table lock poisoned")
+            .clone()
+    }
+    /// The route serving `req`. Routes for a specific host come first, then
+    /// the route matching the longest part of the path. Ties go to the
+    /// first route defined.
+    fn select<B>(&self, req: &Request<B>) -> Option<(Arc<Route>, PathMatch)> {
+        let host = host::request_host(req);
+        let mut best = None;
+        for candidate in self.table().iter() {
+            let host_rank = match &candidate.conf.host {
+                None => (0, 0),
+                Some(pattern) => match host.as_deref().filter(|host| pattern.matches(host)) {
+                    Some(_) => pattern.specificity(),
+                    None => continue,
+                },
+            };
+            let Some(found) = candidate.matcher.matches(req.uri()) else {
+                continue;
+            };
+            let priority = (host_rank, found.len, candidate.matcher.rank());
+            match &best {
+                Some((best_priority, _, _)) if *best_priority >= priority => (),
+                _ => best = Some((priority, Arc::clone(candidate), found)),
+            }
+        }
+        best.map(|(_, route, found)| (route, found))
+    }
+    /// Replaces the routing table, returning the previous one.
+    fn swap(&self, table: RouteTable) -> RouteTable {
+        std::mem::replace(
+            &mut *self.table.write().expect("Route table lock poisoned"),
+            table,
+        )
+    }
 }
 
 impl Service<hyper::Request<Incoming>> for RouterService {
@@ -99,72 +401,155 @@ impl Service<hyper::Request<Incoming>> for RouterService {
         mut req: hyper::Request<Incoming>,
         ip_addr: Option<std::net::IpAddr>,
     ) -> Result<Self::Response, Self::Error> {
-        let mut client = None;
-        for i in 0..self.routes.len() {
-            let route = &self.routes[i];
-            if let Some(new_uri) = strip_prefix(req.uri(), route) {
-                client = Some(&self.clients[i]);
-                *req.uri_mut() = new_uri;
-                break;
+        let route = self.select(&req);
+        match route {
+            None => Ok(not_found()),
+            Some((route, found)) => {
+                *req.uri_mut() = found.uri;
+                route.param_headers.apply(&mut req, &found.params);
+                route.call(req, ip_addr).await
             }
         }
-        match client {
-            None => Ok(hyper::Response::builder()
-                .status(404)
-                .body(ExclusiveBody::plain_text("404 not found"))
-                .expect("Response should build")),
-            Some(client) => client.call(req, ip_addr).await,
+    }
+}
+
+impl RouteConfig {
+    /// The host and prefix of the route, such as `app.example.com/api/`,
+    /// or only the prefix for routes matching any host.
+    fn name(&self) -> String {
+        let path = self
+            .route
+            .as_deref()
+            .or(self.regex.as_deref())
+            .unwrap_or("/");
+        match &self.host {
+            Some(host) => format!("{host}{path}"),
+            None => path.to_string(),
+        }
+    }
+
+    /// The prefix every path matched by the route starts with: `route`
+    /// up to its first parameter, or `/` for regex routes.
+    fn cookie_path(&self) -> &str {
+        let Some(route) = self.route.as_deref() else {
+            return "/";
+        };
+        let Some(param) = route.find('{') else {
+            return route;
+        };
+        // Up to the `/` before the parameter
+        match route[..param].rfind('/') {
+            Some(i) if i > 0 => &route[..=i],
+            _ => "/",
         }
     }
+
+    /// The cookie settings of the route, with the path defaulting to the
+    /// route's prefix.
+    fn lb_cookie(&self) -> LbCookieConfig {
+        let mut lb_cookie = self.config.lb_cookie.clone().unwrap_or_default();
+        lb_cookie
+            .path
+            .get_or_insert_with(|| self.cookie_path().to_string());
+        lb_cookie
+    }
+
+    fn path_matcher(&self) -> Result<PathMatcher, String> {
+        PathMatcher::new(self.route.as_deref(), self.regex.as_deref())
+    }
+
+    fn matcher(&self) -> FaucetResult<(PathMatcher, ParamHeaders)> {
+        let invalid = |e| FaucetError::InvalidConfig(format!("Route {}: {e}", self.name()));
+        let matcher = self.path_matcher().map_err(invalid)?;
+        let param_headers = ParamHeaders::new(&matcher, &self.param_headers).map_err(invalid)?;
+        Ok((matcher, param_headers))
+    }
+
+    /// Validates the route's settings without starting anything.
+    fn backend(&self, ctx: RouterContext) -> FaucetResult<Backend> {
+        let server_type = match self.config.server_type {
+            RouteType::Static => {
+                return Ok(Backend::Static(StaticFiles::new(&self.config.workdir)?))
+            }
+            RouteType::App(server_type) => server_type,
+        };
+        let workers = match server_type {
+            WorkerType::Upstream if self.config.upstream.is_empty() => {
+                return Err(FaucetError::MissingArgument("upstream"))
+            }
+            WorkerType::Upstream => self.config.upstream.len(),
+            _ => self
+                .config
+                .workers
+                .ok_or(FaucetError::MissingArgument("workers"))?
+                .get(),
+        };
+        let server_config = FaucetServerBuilder::new()
+            .workdir(&self.config.workdir)
+            .server_type(server_type)
+            .strategy(self.config.strategy)
+            .rscript(ctx.rscript)
+            .uv(ctx.uv)
+            .quarto(ctx.quarto)
+            .qmd(self.config.qmd.as_deref())
+            .workers(workers)
+            .extractor(ctx.ip_from)
+            .app_dir(self.config.app_dir.as_deref())
+            .entrypoint(self.config.entrypoint.as_deref())
+            .r_options(
+                self.config
+                    .r_options
+                    .iter()
+                    .map(|(name, value)| ROption {
+                        name: name.clone(),
+                        value: value.clone(),
+                    })
+                    .collect(),
+            )
+            .static_dir(self.config.static_dir.as_deref())
+            .upstream(self.config.upstream.clone())
+            .command(self.config.command.clone())
+            .env(self.config.env.clone())
+            .env_file(self.config.env_file.as_deref())
+            .secrets(self.config.secrets.clone())
+            .limits(self.config.limits.clone())
+            .recycle(self.config.recycle.clone())
+            .sample_usage(ctx.sample_usage)
+            .queue(self.config.queue.clone())
+            .hash_key(self.config.hash_key.clone())
+            .lb_cookie(Some(self.lb_cookie()))
+            .route(self.name())
+            .max_rps(self.config.max_rps)
+            .health_check(self.config.health_check.clone())
+            .drain_timeout(Some(ctx.drain_timeout))
+            .watch(self.config.watch)
+            .build()?;
+        Ok(Backend::Workers(Box::new(server_config)))
+    }
 }
 
 impl RouterConfig {
-    async fn into_service(
-        self,
-        rscript: impl AsRef<OsStr>,
-        quarto: impl AsRef<OsStr>,
-        uv: impl AsRef<OsStr>,
-        ip_from: IpExtractor,
-        shutdown: &'static ShutdownSignal,
-        websocket_config: &'static WebSocketConfig,
-    ) -> FaucetResult<(RouterService, Vec<WorkerConfigs>)> {
-        let mut all_workers = Vec::with_capacity(self.route.len());
-        let mut routes = Vec::with_capacity(self.route.len());
-        let mut clients = Vec::with_capacity(self.route.len());
-        let mut routes_set = HashSet::with_capacity(self.route.len());
-        for route_conf in self.route.into_iter() {
-            let route = route_conf.route;
-            if !routes_set.insert(route.clone()) {
-                return Err(FaucetError::DuplicateRoute(route));
-            }
-            let (client, workers) = FaucetServerBuilder::new()
-                .workdir(route_conf.config.workdir)
-                .server_type(route_conf.config.server_type)
-                .strategy(route_conf.config.strategy)
-                .rscript(&rscript)
-                .uv(&uv)
-                .quarto(&quarto)
-                .qmd(route_conf.config.qmd)
-                .workers(route_conf.config.workers.get())
-                .extractor(ip_from)
-                .app_dir(route_conf.config.app_dir)
-                .route(route.clone())
-                .max_rps(route_conf.config.max_rps)
-                .build()?
-                .extract_service(shutdown, websocket_config)
-                .await?;
-            routes.push(route);
-            all_workers.push(workers);
-            clients.push(client);
+    /// Reads and validates the router configuration at `path`.
+    pub fn load(path: impl AsRef<Path>) -> FaucetResult<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            FaucetError::InvalidConfig(format!("Unable to read {}: {e}", path.display()))
+        })?;
+        let mut config: RouterConfig = toml::from_str(&content)
+            .map_err(|e| FaucetError::InvalidConfig(format!("{}: {e}", path.display())))?;
+        let problems = validate::problems(&config, &content);
+        if !problems.is_empty() {
+            return Err(FaucetError::InvalidConfig(validate::report(
+                path, &content, &problems,
+            )));
         }
-        let routes = routes.leak();
-        let clients = clients.into();
-        let service = RouterService { clients, routes };
-        Ok((service, all_workers))
+        config.source = Some(leak!(path.to_path_buf(), Path));
+        Ok(config)
     }
 }
 
 impl RouterConfig {
+    #[allow(clippy::too_many_arguments)]
     pub async fn run(
         self,
         rscript: impl AsRef<OsStr>,
@@ -175,12 +560,44 @@ impl RouterConfig {
         shutdown: &'static ShutdownSignal,
         websocket_config: &'static WebSocketConfig,
     ) -> FaucetResult<()> {
-        let (service, all_workers) = self
-            .into_service(rscript, quarto, uv, ip_from, shutdown, websocket_config)
-            .await?;
+        let tls_acceptor = self
+            .tls
+            .as_ref()
+            .map(|tls| tls.build_acceptor(shutdown))
+            .transpose()?;
+        let scheme = if tls_acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let ctx = RouterContext {
+            rscript: leak!(rscript.as_ref().to_os_string(), OsStr),
+            quarto: leak!(quarto.as_ref().to_os_string(), OsStr),
+            uv: leak!(uv.as_ref().to_os_string(), OsStr),
+            ip_from,
+            drain_timeout: self
+                .drain_timeout
+                .map_or(worker::DEFAULT_DRAIN_TIMEOUT, Duration::from_secs_f64),
+            sample_usage: self.admin.is_some() || self.metrics.is_some(),
+            shutdown,
+            websocket_config,
+        };
+        let router = Arc::new(Router::new(ctx));
+        router.apply(self.route).await?;
+        let groups = router.groups.clone();
+        if let Some(admin_config) = self.admin {
+            admin::spawn(admin_config, groups.clone(), shutdown).await?;
+        }
+        if let Some(metrics_config) = self.metrics {
+            admin::spawn_metrics(metrics_config, groups, shutdown).await?;
+        }
+        if let Some(source) = self.source {
+            reload::spawn(router.clone(), source)?;
+        }
+        let service = router.service.clone();
         // Bind to the port and listen for incoming TCP connections
         let listener = TcpListener::bind(addr).await?;
-        log::info!(target: "faucet", "Listening on http://{addr}");
+        log::info!(target: "faucet", "Listening on {scheme}://{addr}");
         let main_loop = || async {
             loop {
                 match listener.accept().await {
@@ -189,22 +606,36 @@ impl RouterConfig {
                         return;
                     }
                     Ok((tcp, client_addr)) => {
-                        let tcp = TokioIo::new(tcp);
                         log::debug!(target: "faucet", "Accepted TCP connection from {client_addr}");
 
                         let service = service.clone();
+                        let tls_acceptor = tls_acceptor.clone();
 
                         tokio::task::spawn(async move {
-                            let mut conn = http1::Builder::new()
-                                .serve_connection(
-                                    tcp,
-                                    service_fn(|req: Request<Incoming>| {
-                                        service.call(req, Some(client_addr.ip()))
-                                    }),
-                                )
-                                .with_upgrades();
+                            let cleartext = tls_acceptor.is_none();
+                            let handle = move |req: Request<Incoming>| {
+                                let service = service.clone();
+                                async move { service.call(req, Some(client_addr.ip())).await }
+                            };
+                            let stream = match FaucetStream::accept(tcp, tls_acceptor.as_ref())
+                                .await
+                            {
+                                Ok(stream) => TokioIo::new(stream),
+                                Err(e) => {
+                                    log::debug!(target: "faucet", "TLS handshake with {client_addr} failed: {e}");
+                                    return;
+                                }
+                            };
 
-                            let conn = pin!(&mut conn);
+                            let builder = connection_builder();
+                            let conn = builder.serve_connection_with_upgrades(
+                                stream,
+                                service_fn(move |req: Request<Incoming>| {
+                                    h2c::serve(req, cleartext, handle.clone(), shutdown)
+                                }),
+                            );
+
+                            let conn = pin!(conn);
 
                             tokio::select! {
                                 result = conn => {
@@ -227,9 +658,7 @@ impl RouterConfig {
         }
 
         // Kill child process
-        for w in all_workers.iter().flat_map(|ws| &ws.workers) {
-            w.wait_until_done().await;
-        }
+        router.wait_until_done().await;
 
         FaucetResult::Ok(())
     }
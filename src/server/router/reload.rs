@@ -0,0 +1,456 @@
+//! Runtime reloads of the router configuration.
+//!
+//! The new configuration is compared to the routes being served. Routes
+//! whose settings did not change keep their workers, new or changed routes
+//! get new workers, and the routing table is swapped in one go. Removed
+//! routes stop receiving requests right away and their workers are stopped
+//! once their connections finish.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use super::{Backend, Route, RouteConfig, RouteTable, RouterConfig, RouterContext, RouterService};
+use crate::{client::worker::WorkerConfig, error::FaucetResult, server::admin::WorkerGroups};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Editors may write the file in several steps, wait for it to settle.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+pub(super) struct Router {
+    pub service: RouterService,
+    pub groups: WorkerGroups,
+    ctx: RouterContext,
+    /// Every worker started since faucet started, including the ones of
+    /// removed routes, to wait for them on shutdown.
+    started: Mutex<Vec<&'static WorkerConfig>>,
+}
+
+enum Planned {
+    Keep(Arc<Route>),
+    Start(Box<RouteConfig>, Backend),
+}
+
+impl Router {
+    pub fn new(ctx: RouterContext) -> Self {
+        Router {
+            service: RouterService {
+                table: Arc::new(RwLock::new(Arc::new([]))),
+            },
+            groups: WorkerGroups::new(Vec::new()),
+            ctx,
+            started: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Makes `routes` the routing table. Nothing changes if any route fails
+    /// to start.
+    pub async fn apply(&self, routes: Vec<RouteConfig>) -> FaucetResult<()> {
+        let current = self.service.table();
+
+        // Every route is validated before starting any worker.
+        let mut plan = Vec::with_capacity(routes.len());
+        for route_conf in routes {
+            let existing = current.iter().find(|route| route.conf == route_conf);
+            plan.push(match existing {
+                Some(route) => Planned::Keep(Arc::clone(route)),
+                None => {
+                    route_conf.matcher()?;
+                    let backend = route_conf.backend(self.ctx)?;
+                    Planned::Start(Box::new(route_conf), backend)
+                }
+            });
+        }
+
+        let mut table = Vec::with_capacity(plan.len());
+        let mut started = Vec::new();
+        for planned in plan {
+            let route = match planned {
+                Planned::Keep(route) => route,
+                Planned::Start(route_conf, backend) => {
+                    match Route::start(*route_conf, backend, self.ctx).await {
+                        Ok(route) => {
+                            let route = Arc::new(route);
+                            started.push(Arc::clone(&route));
+                            route
+                        }
+                        Err(e) => {
+                            for route in started {
+                                route.shutdown.shutdown();
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+            table.push(route);
+        }
+
+        self.started
+            .lock()
+            .expect("Started workers lock poisoned")
+            .extend(
+                started
+                    .iter()
+                    .flat_map(|route| route.workers.workers.iter().copied()),
+            );
+
+        let table: RouteTable = table.into();
+        let previous = self.service.swap(Arc::clone(&table));
+        self.groups
+            .set(table.iter().map(|route| route.worker_group()).collect());
+
+        let removed = previous
+            .iter()
+            .filter(|route| !table.iter().any(|kept| Arc::ptr_eq(kept, route)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !current.is_empty() {
+            log::info!(
+                target: "faucet",
+                "Routes reloaded: {} started, {} removed, {} unchanged",
+                started.len(),
+                removed.len(),
+                table.len() - started.len()
+            );
+        }
+        for route in removed {
+            log::info!(target: "faucet", "Draining removed route {}", route.name);
+            tokio::spawn(route.drain(self.ctx.drain_timeout));
+        }
+        Ok(())
+    }
+
+    /// Reads `source` again and applies its routes. Top-level settings
+    /// such as `tls` or `admin` only take effect on restart.
+    pub async fn reload(&self, source: &Path) -> FaucetResult<()> {
+        let config = RouterConfig::load(source)?;
+        self.apply(config.route).await
+    }
+
+    pub async fn wait_until_done(&self) {
+        let started = self
+            .started
+            .lock()
+            .expect("Started workers lock poisoned")
+            .clone();
+        for worker in started {
+            worker.wait_until_done().await;
+        }
+    }
+}
+
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Hangup(signal(SignalKind::hangup())?))
+    }
+    async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> std::io::Result<Self> {
+        Ok(Hangup)
+    }
+    async fn recv(&mut self) {
+        std::future::pending::<()>().await
+    }
+}
+
+/// Reloads `source` on SIGHUP and whenever the file changes. Invalid
+/// configurations are logged and the current routes are kept.
+pub(super) fn spawn(router: Arc<Router>, source: &'static Path) -> FaucetResult<()> {
+    let mut hangup = Hangup::new()?;
+    let shutdown = router.ctx.shutdown;
+    tokio::spawn(async move {
+        let mut stamp = file_stamp(source);
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = hangup.recv() => {
+                    log::info!(target: "faucet", "Received SIGHUP, reloading {source:?}");
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {
+                    if file_stamp(source) == stamp {
+                        continue;
+                    }
+                    loop {
+                        let latest = file_stamp(source);
+                        tokio::time::sleep(DEBOUNCE).await;
+                        if file_stamp(source) == latest {
+                            break;
+                        }
+                    }
+                    log::info!(target: "faucet", "{source:?} changed, reloading");
+                }
+            }
+            stamp = file_stamp(source);
+            if let Err(e) = router.reload(source).await {
+                log::error!(target: "faucet", "Unable to reload the router configuration, keeping the current routes: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use hyper::Request;
+    use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+    use super::*;
+    use crate::server::router::RouteService;
+    use crate::{
+        client::load_balancing::IpExtractor, error::FaucetError, leak, shutdown::ShutdownSignal,
+        testing::TempDir,
+    };
+
+    fn router() -> Router {
+        Router::new(RouterContext {
+            rscript: OsStr::new("Rscript"),
+            quarto: OsStr::new("quarto"),
+            uv: OsStr::new("uv"),
+            ip_from: IpExtractor::ClientAddr,
+            drain_timeout: Duration::from_secs(5),
+            sample_usage: false,
+            shutdown: leak!(ShutdownSignal::new()),
+            websocket_config: leak!(WebSocketConfig::default()),
+        })
+    }
+
+    fn routes(config: &str) -> Vec<RouteConfig> {
+        toml::from_str::<RouterConfig>(config).unwrap().route
+    }
+
+    fn route(prefix: &str, workers: usize) -> String {
+        format!("[[route]]\nroute = \"{prefix}\"\nworkers = {workers}\nserver_type = \"Dummy\"\n")
+    }
+
+    fn find(table: &RouteTable, prefix: &str) -> Arc<Route> {
+        table
+            .iter()
+            .find(|route| route.name == prefix)
+            .cloned()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_unchanged_routes_and_drains_removed_ones() {
+        let router = router();
+        router
+            .apply(routes(
+                &(route("/a/", 1) + &route("/b/", 1) + &route("/c/", 1)),
+            ))
+            .await
+            .unwrap();
+        let before = router.service.table();
+
+        router
+            .apply(routes(
+                &(route("/d/", 1) + &route("/a/", 1) + &route("/b/", 2)),
+            ))
+            .await
+            .unwrap();
+        let after = router.service.table();
+
+        let prefixes = after.iter().map(|route| route.name).collect::<Vec<_>>();
+        assert_eq!(prefixes, ["/d/", "/a/", "/b/"]);
+        assert!(Arc::ptr_eq(&find(&before, "/a/"), &find(&after, "/a/")));
+        assert!(!Arc::ptr_eq(&find(&before, "/b/"), &find(&after, "/b/")));
+        assert_eq!(find(&after, "/b/").workers.workers.len(), 2);
+
+        let groups = router.groups.get();
+        assert_eq!(
+            groups.iter().map(|group| group.route).collect::<Vec<_>>(),
+            [Some("/d/"), Some("/a/"), Some("/b/")]
+        );
+
+        for removed in ["/b/", "/c/"] {
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                find(&before, removed).shutdown.wait(),
+            )
+            .await
+            .expect("Removed routes should be stopped");
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_restarts_routes_with_new_matching_settings() {
+        let router = router();
+        let tenant = route("/tenant/{id}/", 1);
+        let regex = "[[route]]\nregex = \"/x/\"\nworkers = 1\nserver_type = \"Dummy\"\n";
+        router
+            .apply(routes(
+                &(tenant.clone() + "param_headers = { id = \"X-Tenant-Id\" }\n" + regex),
+            ))
+            .await
+            .unwrap();
+        let before = router.service.table();
+
+        router
+            .apply(routes(
+                &(tenant + "param_headers = { id = \"X-Tenant\" }\n" + &route("/x/", 1)),
+            ))
+            .await
+            .unwrap();
+        let after = router.service.table();
+
+        for name in ["/tenant/{id}/", "/x/"] {
+            assert!(
+                !Arc::ptr_eq(&find(&before, name), &find(&after, name)),
+                "{name} should be restarted"
+            );
+        }
+        let req = Request::builder().uri("/x/y/z").body(()).unwrap();
+        let (_, found) = router.service.select(&req).unwrap();
+        assert_eq!(found.uri, "/y/z");
+    }
+
+    #[tokio::test]
+    async fn invalid_routes_leave_the_table_untouched() {
+        let router = router();
+        router.apply(routes(&route("/a/", 1))).await.unwrap();
+        let before = router.service.table();
+
+        let invalid = route("/b/", 1) + "[route.health_check]\npath = \"no-slash\"\n";
+        let result = router.apply(routes(&(route("/a/", 1) + &invalid))).await;
+        assert!(result.is_err());
+
+        let after = router.service.table();
+        assert!(Arc::ptr_eq(&before, &after));
+    }
+
+    #[tokio::test]
+    async fn routes_are_selected_by_host_and_longest_match() {
+        let router = router();
+        let config = route("/", 1)
+            + &route("/tenant/{id}/", 1)
+            + "param_headers = { id = \"X-Tenant-Id\" }\n"
+            + &route("/tenant/admin/", 1)
+            + &route("/tenant/", 1)
+            + "[[route]]\nregex = \"/v(?P<version>[0-9]+)/\"\nworkers = 1\nserver_type = \"Dummy\"\n"
+            + "[[route]]\nhost = \"*.example.com\"\nworkers = 1\nserver_type = \"Dummy\"\n";
+        router.apply(routes(&config)).await.unwrap();
+
+        let select = |host: &str, uri: &str| {
+            let req = Request::builder()
+                .uri(uri)
+                .header("host", host)
+                .body(())
+                .unwrap();
+            router
+                .service
+                .select(&req)
+                .map(|(route, found)| (route.name, found.uri.to_string(), found.params))
+        };
+        let param = |value: &str| vec![("id".to_string(), value.to_string())];
+
+        assert_eq!(select("localhost", "/x"), Some(("/", "/x".into(), vec![])));
+        assert_eq!(
+            select("localhost", "/tenant/acme/report"),
+            Some(("/tenant/{id}/", "/report".into(), param("acme")))
+        );
+        assert_eq!(
+            select("localhost", "/tenant/admin/users"),
+            Some(("/tenant/admin/", "/users".into(), vec![]))
+        );
+        assert_eq!(
+            select("localhost", "/tenant/"),
+            Some(("/tenant/", "/".into(), vec![]))
+        );
+        assert_eq!(
+            select("localhost", "/v2/items").map(|(name, uri, _)| (name, uri)),
+            Some(("/v(?P<version>[0-9]+)/", "/items".into()))
+        );
+        assert_eq!(
+            select("acme.example.com", "/tenant/acme/report"),
+            Some(("*.example.com/", "/tenant/acme/report".into(), vec![]))
+        );
+    }
+
+    #[tokio::test]
+    async fn static_routes_serve_files_without_workers() {
+        let dir = TempDir::new("faucet-static-route");
+        std::fs::write(dir.join("logo.svg"), "<svg/>").unwrap();
+
+        let router = router();
+        let config = format!(
+            "[[route]]\nroute = \"/assets/\"\nserver_type = \"static\"\nworkdir = {:?}\n",
+            dir.display().to_string()
+        );
+        router.apply(routes(&config)).await.unwrap();
+        let route = find(&router.service.table(), "/assets/");
+        assert!(route.workers.workers.is_empty());
+
+        let RouteService::Static(files) = &route.service else {
+            panic!("Static routes should not start workers");
+        };
+        let req = Request::builder().uri("/logo.svg").body(()).unwrap();
+        let response = files.serve(&req).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "image/svg+xml");
+        let req = Request::builder().uri("/missing.svg").body(()).unwrap();
+        assert!(files.serve(&req).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn upstream_routes_have_a_worker_per_upstream() {
+        let first = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (first, second) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+
+        let router = router();
+        let config = format!(
+            "[[route]]\nroute = \"/node/\"\nserver_type = \"upstream\"\nupstream = [\"http://{first}\", \"{second}\"]\n"
+        );
+        router.apply(routes(&config)).await.unwrap();
+
+        let route = find(&router.service.table(), "/node/");
+        let addrs = route
+            .workers
+            .workers
+            .iter()
+            .map(|worker| worker.addr())
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [first, second]);
+        route.shutdown.shutdown();
+    }
+
+    #[test]
+    fn load_reports_the_file_with_errors() {
+        let dir = TempDir::new("faucet-reload");
+        let path = dir.join("frouter.toml");
+
+        std::fs::write(&path, "[[route]]\nroute = \"/a/\"\n").unwrap();
+        let error = RouterConfig::load(&path).err().unwrap().to_string();
+        assert!(error.contains(&path.display().to_string()), "{error}");
+
+        std::fs::write(&path, route("/a/", 1) + &route("/a/", 2)).unwrap();
+        let error = RouterConfig::load(&path).err().unwrap();
+        assert!(
+            matches!(&error, FaucetError::InvalidConfig(report) if report.contains(":6:9: route \"/a/\" is already defined on line 1")),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,1094 @@
+//! Checks of the router configuration that go beyond parsing it, so that
+//! mistakes are reported on startup with their position in the file
+//! instead of showing up as failing workers or unreachable routes.
+
+use std::{ops::Range, path::Path};
+
+use toml::Spanned;
+
+use super::{ParamHeaders, RouteConfig, RouteType, RouterConfig};
+use crate::{
+    cli::{is_dash, is_plumber, is_shiny, streamlit_script},
+    client::{
+        load_balancing::Strategy,
+        worker::{upstream_authority, WorkerType},
+        WorkerEnv,
+    },
+    error::FaucetError,
+};
+
+/// Positions of the fields of a route in the TOML source.
+#[derive(serde::Deserialize)]
+struct RouteSpans {
+    route: Option<Spanned<toml::Value>>,
+    regex: Option<Spanned<toml::Value>>,
+    host: Option<Spanned<toml::Value>>,
+    param_headers: Option<Spanned<toml::Value>>,
+    server_type: Option<Spanned<toml::Value>>,
+    workers: Option<Spanned<toml::Value>>,
+    strategy: Option<Spanned<toml::Value>>,
+    workdir: Option<Spanned<toml::Value>>,
+    app_dir: Option<Spanned<toml::Value>>,
+    qmd: Option<Spanned<toml::Value>>,
+    entrypoint: Option<Spanned<toml::Value>>,
+    r_options: Option<Spanned<toml::Value>>,
+    static_dir: Option<Spanned<toml::Value>>,
+    upstream: Option<Spanned<toml::Value>>,
+    command: Option<Spanned<toml::Value>>,
+    env: Option<Spanned<toml::Value>>,
+    env_file: Option<Spanned<toml::Value>>,
+    secrets: Option<Spanned<toml::Value>>,
+    limits: Option<Spanned<toml::Value>>,
+    recycle: Option<Spanned<toml::Value>>,
+    queue: Option<Spanned<toml::Value>>,
+    hash_key: Option<Spanned<toml::Value>>,
+    lb_cookie: Option<Spanned<toml::Value>>,
+    max_rps: Option<Spanned<toml::Value>>,
+    health_check: Option<Spanned<toml::Value>>,
+    watch: Option<Spanned<toml::Value>>,
+}
+
+#[derive(serde::Deserialize)]
+struct ConfigSpans {
+    #[serde(default)]
+    route: Vec<Spanned<RouteSpans>>,
+}
+
+pub(super) struct Problem {
+    span: Option<Range<usize>>,
+    message: String,
+}
+
+/// 1-based line and column of the byte `offset` in `source`.
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Where a field of a route is in the TOML source.
+type FieldSpan = fn(&RouteSpans) -> Option<Range<usize>>;
+
+fn span_of(field: &Option<Spanned<toml::Value>>) -> Option<Range<usize>> {
+    field.as_ref().map(Spanned::span)
+}
+
+/// The message of `error`, without the "Invalid configuration" prefix
+/// that is redundant in a list of problems.
+fn message(error: FaucetError) -> String {
+    match error {
+        FaucetError::InvalidConfig(message) => message,
+        error => error.to_string(),
+    }
+}
+
+struct RouteCheck<'a> {
+    route: &'a RouteConfig,
+    spans: Option<&'a Spanned<RouteSpans>>,
+    problems: &'a mut Vec<Problem>,
+}
+
+impl RouteCheck<'_> {
+    /// Span of `field`, or of the whole route if the field is not set.
+    fn span(&self, field: impl Fn(&RouteSpans) -> Option<Range<usize>>) -> Option<Range<usize>> {
+        let spans = self.spans?;
+        field(spans.get_ref()).or_else(|| Some(spans.span()))
+    }
+
+    fn problem(&mut self, field: impl Fn(&RouteSpans) -> Option<Range<usize>>, message: String) {
+        let span = self.span(field);
+        self.problems.push(Problem { span, message });
+    }
+
+    /// Reports the `fields` that are set but not used by the route, which
+    /// would otherwise be silently ignored.
+    fn check_unused(&mut self, fields: &[&str], routes: &str) {
+        let config = &self.route.config;
+        let set: [(&str, bool, FieldSpan); 20] = [
+            ("workers", config.workers.is_some(), |s| span_of(&s.workers)),
+            ("strategy", config.strategy.is_some(), |s| {
+                span_of(&s.strategy)
+            }),
+            ("app_dir", config.app_dir.is_some(), |s| span_of(&s.app_dir)),
+            ("qmd", config.qmd.is_some(), |s| span_of(&s.qmd)),
+            ("entrypoint", config.entrypoint.is_some(), |s| {
+                span_of(&s.entrypoint)
+            }),
+            ("r_options", !config.r_options.is_empty(), |s| {
+                span_of(&s.r_options)
+            }),
+            ("static_dir", config.static_dir.is_some(), |s| {
+                span_of(&s.static_dir)
+            }),
+            ("upstream", !config.upstream.is_empty(), |s| {
+                span_of(&s.upstream)
+            }),
+            ("command", config.command.is_some(), |s| span_of(&s.command)),
+            ("env", !config.env.is_empty(), |s| span_of(&s.env)),
+            ("env_file", config.env_file.is_some(), |s| {
+                span_of(&s.env_file)
+            }),
+            ("secrets", !config.secrets.is_empty(), |s| {
+                span_of(&s.secrets)
+            }),
+            ("limits", config.limits.is_some(), |s| span_of(&s.limits)),
+            ("recycle", config.recycle.is_some(), |s| span_of(&s.recycle)),
+            ("queue", config.queue.is_some(), |s| span_of(&s.queue)),
+            ("hash_key", config.hash_key.is_some(), |s| {
+                span_of(&s.hash_key)
+            }),
+            ("lb_cookie", config.lb_cookie.is_some(), |s| {
+                span_of(&s.lb_cookie)
+            }),
+            ("max_rps", config.max_rps.is_some(), |s| span_of(&s.max_rps)),
+            ("health_check", config.health_check.is_some(), |s| {
+                span_of(&s.health_check)
+            }),
+            ("watch", config.watch, |s| span_of(&s.watch)),
+        ];
+        for (field, set, span) in set {
+            if set && fields.contains(&field) {
+                self.problem(span, format!("`{field}` is not used by {routes}"));
+            }
+        }
+    }
+
+    fn check_files(&mut self) {
+        let config = &self.route.config;
+        let workdir = &config.workdir;
+        if !workdir.is_dir() {
+            self.problem(
+                |s| span_of(&s.workdir),
+                format!("workdir {workdir:?} does not exist"),
+            );
+            return;
+        }
+
+        let server_type = match config.server_type {
+            RouteType::Static => return,
+            RouteType::App(server_type) => server_type,
+        };
+
+        if let Some(static_dir) = &config.static_dir {
+            if !workdir.join(static_dir).is_dir() {
+                self.problem(
+                    |s| span_of(&s.static_dir),
+                    format!("static_dir {static_dir:?} does not exist in {workdir:?}"),
+                );
+            }
+        }
+
+        if server_type != WorkerType::Upstream {
+            self.check_env();
+            self.check_limits();
+        }
+
+        // Neither runs an application from `app_dir`.
+        if matches!(server_type, WorkerType::Upstream | WorkerType::Command) {
+            return;
+        }
+
+        let app_dir = match &config.app_dir {
+            Some(app_dir) => {
+                let path = workdir.join(app_dir);
+                if !path.is_dir() {
+                    self.problem(
+                        |s| span_of(&s.app_dir),
+                        format!("app_dir {app_dir:?} does not exist in {workdir:?}"),
+                    );
+                    return;
+                }
+                path
+            }
+            None => workdir.clone(),
+        };
+
+        let entrypoint = config
+            .entrypoint
+            .as_deref()
+            .filter(|_| server_type.has_entrypoint())
+            .or_else(|| server_type.default_entrypoint(&app_dir));
+        match (server_type, entrypoint) {
+            (WorkerType::Plumber, None) if !is_plumber(&app_dir) => self.problem(
+                |s| span_of(&s.app_dir).or(span_of(&s.workdir)),
+                format!("no plumber.R or entrypoint.R found in {app_dir:?}"),
+            ),
+            (_, Some(entrypoint)) if !app_dir.join(entrypoint).is_file() => self.problem(
+                |s| {
+                    span_of(&s.entrypoint)
+                        .or(span_of(&s.app_dir))
+                        .or(span_of(&s.workdir))
+                },
+                format!("entrypoint {entrypoint:?} does not exist in {app_dir:?}"),
+            ),
+            _ => (),
+        }
+
+        match server_type {
+            WorkerType::Shiny if !is_shiny(&app_dir) => self.problem(
+                |s| span_of(&s.app_dir).or(span_of(&s.workdir)),
+                format!("no app.R, or ui.R and server.R, found in {app_dir:?}"),
+            ),
+            WorkerType::Streamlit if streamlit_script(workdir).is_none() => self.problem(
+                |s| span_of(&s.workdir),
+                format!("no streamlit_app.py, or app.py importing streamlit, found in {workdir:?}"),
+            ),
+            WorkerType::Dash if !is_dash(workdir) => self.problem(
+                |s| span_of(&s.workdir),
+                format!("no app.py importing dash found in {workdir:?}"),
+            ),
+            WorkerType::QuartoShiny => match &config.qmd {
+                None => self.problem(
+                    |s| span_of(&s.server_type),
+                    "`qmd` is required for quarto-shiny routes".to_string(),
+                ),
+                Some(qmd) if !workdir.join(qmd).is_file() => self.problem(
+                    |s| span_of(&s.qmd),
+                    format!("qmd {qmd:?} does not exist in {workdir:?}"),
+                ),
+                Some(_) => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn check_env(&mut self) {
+        let config = &self.route.config;
+        let workdir = &config.workdir;
+        if let Some(env_file) = &config.env_file {
+            let env = WorkerEnv::new(
+                workdir,
+                Some(env_file),
+                Default::default(),
+                Default::default(),
+            );
+            if let Err(e) = env.vars() {
+                self.problem(|s| span_of(&s.env_file), message(e));
+            }
+        }
+        for (name, file) in &config.secrets {
+            if !workdir.join(file).is_file() {
+                self.problem(
+                    |s| span_of(&s.secrets),
+                    format!("secret {name} file {file:?} does not exist"),
+                );
+            }
+            if config.env.contains_key(name) {
+                self.problem(
+                    |s| span_of(&s.secrets),
+                    format!("{name} is set by both `env` and `secrets`"),
+                );
+            }
+        }
+    }
+
+    fn check_limits(&mut self) {
+        if let Some(Err(e)) = self.route.config.limits.as_ref().map(|l| l.resolve()) {
+            self.problem(|s| span_of(&s.limits), message(e));
+        }
+    }
+
+    fn check_workers(&mut self) {
+        let config = &self.route.config;
+        let server_type = match config.server_type {
+            RouteType::Static => {
+                return self.check_unused(
+                    &[
+                        "workers",
+                        "strategy",
+                        "app_dir",
+                        "qmd",
+                        "entrypoint",
+                        "r_options",
+                        "static_dir",
+                        "upstream",
+                        "command",
+                        "env",
+                        "env_file",
+                        "secrets",
+                        "limits",
+                        "recycle",
+                        "queue",
+                        "hash_key",
+                        "lb_cookie",
+                        "max_rps",
+                        "health_check",
+                        "watch",
+                    ],
+                    "static routes",
+                )
+            }
+            RouteType::App(server_type) => server_type,
+        };
+        match server_type {
+            WorkerType::Upstream => {
+                self.check_unused(
+                    &[
+                        "workers",
+                        "app_dir",
+                        "qmd",
+                        "entrypoint",
+                        "r_options",
+                        "command",
+                        "env",
+                        "env_file",
+                        "secrets",
+                        "limits",
+                        "recycle",
+                        "max_rps",
+                        "watch",
+                    ],
+                    "upstream routes",
+                );
+                if config.upstream.is_empty() {
+                    self.problem(
+                        |s| span_of(&s.server_type),
+                        "`upstream` is required for upstream routes".to_string(),
+                    );
+                }
+                for upstream in &config.upstream {
+                    if let Err(e) = upstream_authority(upstream) {
+                        self.problem(|s| span_of(&s.upstream), e);
+                    }
+                }
+            }
+            WorkerType::Command => {
+                self.check_unused(
+                    &["app_dir", "qmd", "entrypoint", "r_options", "upstream"],
+                    "command routes",
+                );
+                match &config.command {
+                    None => self.problem(
+                        |s| span_of(&s.server_type),
+                        "`command` is required for command routes".to_string(),
+                    ),
+                    Some(command) => {
+                        if let Err(e) = command.resolve() {
+                            self.problem(|s| span_of(&s.command), message(e));
+                        }
+                    }
+                }
+            }
+            _ => {
+                self.check_unused(&["upstream", "command"], "routes running an application");
+                if !server_type.has_entrypoint() {
+                    self.check_unused(&["entrypoint"], &format!("{server_type:?} routes"));
+                }
+                if !server_type.runs_r() {
+                    self.check_unused(&["r_options"], &format!("{server_type:?} routes"));
+                }
+            }
+        }
+        if server_type != WorkerType::Upstream && config.workers.is_none() {
+            self.problem(
+                |s| span_of(&s.server_type),
+                "`workers` is required for routes running an application".to_string(),
+            );
+        }
+        if let Some(health_check) = &config.health_check {
+            if let Err(e) = health_check.resolve(server_type) {
+                self.problem(|s| span_of(&s.health_check), message(e));
+            }
+        }
+        if let Some(recycle) = &config.recycle {
+            if let Err(e) = recycle.resolve() {
+                self.problem(|s| span_of(&s.recycle), message(e));
+            }
+        }
+        if let Some(queue) = &config.queue {
+            if let Err(e) = queue.resolve() {
+                self.problem(|s| span_of(&s.queue), message(e));
+            }
+        }
+        match (&config.hash_key, config.strategy) {
+            (Some(hash_key), Some(Strategy::HeaderHash)) => {
+                if let Err(e) = hash_key.validate() {
+                    self.problem(|s| span_of(&s.hash_key), message(e));
+                }
+            }
+            (Some(_), _) => self.problem(
+                |s| span_of(&s.hash_key),
+                "`hash_key` is only used by the header-hash strategy".to_string(),
+            ),
+            (None, Some(Strategy::HeaderHash)) => self.problem(
+                |s| span_of(&s.strategy),
+                "`hash_key` is required by the header-hash strategy".to_string(),
+            ),
+            (None, _) => (),
+        }
+        if config.lb_cookie.is_some() {
+            if let Err(e) = self.route.lb_cookie().resolve("/") {
+                self.problem(|s| span_of(&s.lb_cookie), message(e));
+            }
+        }
+    }
+}
+
+pub(super) fn problems(config: &RouterConfig, source: &str) -> Vec<Problem> {
+    let spans = toml::from_str::<ConfigSpans>(source)
+        .map(|spans| spans.route)
+        .unwrap_or_default();
+    let mut problems = Vec::new();
+    let route_line = |i: usize| {
+        spans
+            .get(i)
+            .map(|spans| line_column(source, spans.span().start).0)
+    };
+
+    // Host and path matcher of each route, to find routes matching the same
+    // requests. Only the first of them would ever be used.
+    let mut keys = Vec::with_capacity(config.route.len());
+
+    for (i, route) in config.route.iter().enumerate() {
+        let mut check = RouteCheck {
+            route,
+            spans: spans.get(i),
+            problems: &mut problems,
+        };
+
+        let key = match route.path_matcher() {
+            Err(e) => {
+                check.problem(|s| span_of(&s.regex).or(span_of(&s.route)), e);
+                None
+            }
+            Ok(matcher) => {
+                if let Err(e) = ParamHeaders::new(&matcher, &route.param_headers) {
+                    check.problem(|s| span_of(&s.param_headers), e);
+                }
+                Some((route.host.clone(), matcher.key()))
+            }
+        };
+        if let Some(j) = key.as_ref().and_then(|key| {
+            keys.iter()
+                .position(|earlier: &Option<_>| earlier.as_ref() == Some(key))
+        }) {
+            let (name, earlier) = (route.name(), config.route[j].name());
+            let at = route_line(j).map_or(String::new(), |line| format!(" on line {line}"));
+            let message = if earlier == name {
+                format!("route {name:?} is already defined{at}")
+            } else {
+                format!("route {name:?} matches the same requests as route {earlier:?}{at}")
+            };
+            check.problem(
+                |s| span_of(&s.route).or(span_of(&s.regex)).or(span_of(&s.host)),
+                message,
+            );
+        }
+        keys.push(key);
+
+        check.check_workers();
+        check.check_files();
+    }
+    problems
+}
+
+/// Formats `problems` as `file:line:column: message` lines.
+pub(super) fn report(path: &Path, source: &str, problems: &[Problem]) -> String {
+    let path = path.display();
+    let mut report = match problems.len() {
+        1 => format!("{path} has 1 problem:"),
+        n => format!("{path} has {n} problems:"),
+    };
+    for problem in problems {
+        report.push_str("\n  ");
+        match &problem.span {
+            Some(span) => {
+                let (line, column) = line_column(source, span.start);
+                report.push_str(&format!("{path}:{line}:{column}: {}", problem.message));
+            }
+            None => report.push_str(&format!("{path}: {}", problem.message)),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn temp_dir() -> TempDir {
+        TempDir::new("faucet-validate")
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        let config: RouterConfig = toml::from_str(source).unwrap();
+        let problems = problems(&config, source);
+        report(Path::new("frouter.toml"), source, &problems)
+            .lines()
+            .skip(1)
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hosts_are_part_of_the_route() {
+        let source = r#"[[route]]
+workers = 1
+host = "*.apps.example.com"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+host = "acme.apps.example.com"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+host = "acme.apps.example.com"
+route = "/"
+server_type = "Dummy"
+"#;
+        assert_eq!(
+            messages(source),
+            ["frouter.toml:18:9: route \"acme.apps.example.com/\" is already defined on line 6"]
+        );
+    }
+
+    #[test]
+    fn line_and_column() {
+        let source = "a\nbcd\né = 1\n";
+        assert_eq!(line_column(source, 0), (1, 1));
+        assert_eq!(line_column(source, 4), (2, 3));
+        assert_eq!(line_column(source, 9), (3, 3));
+    }
+
+    #[test]
+    fn invalid_and_duplicate_routes() {
+        let source = r#"[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "/api/"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "health"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "/tenant/{id}/"
+server_type = "Dummy"
+param_headers = { id = "X-Tenant-Id" }
+
+[[route]]
+workers = 1
+route = "/tenant/{tenant}/"
+server_type = "Dummy"
+param_headers = { user = "X-User" }
+
+[[route]]
+workers = 1
+regex = "/v(?P<version>[0-9]+/"
+server_type = "Dummy"
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:13:9: route \"/\" is already defined on line 1",
+                "frouter.toml:18:9: route \"health\" must start with '/'",
+                "frouter.toml:31:17: `param_headers` uses \"user\", which is not a parameter of route \"/tenant/{tenant}/\"",
+                "frouter.toml:29:9: route \"/tenant/{tenant}/\" matches the same requests as route \"/tenant/{id}/\" on line 21",
+                "frouter.toml:35:9: invalid regex \"/v(?P<version>[0-9]+/\": unclosed group",
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_routes_do_not_shadow_prefixes() {
+        let source = r#"[[route]]
+workers = 1
+route = "/app"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "/app/"
+server_type = "Dummy"
+"#;
+        assert!(messages(source).is_empty());
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(dir.join("shiny")).unwrap();
+        std::fs::create_dir_all(dir.join("api")).unwrap();
+        std::fs::write(dir.join("api").join("plumber.R"), "").unwrap();
+        let dir = dir.display();
+        let source = format!(
+            r#"[[route]]
+workers = 1
+route = "/missing/"
+server_type = "Shiny"
+workdir = "{dir}/missing"
+
+[[route]]
+workers = 1
+route = "/shiny/"
+server_type = "Shiny"
+workdir = "{dir}"
+app_dir = "shiny"
+
+[[route]]
+workers = 1
+route = "/api/"
+server_type = "Plumber"
+workdir = "{dir}/api"
+
+[[route]]
+workers = 1
+route = "/quarto/"
+server_type = "QuartoShiny"
+workdir = "{dir}"
+
+[[route]]
+workers = 1
+route = "/doc/"
+server_type = "QuartoShiny"
+workdir = "{dir}"
+qmd = "doc.qmd"
+
+[[route]]
+workers = 1
+route = "/plumber/"
+server_type = "Plumber"
+workdir = "{dir}"
+app_dir = "nope"
+
+[[route]]
+workers = 1
+route = "/streamlit/"
+server_type = "streamlit"
+workdir = "{dir}"
+
+[[route]]
+workers = 1
+route = "/dash/"
+server_type = "dash"
+workdir = "{dir}"
+"#
+        );
+        assert_eq!(
+            messages(&source),
+            [
+                format!("frouter.toml:5:11: workdir \"{dir}/missing\" does not exist"),
+                format!(
+                    "frouter.toml:12:11: no app.R, or ui.R and server.R, found in \"{dir}/shiny\""
+                ),
+                "frouter.toml:23:15: `qmd` is required for quarto-shiny routes".to_string(),
+                format!("frouter.toml:31:7: qmd \"doc.qmd\" does not exist in \"{dir}\""),
+                format!("frouter.toml:38:11: app_dir \"nope\" does not exist in \"{dir}\""),
+                format!("frouter.toml:44:11: no streamlit_app.py, or app.py importing streamlit, found in \"{dir}\""),
+                format!("frouter.toml:50:11: no app.py importing dash found in \"{dir}\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn static_routes() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(dir.join("www")).unwrap();
+        let dir = dir.display();
+        let source = format!(
+            r#"[[route]]
+route = "/assets/"
+server_type = "static"
+workdir = "{dir}/www"
+
+[[route]]
+route = "/docs/"
+server_type = "static"
+workdir = "{dir}/www"
+workers = 2
+watch = true
+
+[[route]]
+route = "/"
+server_type = "Dummy"
+workdir = "{dir}"
+static_dir = "public"
+"#
+        );
+        assert_eq!(
+            messages(&source),
+            [
+                "frouter.toml:10:11: `workers` is not used by static routes".to_string(),
+                "frouter.toml:11:9: `watch` is not used by static routes".to_string(),
+                "frouter.toml:15:15: `workers` is required for routes running an application"
+                    .to_string(),
+                format!("frouter.toml:17:14: static_dir \"public\" does not exist in \"{dir}\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn upstream_routes() {
+        let source = r#"[[route]]
+route = "/node/"
+server_type = "upstream"
+upstream = ["http://127.0.0.1:3000", "127.0.0.1:3001"]
+
+[[route]]
+route = "/go/"
+server_type = "upstream"
+workers = 2
+
+[[route]]
+route = "/bad/"
+server_type = "upstream"
+upstream = ["https://go-service:443"]
+
+[[route]]
+route = "/"
+server_type = "Dummy"
+workers = 1
+upstream = ["127.0.0.1:3000"]
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:9:11: `workers` is not used by upstream routes",
+                "frouter.toml:8:15: `upstream` is required for upstream routes",
+                "frouter.toml:14:12: upstream \"https://go-service:443\" uses https, only plain HTTP upstreams are supported",
+                "frouter.toml:20:12: `upstream` is not used by routes running an application",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_routes() {
+        let source = r#"[[route]]
+route = "/dash/"
+server_type = "command"
+workers = 2
+command = { argv = ["gunicorn", "--bind", "{host}:{port}", "app:server"] }
+
+[[route]]
+route = "/streamlit/"
+server_type = "command"
+workers = 1
+app_dir = "app"
+command = { argv = ["streamlit", "run", "app.py"] }
+
+[[route]]
+route = "/node/"
+server_type = "command"
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:11:11: `app_dir` is not used by command routes",
+                "frouter.toml:12:11: command must pass `{port}` to the process in `argv` or `env`",
+                "frouter.toml:16:15: `command` is required for command routes",
+                "frouter.toml:16:15: `workers` is required for routes running an application",
+            ]
+        );
+    }
+
+    #[test]
+    fn entrypoints_and_r_options() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("api.R"), "").unwrap();
+        std::fs::write(dir.join("app.R"), "").unwrap();
+        let dir = dir.display();
+        let source = format!(
+            r#"[[route]]
+workers = 1
+route = "/api/"
+server_type = "plumber"
+workdir = "{dir}"
+entrypoint = "api.R"
+r_options = {{ "plumber.docs" = false }}
+
+[[route]]
+workers = 1
+route = "/v2/"
+server_type = "plumber2"
+workdir = "{dir}"
+
+[[route]]
+workers = 1
+route = "/ambiorix/"
+server_type = "ambiorix"
+workdir = "{dir}"
+entrypoint = "main.R"
+
+[[route]]
+workers = 1
+route = "/docs/"
+server_type = "fast-api"
+workdir = "{dir}"
+entrypoint = "main.py"
+r_options = {{ "shiny.port" = 1 }}
+"#
+        );
+        assert_eq!(
+            messages(&source),
+            [
+                format!("frouter.toml:13:11: entrypoint \"plumber.R\" does not exist in \"{dir}\""),
+                format!("frouter.toml:20:14: entrypoint \"main.R\" does not exist in \"{dir}\""),
+                "frouter.toml:27:14: `entrypoint` is not used by FastAPI routes".to_string(),
+                "frouter.toml:28:13: `r_options` is not used by FastAPI routes".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn worker_environment() {
+        let dir = temp_dir();
+        std::fs::write(dir.join(".env.app"), "MODE=production\n").unwrap();
+        std::fs::write(dir.join("token"), "s3cret").unwrap();
+        let dir = dir.display();
+        let source = format!(
+            r#"[[route]]
+workers = 1
+route = "/app/"
+server_type = "Dummy"
+workdir = "{dir}"
+env_file = ".env.app"
+env = {{ TOKEN = "plain" }}
+secrets = {{ TOKEN = "token" }}
+
+[[route]]
+workers = 1
+route = "/other/"
+server_type = "Dummy"
+workdir = "{dir}"
+env_file = ".env.missing"
+secrets = {{ DB_PASSWORD = "/run/secrets/missing" }}
+
+[[route]]
+route = "/node/"
+server_type = "upstream"
+upstream = ["127.0.0.1:3000"]
+env = {{ MODE = "production" }}
+"#
+        );
+        let messages = messages(&source);
+        assert_eq!(messages.len(), 4, "{messages:?}");
+        assert_eq!(
+            messages[0],
+            "frouter.toml:8:11: TOKEN is set by both `env` and `secrets`"
+        );
+        assert!(
+            messages[1].starts_with("frouter.toml:15:12: Unable to read env_file"),
+            "{messages:?}"
+        );
+        assert_eq!(
+            messages[2],
+            "frouter.toml:16:11: secret DB_PASSWORD file \"/run/secrets/missing\" does not exist"
+        );
+        assert_eq!(
+            messages[3],
+            "frouter.toml:22:7: `env` is not used by upstream routes"
+        );
+    }
+
+    #[test]
+    fn resource_limits() {
+        let dir = temp_dir();
+        let dir = dir.display();
+        let source = format!(
+            r#"[[route]]
+workers = 1
+route = "/app/"
+server_type = "Dummy"
+workdir = "{dir}"
+limits = {{ memory = "2G", cpu_seconds = 3600, open_files = 1024 }}
+
+[[route]]
+workers = 1
+route = "/other/"
+server_type = "Dummy"
+workdir = "{dir}"
+limits = {{ cgroup_memory_max = "1G", user = "faucet-no-such-user" }}
+
+[[route]]
+route = "/node/"
+server_type = "upstream"
+upstream = ["127.0.0.1:3000"]
+limits = {{ memory = "1G" }}
+"#
+        );
+        let messages = messages(&source);
+        assert_eq!(
+            messages,
+            [
+                "frouter.toml:13:10: limits: `cgroup_memory_max` and `cgroup_cpu_max` require `cgroup`",
+                "frouter.toml:19:10: `limits` is not used by upstream routes",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_health_check() {
+        let source = r#"[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+
+[route.health_check]
+path = "health"
+"#;
+        let messages = messages(source);
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].starts_with("frouter.toml:6:1: "),
+            "{messages:?}"
+        );
+    }
+
+    #[test]
+    fn invalid_recycle_policy() {
+        let source = r#"[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+recycle = { max_memory = "1G", max_requests = 0 }
+
+[[route]]
+route = "/node/"
+server_type = "upstream"
+upstream = ["127.0.0.1:3000"]
+recycle = { max_age = 3600 }
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:5:11: recycle max_requests must be greater than 0",
+                "frouter.toml:11:11: `recycle` is not used by upstream routes",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_queue() {
+        let source = r#"[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+queue = { max_length = 10 }
+
+[[route]]
+route = "/static/"
+server_type = "static"
+workdir = "."
+queue = { max_concurrency = 1 }
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:5:9: queue max_concurrency is required",
+                "frouter.toml:11:9: `queue` is not used by static routes",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_hash_key() {
+        let source = r#"[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+strategy = "header-hash"
+
+[[route]]
+workers = 1
+route = "/api/"
+server_type = "Dummy"
+strategy = "header-hash"
+hash_key = { header = "X User" }
+
+[[route]]
+workers = 1
+route = "/other/"
+server_type = "Dummy"
+hash_key = { cookie = "session" }
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:5:12: `hash_key` is required by the header-hash strategy",
+                "frouter.toml:12:12: hash_key header must be a valid header name",
+                "frouter.toml:18:12: `hash_key` is only used by the header-hash strategy",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_lb_cookie() {
+        let source = r#"[[route]]
+workers = 1
+route = "/"
+server_type = "Dummy"
+lb_cookie = { same_site = "none" }
+
+[[route]]
+route = "/static/"
+server_type = "static"
+workdir = "."
+lb_cookie = { secure = true }
+"#;
+        assert_eq!(
+            messages(source),
+            [
+                "frouter.toml:5:13: lb_cookie same_site = \"none\" requires secure = true",
+                "frouter.toml:11:13: `lb_cookie` is not used by static routes",
+            ]
+        );
+    }
+
+    #[test]
+    fn lb_cookie_path_defaults_to_the_route_prefix() {
+        let source = r#"[[route]]
+workers = 1
+route = "/app/"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "/tenant/{id}/app/"
+server_type = "Dummy"
+lb_cookie = { name = "lb" }
+
+[[route]]
+workers = 1
+regex = "^/reports/"
+server_type = "Dummy"
+
+[[route]]
+workers = 1
+route = "/api/"
+server_type = "Dummy"
+lb_cookie = { path = "/" }
+
+[[route]]
+workers = 1
+route = "/docs"
+server_type = "Dummy"
+"#;
+        let config: RouterConfig = toml::from_str(source).unwrap();
+        let paths: Vec<_> = config
+            .route
+            .iter()
+            .map(|route| route.lb_cookie().path.unwrap())
+            .collect();
+        assert_eq!(paths, ["/app/", "/tenant/", "/", "/", "/docs"]);
+    }
+}
@@ -1,9 +1,12 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, sync::Arc};
 
 use crate::{
-    client::{load_balancing::Strategy, Client, ExclusiveBody, UpgradeStatus},
+    client::{
+        decline_h2c_upgrade, is_upgrade_request, load_balancing::Strategy, Client, ExclusiveBody,
+        InFlight, UpgradeStatus,
+    },
     error::FaucetError,
-    server::load_balancing::LoadBalancer,
+    server::{lb_cookie::LbCookie, load_balancing::LoadBalancer},
     shutdown::ShutdownSignal,
 };
 use hyper::{body::Incoming, header::HeaderValue};
@@ -16,16 +19,40 @@ pub(crate) struct State {
     pub uuid: uuid::Uuid,
     pub remote_addr: IpAddr,
     pub client: Client,
+    /// Keeps the request in flight on the worker.
+    pub in_flight: Arc<InFlight>,
 }
 
 impl State {
     #[inline(always)]
-    fn new(remote_addr: IpAddr, client: Client) -> State {
+    fn new(remote_addr: IpAddr, client: Client, in_flight: InFlight) -> State {
         let uuid = uuid::Uuid::now_v7();
         State {
             remote_addr,
             client,
             uuid,
+            in_flight: Arc::new(in_flight),
+        }
+    }
+}
+
+/// A request answered with `503` before it reached a worker. It goes down
+/// to the [`LogLayer`](super::logging::LogLayer) in place of a [`State`],
+/// so that it is logged like any other request.
+#[derive(Clone)]
+pub(crate) struct Rejected {
+    pub uuid: uuid::Uuid,
+    pub remote_addr: IpAddr,
+    pub route: Option<&'static str>,
+    pub reason: &'static str,
+    pub retry_after: u64,
+}
+
+impl Rejected {
+    fn error(&self) -> FaucetError {
+        FaucetError::Overloaded {
+            reason: self.reason,
+            retry_after: self.retry_after,
         }
     }
 }
@@ -34,6 +61,7 @@ impl State {
 pub struct AddStateService<S> {
     inner: S,
     load_balancer: LoadBalancer,
+    lb_cookie: &'static LbCookie,
 }
 
 fn uuid_to_header_value(uuid: uuid::Uuid) -> HeaderValue {
@@ -42,47 +70,6 @@ fn uuid_to_header_value(uuid: uuid::Uuid) -> HeaderValue {
         .expect("Unable to convert from uuid to header value, this is a bug")
 }
 
-fn extract_lb_uuid_from_req_cookies<B>(req: &hyper::Request<B>) -> Option<uuid::Uuid> {
-    req.headers().get("Cookie").and_then(|cookie| {
-        cookie.to_str().ok().and_then(|cookie_str| {
-            for cookie in cookie::Cookie::split_parse(cookie_str) {
-                match cookie {
-                    Err(e) => {
-                        log::error!(target: "faucet", "Error parsing cookie: {e}");
-                        continue;
-                    }
-                    Ok(cookie) => {
-                        if cookie.name() == "FAUCET_LB_COOKIE" {
-                            let parse_res = cookie.value().parse::<uuid::Uuid>();
-                            return match parse_res {
-                                Ok(uuid) => Some(uuid),
-                                Err(e) => {
-                                    log::error!(target: "faucet", "Error parsing UUID from cookie: {e}");
-                                    None
-                                }
-                            };
-                        }
-                    }
-                }
-
-            }
-            None
-        })
-    })
-}
-
-fn add_lb_cookie_to_resp(resp: &mut hyper::Response<ExclusiveBody>, lb_cookie: Option<uuid::Uuid>) {
-    if let Some(lb_cookie) = lb_cookie {
-        resp.headers_mut().append(
-            "Set-Cookie",
-            HeaderValue::from_str(&format!(
-                "FAUCET_LB_COOKIE={lb_cookie}; Path=/; HttpOnly; SameSite=Lax"
-            ))
-            .expect("UUID is invalid, this is a bug! Report it please!"),
-        );
-    }
-}
-
 // Interesting behavior:
 //
 // If using a cookie hash strategy and the browser starts by sending N simultaneous requests
@@ -131,17 +118,47 @@ where
                 .expect("Response should build"));
         }
 
-        let is_cookie_hash = self.load_balancer.get_strategy() == Strategy::CookieHash;
+        decline_h2c_upgrade(&mut req);
 
-        let lb_cookie = (is_cookie_hash)
-            .then_some(extract_lb_uuid_from_req_cookies(&req).unwrap_or(uuid::Uuid::now_v7()));
+        let is_cookie_hash = self.load_balancer.get_strategy() == Strategy::CookieHash;
 
-        let client = self
-            .load_balancer
-            .get_client(remote_addr, lb_cookie)
-            .await?;
+        let lb_cookie = (is_cookie_hash).then(|| {
+            self.lb_cookie
+                .extract(&req)
+                .unwrap_or_else(uuid::Uuid::now_v7)
+        });
+        let ident = self.load_balancer.ident(&req, remote_addr, lb_cookie);
+
+        // WebSocket sessions are long lived and do not wait for a request
+        // slot.
+        let admission = if is_upgrade_request(&req) {
+            self.load_balancer.connect(ident).await
+        } else {
+            self.load_balancer.admit(ident).await
+        };
+        let (client, in_flight) = match admission {
+            Ok(admission) => admission,
+            Err(FaucetError::Overloaded {
+                reason,
+                retry_after,
+            }) => {
+                let rejected = Rejected {
+                    uuid: uuid::Uuid::now_v7(),
+                    remote_addr,
+                    route: self.load_balancer.route(),
+                    reason,
+                    retry_after,
+                };
+                // The access log has the 503 already, under load this
+                // would log every request again.
+                log::debug!(target: "faucet", "Rejecting request from {remote_addr} to {}: {}", req.uri().path(), rejected.error());
+                req.extensions_mut().insert(rejected);
+                return self.inner.call(req, Some(remote_addr)).await;
+            }
+            Err(e) => return Err(e),
+        };
 
-        let state = State::new(remote_addr, client);
+        let state = State::new(remote_addr, client, in_flight);
 
         // Add the state's UUID to the request. `X-` headers are depracted
         // https://www.rfc-editor.org/rfc/rfc6648
@@ -151,10 +168,9 @@ where
         req.extensions_mut().insert(state);
         let mut resp = self.inner.call(req, Some(remote_addr)).await;
 
-        if let Ok(resp) = &mut resp {
-            if is_cookie_hash {
-                add_lb_cookie_to_resp(resp, lb_cookie);
-            }
+        if let (Ok(resp), Some(lb_cookie)) = (&mut resp, lb_cookie) {
+            resp.headers_mut()
+                .append("Set-Cookie", self.lb_cookie.header_value(lb_cookie));
         }
 
         resp
@@ -163,12 +179,16 @@ where
 
 pub struct AddStateLayer {
     load_balancer: LoadBalancer,
+    lb_cookie: &'static LbCookie,
 }
 
 impl AddStateLayer {
     #[inline]
-    pub fn new(load_balancer: LoadBalancer) -> Self {
-        Self { load_balancer }
+    pub fn new(load_balancer: LoadBalancer, lb_cookie: &'static LbCookie) -> Self {
+        Self {
+            load_balancer,
+            lb_cookie,
+        }
     }
 }
 
@@ -178,6 +198,7 @@ impl<S> Layer<S> for AddStateLayer {
         AddStateService {
             inner,
             load_balancer: self.load_balancer.clone(),
+            lb_cookie: self.lb_cookie,
         }
     }
 }
@@ -196,6 +217,9 @@ impl Service<hyper::Request<Incoming>> for ProxyService {
         req: hyper::Request<Incoming>,
         _: Option<IpAddr>,
     ) -> Result<Self::Response, Self::Error> {
+        if let Some(rejected) = req.extensions().get::<Rejected>() {
+            return Ok(rejected.error().into());
+        }
         let state = req
             .extensions()
             .get::<State>()
@@ -203,7 +227,12 @@ impl Service<hyper::Request<Incoming>> for ProxyService {
             .clone();
         match state
             .client
-            .attempt_upgrade(req, self.shutdown, self.websocket_config)
+            .attempt_upgrade(
+                req,
+                state.in_flight.clone(),
+                self.shutdown,
+                self.websocket_config,
+            )
             .await?
         {
             UpgradeStatus::Upgraded(res) => {
@@ -216,7 +245,7 @@ impl Service<hyper::Request<Incoming>> for ProxyService {
                 Ok(res)
             }
             UpgradeStatus::NotUpgraded(req) => {
-                let connection = state.client.get().await?;
+                let connection = state.client.get(state.in_flight).await?;
                 connection.send_request(req).await
             }
         }
@@ -0,0 +1,598 @@
+//! Serving of files from a directory straight from faucet, so assets such
+//! as Shiny's `www/` folder do not go through the workers.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::TryStreamExt;
+use http_body_util::StreamBody;
+use hyper::{
+    body::{Frame, Incoming},
+    header::{self, HeaderMap, HeaderValue},
+    Method, Request, Response, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::onion::{Layer, Service};
+use crate::{
+    client::ExclusiveBody,
+    error::{FaucetError, FaucetResult},
+    leak,
+};
+
+const INDEX: &str = "index.html";
+
+/// Precompressed variants looked up next to each file, by preference.
+const ENCODINGS: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt" | "md") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether the `Accept-Encoding` header accepts `coding`.
+fn accepts_encoding(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case(coding) && !rejected
+        })
+}
+
+/// Range of bytes asked by a `Range` header, inclusive.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single range `Range` header. Multiple ranges are not supported,
+/// those requests get the whole file.
+fn parse_range(value: &str, len: u64) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start.is_empty(), end.is_empty()) {
+        (true, true) => return None,
+        // The last `end` bytes.
+        (true, false) => {
+            let suffix = end.parse::<u64>().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (false, _) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = match end.is_empty() {
+                true => len.saturating_sub(1),
+                false => end.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+            };
+            if start >= len || start > end {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            (start, end)
+        }
+    };
+    Some(ByteRange::Satisfiable(range.0, range.1))
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    header.trim() == "*" || header.split(',').any(|tag| strip_weak(tag) == etag)
+}
+
+/// The file `path` asks for under `root`. Hidden files and paths leaving
+/// `root` are never served.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut resolved = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            _ if segment.starts_with('.') || segment.contains(['\\', '\0']) => return None,
+            _ => resolved.push(segment),
+        }
+    }
+    Some(resolved)
+}
+
+/// A file to send, either the requested one or a precompressed variant.
+struct Representation {
+    path: PathBuf,
+    len: u64,
+    encoding: Option<&'static str>,
+}
+
+fn empty_response(status: StatusCode) -> Response<ExclusiveBody> {
+    Response::builder()
+        .status(status)
+        .body(ExclusiveBody::empty())
+        .expect("Response should build")
+}
+
+/// Files of a directory served at the root of a route or server.
+#[derive(Clone, Copy)]
+pub(crate) struct StaticFiles {
+    root: &'static Path,
+}
+
+impl StaticFiles {
+    pub fn new(dir: &Path) -> FaucetResult<Self> {
+        let root = std::fs::canonicalize(dir)
+            .ok()
+            .filter(|root| root.is_dir())
+            .ok_or_else(|| {
+                FaucetError::InvalidConfig(format!("Static files directory {dir:?} does not exist"))
+            })?;
+        Ok(StaticFiles {
+            root: leak!(root, Path),
+        })
+    }
+
+    /// Answers `req` with a file of the directory, or returns `None` if
+    /// there is no file for it.
+    pub async fn serve<B>(&self, req: &Request<B>) -> Option<Response<ExclusiveBody>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        let request_path = req.uri().path();
+        let mut path = resolve(self.root, request_path)?;
+        let mut metadata = tokio::fs::metadata(&path).await.ok()?;
+
+        if metadata.is_dir() {
+            if !request_path.ends_with('/') {
+                // A relative redirect works behind any route prefix.
+                let name = request_path.rsplit('/').next().unwrap_or_default();
+                let location = match req.uri().query() {
+                    Some(query) => format!("{name}/?{query}"),
+                    None => format!("{name}/"),
+                };
+                let mut response = empty_response(StatusCode::MOVED_PERMANENTLY);
+                response
+                    .headers_mut()
+                    .insert(header::LOCATION, HeaderValue::from_str(&location).ok()?);
+                return Some(response);
+            }
+            path.push(INDEX);
+            metadata = tokio::fs::metadata(&path).await.ok()?;
+        }
+        if !metadata.is_file() {
+            return None;
+        }
+        let path = self.within_root(&path).await?;
+
+        Some(self.respond(req, path, metadata).await)
+    }
+
+    /// The canonical form of `path`, if it is under the directory. Symbolic
+    /// links may point outside of it.
+    async fn within_root(&self, path: &Path) -> Option<PathBuf> {
+        let path = tokio::fs::canonicalize(path).await.ok()?;
+        path.starts_with(self.root).then_some(path)
+    }
+
+    async fn respond<B>(
+        &self,
+        req: &Request<B>,
+        path: PathBuf,
+        metadata: std::fs::Metadata,
+    ) -> Response<ExclusiveBody> {
+        let headers = req.headers();
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let version = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or_default();
+
+        // Ranges are served from the file itself, not its compressed variants.
+        let range_requested = headers.contains_key(header::RANGE);
+        let mut has_variants = false;
+        let mut representation = Representation {
+            len: metadata.len(),
+            path: path.clone(),
+            encoding: None,
+        };
+        for (coding, extension) in ENCODINGS {
+            let mut variant = path.clone().into_os_string();
+            variant.push(".");
+            variant.push(extension);
+            let Some(variant) = self.within_root(Path::new(&variant)).await else {
+                continue;
+            };
+            let Ok(variant_metadata) = tokio::fs::metadata(&variant).await else {
+                continue;
+            };
+            if !variant_metadata.is_file() {
+                continue;
+            }
+            has_variants = true;
+            if representation.encoding.is_none()
+                && !range_requested
+                && accepts_encoding(headers, coding)
+            {
+                representation = Representation {
+                    path: variant,
+                    len: variant_metadata.len(),
+                    encoding: Some(coding),
+                };
+            }
+        }
+
+        let etag = match representation.encoding {
+            Some(coding) => format!("\"{:x}-{version:x}-{coding}\"", metadata.len()),
+            None => format!("\"{:x}-{version:x}\"", metadata.len()),
+        };
+        let last_modified = httpdate::fmt_http_date(modified);
+
+        let mut response = Response::builder()
+            .header(header::ETAG, &etag)
+            .header(header::LAST_MODIFIED, &last_modified)
+            .header(header::ACCEPT_RANGES, "bytes");
+        if has_variants {
+            response = response.header(header::VARY, "Accept-Encoding");
+        }
+
+        let header_str = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+        };
+        let not_modified = match header_str(header::IF_NONE_MATCH) {
+            Some(if_none_match) => etag_matches(if_none_match, &etag),
+            None => header_str(header::IF_MODIFIED_SINCE)
+                .and_then(|since| httpdate::parse_http_date(since).ok())
+                .is_some_and(|since| truncate_to_secs(modified) <= since),
+        };
+        if not_modified {
+            return response
+                .status(StatusCode::NOT_MODIFIED)
+                .body(ExclusiveBody::empty())
+                .expect("Response should build");
+        }
+
+        let range_applies = match header_str(header::IF_RANGE) {
+            None => true,
+            Some(if_range) if if_range.starts_with('"') => if_range == etag,
+            Some(if_range) => if_range == last_modified,
+        };
+        let len = representation.len;
+        let range = header_str(header::RANGE)
+            .filter(|_| range_applies)
+            .and_then(|range| parse_range(range, len));
+
+        let (status, start, end) = match range {
+            None => (StatusCode::OK, 0, len),
+            Some(ByteRange::Unsatisfiable) => {
+                return response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                    .body(ExclusiveBody::empty())
+                    .expect("Response should build");
+            }
+            Some(ByteRange::Satisfiable(start, end)) => {
+                response =
+                    response.header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+                (StatusCode::PARTIAL_CONTENT, start, end + 1)
+            }
+        };
+
+        response = response
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type(&path))
+            .header(header::CONTENT_LENGTH, end - start);
+        if let Some(coding) = representation.encoding {
+            response = response.header(header::CONTENT_ENCODING, coding);
+        }
+
+        let body = match *req.method() == Method::HEAD {
+            true => Ok(ExclusiveBody::empty()),
+            false => file_body(&representation.path, start, end - start).await,
+        };
+        match body {
+            Ok(body) => response.body(body).expect("Response should build"),
+            Err(e) => {
+                log::error!(target: "faucet", "Unable to read {:?}: {e}", representation.path);
+                empty_response(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+async fn file_body(path: &Path, start: u64, len: u64) -> std::io::Result<ExclusiveBody> {
+    let mut file = tokio::fs::File::open(path).await?;
+    if start > 0 {
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+    }
+    let stream = ReaderStream::new(file.take(len))
+        .map_ok(Frame::data)
+        .map_err(FaucetError::from);
+    Ok(ExclusiveBody::new(StreamBody::new(stream), None))
+}
+
+/// Answers requests with the files of a directory when they exist, and
+/// forwards the rest to the workers.
+pub(crate) struct StaticService<S> {
+    files: Option<StaticFiles>,
+    inner: S,
+}
+
+impl<S> Service<Request<Incoming>> for StaticService<S>
+where
+    S: Service<Request<Incoming>, Response = Response<ExclusiveBody>, Error = FaucetError>,
+{
+    type Error = FaucetError;
+    type Response = Response<ExclusiveBody>;
+    async fn call(
+        &self,
+        req: Request<Incoming>,
+        ip_addr: Option<IpAddr>,
+    ) -> Result<Self::Response, Self::Error> {
+        if let Some(files) = &self.files {
+            if let Some(response) = files.serve(&req).await {
+                return Ok(response);
+            }
+        }
+        self.inner.call(req, ip_addr).await
+    }
+}
+
+pub(crate) struct StaticLayer(pub Option<StaticFiles>);
+
+impl<S> Layer<S> for StaticLayer {
+    type Service = StaticService<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        StaticService {
+            files: self.0,
+            inner,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn temp_dir() -> TempDir {
+        TempDir::new("faucet-static")
+    }
+
+    fn get(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    async fn body(response: Response<ExclusiveBody>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        use ByteRange::*;
+        assert_eq!(parse_range("bytes=0-4", 10), Some(Satisfiable(0, 4)));
+        assert_eq!(parse_range("bytes=5-", 10), Some(Satisfiable(5, 9)));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Satisfiable(7, 9)));
+        assert_eq!(parse_range("bytes=8-20", 10), Some(Satisfiable(8, 9)));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-1,3-4", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+    }
+
+    #[test]
+    fn accept_encoding() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            "gzip;q=0.5, br;q=0".parse().unwrap(),
+        );
+        assert!(accepts_encoding(&headers, "gzip"));
+        assert!(!accepts_encoding(&headers, "br"));
+    }
+
+    #[test]
+    fn hidden_files_and_parent_dirs_are_not_resolved() {
+        let root = Path::new("/srv");
+        assert_eq!(
+            resolve(root, "/css/app.css"),
+            Some("/srv/css/app.css".into())
+        );
+        assert_eq!(resolve(root, "/a%20b.txt"), Some("/srv/a b.txt".into()));
+        assert_eq!(resolve(root, "/../etc/passwd"), None);
+        assert_eq!(resolve(root, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve(root, "/.env"), None);
+    }
+
+    #[tokio::test]
+    async fn serve_files() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("docs").join("index.html"), "<h1>Docs</h1>").unwrap();
+        let files = StaticFiles::new(&dir).unwrap();
+
+        let response = files.serve(&get("/app.js", &[])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "14");
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let last_modified = response.headers()[header::LAST_MODIFIED]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(body(response).await, "console.log(1)");
+
+        let response = files
+            .serve(&get("/app.js", &[("if-none-match", &etag)]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = files
+            .serve(&get("/app.js", &[("if-modified-since", &last_modified)]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = files.serve(&get("/docs?x=1", &[])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "docs/?x=1");
+        let response = files.serve(&get("/docs/", &[])).await.unwrap();
+        assert_eq!(body(response).await, "<h1>Docs</h1>");
+
+        assert!(files.serve(&get("/missing.js", &[])).await.is_none());
+        assert!(files.serve(&get("/", &[])).await.is_none());
+        let post = Request::post("/app.js").body(()).unwrap();
+        assert!(files.serve(&post).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn serve_ranges() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("data.txt"), "0123456789").unwrap();
+        let files = StaticFiles::new(&dir).unwrap();
+
+        let response = files
+            .serve(&get("/data.txt", &[("range", "bytes=2-5")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(body(response).await, "2345");
+
+        let response = files
+            .serve(&get("/data.txt", &[("range", "bytes=20-")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+
+        let response = files
+            .serve(&get(
+                "/data.txt",
+                &[("range", "bytes=2-5"), ("if-range", "\"stale\"")],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "0123456789");
+    }
+
+    #[tokio::test]
+    async fn serve_precompressed_variants() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("app.css"), "body {}").unwrap();
+        std::fs::write(dir.join("app.css.gz"), "gzipped").unwrap();
+        std::fs::write(dir.join("app.css.br"), "brotli").unwrap();
+        let files = StaticFiles::new(&dir).unwrap();
+
+        let response = files
+            .serve(&get("/app.css", &[("accept-encoding", "gzip, br")]))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(body(response).await, "brotli");
+
+        let response = files
+            .serve(&get("/app.css", &[("accept-encoding", "gzip")]))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(body(response).await, "gzipped");
+
+        let response = files.serve(&get("/app.css", &[])).await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+        assert_eq!(body(response).await, "body {}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn precompressed_variants_stay_in_the_directory() {
+        let outside = temp_dir();
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        let dir = temp_dir();
+        std::fs::write(dir.join("app.css"), "body {}").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), dir.join("app.css.gz")).unwrap();
+        let files = StaticFiles::new(&dir).unwrap();
+
+        let response = files
+            .serve(&get("/app.css", &[("accept-encoding", "gzip")]))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!response.headers().contains_key(header::VARY));
+        assert_eq!(body(response).await, "body {}");
+    }
+}
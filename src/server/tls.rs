@@ -0,0 +1,359 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::{
+    error::{FaucetError, FaucetResult},
+    shutdown::ShutdownSignal,
+};
+
+// How often we look at the certificate and key files for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        TlsConfig {
+            cert: cert.as_ref().into(),
+            key: key.as_ref().into(),
+        }
+    }
+}
+
+fn load_certified_key(
+    config: &TlsConfig,
+    provider: &CryptoProvider,
+) -> FaucetResult<Arc<CertifiedKey>> {
+    let mut cert_reader = BufReader::new(std::fs::File::open(&config.cert).map_err(|e| {
+        FaucetError::Tls(format!("Unable to open certificate {:?}: {e}", config.cert))
+    })?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| FaucetError::Tls(format!("Invalid certificate {:?}: {e}", config.cert)))?;
+
+    if certs.is_empty() {
+        return Err(FaucetError::Tls(format!(
+            "No certificates found in {:?}",
+            config.cert
+        )));
+    }
+
+    let mut key_reader = BufReader::new(std::fs::File::open(&config.key).map_err(|e| {
+        FaucetError::Tls(format!("Unable to open private key {:?}: {e}", config.key))
+    })?);
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(|e| FaucetError::Tls(format!("Invalid private key {:?}: {e}", config.key)))?
+        .ok_or_else(|| FaucetError::Tls(format!("No private key found in {:?}", config.key)))?;
+
+    let certified_key = CertifiedKey::from_der(certs, key, provider)?;
+
+    Ok(Arc::new(certified_key))
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|md| md.modified()).ok()
+}
+
+/// Certificate resolver that always hands out the latest certificate
+/// loaded from disk. The certificate is swapped in place by a background
+/// task so new handshakes pick it up without restarting faucet.
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertResolver {
+    fn set(&self, key: Arc<CertifiedKey>) {
+        *self.current.write().expect("TLS certificate lock poisoned") = key;
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .expect("TLS certificate lock poisoned")
+                .clone(),
+        )
+    }
+}
+
+fn spawn_reload_task(
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    resolver: Arc<ReloadingCertResolver>,
+    shutdown: &'static ShutdownSignal,
+) {
+    tokio::spawn(async move {
+        let mut last_seen = (modified_at(&config.cert), modified_at(&config.key));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(RELOAD_CHECK_INTERVAL) => (),
+                _ = shutdown.wait() => break,
+            }
+
+            let seen = (modified_at(&config.cert), modified_at(&config.key));
+            if seen == last_seen {
+                continue;
+            }
+
+            match load_certified_key(&config, &provider) {
+                Ok(key) => {
+                    resolver.set(key);
+                    last_seen = seen;
+                    log::info!(target: "faucet", "Reloaded TLS certificate from {:?}", config.cert);
+                }
+                Err(e) => {
+                    // The files may be mid-write, we will try again on the next tick.
+                    log::warn!(target: "faucet", "Unable to reload TLS certificate, keeping the previous one: {e}");
+                }
+            }
+        }
+    });
+}
+
+impl TlsConfig {
+    pub(crate) fn build_acceptor(
+        &self,
+        shutdown: &'static ShutdownSignal,
+    ) -> FaucetResult<TlsAcceptor> {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+
+        let resolver = Arc::new(ReloadingCertResolver {
+            current: RwLock::new(load_certified_key(self, &provider)?),
+        });
+
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        spawn_reload_task(self.clone(), provider, resolver, shutdown);
+
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+}
+
+/// A client connection that may or may not be wrapped in TLS.
+pub(crate) enum FaucetStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl FaucetStream {
+    pub(crate) async fn accept(
+        tcp: TcpStream,
+        acceptor: Option<&TlsAcceptor>,
+    ) -> FaucetResult<Self> {
+        Self::accept_within(tcp, acceptor, HANDSHAKE_TIMEOUT).await
+    }
+
+    /// Clients that do not complete the handshake within `timeout` are
+    /// dropped, so they cannot hold connections open without a request.
+    async fn accept_within(
+        tcp: TcpStream,
+        acceptor: Option<&TlsAcceptor>,
+        timeout: Duration,
+    ) -> FaucetResult<Self> {
+        match acceptor {
+            None => Ok(FaucetStream::Plain(tcp)),
+            Some(acceptor) => {
+                let tls = tokio::time::timeout(timeout, acceptor.accept(tcp))
+                    .await
+                    .map_err(|_| {
+                        FaucetError::Tls(format!("handshake did not complete within {timeout:?}"))
+                    })??;
+                Ok(FaucetStream::Tls(Box::new(tls)))
+            }
+        }
+    }
+}
+
+impl AsyncRead for FaucetStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            FaucetStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            FaucetStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for FaucetStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            FaucetStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            FaucetStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            FaucetStream::Plain(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            FaucetStream::Tls(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            FaucetStream::Plain(s) => s.is_write_vectored(),
+            FaucetStream::Tls(s) => s.is_write_vectored(),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            FaucetStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            FaucetStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            FaucetStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            FaucetStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/server/testdata/cert.pem");
+    const TEST_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/server/testdata/key.pem");
+
+    fn provider() -> CryptoProvider {
+        rustls::crypto::aws_lc_rs::default_provider()
+    }
+
+    #[test]
+    fn load_certified_key_from_pem_files() {
+        let config = TlsConfig::new(TEST_CERT, TEST_KEY);
+        let key = load_certified_key(&config, &provider()).unwrap();
+        assert_eq!(key.cert.len(), 1);
+    }
+
+    #[test]
+    fn load_certified_key_missing_file() {
+        let config = TlsConfig::new("/does/not/exist.pem", TEST_KEY);
+        let err = load_certified_key(&config, &provider()).unwrap_err();
+        assert!(matches!(err, FaucetError::Tls(_)));
+    }
+
+    #[test]
+    fn load_certified_key_swapped_files() {
+        // A key file with no private key in it should be rejected
+        let config = TlsConfig::new(TEST_CERT, TEST_CERT);
+        let err = load_certified_key(&config, &provider()).unwrap_err();
+        assert!(matches!(err, FaucetError::Tls(_)));
+    }
+
+    #[test]
+    fn tls_config_deserializes_from_toml() {
+        let config: TlsConfig = toml::from_str(
+            r#"
+            cert = "cert.pem"
+            key = "key.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cert, PathBuf::from("cert.pem"));
+        assert_eq!(config.key, PathBuf::from("key.pem"));
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_with_built_acceptor() {
+        let shutdown: &'static ShutdownSignal = crate::leak!(ShutdownSignal::new());
+        let acceptor = TlsConfig::new(TEST_CERT, TEST_KEY)
+            .build_acceptor(shutdown)
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let stream = FaucetStream::accept(tcp, Some(&acceptor)).await.unwrap();
+            assert!(matches!(stream, FaucetStream::Tls(_)));
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        let mut reader = BufReader::new(std::fs::File::open(TEST_CERT).unwrap());
+        for cert in rustls_pemfile::certs(&mut reader) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        connector
+            .connect("localhost".try_into().unwrap(), tcp)
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn stalled_handshakes_time_out() {
+        let shutdown: &'static ShutdownSignal = crate::leak!(ShutdownSignal::new());
+        let acceptor = TlsConfig::new(TEST_CERT, TEST_KEY)
+            .build_acceptor(shutdown)
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Connects, but never sends a ClientHello.
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (tcp, _) = listener.accept().await.unwrap();
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            FaucetStream::accept_within(tcp, Some(&acceptor), Duration::from_millis(50)),
+        )
+        .await
+        .expect("the handshake should time out on its own");
+        assert!(matches!(result, Err(FaucetError::Tls(_))));
+        shutdown.shutdown();
+    }
+}
@@ -5,6 +5,7 @@ use tokio::sync::Notify;
 use crate::leak;
 
 const WAIT_STOP_PRINT: std::time::Duration = std::time::Duration::from_secs(5);
+const WAIT_STOP_RECHECK: std::time::Duration = std::time::Duration::from_millis(50);
 
 pub struct ShutdownSignal {
     is_shutdown: AtomicBool,
@@ -39,29 +40,72 @@ impl ShutdownSignal {
     }
 }
 
-pub fn graceful() -> &'static ShutdownSignal {
+/// Blocks until every active connection is closed and then shuts down.
+pub fn wait_for_connections_and_shutdown(signal: &'static ShutdownSignal) {
     use crate::global_conn::current_connections;
 
+    log::info!(target: "faucet", "Received stop signal, waiting for all users to disconnect");
+    let mut last_5_sec = std::time::Instant::now();
+    while current_connections() > 0 {
+        std::thread::yield_now();
+        if last_5_sec.elapsed() > WAIT_STOP_PRINT {
+            log::info!(
+                target: "faucet",
+                "Active connections = {}, waiting for all connections to stop.",
+                current_connections()
+            );
+            last_5_sec = std::time::Instant::now();
+        }
+    }
+    signal.shutdown();
+}
+
+/// Waits, without blocking the runtime, until every active connection is
+/// closed and then shuts down.
+pub async fn drain_and_shutdown(signal: &'static ShutdownSignal) {
+    use crate::global_conn::current_connections;
+
+    log::info!(target: "faucet", "Received stop signal, waiting for all users to disconnect");
+    let mut last_5_sec = tokio::time::Instant::now();
+    while current_connections() > 0 {
+        tokio::time::sleep(WAIT_STOP_RECHECK).await;
+        if last_5_sec.elapsed() > WAIT_STOP_PRINT {
+            log::info!(
+                target: "faucet",
+                "Active connections = {}, waiting for all connections to stop.",
+                current_connections()
+            );
+            last_5_sec = tokio::time::Instant::now();
+        }
+    }
+    signal.shutdown();
+}
+
+/// Runs `handler` on SIGTERM. It is not left to `ctrlc` because its
+/// termination handler also takes SIGHUP, which triggers rolling restarts
+/// and router reloads.
+#[cfg(unix)]
+fn on_terminate(handler: impl Fn() + Send + Sync + 'static) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Unable to set term handler. This is a bug");
+    tokio::spawn(async move {
+        if terminate.recv().await.is_some() {
+            let _ = tokio::task::spawn_blocking(handler).await;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn on_terminate(_handler: impl Fn() + Send + Sync + 'static) {}
+
+pub fn graceful() -> &'static ShutdownSignal {
     let signal = leak!(ShutdownSignal::new()) as &'static ShutdownSignal;
 
     {
-        ctrlc::set_handler(move || {
-        log::info!(target: "faucet", "Received stop signal, waiting for all users to disconnect");
-        let mut last_5_sec = std::time::Instant::now();
-        while current_connections() > 0 {
-            std::thread::yield_now();
-            if last_5_sec.elapsed() > WAIT_STOP_PRINT {
-                log::info!(
-                    target: "faucet",
-                    "Active connections = {}, waiting for all connections to stop.",
-                    current_connections()
-                );
-                last_5_sec = std::time::Instant::now();
-            }
-        }
-        signal.shutdown();
-    })
-    .expect("Unable to set term handler. This is a bug");
+        ctrlc::set_handler(move || wait_for_connections_and_shutdown(signal))
+            .expect("Unable to set term handler. This is a bug");
+        on_terminate(move || wait_for_connections_and_shutdown(signal));
     }
 
     signal
@@ -70,11 +114,12 @@ pub fn graceful() -> &'static ShutdownSignal {
 pub fn immediate() -> &'static ShutdownSignal {
     let signal = leak!(ShutdownSignal::new()) as &'static ShutdownSignal;
     {
-        ctrlc::set_handler(move || {
+        let handler = move || {
             log::info!(target: "faucet", "Starting immediate shutdown handle");
             signal.shutdown()
-        })
-        .expect("Unable to set term handler. This is a bug");
+        };
+        ctrlc::set_handler(handler).expect("Unable to set term handler. This is a bug");
+        on_terminate(handler);
     }
     signal
 }
@@ -0,0 +1,553 @@
+//! Prometheus metrics rendered in the text exposition format.
+//!
+//! Request metrics are fed from the same [`HttpLogData`] used for logging and
+//! PostgreSQL telemetry. Gauges about workers and connections are read from
+//! their source of truth at scrape time.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex,
+    },
+};
+
+use hyper::Method;
+
+use crate::{
+    client::{load_balancing::queue::RequestQueue, worker::WorkerConfig, WebSocketSessionInfo},
+    global_conn::current_connections,
+    server::HttpLogData,
+    shutdown::ShutdownSignal,
+};
+
+/// Upper bounds (in seconds) of the request duration histogram buckets.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RequestKey {
+    route: &'static str,
+    worker: usize,
+    method: Method,
+    status: i16,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RpsValue {
+    rps: f64,
+    max_rps: f64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    requests: Mutex<HashMap<RequestKey, Histogram>>,
+    rps: Mutex<HashMap<(&'static str, usize), RpsValue>>,
+    /// Request queues by route. A route started again by a reload replaces
+    /// the queue of the previous one.
+    queues: Mutex<BTreeMap<&'static str, &'static RequestQueue>>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+// Nothing is recorded unless a listener serves the metrics.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn route_label(route: Option<&'static str>) -> &'static str {
+    route.unwrap_or("/")
+}
+
+/// Records a finished HTTP request.
+pub fn record_http_request(data: &HttpLogData) {
+    if !is_enabled() {
+        return;
+    }
+    let key = RequestKey {
+        route: route_label(data.state_data.worker_route),
+        worker: data.state_data.worker_id,
+        method: data.method.clone(),
+        status: data.status,
+    };
+    let seconds = data.elapsed as f64 / 1000.0;
+    METRICS
+        .requests
+        .lock()
+        .expect("Metrics lock poisoned")
+        .entry(key)
+        .or_default()
+        .observe(seconds);
+}
+
+/// Stores the latest requests per second computed by the RPS autoscaler for a worker.
+pub(crate) fn set_worker_rps(worker: &WorkerConfig, rps: f64, max_rps: f64) {
+    if !is_enabled() {
+        return;
+    }
+    METRICS.rps.lock().expect("Metrics lock poisoned").insert(
+        (route_label(worker.worker_route), worker.worker_id),
+        RpsValue { rps, max_rps },
+    );
+}
+
+/// Adds the request queue of a route to the metrics until `shutdown`
+/// stops the route.
+pub(crate) fn register_queue(queue: &'static RequestQueue, shutdown: &'static ShutdownSignal) {
+    METRICS
+        .queues
+        .lock()
+        .expect("Metrics lock poisoned")
+        .insert(route_label(queue.route), queue);
+    tokio::spawn(async move {
+        shutdown.wait().await;
+        unregister_queue(queue);
+    });
+}
+
+/// Removes `queue` from the metrics, unless a newer queue replaced it.
+fn unregister_queue(queue: &'static RequestQueue) {
+    let mut queues = METRICS.queues.lock().expect("Metrics lock poisoned");
+    let route = route_label(queue.route);
+    if queues
+        .get(route)
+        .is_some_and(|registered| std::ptr::eq(*registered, queue))
+    {
+        queues.remove(route);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn render_requests(out: &mut String) {
+    let requests = METRICS.requests.lock().expect("Metrics lock poisoned");
+    let mut keys: Vec<&RequestKey> = requests.keys().collect();
+    keys.sort_by(|a, b| {
+        (a.route, a.worker, a.method.as_str(), a.status).cmp(&(
+            b.route,
+            b.worker,
+            b.method.as_str(),
+            b.status,
+        ))
+    });
+
+    let labels = |key: &RequestKey| {
+        format!(
+            r#"route="{}",worker="{}",method="{}",status="{}""#,
+            escape_label(key.route),
+            key.worker,
+            escape_label(key.method.as_str()),
+            key.status
+        )
+    };
+
+    header(
+        out,
+        "faucet_http_requests_total",
+        "counter",
+        "Total HTTP requests proxied to the workers.",
+    );
+    for key in &keys {
+        let _ = writeln!(
+            out,
+            "faucet_http_requests_total{{{}}} {}",
+            labels(key),
+            requests[*key].count
+        );
+    }
+
+    header(
+        out,
+        "faucet_http_request_duration_seconds",
+        "histogram",
+        "Time to get the response headers from the workers.",
+    );
+    for key in &keys {
+        let histogram = &requests[*key];
+        let labels = labels(key);
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                r#"faucet_http_request_duration_seconds_bucket{{{labels},le="{bound}"}} {count}"#
+            );
+        }
+        let _ = writeln!(
+            out,
+            r#"faucet_http_request_duration_seconds_bucket{{{labels},le="+Inf"}} {}"#,
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "faucet_http_request_duration_seconds_sum{{{labels}}} {}",
+            histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "faucet_http_request_duration_seconds_count{{{labels}}} {}",
+            histogram.count
+        );
+    }
+}
+
+fn render_workers(out: &mut String, workers: &[&WorkerConfig]) {
+    let labels = |worker: &WorkerConfig| {
+        format!(
+            r#"route="{}",worker="{}""#,
+            escape_label(route_label(worker.worker_route)),
+            worker.worker_id
+        )
+    };
+
+    header(
+        out,
+        "faucet_worker_online",
+        "gauge",
+        "Whether the worker is receiving traffic (1) or not (0).",
+    );
+    for worker in workers {
+        let online = worker.is_online.load(Ordering::SeqCst) as u8;
+        let _ = writeln!(out, "faucet_worker_online{{{}}} {online}", labels(worker));
+    }
+
+    header(
+        out,
+        "faucet_worker_restarts_total",
+        "counter",
+        "Times the worker's process was started again.",
+    );
+    for worker in workers {
+        let restarts = worker.restarts.load(Ordering::SeqCst);
+        let _ = writeln!(
+            out,
+            "faucet_worker_restarts_total{{{}}} {restarts}",
+            labels(worker)
+        );
+    }
+
+    header(
+        out,
+        "faucet_worker_in_flight",
+        "gauge",
+        "Requests and WebSocket sessions the worker is serving.",
+    );
+    for worker in workers {
+        let in_flight = worker.in_flight.load(Ordering::SeqCst);
+        let _ = writeln!(
+            out,
+            "faucet_worker_in_flight{{{}}} {in_flight}",
+            labels(worker)
+        );
+    }
+
+    header(
+        out,
+        "faucet_worker_memory_bytes",
+        "gauge",
+        "Resident memory of the worker's processes.",
+    );
+    for worker in workers {
+        let _ = writeln!(
+            out,
+            "faucet_worker_memory_bytes{{{}}} {}",
+            labels(worker),
+            worker.stats.memory()
+        );
+    }
+
+    header(
+        out,
+        "faucet_worker_cpu",
+        "gauge",
+        "CPUs used by the worker's processes.",
+    );
+    for worker in workers {
+        let _ = writeln!(
+            out,
+            "faucet_worker_cpu{{{}}} {}",
+            labels(worker),
+            worker.stats.cpu()
+        );
+    }
+
+    let rps = METRICS.rps.lock().expect("Metrics lock poisoned");
+    let rps_values = workers
+        .iter()
+        .filter_map(|worker| {
+            rps.get(&(route_label(worker.worker_route), worker.worker_id))
+                .map(|value| (labels(worker), *value))
+        })
+        .collect::<Vec<_>>();
+    if !rps_values.is_empty() {
+        header(
+            out,
+            "faucet_worker_rps",
+            "gauge",
+            "Requests per second measured by the RPS autoscaler in its last window.",
+        );
+        for (labels, value) in &rps_values {
+            let _ = writeln!(out, "faucet_worker_rps{{{labels}}} {}", value.rps);
+        }
+        header(
+            out,
+            "faucet_worker_max_rps",
+            "gauge",
+            "Requests per second above which the RPS autoscaler spawns another worker.",
+        );
+        for (labels, value) in &rps_values {
+            let _ = writeln!(out, "faucet_worker_max_rps{{{labels}}} {}", value.max_rps);
+        }
+    }
+}
+
+fn render_queues(out: &mut String) {
+    let queues = METRICS.queues.lock().expect("Metrics lock poisoned");
+    if queues.is_empty() {
+        return;
+    }
+    let route = |queue: &RequestQueue| escape_label(route_label(queue.route));
+
+    header(
+        out,
+        "faucet_queue_length",
+        "gauge",
+        "Requests waiting for a worker with a free request slot.",
+    );
+    for queue in queues.values() {
+        let _ = writeln!(
+            out,
+            r#"faucet_queue_length{{route="{}"}} {}"#,
+            route(queue),
+            queue.len()
+        );
+    }
+
+    header(
+        out,
+        "faucet_queue_rejected_total",
+        "counter",
+        "Requests answered with 503 because the queue was full or they waited too long.",
+    );
+    for queue in queues.values() {
+        let route = route(queue);
+        let _ = writeln!(
+            out,
+            r#"faucet_queue_rejected_total{{route="{route}",reason="full"}} {}"#,
+            queue.rejected_full()
+        );
+        let _ = writeln!(
+            out,
+            r#"faucet_queue_rejected_total{{route="{route}",reason="timeout"}} {}"#,
+            queue.rejected_timeout()
+        );
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub fn render(workers: &[&WorkerConfig], sessions: &[WebSocketSessionInfo]) -> String {
+    let mut out = String::new();
+
+    render_requests(&mut out);
+
+    header(
+        &mut out,
+        "faucet_active_connections",
+        "gauge",
+        "Connections currently open to the workers.",
+    );
+    let _ = writeln!(out, "faucet_active_connections {}", current_connections());
+
+    header(
+        &mut out,
+        "faucet_websocket_sessions",
+        "gauge",
+        "WebSocket sessions held by faucet, by whether a client is attached.",
+    );
+    let connected = sessions.iter().filter(|s| s.connected).count();
+    let _ = writeln!(
+        out,
+        r#"faucet_websocket_sessions{{state="connected"}} {connected}"#
+    );
+    let _ = writeln!(
+        out,
+        r#"faucet_websocket_sessions{{state="idle"}} {}"#,
+        sessions.len() - connected
+    );
+
+    render_workers(&mut out, workers);
+    render_queues(&mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        leak,
+        server::{logging::StateData, LogOption},
+    };
+    use hyper::{Uri, Version};
+
+    fn log_data(route: Option<&'static str>, status: i16, elapsed: i64) -> HttpLogData {
+        HttpLogData {
+            state_data: StateData {
+                uuid: uuid::Uuid::now_v7(),
+                ip: [127, 0, 0, 1].into(),
+                worker_route: route,
+                worker_id: 1,
+                target: "Worker::1",
+            },
+            method: Method::GET,
+            path: Uri::from_static("/"),
+            version: Version::HTTP_11,
+            status,
+            user_agent: LogOption::None,
+            elapsed,
+        }
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(20.0);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[5], 2);
+        assert_eq!(histogram.buckets[10], 2);
+        assert_eq!(histogram.count, 3);
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape_label("a\nb"), r"a\nb");
+    }
+
+    #[tokio::test]
+    async fn render_requests_and_workers() {
+        enable();
+        record_http_request(&log_data(Some("/metrics-test/"), 200, 30));
+        record_http_request(&log_data(Some("/metrics-test/"), 200, 3000));
+        record_http_request(&log_data(Some("/metrics-test/"), 500, 1));
+
+        let mut worker = WorkerConfig::dummy("Worker::1", "127.0.0.1:9999", true);
+        worker.worker_route = Some("/metrics-test/");
+        let worker: &'static WorkerConfig = leak!(worker);
+        worker.restarts.store(2, Ordering::SeqCst);
+        set_worker_rps(worker, 1.5, 10.0);
+        let policy = leak!(crate::client::QueueConfig {
+            max_concurrency: Some(1),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap());
+        register_queue(
+            leak!(RequestQueue::new(
+                policy,
+                Some("/metrics-test/"),
+                leak!(tokio::sync::Notify::new())
+            )),
+            leak!(ShutdownSignal::new()),
+        );
+
+        let output = render(&[worker], &[]);
+
+        let labels = r#"route="/metrics-test/",worker="1""#;
+        assert!(output.contains(&format!(
+            r#"faucet_http_requests_total{{{labels},method="GET",status="200"}} 2"#
+        )));
+        assert!(output.contains(&format!(
+            r#"faucet_http_requests_total{{{labels},method="GET",status="500"}} 1"#
+        )));
+        assert!(output.contains(&format!(
+            r#"faucet_http_request_duration_seconds_bucket{{{labels},method="GET",status="200",le="0.05"}} 1"#
+        )));
+        assert!(output.contains(&format!(
+            r#"faucet_http_request_duration_seconds_bucket{{{labels},method="GET",status="200",le="+Inf"}} 2"#
+        )));
+        assert!(output.contains(&format!("faucet_worker_online{{{labels}}} 1")));
+        assert!(output.contains(&format!("faucet_worker_restarts_total{{{labels}}} 2")));
+        assert!(output.contains(&format!("faucet_worker_in_flight{{{labels}}} 0")));
+        assert!(output.contains(&format!("faucet_worker_memory_bytes{{{labels}}} 0")));
+        assert!(output.contains(&format!("faucet_worker_cpu{{{labels}}} 0")));
+        assert!(output.contains(&format!("faucet_worker_rps{{{labels}}} 1.5")));
+        assert!(output.contains(&format!("faucet_worker_max_rps{{{labels}}} 10")));
+        assert!(output.contains(r#"faucet_websocket_sessions{state="connected"} 0"#));
+        assert!(output.contains(r#"faucet_queue_length{route="/metrics-test/"} 0"#));
+        assert!(output
+            .contains(r#"faucet_queue_rejected_total{route="/metrics-test/",reason="full"} 0"#));
+        assert!(output.contains("# TYPE faucet_http_request_duration_seconds histogram"));
+    }
+    #[tokio::test]
+    async fn queues_are_rendered_once_per_route() {
+        let policy = leak!(crate::client::QueueConfig {
+            max_concurrency: Some(1),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap());
+        let queue = || -> &'static RequestQueue {
+            leak!(RequestQueue::new(
+                policy,
+                Some("/reloaded-test/"),
+                leak!(tokio::sync::Notify::new())
+            ))
+        };
+        let series = r#"faucet_queue_length{route="/reloaded-test/"}"#;
+        let count = || render(&[], &[]).matches(series).count();
+
+        // A reload starts the route again before the old one is drained.
+        let old_shutdown: &'static ShutdownSignal = leak!(ShutdownSignal::new());
+        let new_shutdown: &'static ShutdownSignal = leak!(ShutdownSignal::new());
+        register_queue(queue(), old_shutdown);
+        register_queue(queue(), new_shutdown);
+        assert_eq!(count(), 1);
+
+        // Draining the old route keeps the queue of the new one.
+        old_shutdown.shutdown();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(count(), 1);
+
+        new_shutdown.shutdown();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(count(), 0);
+    }
+}
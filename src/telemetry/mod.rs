@@ -1,5 +1,6 @@
 use std::{path::Path, str::FromStr, sync::OnceLock};
 
+pub mod metrics;
 mod pg;
 
 use chrono::Local;
@@ -92,17 +93,19 @@ impl TelemetryManager {
     }
 }
 
+type EventHandles = (
+    UnboundedSender<(chrono::DateTime<Local>, HttpLogData)>,
+    UnboundedSender<(chrono::DateTime<Local>, EventLogData)>,
+    JoinHandle<()>,
+    JoinHandle<()>,
+);
+
 fn handle_http_events(
     pool: Pool,
     namespace: &'static str,
     version: Option<&'static str>,
     shutdown_signal: &'static ShutdownSignal,
-) -> (
-    UnboundedSender<(chrono::DateTime<Local>, HttpLogData)>,
-    UnboundedSender<(chrono::DateTime<Local>, EventLogData)>,
-    JoinHandle<()>,
-    JoinHandle<()>,
-) {
+) -> EventHandles {
     let (http_tx, http_rx) = tokio::sync::mpsc::unbounded_channel::<(_, HttpLogData)>();
     let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel::<(_, EventLogData)>();
 